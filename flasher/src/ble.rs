@@ -0,0 +1,188 @@
+//! BLE link to a device built with the firmware's `ble_update` feature
+//! (the `ble` cargo feature here; btleplug brings an async runtime
+//! along, so it stays optional).
+//!
+//! The firmware's GATT server speaks the Nordic UART Service layout:
+//! writes to one characteristic carry host -> MCU bytes, notifications
+//! on the other carry MCU -> host bytes. The protocol on top is
+//! byte-for-byte the serial one - both ends reassemble frames with the
+//! usual accumulation, so the ATT-sized chunks this link writes and the
+//! notification-sized chunks it receives need no framing of their own.
+//!
+//! btleplug's API is async; the link owns a small runtime and blocks on
+//! each operation, which is exactly the discipline [`Transport`]
+//! callers expect. Notifications are pumped into a channel by a
+//! background task so bytes arriving between reads are not lost.
+
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use btleplug::api::{
+    Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType,
+};
+use btleplug::platform::{Manager, Peripheral};
+use futures::StreamExt;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use messages::transport::Transport;
+
+/// Nordic UART Service characteristics, as the firmware registers them:
+/// `...0002` takes writes, `...0003` notifies.
+const WRITE_UUID: Uuid = Uuid::from_u128(0x6e400002_b5a3_f393_e0a9_e50e24dcca9e);
+const NOTIFY_UUID: Uuid = Uuid::from_u128(0x6e400003_b5a3_f393_e0a9_e50e24dcca9e);
+
+/// How long to scan for the named device before giving up.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Payload bytes per ATT write. btleplug does not expose the negotiated
+/// MTU, so writes stay within the 23-byte floor every link supports;
+/// the MTU only affects throughput, not correctness.
+const ATT_CHUNK: usize = 20;
+
+pub struct BleLink {
+    runtime: Runtime,
+    peripheral: Peripheral,
+    write: Characteristic,
+    /// Notification payloads pumped in by the background task.
+    rx: mpsc::Receiver<Vec<u8>>,
+    /// Bytes of a notification not yet handed to a caller.
+    pending: Vec<u8>,
+}
+
+impl BleLink {
+    /// Scans for the device advertising `name`, connects, and hooks up
+    /// the two NUS characteristics.
+    pub fn connect(name: &str) -> Result<Self> {
+        let runtime = Runtime::new().context("Cannot start the BLE runtime")?;
+
+        let (peripheral, write, notify) = runtime.block_on(find_device(name))?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut notifications = runtime.block_on(peripheral.notifications())?;
+        runtime.block_on(peripheral.subscribe(&notify))?;
+
+        runtime.spawn(async move {
+            while let Some(notification) = notifications.next().await {
+                if tx.send(notification.value).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            runtime,
+            peripheral,
+            write,
+            rx,
+            pending: Vec::new(),
+        })
+    }
+}
+
+/// Finds the named peripheral on the first adapter and returns it
+/// connected, with the write and notify characteristics resolved.
+async fn find_device(name: &str) -> Result<(Peripheral, Characteristic, Characteristic)> {
+    let manager = Manager::new().await?;
+    let adapter = manager
+        .adapters()
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No Bluetooth adapter found"))?;
+
+    adapter.start_scan(ScanFilter::default()).await?;
+
+    let deadline = Instant::now() + SCAN_TIMEOUT;
+    let peripheral = 'scan: loop {
+        for peripheral in adapter.peripherals().await? {
+            let advertised = peripheral
+                .properties()
+                .await?
+                .and_then(|props| props.local_name);
+
+            if advertised.as_deref() == Some(name) {
+                break 'scan peripheral;
+            }
+        }
+
+        if Instant::now() >= deadline {
+            bail!("No BLE device named {:?} found", name);
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    };
+    adapter.stop_scan().await.ok();
+
+    peripheral
+        .connect()
+        .await
+        .with_context(|| format!("Cannot connect to {:?}", name))?;
+    peripheral.discover_services().await?;
+
+    let find = |uuid: Uuid, role: &str| {
+        peripheral
+            .characteristics()
+            .into_iter()
+            .find(|characteristic| characteristic.uuid == uuid)
+            .ok_or_else(|| anyhow!("Device lacks the update {} characteristic", role))
+    };
+    let write = find(WRITE_UUID, "write")?;
+    let notify = find(NOTIFY_UUID, "notify")?;
+
+    Ok((peripheral, write, notify))
+}
+
+impl Transport for BleLink {
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        // Wait for the first notification only; whatever else is
+        // already queued behind it is drained without waiting, like a
+        // serial port read returning a burst
+        if self.pending.is_empty() {
+            match self.rx.recv_timeout(timeout) {
+                Ok(bytes) => self.pending = bytes,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        while let Ok(more) = self.rx.try_recv() {
+            self.pending.extend(more);
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        // Every ATT write is acknowledged at the transport layer, so
+        // there is no pacing to do beyond the chunking itself
+        for chunk in buf.chunks(ATT_CHUNK) {
+            self.runtime
+                .block_on(
+                    self.peripheral
+                        .write(&self.write, chunk, WriteType::WithResponse),
+                )
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BleLink {
+    fn drop(&mut self) {
+        let _ = self.runtime.block_on(self.peripheral.disconnect());
+    }
+}
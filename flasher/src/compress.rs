@@ -0,0 +1,96 @@
+//! Heatshrink compression helpers.
+//!
+//! The parameters are shared with the firmware via the `messages` crate; if
+//! they ever change, both sides have to move in lockstep.
+//!
+//! Every segment is a self-contained heatshrink stream: no encoder or
+//! decoder state crosses a segment boundary. That costs a little ratio
+//! against one stream spanning the whole image, but it means a
+//! retransmitted segment decodes from a fresh decoder on the device -
+//! there is no stream position to corrupt and nothing to reset on retry.
+
+use anyhow::{anyhow, Result};
+
+use messages::{HEATSHRINK_LOOKAHEAD_SZ2, HEATSHRINK_WINDOW_SZ2};
+
+fn config() -> heatshrink::Config {
+    heatshrink::Config::new(HEATSHRINK_WINDOW_SZ2, HEATSHRINK_LOOKAHEAD_SZ2)
+        .expect("valid heatshrink parameters")
+}
+
+/// Compresses `raw`, returning `None` if the block is incompressible (the
+/// caller then sends it as a plain segment instead).
+pub fn encode(raw: &[u8]) -> Option<Vec<u8>> {
+    // Worst case heatshrink inflates the input slightly
+    let mut out = vec![0_u8; raw.len() + raw.len() / 2 + 4];
+
+    match heatshrink::encode(raw, &mut out, &config()) {
+        Ok(used) => Some(used.to_vec()),
+        Err(_) => None,
+    }
+}
+
+/// Decompresses a block of known raw length; used by the simulator.
+pub fn decode(data: &[u8], raw_len: usize) -> Result<Vec<u8>> {
+    // One spare byte: heatshrink 0.2 reports `OutputFull` when the
+    // output lands exactly at the end of the buffer
+    let mut out = vec![0_u8; raw_len + 1];
+
+    let used = heatshrink::decode(data, &mut out, &config())
+        .map_err(|err| anyhow!("Heatshrink decode failed: {:?}", err))?;
+
+    if used.len() != raw_len {
+        return Err(anyhow!(
+            "Decompressed length mismatch: expected {}, got {}",
+            raw_len,
+            used.len()
+        ));
+    }
+
+    out.truncate(raw_len);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressible() -> Vec<u8> {
+        // Repetitive enough that heatshrink actually wins
+        b"0123456789abcdef".repeat(16)
+    }
+
+    #[test]
+    fn segments_round_trip() {
+        let raw = compressible();
+        let encoded = encode(&raw).unwrap();
+
+        assert!(encoded.len() < raw.len());
+        assert_eq!(decode(&encoded, raw.len()).unwrap(), raw);
+    }
+
+    // The property the per-segment scheme buys: decoding is a pure
+    // function of the segment bytes, so a retransmission decodes
+    // identically no matter what was (or was not) decoded before it
+    #[test]
+    fn a_retransmitted_segment_decodes_identically() {
+        let raw = compressible();
+        let encoded = encode(&raw).unwrap();
+
+        let first = decode(&encoded, raw.len()).unwrap();
+
+        // A corrupt frame in between leaves no decoder state behind
+        decode(&encoded[..encoded.len() / 2], raw.len()).unwrap_err();
+
+        assert_eq!(decode(&encoded, raw.len()).unwrap(), first);
+    }
+
+    #[test]
+    fn truncated_input_or_a_wrong_raw_length_is_rejected() {
+        let raw = compressible();
+        let encoded = encode(&raw).unwrap();
+
+        assert!(decode(&encoded[..4], raw.len()).is_err());
+        assert!(decode(&encoded, raw.len() - 1).is_err());
+    }
+}
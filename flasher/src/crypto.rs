@@ -0,0 +1,73 @@
+//! Key handling for segment encryption.
+//!
+//! The cipher itself (ChaCha20-Poly1305) lives in [`messages::crypto`]
+//! so the device decrypts through the exact code path used here; this
+//! module only loads key files and adapts the errors. Key material is
+//! kept inside [`Zeroizing`] buffers so it is wiped when the flasher
+//! exits, and it is never echoed to the trace output.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use zeroize::Zeroizing;
+
+use messages::NONCE_PREFIX_LEN;
+
+pub use messages::crypto::KEY_LEN;
+
+/// Loads a 32-byte key from `path`, accepting either raw bytes or a hex
+/// string (surrounding whitespace ignored).
+pub fn load_key(path: &Path) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let raw = Zeroizing::new(
+        fs::read(path).with_context(|| format!("Cannot read key file {}", path.display()))?,
+    );
+
+    let mut key = Zeroizing::new([0_u8; KEY_LEN]);
+
+    if raw.len() == KEY_LEN {
+        key.copy_from_slice(&raw);
+        return Ok(key);
+    }
+
+    let hex: String = raw
+        .iter()
+        .map(|&b| b as char)
+        .filter(|c| !c.is_whitespace())
+        .collect();
+
+    if hex.len() == KEY_LEN * 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        for (i, chunk) in hex.as_bytes().chunks(2).enumerate() {
+            let s = std::str::from_utf8(chunk).unwrap();
+            key[i] = u8::from_str_radix(s, 16).unwrap();
+        }
+        return Ok(key);
+    }
+
+    bail!(
+        "Key file {} must contain exactly {} raw bytes or {} hex digits",
+        path.display(),
+        KEY_LEN,
+        KEY_LEN * 2
+    );
+}
+
+pub fn encrypt_segment(
+    key: &[u8; KEY_LEN],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    id: u16,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    Ok(messages::crypto::seal_segment(key, prefix, id, plaintext))
+}
+
+/// Decrypts and authenticates a segment; used by the simulator.
+pub fn decrypt_segment(
+    key: &[u8; KEY_LEN],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    id: u16,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    messages::crypto::open_segment(key, prefix, id, ciphertext)
+        .map_err(|_| anyhow!("Segment {} failed authentication", id))
+}
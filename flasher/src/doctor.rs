@@ -0,0 +1,167 @@
+//! Bad-connection diagnosis: the checks behind the `doctor` subcommand.
+//!
+//! Wrong baud rate, TX/RX swapped, a device that is not running this
+//! firmware at all - to the flash loop they all look like the same read
+//! timeout. Each check here is an independent function over the
+//! [`Transport`] abstraction, so the subcommand can walk them in order
+//! and name the first thing actually wrong, and the tests can drive
+//! each against a simulator misbehaving in exactly that way.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use messages::transport::Transport;
+use messages::{codec, Info, MessageTypeHost, MessageTypeMcu, PROTOCOL_VERSION};
+
+use crate::{send_message, FrameReader, Stats};
+
+/// How long each probe listens before giving its verdict: long enough
+/// for a device busy booting, short enough to walk every check quickly.
+pub const PROBE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Baud rates worth probing when the configured one only yields noise.
+pub const COMMON_BAUDS: &[u32] = &[115_200, 460_800, 921_600];
+
+/// What listening on the line turned up, worst to best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineProbe {
+    /// Not a single byte arrived: the device is off, not connected, or
+    /// its TX never reaches our RX.
+    Silent,
+    /// Bytes arrived but none decoded as a protocol frame: usually the
+    /// wrong baud rate, sometimes a device speaking something else.
+    Noise,
+    /// At least one checksummed frame decoded; the line itself is fine.
+    Frames,
+}
+
+/// Listens for `window` after poking the device with a `Ping`, so even
+/// a device with nothing to say has a reason to talk. The scan looks
+/// for a frame at every offset: boot-log noise in front of the first
+/// frame must not hide it.
+pub fn probe_line<S: Transport>(link: &mut S, window: Duration) -> Result<LineProbe> {
+    send_message(link, &MessageTypeHost::Ping)?;
+
+    let deadline = Instant::now() + window;
+    let mut accumulated: Vec<u8> = Vec::new();
+    let mut buf = [0_u8; 256];
+
+    loop {
+        for start in 0..accumulated.len() {
+            if codec::take::<MessageTypeMcu>(&accumulated[start..]).is_ok() {
+                return Ok(LineProbe::Frames);
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        match link.read_available(&mut buf, deadline - now) {
+            // A closed link has told us all it ever will
+            Ok(0) => break,
+            Ok(n) => accumulated.extend_from_slice(&buf[..n]),
+            Err(ref err) if err.kind() == std::io::ErrorKind::TimedOut => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(if accumulated.is_empty() {
+        LineProbe::Silent
+    } else {
+        LineProbe::Noise
+    })
+}
+
+/// Whether a `Ping` earns any pong within `window`; mirrored logs and
+/// stale replies are skipped, not answers.
+pub fn probe_pong<S: Transport>(link: &mut S, window: Duration) -> Result<bool> {
+    send_message(link, &MessageTypeHost::Ping)?;
+
+    let mut reader = FrameReader::new();
+    let mut stats = Stats::default();
+    let deadline = Instant::now() + window;
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+
+        match reader.read_message(link, deadline - now, &mut stats) {
+            Ok(MessageTypeMcu::Pong) | Ok(MessageTypeMcu::TimedPong { .. }) => return Ok(true),
+            Ok(_) => (),
+            Err(_) => return Ok(false),
+        }
+    }
+}
+
+/// The device's `Info`, or `None` when `GetInfo` goes unanswered - old
+/// firmware, or not this firmware at all.
+pub fn probe_info<S: Transport>(link: &mut S, window: Duration) -> Result<Option<Info>> {
+    send_message(link, &MessageTypeHost::GetInfo)?;
+
+    let mut reader = FrameReader::new();
+    let mut stats = Stats::default();
+    let deadline = Instant::now() + window;
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+
+        match reader.read_message(link, deadline - now, &mut stats) {
+            Ok(MessageTypeMcu::Info(info)) => return Ok(Some(info)),
+            Ok(_) => (),
+            Err(_) => return Ok(None),
+        }
+    }
+}
+
+/// `None` when the protocol versions match, otherwise the mismatch.
+pub fn protocol_mismatch(info: &Info) -> Option<String> {
+    if info.protocol_version == PROTOCOL_VERSION {
+        return None;
+    }
+
+    Some(format!(
+        "device speaks protocol {}, this flasher speaks {}",
+        info.protocol_version, PROTOCOL_VERSION
+    ))
+}
+
+/// Whether the device reports nowhere to put an update: no slot size
+/// and no update slot means a partition table without OTA slots.
+pub fn missing_ota_slot(info: &Info) -> bool {
+    info.slot_size.is_none() && info.update_slot.is_none()
+}
+
+/// Everything the probes found, for the subcommand to narrate.
+#[derive(Debug)]
+pub struct Report {
+    pub line: LineProbe,
+    pub pong: bool,
+    pub info: Option<Info>,
+}
+
+/// Walks the probes in order. Once the line itself fails, the rest
+/// cannot pass and are not attempted.
+pub fn run<S: Transport>(link: &mut S, window: Duration) -> Result<Report> {
+    let line = probe_line(link, window)?;
+
+    if line != LineProbe::Frames {
+        return Ok(Report {
+            line,
+            pong: false,
+            info: None,
+        });
+    }
+
+    let pong = probe_pong(link, window)?;
+    let info = probe_info(link, window)?;
+
+    Ok(Report { line, pong, info })
+}
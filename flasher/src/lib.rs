@@ -0,0 +1,1314 @@
+//! Host-side client for the serial update protocol.
+//!
+//! The binary in `main.rs` is a thin CLI on top of this; keeping the logic
+//! here lets the integration tests drive it against the [`simulator`],
+//! and other host tools drive updates through [`flash`] directly. Any
+//! [`Transport`] works: the [`serial`] and [`tcp`] links here, or an
+//! arbitrary `Read + Write` type behind [`IoLink`].
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use zeroize::Zeroizing;
+
+use messages::{
+    codec, Caps, DeltaBase, DeltaOp, FailureReason, MessageTypeHost, MessageTypeMcu, Status,
+    UpdateEnd, UpdateRecord, UpdateSegment, UpdateSegmentCompressed, UpdateSegmentDelta,
+    UpdateSegmentEncrypted, UpdateStart, HASH_LEN, NONCE_PREFIX_LEN, PROTOCOL_VERSION,
+    SEGMENT_SIZE, SEGMENT_SIZE_FLOW_CONTROLLED,
+};
+
+#[cfg(feature = "ble")]
+pub mod ble;
+pub mod compress;
+pub mod crypto;
+pub mod delta;
+pub mod doctor;
+pub mod manifest;
+pub mod ports;
+pub mod profile;
+mod progress;
+pub mod reconnect;
+pub mod record;
+pub mod serial;
+pub mod shell;
+pub mod sign;
+pub mod simulator;
+pub mod stats;
+pub mod tcp;
+pub mod version;
+
+pub use messages::transport::{IoLink, Transport};
+pub use stats::Stats;
+
+/// How long to wait for a reply from the device.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a failed segment is retransmitted before giving up.
+pub const SEGMENT_RETRIES: u32 = 3;
+
+/// When the send loop retransmits and when it gives up. The defaults
+/// suit a short bench cable; every field is a `flash` flag, so a long
+/// or flaky line gets a looser policy without recompiling.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retransmissions per segment before the transfer is abandoned.
+    pub max_retries: u32,
+    /// Pause before retransmitting after a lost or refused ack, giving
+    /// a glitching link a moment to settle instead of hammering it.
+    /// Backpressure waits the device asks for (`Busy`) are separate
+    /// and never delayed further.
+    pub backoff: Duration,
+    /// Override [`RESPONSE_TIMEOUT`], the silence - no reply, no Pong -
+    /// after which an ack is declared lost.
+    pub ack_timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: SEGMENT_RETRIES,
+            backoff: Duration::ZERO,
+            ack_timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sits out the backoff before a retransmission; a no-op by default.
+    fn settle(&self) {
+        if !self.backoff.is_zero() {
+            std::thread::sleep(self.backoff);
+        }
+    }
+}
+
+/// How long to wait for the `GetInfo` reply; old firmware never answers,
+/// so this is kept short.
+pub const INFO_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long to wait for the `HelloAck`; firmware that predates the
+/// handshake never answers, so this is kept short for the same reason.
+pub const HELLO_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// What this client can do, announced in `Hello`. Everything here has a
+/// code path below; the device is free to ignore any of it.
+pub const HOST_CAPABILITIES: Caps = Caps::from_bits(
+    Caps::COMPRESSED_SEGMENTS.bits()
+        | Caps::ENCRYPTED_SEGMENTS.bits()
+        | Caps::DELTA_UPDATES.bits()
+        | Caps::STATS.bits(),
+);
+
+/// How long to wait for the `GetStats` reply at the end of a transfer;
+/// firmware that predates the exchange never answers, so this is kept
+/// short too.
+pub const STATS_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Ping the device after this long without any bytes while awaiting a
+/// status, instead of giving up.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Declare a timeout only after this long with neither the awaited status
+/// nor any Pong. Erasing or verifying a large image legitimately takes
+/// seconds on the device.
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct FlashOpts {
+    /// Send plain segments even if the device can decompress.
+    pub no_compress: bool,
+    /// Encrypt segments with this key; compression happens before
+    /// sealing, since the ciphertext itself would not compress.
+    pub key: Option<Zeroizing<[u8; crypto::KEY_LEN]>>,
+    /// Permit a cleartext transfer although a key was given, when the
+    /// device does not support encryption.
+    pub allow_plain: bool,
+    /// Previous image to compute a binary delta against. Ignored when
+    /// encrypting, and falls back to a full transfer when the device does
+    /// not run this exact base.
+    pub base: Option<Vec<u8>>,
+    /// Target partition label; `None` flashes the next OTA app slot.
+    pub partition: Option<String>,
+    /// Reboot the device after writing a non-app partition.
+    pub reboot: bool,
+    /// Detached Ed25519 signature to send with `UpdateEnd`.
+    pub signature: Option<Vec<u8>>,
+    /// Sign the image on the fly instead of using a precomputed signature.
+    pub sign_key: Option<ed25519_dalek::SigningKey>,
+    /// Refuse to flash devices running an app older than this.
+    pub min_version: Option<String>,
+    /// Refuse to flash devices speaking a different protocol version.
+    pub require_protocol: Option<u8>,
+    /// Downgrade version-gate refusals to warnings, for recovery.
+    pub force: bool,
+    /// Skip the transfer when the device already runs the version named
+    /// in the image's app descriptor. Only acts when both sides state a
+    /// version; partition images never match.
+    pub skip_if_same: bool,
+    /// Run the handshake and all pre-flight checks, then stop before
+    /// sending a single segment.
+    pub dry_run: bool,
+    /// Transfer nothing: ask the device for the SHA-256 of what the
+    /// target slot currently holds and compare it against the local
+    /// image, failing on a mismatch.
+    pub verify_only: bool,
+    /// Override [`KEEPALIVE_INTERVAL`].
+    pub keepalive_interval: Option<Duration>,
+    /// When to retransmit and when to give up; see [`RetryPolicy`].
+    pub retry: RetryPolicy,
+    /// The port runs RTS/CTS hardware flow control, so the larger
+    /// segment size the device may advertise is safe to use.
+    pub flow_control: bool,
+    /// Segment payload size to use instead of the negotiated one; a
+    /// smaller size can help a marginal line. Refused when it exceeds
+    /// what the link supports.
+    pub chunk_size: Option<u16>,
+    /// After a device-pushed abort for a transient reason (its
+    /// inactivity timeout), restart once from `UpdateStart` instead of
+    /// failing.
+    pub retry_session: bool,
+    /// Ask the device to resume an interrupted transfer of this very
+    /// image from its checkpoint, skipping the segments already in
+    /// flash. Ignored for partition and delta transfers.
+    pub resume: bool,
+    /// Segments kept in flight before waiting for acks; `0` and `1`
+    /// both mean the classic lockstep. Larger windows hide the ack
+    /// round-trip and lean on the device's `Busy` backpressure.
+    pub window: usize,
+    /// Suppress the interactive progress line. Parallel batch runs set
+    /// this: several transfers redrawing the same terminal line would
+    /// garble it.
+    pub quiet: bool,
+}
+
+/// The device pushed an [`UpdateAborted`](MessageTypeMcu::UpdateAborted)
+/// mid-session. Typed so [`flash`] can tell a deliberate device-side
+/// abort from a lost reply and decide whether `--retry-session` applies.
+#[derive(Debug)]
+pub struct UpdateAborted(pub FailureReason);
+
+impl fmt::Display for UpdateAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Device aborted the update on its own: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UpdateAborted {}
+
+/// One unit of retransmission. Compressed payloads are built once and cached
+/// so a retry puts the exact same bytes on the wire - heatshrink output is
+/// not guaranteed stable if the settings ever change between calls.
+enum Segment {
+    Plain(UpdateSegment),
+    Compressed(UpdateSegmentCompressed),
+    Encrypted(UpdateSegmentEncrypted),
+    Delta(UpdateSegmentDelta),
+}
+
+impl Segment {
+    fn id(&self) -> u16 {
+        match self {
+            Segment::Plain(segment) => segment.id,
+            Segment::Compressed(segment) => segment.id,
+            Segment::Encrypted(segment) => segment.id,
+            Segment::Delta(segment) => segment.id,
+        }
+    }
+
+    fn wire_len(&self) -> usize {
+        match self {
+            Segment::Plain(segment) => segment.data.len(),
+            Segment::Compressed(segment) => segment.data.len(),
+            Segment::Encrypted(segment) => segment.data.len(),
+            Segment::Delta(segment) => match &segment.op {
+                // offset + length varints; close enough for the summary
+                DeltaOp::Copy { .. } => 8,
+                DeltaOp::Data(data) => data.len(),
+            },
+        }
+    }
+
+    fn to_message(&self) -> MessageTypeHost {
+        match self {
+            Segment::Plain(segment) => MessageTypeHost::UpdateSegment(segment.clone()),
+            Segment::Compressed(segment) => {
+                MessageTypeHost::UpdateSegmentCompressed(segment.clone())
+            }
+            Segment::Encrypted(segment) => MessageTypeHost::UpdateSegmentEncrypted(segment.clone()),
+            Segment::Delta(segment) => MessageTypeHost::UpdateSegmentDelta(segment.clone()),
+        }
+    }
+}
+
+/// One in-flight segment: its index into the build order, how often it
+/// went out, and when it last did.
+struct Sent {
+    index: usize,
+    attempts: u32,
+    sent_at: Instant,
+}
+
+/// Retransmits the given in-flight segments in order, restarting their
+/// round-trip clocks. The repeated payloads count towards `sent_bytes`:
+/// the field tracks what actually went over the wire, not what the
+/// device kept.
+fn resend<'a, S: Transport>(
+    link: &mut S,
+    segments: &[Segment],
+    stats: &mut Stats,
+    sents: impl Iterator<Item = &'a mut Sent>,
+) -> Result<()> {
+    for sent in sents {
+        send_message(link, &segments[sent.index].to_message())?;
+        stats.sent_bytes += segments[sent.index].wire_len();
+        sent.sent_at = Instant::now();
+    }
+
+    Ok(())
+}
+
+/// Flashes `image` over `link`, negotiating compression with the device.
+pub fn flash<S: Transport>(link: &mut S, image: &[u8], opts: &FlashOpts) -> Result<Stats> {
+    let mut stats = Stats {
+        image_size: image.len(),
+        ..Default::default()
+    };
+    let mut reader = FrameReader::new();
+
+    let started = Instant::now();
+
+    // Session handshake first. Firmware that predates Hello ignores the
+    // frame entirely; the short timeout expires and everything below
+    // falls back to sniffing the legacy UpdateStartStatus byte.
+    send_message(
+        link,
+        &MessageTypeHost::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: HOST_CAPABILITIES,
+        },
+    )?;
+
+    let timeouts_before = stats.timeouts;
+    let hello = loop {
+        match reader.read_message(link, HELLO_TIMEOUT, &mut stats) {
+            Ok(MessageTypeMcu::HelloAck {
+                protocol_version,
+                capabilities,
+                max_segment_len,
+            }) => break Some((protocol_version, capabilities, max_segment_len)),
+            // Boot noise, log mirror frames: skip to the ack
+            Ok(_) => (),
+            Err(_) => break None,
+        }
+    };
+    // A silent probe is the expected old-firmware outcome, not an error
+    stats.timeouts = timeouts_before;
+
+    // A mismatched generation is caught right here, before anything the
+    // other side might misparse goes over the line
+    version::check_protocol(hello.map(|(version, ..)| version), opts.force)?;
+
+    // Version handshake before anything is written; old firmware simply
+    // does not answer GetInfo.
+    send_message(link, &MessageTypeHost::GetInfo)?;
+
+    let info = match reader.read_message(link, INFO_TIMEOUT, &mut stats) {
+        Ok(MessageTypeMcu::Info(info)) => Some(info),
+        _ => None,
+    };
+
+    version::check(
+        info.as_ref(),
+        opts.min_version.as_deref(),
+        opts.require_protocol,
+        opts.force,
+    )?;
+
+    // An identical version already running makes the whole transfer a
+    // no-op; only trust the claim when both sides actually state one
+    if opts.skip_if_same && opts.partition.is_none() {
+        match (info.as_ref(), version::image_version(image)) {
+            (Some(info), Some(version)) if info.app_version == version => {
+                println!("Device already runs {}; skipping the update", version);
+                stats.handshake_ms = started.elapsed().as_millis() as u64;
+                return Ok(stats);
+            }
+            _ => (),
+        }
+    }
+
+    // Catch "image bigger than the slot" here instead of debugging
+    // mysterious Failed replies later.
+    if opts.partition.is_none() {
+        check_slot_size(image.len(), info.as_ref())?;
+    }
+
+    if opts.dry_run {
+        stats.handshake_ms = started.elapsed().as_millis() as u64;
+        return Ok(stats);
+    }
+
+    if opts.verify_only {
+        verify_flash(link, &mut reader, &mut stats, image, opts)?;
+        stats.handshake_ms = started.elapsed().as_millis() as u64;
+        return Ok(stats);
+    }
+
+    let nonce_prefix: Option<[u8; NONCE_PREFIX_LEN]> = opts.key.as_ref().map(|_| rand::random());
+
+    // Deltas reference cleartext base offsets of the running app, so the
+    // base is only honoured for plain transfers into the app slot.
+    let mut delta_base = match (&opts.base, &opts.key, &opts.partition) {
+        (Some(base), None, None) => Some(DeltaBase {
+            hash: image_hash(base),
+            len: base.len() as u32,
+        }),
+        _ => None,
+    };
+
+    let mut start_status = start_update(
+        link,
+        &mut reader,
+        &mut stats,
+        image,
+        nonce_prefix,
+        &delta_base,
+        opts,
+    )?;
+
+    if start_status.status == Status::BaseMismatch {
+        eprintln!(
+            "warning: device does not run the given base image, falling back to a full transfer"
+        );
+
+        delta_base = None;
+        start_status = start_update(
+            link,
+            &mut reader,
+            &mut stats,
+            image,
+            nonce_prefix,
+            &delta_base,
+            opts,
+        )?;
+    }
+
+    match start_status.status {
+        Status::Ok => (),
+        Status::NoOtaPartition => bail!(
+            "Device has no OTA update slot; it needs to be flashed over USB with an \
+             OTA-capable partition table (e.g. CONFIG_PARTITION_TABLE_TWO_OTA) first"
+        ),
+        status => bail!("Device refused the update: {:?}", status),
+    }
+
+    // One capability vocabulary for the gates below: the session set
+    // when the device spoke Hello, otherwise the legacy byte widened.
+    let caps = match hello {
+        Some((_, capabilities, _)) => capabilities,
+        None => Caps::from_legacy(start_status.capabilities),
+    };
+
+    let use_delta = delta_base.is_some() && caps.contains(Caps::DELTA_UPDATES);
+
+    let signature = match (&opts.signature, &opts.sign_key) {
+        (Some(signature), _) => Some(signature.clone()),
+        (None, Some(key)) => Some(sign::sign_image(key, image)),
+        (None, None) => None,
+    };
+
+    if signature.is_none() && caps.contains(Caps::SIGNATURE_REQUIRED) {
+        bail!("Device requires signed updates; pass --signature or --sign-key");
+    }
+
+    let encrypt = match &opts.key {
+        Some(key) => {
+            if caps.contains(Caps::ENCRYPTED_SEGMENTS) {
+                Some((key, nonce_prefix.unwrap()))
+            } else if opts.allow_plain {
+                eprintln!(
+                    "warning: device does not support encrypted segments, sending cleartext (--allow-plain)"
+                );
+                None
+            } else {
+                bail!(
+                    "A key was given but the device does not support encrypted segments; \
+                     pass --allow-plain to send cleartext anyway"
+                );
+            }
+        }
+        None => None,
+    };
+
+    let compress = !opts.no_compress && caps.contains(Caps::COMPRESSED_SEGMENTS);
+
+    // Larger segments are only safe when both ends throttle the line;
+    // the device only advertises them with its flow control enabled,
+    // and ours has to be enabled too. The device now advertises its
+    // real receive capacity, which can exceed the size we announced in
+    // UpdateStart - never go past our own announcement. The per-update
+    // advertisement wins over the session one: it reflects the link the
+    // transfer actually runs on.
+    let advertised = start_status
+        .max_segment_size
+        .or(hello.map(|(_, _, max_segment_len)| max_segment_len));
+    let negotiated = match advertised {
+        Some(size) if opts.flow_control && size as usize > SEGMENT_SIZE => {
+            (size as usize).min(SEGMENT_SIZE_FLOW_CONTROLLED)
+        }
+        _ => SEGMENT_SIZE,
+    };
+
+    // An explicit override may shrink the segments at will, but never
+    // push past what the negotiation established the device can take
+    let segment_size = match opts.chunk_size {
+        Some(0) => bail!("Chunk size cannot be zero"),
+        Some(size) if size as usize <= negotiated => size as usize,
+        Some(size) => bail!(
+            "Chunk size {} exceeds what this link supports ({} bytes)",
+            size,
+            negotiated
+        ),
+        None => negotiated,
+    };
+
+    let segments = match encrypt {
+        Some((key, prefix)) => {
+            build_encrypted_segments(image, key, &prefix, compress, segment_size)?
+        }
+        None if use_delta => build_delta_segments(opts.base.as_ref().unwrap(), image),
+        None => build_segments(image, compress, segment_size),
+    };
+
+    stats.handshake_ms = started.elapsed().as_millis() as u64;
+    stats.segments = segments.len();
+    stats.compressed_segments = segments
+        .iter()
+        .filter(|segment| {
+            matches!(
+                segment,
+                Segment::Compressed(_)
+                    | Segment::Encrypted(UpdateSegmentEncrypted {
+                        raw_len: Some(_),
+                        ..
+                    })
+            )
+        })
+        .count();
+
+    // How many leading segments the device already holds from an
+    // interrupted run. It verified the kept bytes against the same
+    // image hash before offering, but the offset still has to fit our
+    // chunking to mean anything.
+    let resume_skip = |status: &messages::UpdateStartStatus| -> usize {
+        match status.resume_offset {
+            Some(offset)
+                if opts.resume
+                    && (offset as usize).is_multiple_of(segment_size)
+                    && offset as usize <= image.len() =>
+            {
+                offset as usize / segment_size
+            }
+            Some(offset) => {
+                eprintln!("warning: ignoring unusable resume offset {}", offset);
+                0
+            }
+            None => 0,
+        }
+    };
+
+    let mut skip = resume_skip(&start_status);
+    if skip > 0 {
+        println!(
+            "Resuming the interrupted transfer: {} of {} segments already on the device",
+            skip,
+            segments.len()
+        );
+    }
+
+    let transfer_started = Instant::now();
+    let mut session_retried = false;
+    let mut progress = progress::Progress::new(segments.len(), skip);
+    if opts.quiet {
+        progress.silence();
+    }
+
+    // How many segments may be on the wire before waiting for acks. The
+    // device writes and acks them in arrival order, so pipelining needs
+    // no protocol change - the acks carry ids and are matched up here.
+    let window = opts.window.max(1);
+
+    'session: loop {
+        let mut next = skip;
+        let mut outstanding: VecDeque<Sent> = VecDeque::new();
+
+        loop {
+            // Keep the window full; lockstep is just a window of one
+            while outstanding.len() < window && next < segments.len() {
+                send_message(link, &segments[next].to_message())?;
+                outstanding.push_back(Sent {
+                    index: next,
+                    attempts: 0,
+                    sent_at: Instant::now(),
+                });
+                next += 1;
+            }
+
+            if outstanding.is_empty() {
+                break;
+            }
+
+            let reply = match await_reply(link, &mut reader, &mut stats, opts) {
+                Ok(reply) => reply,
+                Err(err) => {
+                    // A pushed abort is not a lost ack: the device
+                    // is idle again and every resend would only earn
+                    // a WrongState. A transient reason may restart
+                    // the whole session once when asked to.
+                    if let Some(UpdateAborted(reason)) = err.downcast_ref::<UpdateAborted>() {
+                        if opts.retry_session && !session_retried && reason.is_transient() {
+                            session_retried = true;
+                            eprintln!(
+                                "warning: device aborted the session ({:?}), \
+                                 restarting from UpdateStart",
+                                reason
+                            );
+
+                            let restarted = start_update(
+                                link,
+                                &mut reader,
+                                &mut stats,
+                                image,
+                                nonce_prefix,
+                                &delta_base,
+                                opts,
+                            )?;
+
+                            if restarted.status != Status::Ok {
+                                bail!(
+                                    "Device refused the restarted update: {:?}",
+                                    restarted.status
+                                );
+                            }
+
+                            skip = resume_skip(&restarted);
+                            progress.restart(skip);
+                            continue 'session;
+                        }
+
+                        return Err(err);
+                    }
+
+                    // A reconnect mid-frame loses pending acks; probe the
+                    // device and lean on the retry path instead of giving up
+                    for sent in outstanding.iter_mut() {
+                        sent.attempts += 1;
+                        stats.retransmitted.push(segments[sent.index].id());
+
+                        if sent.attempts > opts.retry.max_retries {
+                            return Err(err);
+                        }
+                    }
+
+                    probe_alive(link, &mut reader, &mut stats)?;
+                    opts.retry.settle();
+                    resend(link, &segments, &mut stats, outstanding.iter_mut())?;
+                    continue;
+                }
+            };
+
+            match reply {
+                MessageTypeMcu::UpdateSegmentStatus { id, status } => {
+                    // An ack for an id no longer in flight is stale - a
+                    // leftover from before a resend - and carries no news
+                    let pos = match outstanding
+                        .iter()
+                        .position(|sent| segments[sent.index].id() == id)
+                    {
+                        Some(pos) => pos,
+                        None => continue,
+                    };
+
+                    if status == Status::Ok {
+                        let sent = outstanding.remove(pos).unwrap();
+                        stats.record_segment(
+                            segments[sent.index].wire_len(),
+                            sent.sent_at.elapsed(),
+                        );
+                        progress.segment_done(&stats);
+                        continue;
+                    }
+
+                    // The device aborted and is idle again; resending
+                    // would only earn "no update in progress" errors
+                    if status == Status::FlashWrite {
+                        bail!(
+                            "Segment {}: device hit a fatal flash write error and aborted the update",
+                            id
+                        );
+                    }
+
+                    if status == Status::VersionTooOld {
+                        bail!(
+                            "Device refused the image as older than what it is running \
+                             (anti-rollback) and aborted the update"
+                        );
+                    }
+
+                    let sent = &mut outstanding[pos];
+                    sent.attempts += 1;
+                    stats.retransmitted.push(id);
+
+                    if sent.attempts > opts.retry.max_retries {
+                        bail!(
+                            "Segment {} failed after {} retries",
+                            id,
+                            opts.retry.max_retries
+                        );
+                    }
+
+                    // A failed write keeps the device's position, so every
+                    // in-flight segment behind it was rejected too; resend
+                    // the tail in order and let stale acks fall through above
+                    opts.retry.settle();
+                    resend(
+                        link,
+                        &segments,
+                        &mut stats,
+                        outstanding.iter_mut().skip(pos),
+                    )?;
+                }
+                MessageTypeMcu::Busy { retry_after_ms } => {
+                    // The device dropped a segment before queueing it -
+                    // its inbound queue is full behind a long flash
+                    // operation - without saying which. Wait as told and
+                    // resend everything unacked; duplicates are acked
+                    // without a write, and backpressure is not a failure,
+                    // so the retry budget is untouched.
+                    stats.busy_waits += 1;
+                    std::thread::sleep(Duration::from_millis(u64::from(retry_after_ms)));
+                    resend(link, &segments, &mut stats, outstanding.iter_mut())?;
+                }
+                other => bail!("Unexpected reply to segment: {:?}", other),
+            }
+        }
+
+        break;
+    }
+
+    progress.finish();
+    stats.transfer_ms = transfer_started.elapsed().as_millis() as u64;
+
+    // The device's wire counters, collected before UpdateEnd - an app
+    // update reboots the device right after, taking the counters (and
+    // any chance of a reply) with it. Best effort: firmware without the
+    // exchange stays silent and earns a `None`.
+    send_message(link, &MessageTypeHost::GetStats { reset: false })?;
+
+    let timeouts_before = stats.timeouts;
+    stats.remote = loop {
+        match reader.read_message(link, STATS_TIMEOUT, &mut stats) {
+            Ok(MessageTypeMcu::Stats(remote)) => break Some(remote),
+            // Stale pongs or mirrored logs; the counters are behind them
+            Ok(_) => (),
+            Err(_) => break None,
+        }
+    };
+    // A silent probe is expected on old firmware, not a timeout worth
+    // reporting
+    stats.timeouts = timeouts_before;
+
+    let finalize_started = Instant::now();
+
+    send_message(
+        link,
+        &MessageTypeHost::UpdateEnd(UpdateEnd {
+            signature,
+            reboot: opts.reboot,
+            sha256: Some(image_hash(image)),
+        }),
+    )?;
+
+    // The device checks size, digest and signature before answering, so
+    // the verdict is worth waiting for - sending the digest would be
+    // pointless otherwise. But firmware reboots into the new image
+    // right after the ack and the oldest builds restart without one at
+    // all, so a link going quiet here is a warning, not a failure.
+    loop {
+        match await_reply(link, &mut reader, &mut stats, opts) {
+            Ok(MessageTypeMcu::UpdateEndStatus(Status::Ok)) => break,
+            Ok(MessageTypeMcu::UpdateEndStatus(Status::InvalidImage)) => bail!(
+                "Device rejected the image: what reached flash does not match the announced \
+                 size or SHA-256"
+            ),
+            Ok(MessageTypeMcu::UpdateEndStatus(status)) => {
+                bail!("Device refused to activate the update: {:?}", status)
+            }
+            // Stale acks from the tail of the transfer
+            Ok(_) => (),
+            Err(err) if err.downcast_ref::<UpdateAborted>().is_some() => return Err(err),
+            Err(_) => {
+                eprintln!(
+                    "warning: no UpdateEndStatus from the device (rebooted already?), \
+                     assuming success"
+                );
+                break;
+            }
+        }
+    }
+
+    stats.finalize_ms = finalize_started.elapsed().as_millis() as u64;
+
+    Ok(stats)
+}
+
+/// Splits the image into segments, compressing each block when enabled and
+/// worthwhile. Incompressible blocks fall back to plain segments; the id
+/// space is shared, so the device never needs to care which kind a retry is.
+fn build_segments(image: &[u8], compress: bool, segment_size: usize) -> Vec<Segment> {
+    image
+        .chunks(segment_size)
+        .enumerate()
+        .map(|(id, chunk)| {
+            let id = id as u16;
+
+            if compress {
+                if let Some(compressed) = compress::encode(chunk) {
+                    if compressed.len() < chunk.len() {
+                        return Segment::Compressed(UpdateSegmentCompressed {
+                            id,
+                            raw_len: chunk.len() as u16,
+                            data: compressed,
+                        });
+                    }
+                }
+            }
+
+            Segment::Plain(UpdateSegment {
+                id,
+                data: chunk.to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Turns the delta ops into segments, ids shared with the plain space.
+fn build_delta_segments(base: &[u8], image: &[u8]) -> Vec<Segment> {
+    delta::build_delta(base, image)
+        .into_iter()
+        .enumerate()
+        .map(|(id, op)| Segment::Delta(UpdateSegmentDelta { id: id as u16, op }))
+        .collect()
+}
+
+/// SHA-256 of an image, as carried in [`DeltaBase`].
+pub fn image_hash(image: &[u8]) -> [u8; HASH_LEN] {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(image).into()
+}
+
+/// Lowercase hex of a digest, for reports and error messages.
+pub fn hash_hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Drives `--verify-only`: asks the device for the digest of what the
+/// target slot holds and compares it against the local image, without
+/// writing a byte of flash.
+fn verify_flash<S: Transport>(
+    link: &mut S,
+    reader: &mut FrameReader,
+    stats: &mut Stats,
+    image: &[u8],
+    opts: &FlashOpts,
+) -> Result<()> {
+    send_message(
+        link,
+        &MessageTypeHost::HashFlash {
+            partition: opts.partition.clone(),
+            len: image.len() as u32,
+        },
+    )?;
+
+    // Hashing megabytes of flash takes the device a moment, and
+    // nothing comes back until it is done
+    let timeout = opts.retry.ack_timeout.unwrap_or(RESPONSE_TIMEOUT);
+
+    let (status, sha256) =
+        loop {
+            match reader.read_message(link, timeout, stats) {
+                Ok(MessageTypeMcu::FlashHash { status, sha256 }) => break (status, sha256),
+                // Log mirror frames and other chatter; skip to the reply
+                Ok(_) => (),
+                Err(err) => return Err(err.context(
+                    "Device did not answer the hash request; its firmware may predate HashFlash",
+                )),
+            }
+        };
+
+    match (status, sha256) {
+        (Status::Ok, Some(found)) => {
+            let expected = image_hash(image);
+
+            if found != expected {
+                bail!(
+                    "Device flash does not match the image: device sha256 {}, local {}",
+                    hash_hex(&found),
+                    hash_hex(&expected)
+                );
+            }
+
+            println!(
+                "Device flash matches the image ({} bytes, sha256 {})",
+                image.len(),
+                hash_hex(&expected)
+            );
+
+            Ok(())
+        }
+        (status, _) => bail!("Device refused the hash request: {:?}", status),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_update<S: Transport>(
+    link: &mut S,
+    reader: &mut FrameReader,
+    stats: &mut Stats,
+    image: &[u8],
+    nonce_prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    delta_base: &Option<DeltaBase>,
+    opts: &FlashOpts,
+) -> Result<messages::UpdateStartStatus> {
+    // The size we intend to chunk by, before seeing what the device
+    // supports; it gates whether the device can offer a resume offset
+    let segment_size = match (opts.chunk_size, opts.flow_control) {
+        (Some(size), _) => size as usize,
+        (None, true) => SEGMENT_SIZE_FLOW_CONTROLLED,
+        (None, false) => SEGMENT_SIZE,
+    };
+
+    send_message(
+        link,
+        &MessageTypeHost::UpdateStart(UpdateStart {
+            size: image.len() as u32,
+            nonce_prefix,
+            delta_base: delta_base.clone(),
+            partition: opts.partition.clone(),
+            sha256: Some(image_hash(image)),
+            segment_size: Some(segment_size as u16),
+            // The device only honours a resume for a plain transfer
+            // into the app slot; do not even ask otherwise
+            resume: opts.resume && opts.partition.is_none() && delta_base.is_none(),
+            progress: false,
+        }),
+    )?;
+
+    match await_reply(link, reader, stats, opts)? {
+        MessageTypeMcu::UpdateStartStatus(status) => Ok(status),
+        other => bail!("Unexpected reply to UpdateStart: {:?}", other),
+    }
+}
+
+/// How long the firmware historically needed to abort an update; the
+/// blind fallback wait for firmware that predates the Cancel ack.
+const CANCEL_FALLBACK_WAIT: Duration = Duration::from_millis(50);
+
+/// Asks the device to abandon any in-flight update and waits for the
+/// `CancelStatus` ack - the device only sends it once the abort has
+/// actually finished. Old firmware never acks; after the response
+/// timeout, fall back to the historical blind wait.
+pub fn cancel<S: Transport>(link: &mut S, opts: &FlashOpts) -> Result<()> {
+    let mut reader = FrameReader::new();
+    let mut stats = Stats::default();
+
+    send_message(link, &MessageTypeHost::Cancel)?;
+
+    loop {
+        match await_reply(link, &mut reader, &mut stats, opts) {
+            Ok(MessageTypeMcu::CancelStatus(Status::Ok)) => return Ok(()),
+            Ok(MessageTypeMcu::CancelStatus(status)) => {
+                bail!("Device failed to cancel the update: {:?}", status)
+            }
+            // Stale replies to the interrupted transfer
+            Ok(_) => (),
+            Err(_) => {
+                eprintln!(
+                    "warning: no cancel ack from the device (old firmware?), waiting blindly"
+                );
+                std::thread::sleep(CANCEL_FALLBACK_WAIT);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The host half of confirm-after-boot: tells the device the image it
+/// is running is good, cancelling a pending rollback. Sent once
+/// whatever host-side acceptance checks pass; a device that never hears
+/// it rolls back to the previous image on its own.
+pub fn mark_valid<S: Transport>(link: &mut S, opts: &FlashOpts) -> Result<()> {
+    let mut reader = FrameReader::new();
+    let mut stats = Stats::default();
+
+    send_message(link, &MessageTypeHost::MarkValid)?;
+
+    loop {
+        match await_reply(link, &mut reader, &mut stats, opts)? {
+            MessageTypeMcu::MarkValidStatus(Status::Ok) => return Ok(()),
+            MessageTypeMcu::MarkValidStatus(status) => {
+                bail!(
+                    "Device could not mark the running image valid: {:?}",
+                    status
+                )
+            }
+            // Stale replies from before the command
+            _ => (),
+        }
+    }
+}
+
+/// Asks the device to reject the image it is running and reboot into
+/// the previous one. The `Ok` ack arrives just before the reboot; a
+/// `Failed` means there is nothing to roll back to.
+pub fn rollback<S: Transport>(link: &mut S, opts: &FlashOpts) -> Result<()> {
+    let mut reader = FrameReader::new();
+    let mut stats = Stats::default();
+
+    send_message(link, &MessageTypeHost::Rollback)?;
+
+    loop {
+        match await_reply(link, &mut reader, &mut stats, opts)? {
+            MessageTypeMcu::RollbackStatus(Status::Ok) => return Ok(()),
+            MessageTypeMcu::RollbackStatus(status) => {
+                bail!("Device cannot roll back: {:?}", status)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Asks the device what its last successful update looked like.
+/// `Ok(None)` is a device that answered but never completed an update
+/// (or whose NVS is unavailable); firmware that predates the query
+/// never answers at all, which surfaces as a timeout.
+pub fn last_update<S: Transport>(link: &mut S, opts: &FlashOpts) -> Result<Option<UpdateRecord>> {
+    let mut reader = FrameReader::new();
+    let mut stats = Stats::default();
+
+    send_message(link, &MessageTypeHost::GetUpdateInfo)?;
+
+    // Anything else is a stale reply from before the query
+    loop {
+        if let MessageTypeMcu::UpdateInfo(record) =
+            await_reply(link, &mut reader, &mut stats, opts)?
+        {
+            return Ok(record);
+        }
+    }
+}
+
+/// Waits for the next meaningful device message. Instead of one long
+/// timeout, the device is pinged whenever the keepalive interval passes
+/// without bytes; a timeout is declared only once the response timeout
+/// elapses with neither a reply nor any Pong. A Pong is informational -
+/// the device is alive, still working - never an answer.
+fn await_reply<S: Transport>(
+    link: &mut S,
+    reader: &mut FrameReader,
+    stats: &mut Stats,
+    opts: &FlashOpts,
+) -> Result<MessageTypeMcu> {
+    let keepalive = opts.keepalive_interval.unwrap_or(KEEPALIVE_INTERVAL);
+    let response_timeout = opts.retry.ack_timeout.unwrap_or(RESPONSE_TIMEOUT);
+
+    let mut deadline = Instant::now() + response_timeout;
+    let timeouts_before = stats.timeouts;
+
+    loop {
+        match reader.read_message(link, keepalive, stats) {
+            Ok(MessageTypeMcu::Pong) => {
+                eprint!("\rdevice is alive, still working... ");
+                deadline = Instant::now() + response_timeout;
+            }
+            Ok(MessageTypeMcu::TimedPong { uptime_ms, .. }) => {
+                eprint!(
+                    "\rdevice is alive (up {}s), still working... ",
+                    uptime_ms / 1000
+                );
+                deadline = Instant::now() + response_timeout;
+            }
+            // A mirrored log record is informational, never an answer;
+            // like a Pong it at least proves the device is alive
+            Ok(MessageTypeMcu::Log(record)) => {
+                eprintln!(
+                    "device: [{} {}] {}",
+                    record.level_name(),
+                    record.target,
+                    record.message
+                );
+                deadline = Instant::now() + response_timeout;
+            }
+            // A pushed abort answers whatever was awaited: the device
+            // is idle again and nothing else is coming
+            Ok(MessageTypeMcu::UpdateAborted(reason)) => return Err(UpdateAborted(reason).into()),
+            Ok(msg) => return Ok(msg),
+            Err(err) => {
+                if stats.timeouts == timeouts_before {
+                    // A real io error, not just a quiet link
+                    return Err(err);
+                }
+
+                // Keepalive probes are not timeouts worth reporting
+                stats.timeouts = timeouts_before;
+
+                if Instant::now() >= deadline {
+                    stats.timeouts += 1;
+                    bail!("Timed out waiting for a reply from the device");
+                }
+
+                send_message(link, &MessageTypeHost::Ping)?;
+            }
+        }
+    }
+}
+
+/// Splits the image into ChaCha20-Poly1305 sealed segments, compressing
+/// each block before sealing when enabled and worthwhile - ciphertext
+/// itself never compresses. `raw_len` rides outside the ciphertext so
+/// the device knows to decode what it authenticated.
+fn build_encrypted_segments(
+    image: &[u8],
+    key: &Zeroizing<[u8; crypto::KEY_LEN]>,
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    compress: bool,
+    segment_size: usize,
+) -> Result<Vec<Segment>> {
+    image
+        .chunks(segment_size)
+        .enumerate()
+        .map(|(id, chunk)| {
+            let id = id as u16;
+
+            let (plain, raw_len) = match compress.then(|| compress::encode(chunk)).flatten() {
+                Some(encoded) if encoded.len() < chunk.len() => (encoded, Some(chunk.len() as u16)),
+                _ => (chunk.to_vec(), None),
+            };
+
+            Ok(Segment::Encrypted(UpdateSegmentEncrypted {
+                id,
+                raw_len,
+                data: crypto::encrypt_segment(key, prefix, id, &plain)?,
+            }))
+        })
+        .collect()
+}
+
+/// Serializes `msg` into a checksummed frame and writes it to the link.
+pub fn send_message<S: Transport>(link: &mut S, msg: &MessageTypeHost) -> Result<()> {
+    let frame = codec::encode(msg).context("Serializing message failed")?;
+
+    link.write_all(&frame)?;
+    link.flush()?;
+
+    Ok(())
+}
+
+/// Buffering frame reader, shared by the flash loop and the listening
+/// modes. Corrupt frames are counted in [`Stats`] and skipped rather than
+/// aborting the run.
+#[derive(Default)]
+pub struct FrameReader {
+    accumulated: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the next valid device message, accumulating bytes until a
+    /// whole frame parses or `timeout` elapses.
+    pub fn read_message<S: Transport>(
+        &mut self,
+        link: &mut S,
+        timeout: Duration,
+        stats: &mut Stats,
+    ) -> Result<MessageTypeMcu> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0_u8; 256];
+
+        loop {
+            match codec::take::<MessageTypeMcu>(&self.accumulated) {
+                Ok((payload, rest)) => {
+                    let consumed = self.accumulated.len() - rest.len();
+                    self.accumulated.drain(..consumed);
+
+                    stats.frames_received += 1;
+                    return Ok(payload);
+                }
+                Err(codec::DecodeError::Truncated) => (),
+                // Drop exactly the corrupt frame and keep reading
+                Err(codec::DecodeError::BadCrc { frame_len, .. }) => {
+                    stats.crc_errors += 1;
+                    self.accumulated.drain(..frame_len);
+                    continue;
+                }
+                Err(_) => {
+                    // The buffer does not start with anything decodable;
+                    // drop it and hope to resynchronize on fresh bytes
+                    stats.decode_errors += 1;
+                    self.accumulated.clear();
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                stats.timeouts += 1;
+                bail!("Timed out waiting for a reply from the device");
+            }
+
+            match link.read_available(&mut buf, deadline - now) {
+                Ok(0) => bail!("Link closed by the device"),
+                Ok(n) => {
+                    stats.received_bytes += n;
+                    self.accumulated.extend_from_slice(&buf[..n]);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Reads one device message with a one-off reader; convenience for
+/// callers outside the flash loop.
+pub fn read_message<S: Transport>(link: &mut S, timeout: Duration) -> Result<MessageTypeMcu> {
+    FrameReader::new().read_message(link, timeout, &mut Stats::default())
+}
+
+/// Sorts "a frame got lost" from "the device rebooted" after a hiccup on
+/// the link: a live device answers `Ping` even mid-update, while one that
+/// rebooted lost all transfer state and resumption is pointless.
+fn probe_alive<S: Transport>(
+    link: &mut S,
+    reader: &mut FrameReader,
+    stats: &mut Stats,
+) -> Result<()> {
+    send_message(link, &MessageTypeHost::Ping)?;
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+
+    while Instant::now() < deadline {
+        match reader.read_message(link, REPLY_TIMEOUT, stats) {
+            Ok(MessageTypeMcu::Pong) | Ok(MessageTypeMcu::TimedPong { .. }) => return Ok(()),
+            // Stale replies from before the drop are not an answer
+            Ok(_) => (),
+            Err(err) => {
+                return Err(err.context(
+                    "Device did not answer a ping; it probably rebooted and the transfer cannot be resumed",
+                ))
+            }
+        }
+    }
+
+    bail!("Device did not answer a ping; it probably rebooted and the transfer cannot be resumed");
+}
+
+/// Refuses images that cannot fit the device's OTA app slot. Old firmware
+/// does not report a slot size; warn and let the device sort it out.
+fn check_slot_size(image_len: usize, info: Option<&messages::Info>) -> Result<()> {
+    match info.and_then(|info| info.slot_size) {
+        Some(slot_size) if image_len > slot_size as usize => bail!(
+            "image is {} bytes but the update slot is {} bytes",
+            group_digits(image_len),
+            group_digits(slot_size as usize)
+        ),
+        Some(_) => Ok(()),
+        None => {
+            eprintln!(
+                "warning: device did not report its update slot size, cannot check the image fits"
+            );
+            Ok(())
+        }
+    }
+}
+
+/// `1572864` -> `"1,572,864"`, for error messages about sizes.
+fn group_digits(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(slot_size: Option<u32>) -> messages::Info {
+        messages::Info {
+            protocol_version: messages::PROTOCOL_VERSION,
+            app_version: "0.25.0".to_string(),
+            slot_size,
+            project_name: "demo".to_string(),
+            build: String::new(),
+            running_slot: None,
+            update_slot: None,
+            free_heap: 0,
+            min_free_heap: 0,
+            updater_stack_free: 0,
+            desyncs: 0,
+            reset_reason: 0,
+            boot_count: None,
+            pending_verify: false,
+            serial_priority: 0,
+            updater_priority: 0,
+            serial_core: None,
+            updater_core: None,
+            self_test_passed: None,
+        }
+    }
+
+    #[test]
+    fn image_fitting_the_slot_passes() {
+        check_slot_size(1_310_720, Some(&info(Some(1_310_720)))).unwrap();
+    }
+
+    #[test]
+    fn oversized_image_is_refused_with_both_sizes() {
+        let err = check_slot_size(1_572_864, Some(&info(Some(1_310_720)))).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "image is 1,572,864 bytes but the update slot is 1,310,720 bytes"
+        );
+    }
+
+    #[test]
+    fn unknown_slot_size_only_warns() {
+        check_slot_size(usize::MAX, Some(&info(None))).unwrap();
+        check_slot_size(usize::MAX, None).unwrap();
+    }
+
+    #[test]
+    fn digit_grouping() {
+        assert_eq!(group_digits(0), "0");
+        assert_eq!(group_digits(999), "999");
+        assert_eq!(group_digits(1_000), "1,000");
+        assert_eq!(group_digits(1_572_864), "1,572,864");
+    }
+}
@@ -0,0 +1,1321 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use zeroize::Zeroizing;
+
+use flasher::profile::{Profile, ProfileStore};
+use flasher::{flash, FlashOpts};
+
+/// Baud rate used when neither the command line nor a stored profile
+/// says otherwise.
+const DEFAULT_BAUD: u32 = 921_600;
+
+#[derive(Parser)]
+#[clap(
+    version,
+    about = "Flash firmware updates over the serial update protocol"
+)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+// One instance for the process lifetime; the Flash variant's size is fine
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Flash an application image over the update UART
+    Flash {
+        /// Path to the firmware image
+        image: PathBuf,
+
+        /// Serial port: a device path, or `serial:<number>` to find the
+        /// adapter by USB serial number; omit to rely on the filters
+        #[clap(short, long)]
+        port: Option<String>,
+
+        /// Connect over TCP (`host` or `host:port`, default port 3232)
+        /// instead of a serial port; the device must be built with the
+        /// firmware's `tcp_update` feature
+        #[clap(long, conflicts_with_all = &["port", "product", "serial-number"])]
+        tcp: Option<String>,
+
+        /// Connect over BLE to the device advertising this name; needs
+        /// a flasher built with the `ble` cargo feature and a device
+        /// built with the firmware's `ble_update` feature
+        #[clap(long, conflicts_with_all = &["port", "tcp", "product", "serial-number"])]
+        ble: Option<String>,
+
+        /// Only consider ports whose USB product contains this (case-insensitive)
+        #[clap(long)]
+        product: Option<String>,
+
+        /// Only consider the port with exactly this USB serial number
+        #[clap(long)]
+        serial_number: Option<String>,
+
+        /// Flash several devices in parallel: a comma-separated list of
+        /// serial ports, each getting its own thread and link
+        #[clap(
+            long,
+            use_value_delimiter = true,
+            conflicts_with_all = &["port", "tcp", "ble", "product", "serial-number", "json"]
+        )]
+        ports: Vec<String>,
+
+        /// Flash every serial port matching the --product / --serial-number
+        /// filters in parallel, for small production batches
+        #[clap(long, conflicts_with_all = &["port", "tcp", "ble", "ports", "json"])]
+        uart_all: bool,
+
+        /// Baud rate of the update link (defaults to 921600, or to the
+        /// device's stored profile)
+        #[clap(short, long)]
+        baud: Option<u32>,
+
+        /// Use RTS/CTS hardware flow control (the device must be built
+        /// with it too); enables the larger segments the device offers
+        #[clap(long)]
+        flow_control: bool,
+
+        /// Send plain segments even if the device can decompress
+        #[clap(long)]
+        no_compress: bool,
+
+        /// Segment payload size in bytes, overriding the negotiated one;
+        /// smaller can help a marginal line
+        #[clap(long)]
+        chunk_size: Option<u16>,
+
+        /// Encrypt segments with the 32-byte key (raw or hex) in this file
+        #[clap(long)]
+        key_file: Option<PathBuf>,
+
+        /// Send cleartext if a key was given but the device cannot decrypt
+        #[clap(long)]
+        allow_plain: bool,
+
+        /// Write this partition (by label) instead of the next OTA app slot
+        #[clap(long)]
+        partition: Option<String>,
+
+        /// Reboot after writing a non-app partition
+        #[clap(long, requires = "partition")]
+        reboot: bool,
+
+        /// Previous image; transfers a binary delta instead of the full
+        /// image when the device runs exactly this base
+        #[clap(long)]
+        base: Option<PathBuf>,
+
+        /// Detached Ed25519 signature file to send with UpdateEnd
+        #[clap(long, conflicts_with = "sign-key")]
+        signature: Option<PathBuf>,
+
+        /// Ed25519 private key; signs the image on the fly
+        #[clap(long)]
+        sign_key: Option<PathBuf>,
+
+        /// Refuse to flash devices running an app older than this
+        #[clap(long)]
+        min_version: Option<String>,
+
+        /// Refuse to flash devices speaking a different protocol version
+        #[clap(long)]
+        require_protocol: Option<u8>,
+
+        /// Turn version-gate refusals into warnings (recovery)
+        #[clap(long)]
+        force: bool,
+
+        /// Skip the transfer when the device already runs the version
+        /// in the image's app descriptor
+        #[clap(long)]
+        skip_if_same: bool,
+
+        /// Print the end-of-run statistics as JSON instead of text
+        #[clap(long)]
+        json: bool,
+
+        /// Handshake and run the pre-flight checks without flashing
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Flash nothing: have the device hash what the target slot
+        /// holds and compare against the image, failing on a mismatch
+        #[clap(long, conflicts_with = "dry-run")]
+        verify_only: bool,
+
+        /// Seconds to wait for the port to come back after a disconnect
+        #[clap(long, default_value_t = 5.0)]
+        reconnect_timeout: f64,
+
+        /// Seconds of silence before pinging the device instead of giving up
+        #[clap(long)]
+        keepalive_interval: Option<f64>,
+
+        /// Seconds without a status or Pong before declaring a timeout
+        #[clap(long)]
+        response_timeout: Option<f64>,
+
+        /// Retransmissions per segment before giving up
+        #[clap(long, default_value_t = flasher::SEGMENT_RETRIES)]
+        max_retries: u32,
+
+        /// Milliseconds to sit out before retransmitting a lost or
+        /// refused segment, giving a glitching line a moment to settle
+        #[clap(long, default_value_t = 0)]
+        retry_backoff_ms: u64,
+
+        /// Like --response-timeout but in milliseconds, for links where
+        /// whole seconds are too coarse
+        #[clap(long, conflicts_with = "response-timeout")]
+        ack_timeout_ms: Option<u64>,
+
+        /// After the device aborts for a transient reason (its
+        /// inactivity timeout), restart the session once instead of failing
+        #[clap(long)]
+        retry_session: bool,
+
+        /// Resume an interrupted transfer of this very image from the
+        /// device's checkpoint instead of rewriting everything
+        #[clap(long)]
+        resume: bool,
+
+        /// Segments kept in flight before waiting for acks; 1 is the
+        /// classic lockstep, larger hides the ack round-trip
+        #[clap(long, default_value_t = 1)]
+        window: usize,
+
+        /// Ignore the stored per-device profile and do not update it
+        #[clap(long)]
+        no_profile: bool,
+    },
+    /// Flash every image listed in a manifest over one connection
+    FlashManifest {
+        /// TOML or JSON manifest file (see the `manifest` module docs)
+        manifest: PathBuf,
+
+        /// Serial port the device is connected to
+        #[clap(short, long)]
+        port: String,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+
+        /// Only flash the entry with this target
+        #[clap(long)]
+        only: Option<String>,
+
+        /// Continue with the remaining entries after a failure
+        #[clap(long)]
+        keep_going: bool,
+    },
+    /// Record ADC telemetry frames to a CSV file
+    AdcRecord {
+        /// Serial port the device is connected to
+        #[clap(short, long)]
+        port: String,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+
+        /// CSV file to append `timestamp_ms,channel,seq,value` rows to
+        #[clap(short, long)]
+        out: PathBuf,
+
+        /// Stop after this many seconds
+        #[clap(long)]
+        duration: Option<f64>,
+
+        /// Stop after this many samples
+        #[clap(long)]
+        count: Option<u64>,
+    },
+    /// Start the device's ADC stream and print the samples live
+    AdcMonitor {
+        /// Serial port the device is connected to
+        #[clap(short, long)]
+        port: String,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+
+        /// Sampling interval in milliseconds; 0 keeps the device's
+        /// current one
+        #[clap(long, default_value_t = 0)]
+        interval: u32,
+
+        /// Stop after this many seconds
+        #[clap(long)]
+        duration: Option<f64>,
+
+        /// Stop after this many samples
+        #[clap(long)]
+        count: Option<u64>,
+    },
+    /// Stream the device's mirrored log records to the terminal
+    Logs {
+        /// Serial port the device is connected to
+        #[clap(short, long)]
+        port: String,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+
+        /// Severity to mirror, in the device's numbering: 1 errors
+        /// only, 3 info, 5 everything up to trace
+        #[clap(short, long, default_value_t = 3)]
+        level: u8,
+    },
+    /// Sign an image, emitting a detached signature file
+    Sign {
+        /// Path to the firmware image
+        image: PathBuf,
+
+        /// Ed25519 private key (raw seed/keypair or PKCS#8 PEM)
+        #[clap(short, long)]
+        key: PathBuf,
+
+        /// Where to write the signature (defaults to `<image>.sig`)
+        #[clap(short, long)]
+        out: Option<PathBuf>,
+
+        /// Also print the verifying key for baking into firmware
+        #[clap(long)]
+        print_public_key: bool,
+    },
+    /// Generate a random segment-encryption key for --key-file
+    GenKey {
+        /// Where to write the key (64 hex digits); refuses to overwrite
+        path: PathBuf,
+    },
+    /// Diagnose a link that will not flash
+    Doctor {
+        /// Serial port: a device path, or `serial:<number>` to find the
+        /// adapter by USB serial number; omit to rely on the filters
+        #[clap(short, long)]
+        port: Option<String>,
+
+        /// Diagnose a TCP link (`host` or `host:port`, default port 3232)
+        /// instead of a serial port
+        #[clap(long, conflicts_with_all = &["port", "product", "serial-number"])]
+        tcp: Option<String>,
+
+        /// Only consider ports whose USB product contains this (case-insensitive)
+        #[clap(long)]
+        product: Option<String>,
+
+        /// Only consider the port with exactly this USB serial number
+        #[clap(long)]
+        serial_number: Option<String>,
+
+        /// Baud rate to diagnose at; alternates are probed automatically
+        /// when this one only yields noise
+        #[clap(short, long, default_value_t = DEFAULT_BAUD)]
+        baud: u32,
+    },
+    /// Confirm the image the device booted into, cancelling its pending
+    /// rollback (the host half of confirm-after-boot)
+    MarkValid {
+        /// Serial port the device is connected to
+        #[clap(short, long, required_unless_present = "tcp")]
+        port: Option<String>,
+
+        /// Confirm over TCP (`host` or `host:port`, default port 3232)
+        /// instead of a serial port
+        #[clap(long, conflicts_with = "port")]
+        tcp: Option<String>,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+    },
+    /// Reject the running image and reboot the device into the previous one
+    Rollback {
+        /// Serial port the device is connected to
+        #[clap(short, long, required_unless_present = "tcp")]
+        port: Option<String>,
+
+        /// Roll back over TCP (`host` or `host:port`, default port 3232)
+        /// instead of a serial port
+        #[clap(long, conflicts_with = "port")]
+        tcp: Option<String>,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+    },
+    /// Show what the device's last successful update looked like
+    LastUpdate {
+        /// Serial port the device is connected to
+        #[clap(short, long, required_unless_present = "tcp")]
+        port: Option<String>,
+
+        /// Query over TCP (`host` or `host:port`, default port 3232)
+        /// instead of a serial port
+        #[clap(long, conflicts_with = "port")]
+        tcp: Option<String>,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+    },
+    /// Open an interactive protocol shell: typed commands become host
+    /// messages, decoded replies are printed
+    Shell {
+        /// Serial port the device is connected to
+        #[clap(short, long, required_unless_present = "tcp")]
+        port: Option<String>,
+
+        /// Talk over TCP (`host` or `host:port`, default port 3232)
+        /// instead of a serial port
+        #[clap(long, conflicts_with = "port")]
+        tcp: Option<String>,
+
+        /// Baud rate of the update link
+        #[clap(short, long, default_value_t = 921_600)]
+        baud: u32,
+    },
+    /// List or delete the stored per-device profiles
+    Profiles {
+        /// Delete the entry stored under this key instead of listing
+        #[clap(long)]
+        delete: Option<String>,
+    },
+    /// List the serial ports available on this host
+    ListPorts {
+        /// Only list ports whose USB product contains this (case-insensitive)
+        #[clap(long)]
+        product: Option<String>,
+
+        /// Only list the port with exactly this USB serial number
+        #[clap(long)]
+        serial_number: Option<String>,
+    },
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Flash {
+            image,
+            port,
+            tcp,
+            ble,
+            product,
+            serial_number,
+            ports,
+            uart_all,
+            baud,
+            flow_control,
+            no_compress,
+            chunk_size,
+            key_file,
+            allow_plain,
+            partition,
+            reboot,
+            base,
+            signature,
+            sign_key,
+            min_version,
+            require_protocol,
+            force,
+            skip_if_same,
+            json,
+            dry_run,
+            verify_only,
+            reconnect_timeout,
+            keepalive_interval,
+            response_timeout,
+            max_retries,
+            retry_backoff_ms,
+            ack_timeout_ms,
+            retry_session,
+            resume,
+            window,
+            no_profile,
+        } => {
+            let image = fs::read(&image)
+                .with_context(|| format!("Cannot read image {}", image.display()))?;
+
+            let batch = uart_all || !ports.is_empty();
+
+            // Resolve the port up front so the device's profile can fill
+            // in whatever the command line left unset; TCP and BLE
+            // targets have no USB identity to file a profile under, and
+            // a batch spans devices whose profiles would contradict
+            let serial_target = match (&tcp, &ble) {
+                _ if batch => None,
+                (Some(_), _) | (_, Some(_)) => None,
+                (None, None) => {
+                    let available = serialport::available_ports()?;
+                    let name = flasher::ports::select(
+                        available.clone(),
+                        port.as_deref(),
+                        product.as_deref(),
+                        serial_number.as_deref(),
+                    )?;
+                    let key = flasher::profile::key_for(&available, &name);
+
+                    Some((name, key))
+                }
+            };
+
+            let store = match (&serial_target, no_profile) {
+                (Some(_), false) => Some(ProfileStore::load(ProfileStore::default_path()?)),
+                _ => None,
+            };
+
+            let stored = store
+                .as_ref()
+                .zip(serial_target.as_ref())
+                .and_then(|(store, (_, key))| store.get(key))
+                .cloned()
+                .unwrap_or_default();
+
+            let mut applied = Vec::new();
+
+            let baud = match (baud, stored.baud) {
+                (Some(baud), _) => baud,
+                (None, Some(stored_baud)) => {
+                    applied.push(format!("baud {}", stored_baud));
+                    stored_baud
+                }
+                (None, None) => DEFAULT_BAUD,
+            };
+
+            let mut flow_control = flow_control;
+            if !flow_control && stored.flow_control == Some(true) {
+                flow_control = true;
+                applied.push("flow control".to_string());
+            }
+
+            let mut no_compress = no_compress;
+            if !no_compress && stored.no_compress == Some(true) {
+                no_compress = true;
+                applied.push("no compression".to_string());
+            }
+
+            if let (false, Some((_, key))) = (applied.is_empty(), &serial_target) {
+                println!(
+                    "Applying stored profile for {}: {}",
+                    key,
+                    applied.join(", ")
+                );
+            }
+
+            let key = key_file
+                .as_deref()
+                .map(flasher::crypto::load_key)
+                .transpose()?;
+
+            let base = base
+                .map(|path| {
+                    fs::read(&path)
+                        .with_context(|| format!("Cannot read base image {}", path.display()))
+                })
+                .transpose()?;
+
+            let signature = signature
+                .as_deref()
+                .map(flasher::sign::load_signature)
+                .transpose()?;
+
+            let sign_key = sign_key
+                .as_deref()
+                .map(flasher::sign::load_signing_key)
+                .transpose()?;
+
+            let opts = FlashOpts {
+                no_compress,
+                key,
+                allow_plain,
+                partition,
+                reboot,
+                base,
+                signature,
+                sign_key,
+                min_version,
+                require_protocol,
+                force,
+                skip_if_same,
+                dry_run,
+                verify_only,
+                keepalive_interval: keepalive_interval.map(Duration::from_secs_f64),
+                retry: flasher::RetryPolicy {
+                    max_retries,
+                    backoff: Duration::from_millis(retry_backoff_ms),
+                    ack_timeout: ack_timeout_ms
+                        .map(Duration::from_millis)
+                        .or(response_timeout.map(Duration::from_secs_f64)),
+                },
+                flow_control,
+                retry_session,
+                resume,
+                chunk_size,
+                window,
+                quiet: batch,
+            };
+
+            // Several devices at once: one thread and link per port,
+            // results aggregated into a pass/fail table at the end
+            if batch {
+                let targets: Vec<String> = if ports.is_empty() {
+                    flasher::ports::filter(
+                        serialport::available_ports()?,
+                        product.as_deref(),
+                        serial_number.as_deref(),
+                    )
+                    .into_iter()
+                    .map(|info| info.port_name)
+                    .collect()
+                } else {
+                    ports
+                };
+
+                return flash_batch(
+                    &targets,
+                    &image,
+                    &opts,
+                    baud,
+                    flow_control,
+                    reconnect_timeout,
+                );
+            }
+
+            let stats = if let Some(addr) = tcp {
+                let mut link = flasher::tcp::TcpLink::connect(&addr)?;
+
+                flash(&mut link, &image, &opts)?
+            } else if let Some(name) = &ble {
+                flash_ble(name, &image, &opts)?
+            } else {
+                let (port, _) = serial_target.clone().unwrap();
+                let mut link = open_flash_link(&port, baud, flow_control, reconnect_timeout)?;
+
+                flash(&mut link, &image, &opts)?
+            };
+
+            if dry_run {
+                println!("Dry run: all pre-flight checks passed, nothing was flashed");
+                return Ok(());
+            }
+
+            // The match/mismatch line came from the library; there is
+            // no transfer to report on or profile to remember
+            if verify_only {
+                return Ok(());
+            }
+
+            // The flash went through: remember what worked for this
+            // device. Losing the update is not worth failing over.
+            if let (Some(mut store), Some((_, key))) = (store, &serial_target) {
+                store.remember(
+                    key,
+                    Profile {
+                        baud: Some(baud),
+                        flow_control: Some(flow_control),
+                        no_compress: Some(no_compress),
+                    },
+                );
+
+                if let Err(err) = store.save() {
+                    eprintln!("warning: cannot update the profile store: {:#}", err);
+                }
+            }
+
+            if json {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else {
+                println!("{}", stats);
+
+                if stats.compressed_segments > 0 {
+                    println!(
+                        "Compression ratio {:.2} (effective speedup {:.2}x)",
+                        stats.compression_ratio(),
+                        1.0 / stats.compression_ratio()
+                    );
+                }
+            }
+        }
+        Command::FlashManifest {
+            manifest,
+            port,
+            baud,
+            only,
+            keep_going,
+        } => {
+            let manifest = flasher::manifest::load(&manifest)?;
+
+            let entries: Vec<_> = manifest
+                .entries
+                .iter()
+                .filter(|entry| {
+                    only.as_deref()
+                        .is_none_or(|only| entry.target.to_string() == only)
+                })
+                .collect();
+
+            if entries.is_empty() {
+                anyhow::bail!(
+                    "No manifest entry matches --only {}",
+                    only.as_deref().unwrap_or("")
+                );
+            }
+
+            let link = serialport::new(&port, baud)
+                .timeout(Duration::from_millis(100))
+                .open()
+                .with_context(|| format!("Cannot open port {}", port))?;
+            let mut link = flasher::serial::SerialLink::new(link);
+
+            let total = entries.len();
+            let mut failed = 0;
+
+            for entry in entries {
+                println!("=== {} <- {}", entry.target, entry.path.display());
+
+                let result = fs::read(&entry.path)
+                    .with_context(|| format!("Cannot read image {}", entry.path.display()))
+                    .and_then(|data| {
+                        flasher::manifest::verify_sha256(entry, &data)?;
+
+                        flash(
+                            &mut link,
+                            &data,
+                            &FlashOpts {
+                                partition: match &entry.target {
+                                    flasher::manifest::Target::App => None,
+                                    flasher::manifest::Target::Partition(label) => {
+                                        Some(label.clone())
+                                    }
+                                },
+                                ..Default::default()
+                            },
+                        )
+                        .map(|_| ())
+                    });
+
+                match result {
+                    Ok(()) => println!("{}: ok", entry.target),
+                    Err(err) => {
+                        eprintln!("{}: failed: {:#}", entry.target, err);
+                        failed += 1;
+
+                        if !keep_going {
+                            anyhow::bail!(
+                                "Stopping after the first failure; pass --keep-going to continue"
+                            );
+                        }
+                    }
+                }
+            }
+
+            if failed > 0 {
+                anyhow::bail!("{} of {} manifest entries failed", failed, total);
+            }
+
+            println!("All {} manifest entries flashed", total);
+        }
+        Command::AdcRecord {
+            port,
+            baud,
+            out,
+            duration,
+            count,
+        } => {
+            let link = serialport::new(&port, baud)
+                .timeout(Duration::from_millis(100))
+                .open()
+                .with_context(|| format!("Cannot open port {}", port))?;
+            let mut link = flasher::serial::SerialLink::new(link);
+
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&out)
+                .with_context(|| format!("Cannot open {}", out.display()))?;
+
+            let stop = Arc::new(AtomicBool::new(false));
+            {
+                let stop = stop.clone();
+                ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))?;
+            }
+
+            let summary = flasher::record::record(
+                &mut link,
+                &mut file,
+                &flasher::record::RecordOpts {
+                    duration: duration.map(Duration::from_secs_f64),
+                    count,
+                },
+                &stop,
+            )?;
+
+            println!(
+                "Recorded {} samples to {} ({} missed)",
+                summary.samples,
+                out.display(),
+                summary.missed
+            );
+        }
+        Command::AdcMonitor {
+            port,
+            baud,
+            interval,
+            duration,
+            count,
+        } => {
+            use std::io::Write;
+
+            let mut link = open_probe_port(&port, baud)?;
+
+            // Unlike adc-record this is not passive: the stream is
+            // started here and stopped again on the way out, so a
+            // device that boots with telemetry off works too
+            flasher::send_message(
+                &mut link,
+                &messages::MessageTypeHost::AdcStart {
+                    interval_ms: interval,
+                },
+            )?;
+
+            let stop = Arc::new(AtomicBool::new(false));
+            {
+                let stop = stop.clone();
+                ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))?;
+            }
+
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+
+            let result = flasher::record::monitor(
+                &mut link,
+                &mut out,
+                &flasher::record::RecordOpts {
+                    duration: duration.map(Duration::from_secs_f64),
+                    count,
+                },
+                &stop,
+            );
+
+            // Best effort: failing to stop only leaves the stream
+            // running, like it was before adc-monitor existed
+            let _ = flasher::send_message(&mut link, &messages::MessageTypeHost::AdcStop);
+
+            let summary = result?;
+            out.flush()?;
+
+            println!("{} samples ({} missed)", summary.samples, summary.missed);
+        }
+        Command::Logs { port, baud, level } => {
+            let mut link = open_probe_port(&port, baud)?;
+
+            // The mirror boots switched off; ask for the requested
+            // severity and switch it back off again on the way out
+            flasher::send_message(&mut link, &messages::MessageTypeHost::SetLogLevel(level))?;
+
+            let stop = Arc::new(AtomicBool::new(false));
+            {
+                let stop = stop.clone();
+                ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))?;
+            }
+
+            eprintln!("Streaming device logs at level {}; Ctrl-C stops", level);
+
+            let mut reader = flasher::FrameReader::new();
+            let mut stats = flasher::Stats::default();
+
+            let result = loop {
+                if stop.load(Ordering::Relaxed) {
+                    break Ok(());
+                }
+
+                let timeouts_before = stats.timeouts;
+                match reader.read_message(&mut link, Duration::from_millis(200), &mut stats) {
+                    Ok(messages::MessageTypeMcu::Log(record)) => {
+                        println!(
+                            "[{:5} {}] {}",
+                            record.level_name(),
+                            record.target,
+                            record.message
+                        );
+                    }
+                    // Telemetry and stray acks share the line; only
+                    // the log mirror is of interest here
+                    Ok(_) => (),
+                    Err(err) if stats.timeouts == timeouts_before => {
+                        break Err(err.context("Reading from the device failed"));
+                    }
+                    // A quiet link is just a device with nothing to say
+                    Err(_) => (),
+                }
+            };
+
+            let _ = flasher::send_message(&mut link, &messages::MessageTypeHost::SetLogLevel(0));
+
+            result?;
+        }
+        Command::Sign {
+            image,
+            key,
+            out,
+            print_public_key,
+        } => {
+            let data = fs::read(&image)
+                .with_context(|| format!("Cannot read image {}", image.display()))?;
+
+            let key = flasher::sign::load_signing_key(&key)?;
+            let signature = flasher::sign::sign_image(&key, &data);
+
+            let out = out.unwrap_or_else(|| {
+                let mut sig = image.clone();
+                sig.set_extension("sig");
+                sig
+            });
+
+            fs::write(&out, &signature)
+                .with_context(|| format!("Cannot write signature {}", out.display()))?;
+            println!("Wrote signature to {}", out.display());
+
+            if print_public_key {
+                println!("Verifying key: {}", flasher::sign::public_key_hex(&key));
+            }
+        }
+        Command::GenKey { path } => {
+            use std::io::Write;
+
+            let key = Zeroizing::new(rand::random::<[u8; flasher::crypto::KEY_LEN]>());
+            let hex = Zeroizing::new(
+                key.iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>(),
+            );
+
+            // create_new refuses to clobber a key already in use, and on
+            // unix the file starts out readable by its owner only
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+
+            let mut file = options
+                .open(&path)
+                .with_context(|| format!("Cannot create key file {}", path.display()))?;
+            writeln!(file, "{}", *hex)
+                .with_context(|| format!("Cannot write key file {}", path.display()))?;
+
+            println!(
+                "Wrote a new {}-byte key to {}; flash with --key-file and \
+                 provision the same key on the device",
+                flasher::crypto::KEY_LEN,
+                path.display()
+            );
+        }
+        Command::Doctor {
+            port,
+            tcp,
+            product,
+            serial_number,
+            baud,
+        } => {
+            use flasher::doctor::{self, LineProbe};
+
+            let mut working_baud = None;
+
+            let report = if let Some(addr) = &tcp {
+                let mut link = flasher::tcp::TcpLink::connect(addr)?;
+                println!("ok: connected to {}", addr);
+
+                doctor::run(&mut link, doctor::PROBE_WINDOW)?
+            } else {
+                let name = flasher::ports::select(
+                    serialport::available_ports()?,
+                    port.as_deref(),
+                    product.as_deref(),
+                    serial_number.as_deref(),
+                )?;
+
+                let mut link = open_probe_port(&name, baud)?;
+                println!("ok: port {} opens", name);
+
+                let report = doctor::run(&mut link, doctor::PROBE_WINDOW)?;
+                drop(link);
+
+                // Only noise at the configured rate: see whether any of
+                // the usual rates yields real frames before blaming the
+                // wiring
+                if report.line == LineProbe::Noise {
+                    for &alt in doctor::COMMON_BAUDS.iter().filter(|&&alt| alt != baud) {
+                        let mut link = match open_probe_port(&name, alt) {
+                            Ok(link) => link,
+                            Err(_) => continue,
+                        };
+
+                        if doctor::probe_line(&mut link, doctor::PROBE_WINDOW)? == LineProbe::Frames
+                        {
+                            working_baud = Some(alt);
+                            break;
+                        }
+                    }
+                }
+
+                report
+            };
+
+            let failed = report_findings(&report, working_baud);
+            if failed > 0 {
+                anyhow::bail!("{} check(s) failed", failed);
+            }
+
+            println!("All checks passed; if flashing still fails, run it with --dry-run");
+        }
+        Command::MarkValid { port, tcp, baud } => {
+            match (port, tcp) {
+                (_, Some(addr)) => {
+                    let mut link = flasher::tcp::TcpLink::connect(&addr)?;
+                    flasher::mark_valid(&mut link, &FlashOpts::default())?;
+                }
+                (Some(port), None) => {
+                    let mut link = open_probe_port(&port, baud)?;
+                    flasher::mark_valid(&mut link, &FlashOpts::default())?;
+                }
+                (None, None) => unreachable!("clap requires one of --port and --tcp"),
+            }
+
+            println!("Running image marked valid; the pending rollback is cancelled");
+        }
+        Command::Rollback { port, tcp, baud } => {
+            match (port, tcp) {
+                (_, Some(addr)) => {
+                    let mut link = flasher::tcp::TcpLink::connect(&addr)?;
+                    flasher::rollback(&mut link, &FlashOpts::default())?;
+                }
+                (Some(port), None) => {
+                    let mut link = open_probe_port(&port, baud)?;
+                    flasher::rollback(&mut link, &FlashOpts::default())?;
+                }
+                (None, None) => unreachable!("clap requires one of --port and --tcp"),
+            }
+
+            println!("Device acknowledged the rollback and is rebooting into the previous image");
+        }
+        Command::LastUpdate { port, tcp, baud } => {
+            let record = match (port, tcp) {
+                (_, Some(addr)) => {
+                    let mut link = flasher::tcp::TcpLink::connect(&addr)?;
+                    flasher::last_update(&mut link, &FlashOpts::default())?
+                }
+                (Some(port), None) => {
+                    let mut link = open_probe_port(&port, baud)?;
+                    flasher::last_update(&mut link, &FlashOpts::default())?
+                }
+                (None, None) => unreachable!("clap requires one of --port and --tcp"),
+            };
+
+            match record {
+                Some(record) => {
+                    // A stamp before the year 2000 means the device's
+                    // clock was never set and counted from 1970
+                    const Y2K: u64 = 946_684_800;
+
+                    let when = if record.timestamp < Y2K {
+                        format!(
+                            "{} s after boot (device clock was never set)",
+                            record.timestamp
+                        )
+                    } else {
+                        format!("{} (unix seconds)", record.timestamp)
+                    };
+
+                    println!("Last successful update:");
+                    println!("  sha256:    {}", flasher::hash_hex(&record.sha256));
+                    println!("  source:    {}", record.source);
+                    println!("  when:      {}", when);
+                    println!("  segments:  {}", record.segments);
+                    println!("  retries:   {}", record.retries);
+                }
+                None => println!("Device has no recorded update"),
+            }
+        }
+        Command::Shell { port, tcp, baud } => {
+            let stdin = std::io::stdin();
+
+            match (port, tcp) {
+                (_, Some(addr)) => {
+                    let mut link = flasher::tcp::TcpLink::connect(&addr)?;
+                    flasher::shell::run(&mut link, stdin.lock())?;
+                }
+                (Some(port), None) => {
+                    let mut link = open_probe_port(&port, baud)?;
+                    flasher::shell::run(&mut link, stdin.lock())?;
+                }
+                (None, None) => unreachable!("clap requires one of --port and --tcp"),
+            }
+        }
+        Command::Profiles { delete } => {
+            let mut store = ProfileStore::load(ProfileStore::default_path()?);
+
+            match delete {
+                Some(key) => {
+                    if !store.remove(&key) {
+                        anyhow::bail!("No profile stored under {:?}", key);
+                    }
+
+                    store.save()?;
+                    println!("Deleted profile {}", key);
+                }
+                None if store.is_empty() => {
+                    println!("No profiles stored in {}", store.path().display());
+                }
+                None => {
+                    println!("Profiles in {}:", store.path().display());
+                    for (key, profile) in store.iter() {
+                        println!("  {:<24} {}", key, profile);
+                    }
+                }
+            }
+        }
+        Command::ListPorts {
+            product,
+            serial_number,
+        } => {
+            let ports = flasher::ports::filter(
+                serialport::available_ports()?,
+                product.as_deref(),
+                serial_number.as_deref(),
+            );
+
+            for port in ports {
+                println!("{}", flasher::ports::describe(&port));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Flashes over a BLE link to the device advertising `name`.
+#[cfg(feature = "ble")]
+fn flash_ble(name: &str, image: &[u8], opts: &FlashOpts) -> Result<flasher::Stats> {
+    let mut link = flasher::ble::BleLink::connect(name)?;
+
+    flash(&mut link, image, opts)
+}
+
+#[cfg(not(feature = "ble"))]
+fn flash_ble(_name: &str, _image: &[u8], _opts: &FlashOpts) -> Result<flasher::Stats> {
+    anyhow::bail!("This flasher was built without BLE support; rebuild with --features ble")
+}
+
+/// Opens `name` at `baud` with the same settings the flash path uses,
+/// minus flow control; shared by the doctor and the one-shot commands.
+/// Opens a serial port wrapped in the reconnecting link transfers run
+/// over, so an unplug mid-update gets its grace period.
+fn open_flash_link(
+    port: &str,
+    baud: u32,
+    flow_control: bool,
+    reconnect_timeout: f64,
+) -> Result<impl flasher::Transport> {
+    let port_flow_control = if flow_control {
+        serialport::FlowControl::Hardware
+    } else {
+        serialport::FlowControl::None
+    };
+
+    let link = serialport::new(port, baud)
+        .timeout(Duration::from_millis(100))
+        .flow_control(port_flow_control)
+        .open()
+        .with_context(|| format!("Cannot open port {}", port))?;
+
+    let usb_serial = flasher::reconnect::usb_serial_of(port);
+    let port = port.to_string();
+
+    Ok(flasher::reconnect::ReconnectingLink::new(
+        flasher::serial::SerialLink::new(link),
+        move || flasher::reconnect::reopen(&port, usb_serial.as_deref(), baud, port_flow_control),
+        Duration::from_secs_f64(reconnect_timeout),
+    ))
+}
+
+/// Flashes the same image to several devices at once, one thread and
+/// link per port, then prints a pass/fail table once the last one is
+/// done. A port that cannot be opened fails that device, not the batch;
+/// the exit status reflects whether every device made it.
+fn flash_batch(
+    ports: &[String],
+    image: &[u8],
+    opts: &FlashOpts,
+    baud: u32,
+    flow_control: bool,
+    reconnect_timeout: f64,
+) -> Result<()> {
+    if ports.is_empty() {
+        anyhow::bail!("No serial port matches the given filters");
+    }
+
+    println!("Flashing {} device(s) in parallel", ports.len());
+
+    let results: Vec<(&str, Duration, Result<flasher::Stats>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = ports
+            .iter()
+            .map(|port| {
+                scope.spawn(move || {
+                    let started = std::time::Instant::now();
+
+                    let result = open_flash_link(port, baud, flow_control, reconnect_timeout)
+                        .and_then(|mut link| flash(&mut link, image, opts));
+
+                    match &result {
+                        Ok(_) => println!("{}: done", port),
+                        Err(err) => println!("{}: FAILED: {:#}", port, err),
+                    }
+
+                    (port.as_str(), started.elapsed(), result)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("a flashing thread panicked"))
+            .collect()
+    });
+
+    println!();
+    println!(
+        "{:<20} {:<8} {:>8} {:>10} {:>9}",
+        "Port", "Result", "Time", "Segments", "Retries"
+    );
+
+    let mut failed = 0;
+    for (port, elapsed, result) in &results {
+        match result {
+            Ok(stats) => println!(
+                "{:<20} {:<8} {:>7.1}s {:>10} {:>9}",
+                port,
+                "ok",
+                elapsed.as_secs_f64(),
+                stats.segments,
+                stats.retries()
+            ),
+            Err(_) => {
+                failed += 1;
+                println!(
+                    "{:<20} {:<8} {:>7.1}s {:>10} {:>9}",
+                    port,
+                    "FAILED",
+                    elapsed.as_secs_f64(),
+                    "-",
+                    "-"
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} device(s) failed", failed, results.len());
+    }
+
+    println!("All {} device(s) flashed", results.len());
+
+    Ok(())
+}
+
+fn open_probe_port(name: &str, baud: u32) -> Result<flasher::serial::SerialLink> {
+    let port = serialport::new(name, baud)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .with_context(|| format!("Cannot open port {}", name))?;
+
+    Ok(flasher::serial::SerialLink::new(port))
+}
+
+/// Prints a verdict per check with the most likely cause and remedy on
+/// failure; returns how many checks failed. `working_baud` is a rate at
+/// which frames did decode, when the configured one only yielded noise.
+fn report_findings(report: &flasher::doctor::Report, working_baud: Option<u32>) -> usize {
+    use flasher::doctor::LineProbe;
+
+    let mut failed = 0;
+
+    match report.line {
+        LineProbe::Silent => {
+            failed += 1;
+            println!("FAIL: not a single byte arrived");
+            println!("      likely: device off, TX/RX swapped, or the wrong port");
+        }
+        LineProbe::Noise => {
+            failed += 1;
+            println!("FAIL: bytes arrive but none decode as protocol frames");
+            match working_baud {
+                Some(alt) => println!(
+                    "      likely: wrong baud rate; frames decode at {} baud, pass --baud {}",
+                    alt, alt
+                ),
+                None => {
+                    println!("      likely: wrong baud rate, or this UART carries the console log")
+                }
+            }
+        }
+        LineProbe::Frames => println!("ok: protocol frames decode"),
+    }
+
+    if report.line != LineProbe::Frames {
+        println!("skipped: the remaining checks need a decodable line");
+        return failed;
+    }
+
+    if report.pong {
+        println!("ok: Ping answered with Pong");
+    } else {
+        failed += 1;
+        println!("FAIL: frames decode but Ping goes unanswered");
+        println!("      likely: the updater task is not running, or another program on this port is eating the replies");
+    }
+
+    match &report.info {
+        None => {
+            failed += 1;
+            println!("FAIL: GetInfo goes unanswered");
+            println!("      likely: firmware predates the info exchange; flashing may still work");
+        }
+        Some(info) => {
+            match flasher::doctor::protocol_mismatch(info) {
+                Some(problem) => {
+                    failed += 1;
+                    println!("FAIL: {}", problem);
+                    println!("      likely: flasher and firmware from different releases; update one of them");
+                }
+                None => println!(
+                    "ok: protocol version {} on both ends",
+                    info.protocol_version
+                ),
+            }
+
+            if flasher::doctor::missing_ota_slot(info) {
+                failed += 1;
+                println!("FAIL: the device reports no OTA update slot");
+                println!("      likely: a partition table without OTA slots; fix the table before flashing");
+            } else {
+                println!("ok: an OTA update slot is available");
+            }
+        }
+    }
+
+    failed
+}
@@ -0,0 +1,340 @@
+//! Manifest-driven provisioning: several images flashed in one session.
+//!
+//! A manifest is a TOML or JSON file listing images and where they go:
+//!
+//! ```toml
+//! [[image]]
+//! path = "app.bin"
+//! target = "app"
+//!
+//! [[image]]
+//! path = "web.bin"
+//! target = "spiffs"
+//! sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! ```
+//!
+//! `target` is either `app` (the next OTA app slot) or a partition label.
+//! Relative paths are resolved against the manifest's directory.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use messages::HASH_LEN;
+
+/// ESP-IDF partition labels are at most this many bytes.
+const MAX_LABEL_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The next OTA app slot.
+    App,
+    /// A non-app partition, looked up by label.
+    Partition(String),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::App => write!(f, "app"),
+            Target::Partition(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    /// Image path, already resolved against the manifest's directory.
+    pub path: PathBuf,
+    pub target: Target,
+    /// Expected image digest; checked before anything goes on the wire.
+    pub sha256: Option<[u8; HASH_LEN]>,
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+/// The manifest as it appears on disk, before validation.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawManifest {
+    #[serde(rename = "image")]
+    images: Vec<RawEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEntry {
+    path: PathBuf,
+    target: String,
+    sha256: Option<String>,
+}
+
+/// Reads and validates a manifest file; the format is chosen by the
+/// `.json` extension, anything else parses as TOML.
+pub fn load(path: &Path) -> Result<Manifest> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read manifest {}", path.display()))?;
+
+    let raw: RawManifest = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&text)
+            .with_context(|| format!("Manifest {} is not valid JSON", path.display()))?
+    } else {
+        toml::from_str(&text)
+            .with_context(|| format!("Manifest {} is not valid TOML", path.display()))?
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    validate(raw, dir)
+}
+
+/// Turns the raw manifest into a validated one: known targets, no
+/// duplicates, existing files, well-formed digests.
+fn validate(raw: RawManifest, dir: &Path) -> Result<Manifest> {
+    if raw.images.is_empty() {
+        bail!("Manifest lists no images");
+    }
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut entries = Vec::with_capacity(raw.images.len());
+
+    for (index, image) in raw.images.into_iter().enumerate() {
+        let entry = index + 1;
+
+        let target =
+            parse_target(&image.target).with_context(|| format!("Manifest entry {}", entry))?;
+
+        if let Some(first) = seen.insert(image.target.clone(), entry) {
+            bail!(
+                "Manifest entries {} and {} both write target {:?}",
+                first,
+                entry,
+                image.target
+            );
+        }
+
+        let path = if image.path.is_absolute() {
+            image.path
+        } else {
+            dir.join(&image.path)
+        };
+
+        if !path.is_file() {
+            bail!(
+                "Manifest entry {}: image {} does not exist",
+                entry,
+                path.display()
+            );
+        }
+
+        let sha256 = image
+            .sha256
+            .as_deref()
+            .map(parse_sha256)
+            .transpose()
+            .with_context(|| format!("Manifest entry {}", entry))?;
+
+        entries.push(Entry {
+            path,
+            target,
+            sha256,
+        });
+    }
+
+    Ok(Manifest { entries })
+}
+
+fn parse_target(target: &str) -> Result<Target> {
+    if target == "app" {
+        return Ok(Target::App);
+    }
+
+    let valid = !target.is_empty()
+        && target.len() <= MAX_LABEL_LEN
+        && target
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if !valid {
+        bail!(
+            "Unknown target {:?}; expected \"app\" or a partition label \
+             (up to {} alphanumeric/underscore/dash characters)",
+            target,
+            MAX_LABEL_LEN
+        );
+    }
+
+    Ok(Target::Partition(target.to_string()))
+}
+
+fn parse_sha256(hex: &str) -> Result<[u8; HASH_LEN]> {
+    let hex = hex.trim();
+
+    if hex.len() != HASH_LEN * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(
+            "sha256 must be {} hex characters, got {:?}",
+            HASH_LEN * 2,
+            hex
+        );
+    }
+
+    let mut digest = [0_u8; HASH_LEN];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+    }
+
+    Ok(digest)
+}
+
+/// Checks `data` against the digest pinned in the manifest, if any.
+pub fn verify_sha256(entry: &Entry, data: &[u8]) -> Result<()> {
+    if let Some(expected) = &entry.sha256 {
+        let actual: [u8; HASH_LEN] = Sha256::digest(data).into();
+
+        if &actual != expected {
+            bail!(
+                "{} does not match the sha256 pinned in the manifest",
+                entry.path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    /// A directory holding an `app.bin` and `web.bin` for path checks.
+    fn image_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flasher-manifest-{}", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("app.bin")).unwrap();
+        File::create(dir.join("web.bin")).unwrap();
+
+        dir
+    }
+
+    fn validate_toml(text: &str, dir: &Path) -> Result<Manifest> {
+        validate(toml::from_str(text).unwrap(), dir)
+    }
+
+    #[test]
+    fn accepts_app_and_partition_targets() {
+        let dir = image_dir();
+
+        let manifest = validate_toml(
+            r#"
+            [[image]]
+            path = "app.bin"
+            target = "app"
+
+            [[image]]
+            path = "web.bin"
+            target = "spiffs"
+            sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+            "#,
+            &dir,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[0].target, Target::App);
+        assert_eq!(manifest.entries[0].path, dir.join("app.bin"));
+        assert_eq!(
+            manifest.entries[1].target,
+            Target::Partition("spiffs".to_string())
+        );
+        assert!(manifest.entries[1].sha256.is_some());
+    }
+
+    #[test]
+    fn rejects_unknown_targets() {
+        let err = validate_toml(
+            r#"
+            [[image]]
+            path = "app.bin"
+            target = "not a label!"
+            "#,
+            &image_dir(),
+        )
+        .unwrap_err();
+
+        assert!(format!("{:#}", err).contains("Unknown target"));
+    }
+
+    #[test]
+    fn rejects_duplicate_targets() {
+        let err = validate_toml(
+            r#"
+            [[image]]
+            path = "app.bin"
+            target = "spiffs"
+
+            [[image]]
+            path = "web.bin"
+            target = "spiffs"
+            "#,
+            &image_dir(),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("entries 1 and 2"));
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        let err = validate_toml(
+            r#"
+            [[image]]
+            path = "nonexistent.bin"
+            target = "app"
+            "#,
+            &image_dir(),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn rejects_malformed_digests() {
+        let err = validate_toml(
+            r#"
+            [[image]]
+            path = "app.bin"
+            target = "app"
+            sha256 = "beef"
+            "#,
+            &image_dir(),
+        )
+        .unwrap_err();
+
+        assert!(format!("{:#}", err).contains("64 hex characters"));
+    }
+
+    #[test]
+    fn digest_mismatch_is_caught() {
+        let entry = Entry {
+            path: PathBuf::from("app.bin"),
+            target: Target::App,
+            sha256: Some([0_u8; HASH_LEN]),
+        };
+
+        let err = verify_sha256(&entry, b"not the pinned bytes").unwrap_err();
+
+        assert!(err.to_string().contains("does not match"));
+    }
+}
@@ -0,0 +1,270 @@
+//! Listing and selecting serial ports when several adapters are attached.
+//!
+//! Everything here is a pure function over `Vec<SerialPortInfo>` so the
+//! selection rules can be unit-tested without hardware; `main.rs` feeds in
+//! `serialport::available_ports()`.
+
+use anyhow::{bail, Result};
+use serialport::{SerialPortInfo, SerialPortType};
+
+/// Prefix selecting a port by USB serial number instead of device path,
+/// immune to udev renumbering: `serial:0123ABCD`.
+pub const SERIAL_PREFIX: &str = "serial:";
+
+/// Espressif's USB vendor id. Chips speaking USB natively - the
+/// USB-Serial-JTAG port and the TinyUSB CDC-ACM device - enumerate
+/// with it themselves, unlike the third-party UART bridge chips on
+/// older devkits, so the vid singles out the device among bystanders.
+pub const ESPRESSIF_VID: u16 = 0x303a;
+
+fn is_native_usb(port: &SerialPortInfo) -> bool {
+    matches!(&port.port_type, SerialPortType::UsbPort(info) if info.vid == ESPRESSIF_VID)
+}
+
+fn usb_serial(port: &SerialPortInfo) -> Option<&str> {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => info.serial_number.as_deref(),
+        _ => None,
+    }
+}
+
+/// One listing line: device path plus whatever USB metadata the OS knows.
+pub fn describe(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => format!(
+            "{:<20} {:04x}:{:04x}  {:<24} {:<24} {}",
+            port.port_name,
+            info.vid,
+            info.pid,
+            info.manufacturer.as_deref().unwrap_or("-"),
+            info.product.as_deref().unwrap_or("-"),
+            info.serial_number.as_deref().unwrap_or("-"),
+        ),
+        other => format!("{:<20} {:?}", port.port_name, other),
+    }
+}
+
+/// Narrows `ports` down by a case-insensitive product substring and/or an
+/// exact USB serial number.
+pub fn filter(
+    ports: Vec<SerialPortInfo>,
+    product: Option<&str>,
+    serial_number: Option<&str>,
+) -> Vec<SerialPortInfo> {
+    ports
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                let product_matches = product.is_none_or(|wanted| {
+                    info.product
+                        .as_deref()
+                        .is_some_and(|p| p.to_lowercase().contains(&wanted.to_lowercase()))
+                });
+
+                let serial_matches = serial_number
+                    .is_none_or(|wanted| info.serial_number.as_deref() == Some(wanted));
+
+                product_matches && serial_matches
+            }
+            // Non-USB ports have none of the metadata to match on
+            _ => product.is_none() && serial_number.is_none(),
+        })
+        .collect()
+}
+
+/// Picks the port to open. `spec` is a device path, `serial:<number>`, or
+/// `None` to rely on the `--product` / `--serial-number` filters alone;
+/// anything ambiguous is an error rather than a guess.
+pub fn select(
+    ports: Vec<SerialPortInfo>,
+    spec: Option<&str>,
+    product: Option<&str>,
+    serial_number: Option<&str>,
+) -> Result<String> {
+    // Explicit device paths pass through untouched: udev symlinks do not
+    // show up in the enumeration
+    if let Some(spec) = spec {
+        match spec.strip_prefix(SERIAL_PREFIX) {
+            None => return Ok(spec.to_string()),
+            Some(serial) => {
+                let matches: Vec<_> = filter(ports, product, serial_number)
+                    .into_iter()
+                    .filter(|port| usb_serial(port) == Some(serial))
+                    .collect();
+
+                return match matches.len() {
+                    1 => Ok(matches[0].port_name.clone()),
+                    0 => bail!("No serial port with USB serial number {:?}", serial),
+                    _ => bail!(
+                        "USB serial number {:?} matches several ports:\n{}",
+                        serial,
+                        listing(&matches)
+                    ),
+                };
+            }
+        }
+    }
+
+    let candidates = filter(ports, product, serial_number);
+
+    match candidates.len() {
+        1 => Ok(candidates[0].port_name.clone()),
+        0 => bail!("No serial port matches the given filters"),
+        _ => {
+            // Several matches, but only one of them is the device
+            // itself: a lone Espressif-vid port wins over whatever
+            // UART bridges happen to share the bench
+            let native: Vec<_> = candidates
+                .iter()
+                .filter(|port| is_native_usb(port))
+                .collect();
+
+            if let [port] = native.as_slice() {
+                return Ok(port.port_name.clone());
+            }
+
+            bail!(
+                "Several ports match; narrow it down with --port, --product or --serial-number:\n{}",
+                listing(&candidates)
+            )
+        }
+    }
+}
+
+fn listing(ports: &[SerialPortInfo]) -> String {
+    ports
+        .iter()
+        .map(|port| format!("  {}", describe(port)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serialport::UsbPortInfo;
+
+    fn usb(name: &str, product: &str, serial: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x10c4,
+                pid: 0xea60,
+                serial_number: Some(serial.to_string()),
+                manufacturer: Some("Silicon Labs".to_string()),
+                product: Some(product.to_string()),
+            }),
+        }
+    }
+
+    fn bench() -> Vec<SerialPortInfo> {
+        vec![
+            usb("/dev/ttyUSB0", "CP2102 USB to UART Bridge", "0001"),
+            usb("/dev/ttyUSB1", "CP2102 USB to UART Bridge", "0002"),
+            usb("/dev/ttyUSB2", "FT232R USB UART", "A5004"),
+        ]
+    }
+
+    fn native(name: &str, serial: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: ESPRESSIF_VID,
+                pid: 0x4001,
+                serial_number: Some(serial.to_string()),
+                manufacturer: Some("Espressif".to_string()),
+                product: Some("USB CDC device".to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn product_filter_is_a_case_insensitive_substring() {
+        let matches = filter(bench(), Some("ft232"), None);
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].port_name, "/dev/ttyUSB2");
+    }
+
+    #[test]
+    fn serial_number_filter_is_exact() {
+        assert_eq!(filter(bench(), None, Some("0001")).len(), 1);
+        assert_eq!(filter(bench(), None, Some("000")).len(), 0);
+    }
+
+    #[test]
+    fn filters_combine() {
+        let matches = filter(bench(), Some("cp2102"), Some("0002"));
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].port_name, "/dev/ttyUSB1");
+
+        assert!(filter(bench(), Some("ft232"), Some("0002")).is_empty());
+    }
+
+    #[test]
+    fn explicit_paths_pass_through() {
+        let port = select(bench(), Some("/dev/serial/by-id/foo"), None, None).unwrap();
+
+        assert_eq!(port, "/dev/serial/by-id/foo");
+    }
+
+    #[test]
+    fn serial_spec_resolves_to_the_current_path() {
+        let port = select(bench(), Some("serial:A5004"), None, None).unwrap();
+
+        assert_eq!(port, "/dev/ttyUSB2");
+    }
+
+    #[test]
+    fn unknown_serial_spec_errors() {
+        let err = select(bench(), Some("serial:FFFF"), None, None).unwrap_err();
+
+        assert!(err.to_string().contains("FFFF"));
+    }
+
+    #[test]
+    fn unique_filter_match_is_auto_selected() {
+        let port = select(bench(), None, Some("ft232"), None).unwrap();
+
+        assert_eq!(port, "/dev/ttyUSB2");
+    }
+
+    #[test]
+    fn ambiguous_matches_error_instead_of_guessing() {
+        let err = select(bench(), None, Some("cp2102"), None).unwrap_err();
+
+        let msg = err.to_string();
+        assert!(msg.contains("/dev/ttyUSB0"));
+        assert!(msg.contains("/dev/ttyUSB1"));
+    }
+
+    #[test]
+    fn no_match_errors() {
+        select(bench(), None, Some("nonexistent"), None).unwrap_err();
+    }
+
+    #[test]
+    fn a_lone_native_usb_port_wins_over_uart_bridges() {
+        let mut ports = bench();
+        ports.push(native("/dev/ttyACM0", "E0F1"));
+
+        let port = select(ports, None, None, None).unwrap();
+
+        assert_eq!(port, "/dev/ttyACM0");
+    }
+
+    #[test]
+    fn two_native_usb_ports_are_still_ambiguous() {
+        let mut ports = bench();
+        ports.push(native("/dev/ttyACM0", "E0F1"));
+        ports.push(native("/dev/ttyACM1", "E0F2"));
+
+        let err = select(ports, None, None, None).unwrap_err();
+
+        let msg = err.to_string();
+        assert!(msg.contains("/dev/ttyACM0"));
+        assert!(msg.contains("/dev/ttyACM1"));
+    }
+}
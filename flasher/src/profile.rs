@@ -0,0 +1,340 @@
+//! Per-device flash profiles: the link settings that worked last time,
+//! keyed by the adapter's USB serial number.
+//!
+//! After a successful flash the settings in play are stored in a TOML
+//! file in the platform config directory; the next run against the same
+//! device applies them wherever the command line left a value unset.
+//! `--no-profile` skips the store entirely and the `profiles`
+//! subcommand lists and deletes entries.
+//!
+//! ```toml
+//! [device.0123ABCD]
+//! baud = 460800
+//! flow_control = true
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serialport::{SerialPortInfo, SerialPortType};
+
+/// The remembered subset of the flash options: link settings someone
+/// retypes per bench device, not anything tied to one image. Every
+/// field is optional so files written by newer versions still parse.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baud: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_control: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_compress: Option<bool>,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+
+        if let Some(baud) = self.baud {
+            parts.push(format!("baud {}", baud));
+        }
+        if let Some(flow_control) = self.flow_control {
+            parts.push(format!(
+                "flow control {}",
+                if flow_control { "on" } else { "off" }
+            ));
+        }
+        if let Some(no_compress) = self.no_compress {
+            parts.push(format!(
+                "compression {}",
+                if no_compress { "off" } else { "on" }
+            ));
+        }
+
+        if parts.is_empty() {
+            return write!(f, "(empty)");
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// The store as it appears on disk, one `[device.<key>]` table per entry.
+#[derive(Default, Serialize, Deserialize)]
+struct RawStore {
+    #[serde(default, rename = "device")]
+    devices: BTreeMap<String, Profile>,
+}
+
+/// The key a device's settings are filed under: the adapter's USB
+/// serial number when it reports one - stable however the ports
+/// renumber - otherwise VID:PID plus the port name, which at least pins
+/// "the board on this cable". Non-USB ports fall back to the bare name.
+pub fn key_for(ports: &[SerialPortInfo], port_name: &str) -> String {
+    let port = ports.iter().find(|port| port.port_name == port_name);
+
+    match port.map(|port| &port.port_type) {
+        Some(SerialPortType::UsbPort(info)) => match info.serial_number.as_deref() {
+            Some(serial) if !serial.is_empty() => serial.to_string(),
+            _ => format!("{:04x}:{:04x}@{}", info.vid, info.pid, port_name),
+        },
+        _ => port_name.to_string(),
+    }
+}
+
+/// The profile file, loaded once and written back atomically.
+pub struct ProfileStore {
+    path: PathBuf,
+    devices: BTreeMap<String, Profile>,
+}
+
+impl ProfileStore {
+    /// Where the store lives by default: `flasher/profiles.toml` in the
+    /// platform config directory.
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(config_dir()?.join("flasher").join("profiles.toml"))
+    }
+
+    /// Loads the store at `path`. A missing file is the normal first
+    /// run; an unreadable or corrupt one is warned about and treated as
+    /// empty rather than blocking the flash it was meant to speed up.
+    pub fn load(path: PathBuf) -> Self {
+        let devices = match fs::read_to_string(&path) {
+            Ok(text) => match toml::from_str::<RawStore>(&text) {
+                Ok(raw) => raw.devices,
+                Err(err) => {
+                    eprintln!(
+                        "warning: profile store {} is corrupt ({}); starting fresh",
+                        path.display(),
+                        err
+                    );
+                    BTreeMap::new()
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                eprintln!(
+                    "warning: cannot read profile store {}: {}",
+                    path.display(),
+                    err
+                );
+                BTreeMap::new()
+            }
+        };
+
+        Self { path, devices }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Profile> {
+        self.devices.get(key)
+    }
+
+    pub fn remember(&mut self, key: &str, profile: Profile) {
+        self.devices.insert(key.to_string(), profile);
+    }
+
+    /// Removes the entry under `key`; `false` when there was none.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.devices.remove(key).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Profile)> {
+        self.devices.iter()
+    }
+
+    /// Writes the store back via a temp file in the same directory and
+    /// a rename, so a concurrent flasher reading the file sees either
+    /// version in full, never a torn mix.
+    pub fn save(&self) -> Result<()> {
+        let dir = self
+            .path
+            .parent()
+            .with_context(|| format!("Profile store path {} has no parent", self.path.display()))?;
+
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Cannot create config directory {}", dir.display()))?;
+
+        let raw = RawStore {
+            devices: self.devices.clone(),
+        };
+        let text = toml::to_string(&raw).context("Cannot serialize the profile store")?;
+
+        // The pid keeps two racing instances off each other's temp file;
+        // last rename wins, which is fine for settings this small
+        let tmp = self.path.with_extension(format!("tmp.{}", process::id()));
+
+        fs::write(&tmp, text).with_context(|| format!("Cannot write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Cannot move {} into place", tmp.display()))?;
+
+        Ok(())
+    }
+}
+
+/// The platform's per-user config directory, without pulling in a
+/// directories crate for one path.
+fn config_dir() -> Result<PathBuf> {
+    if cfg!(target_os = "windows") {
+        let appdata = env::var_os("APPDATA").context("APPDATA is not set")?;
+        return Ok(PathBuf::from(appdata));
+    }
+
+    if cfg!(target_os = "macos") {
+        let home = env::var_os("HOME").context("HOME is not set")?;
+        return Ok(PathBuf::from(home).join("Library/Application Support"));
+    }
+
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => {
+            let home = env::var_os("HOME").context("HOME is not set")?;
+            Ok(PathBuf::from(home).join(".config"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serialport::UsbPortInfo;
+
+    /// A fresh path under the temp dir; the file itself may or may not
+    /// exist depending on the test.
+    fn store_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flasher-profile-{}", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+
+        dir.join(name)
+    }
+
+    fn bench_profile() -> Profile {
+        Profile {
+            baud: Some(460_800),
+            flow_control: Some(true),
+            no_compress: None,
+        }
+    }
+
+    fn usb(name: &str, serial: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x10c4,
+                pid: 0xea60,
+                serial_number: serial.map(str::to_string),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn store_round_trips() {
+        let path = store_path("roundtrip.toml");
+        let _ = fs::remove_file(&path);
+
+        let mut store = ProfileStore::load(path.clone());
+        assert!(store.is_empty());
+
+        store.remember("0001", bench_profile());
+        store.save().unwrap();
+
+        let reloaded = ProfileStore::load(path);
+        assert_eq!(reloaded.get("0001"), Some(&bench_profile()));
+    }
+
+    #[test]
+    fn corrupt_file_starts_fresh_and_stays_writable() {
+        let path = store_path("corrupt.toml");
+        fs::write(&path, "[device.0001]\nbaud = \"not a number").unwrap();
+
+        let mut store = ProfileStore::load(path.clone());
+        assert!(store.is_empty());
+
+        store.remember("0002", bench_profile());
+        store.save().unwrap();
+
+        assert_eq!(ProfileStore::load(path).get("0002"), Some(&bench_profile()));
+    }
+
+    #[test]
+    fn unknown_fields_from_a_newer_version_are_ignored() {
+        let path = store_path("newer.toml");
+        fs::write(&path, "[device.0001]\nbaud = 115200\nchunk_size = 512\n").unwrap();
+
+        let store = ProfileStore::load(path);
+
+        assert_eq!(store.get("0001").and_then(|p| p.baud), Some(115_200));
+    }
+
+    #[test]
+    fn remove_deletes_an_entry() {
+        let path = store_path("remove.toml");
+        let _ = fs::remove_file(&path);
+
+        let mut store = ProfileStore::load(path.clone());
+        store.remember("0001", bench_profile());
+        store.save().unwrap();
+
+        assert!(store.remove("0001"));
+        assert!(!store.remove("0001"));
+        store.save().unwrap();
+
+        assert!(ProfileStore::load(path).is_empty());
+    }
+
+    #[test]
+    fn save_leaves_no_temp_file_behind() {
+        let path = store_path("atomic.toml");
+
+        let mut store = ProfileStore::load(path.clone());
+        store.remember("0001", bench_profile());
+        store.save().unwrap();
+
+        let stray: Vec<_> = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                name.starts_with("atomic.tmp").then_some(name)
+            })
+            .collect();
+
+        assert!(stray.is_empty(), "stray temp files: {:?}", stray);
+    }
+
+    #[test]
+    fn key_prefers_the_usb_serial_number() {
+        let ports = vec![usb("/dev/ttyUSB0", Some("0123ABCD"))];
+
+        assert_eq!(key_for(&ports, "/dev/ttyUSB0"), "0123ABCD");
+    }
+
+    #[test]
+    fn key_falls_back_to_vid_pid_and_port() {
+        let ports = vec![usb("/dev/ttyUSB0", None)];
+
+        assert_eq!(key_for(&ports, "/dev/ttyUSB0"), "10c4:ea60@/dev/ttyUSB0");
+    }
+
+    #[test]
+    fn unlisted_ports_key_on_the_bare_name() {
+        assert_eq!(key_for(&[], "/dev/ttyS0"), "/dev/ttyS0");
+    }
+}
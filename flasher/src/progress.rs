@@ -0,0 +1,98 @@
+//! A single-line transfer progress display for interactive runs.
+//!
+//! Redrawn in place with carriage returns, at most ten times a second,
+//! and only when stderr is a terminal - redirected runs stay quiet and
+//! just get the summary at the end.
+
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crate::stats::Stats;
+
+/// Minimum delay between two redraws; acks can arrive far faster than
+/// a terminal is worth updating.
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+
+pub(crate) struct Progress {
+    /// Segments in the whole image, including any the device already holds.
+    total: usize,
+    /// Segments counted as done before this run sent anything (a resume).
+    skipped: usize,
+    /// Segments acked since `started`.
+    done: usize,
+    started: Instant,
+    last_draw: Option<Instant>,
+    enabled: bool,
+}
+
+impl Progress {
+    pub(crate) fn new(total: usize, skipped: usize) -> Self {
+        Self {
+            total,
+            skipped,
+            done: 0,
+            started: Instant::now(),
+            last_draw: None,
+            enabled: io::stderr().is_terminal(),
+        }
+    }
+
+    /// Switches the display off regardless of the terminal, for runs
+    /// where several transfers would fight over the same line.
+    pub(crate) fn silence(&mut self) {
+        self.enabled = false;
+    }
+
+    /// A session restart starts the count over from the (possibly new)
+    /// resume point; throughput keeps its origin, the wire bytes did flow.
+    pub(crate) fn restart(&mut self, skipped: usize) {
+        self.skipped = skipped;
+        self.done = 0;
+    }
+
+    /// Counts one acked segment and redraws the line when due.
+    pub(crate) fn segment_done(&mut self, stats: &Stats) {
+        self.done += 1;
+
+        if !self.enabled {
+            return;
+        }
+
+        let due = match self.last_draw {
+            Some(last) => last.elapsed() >= REDRAW_EVERY,
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let percent = 100.0 * (self.skipped + self.done) as f64 / self.total.max(1) as f64;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            stats.sent_bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+        let remaining = self.total - self.skipped - self.done;
+        let eta = elapsed / self.done as f64 * remaining as f64;
+
+        // Trailing spaces cover a previous, longer line
+        eprint!(
+            "\r{:3.0}% | {:.1} kB/s | eta {:.0} s | {} retries   ",
+            percent,
+            rate / 1000.0,
+            eta,
+            stats.retries()
+        );
+        let _ = io::stderr().flush();
+    }
+
+    /// Clears the line so whatever prints next starts on a clean one.
+    pub(crate) fn finish(&mut self) {
+        if self.enabled && self.last_draw.is_some() {
+            eprint!("\r{:60}\r", "");
+            let _ = io::stderr().flush();
+        }
+    }
+}
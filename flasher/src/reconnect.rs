@@ -0,0 +1,150 @@
+//! Transparent reconnection for flaky serial links.
+//!
+//! USB hubs occasionally drop and re-enumerate the adapter for a moment;
+//! without this, a multi-minute flash dies on a single `BrokenPipe`.
+//! [`ReconnectingLink`] wraps any [`Transport`] link and, when an
+//! operation fails with a disconnect-looking error, polls a factory for a
+//! replacement link until `--reconnect-timeout` elapses.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use messages::transport::Transport;
+
+/// How often the factory is polled while the port is gone.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Whether an io error looks like the port went away (as opposed to a
+/// read timeout, which is business as usual on a serial link).
+fn is_disconnect(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::BrokenPipe | io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound
+    )
+}
+
+/// A [`Transport`] link that survives transient disconnects by asking
+/// `factory` for a replacement. The factory returns `None` while the
+/// device has not re-enumerated yet.
+pub struct ReconnectingLink<L, F> {
+    link: L,
+    factory: F,
+    timeout: Duration,
+    /// Reconnections performed so far, for the summary.
+    reconnects: u32,
+}
+
+impl<L, F> ReconnectingLink<L, F>
+where
+    L: Transport,
+    F: FnMut() -> Option<L>,
+{
+    pub fn new(link: L, factory: F, timeout: Duration) -> Self {
+        Self {
+            link,
+            factory,
+            timeout,
+            reconnects: 0,
+        }
+    }
+
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    /// Runs `op`, reconnecting and retrying it when the link looks gone.
+    /// The original error is surfaced once the timeout is exhausted.
+    fn with_link<T>(&mut self, mut op: impl FnMut(&mut L) -> io::Result<T>) -> io::Result<T> {
+        loop {
+            let err = match op(&mut self.link) {
+                Err(err) if is_disconnect(err.kind()) => err,
+                result => return result,
+            };
+
+            eprintln!(
+                "warning: serial link dropped ({}), waiting up to {:?} for it to come back",
+                err, self.timeout
+            );
+
+            let deadline = Instant::now() + self.timeout;
+
+            self.link = loop {
+                if let Some(link) = (self.factory)() {
+                    break link;
+                }
+
+                if Instant::now() >= deadline {
+                    return Err(err);
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            };
+
+            self.reconnects += 1;
+            eprintln!("serial link is back, resuming");
+        }
+    }
+}
+
+/// USB serial number of the adapter behind `port_name`, if the OS knows
+/// it. Re-enumeration can change the port name, so this is the better key
+/// to find the device again.
+pub fn usb_serial_of(port_name: &str) -> Option<String> {
+    serialport::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|port| port.port_name == port_name)
+        .and_then(|port| match port.port_type {
+            serialport::SerialPortType::UsbPort(info) => info.serial_number,
+            _ => None,
+        })
+}
+
+/// One reconnection attempt: find the device among the currently
+/// available ports — by USB serial number when known, by name otherwise —
+/// and reopen it with the original settings.
+pub fn reopen(
+    port_name: &str,
+    usb_serial: Option<&str>,
+    baud: u32,
+    flow_control: serialport::FlowControl,
+) -> Option<crate::serial::SerialLink> {
+    let ports = serialport::available_ports().ok()?;
+
+    let name = ports
+        .iter()
+        .find(|port| match (&port.port_type, usb_serial) {
+            (serialport::SerialPortType::UsbPort(info), Some(serial)) => {
+                info.serial_number.as_deref() == Some(serial)
+            }
+            _ => false,
+        })
+        .or_else(|| ports.iter().find(|port| port.port_name == port_name))
+        .map(|port| port.port_name.clone())?;
+
+    serialport::new(&name, baud)
+        .timeout(Duration::from_millis(100))
+        .flow_control(flow_control)
+        .open()
+        .ok()
+        .map(crate::serial::SerialLink::new)
+}
+
+impl<L, F> Transport for ReconnectingLink<L, F>
+where
+    L: Transport,
+    F: FnMut() -> Option<L>,
+{
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.with_link(|link| link.read_available(buf, timeout))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.with_link(|link| link.write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_link(|link| link.flush())
+    }
+}
@@ -0,0 +1,46 @@
+//! Serial-port end of the shared [`Transport`] abstraction.
+//!
+//! `serialport` keeps the read timeout as port state rather than taking
+//! it per call, so this adapter reprograms it only when a caller's
+//! timeout actually changes - the flash loop polls with a stable
+//! timeout, and one ioctl per change beats one per read.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use messages::transport::Transport;
+
+pub struct SerialLink {
+    port: Box<dyn serialport::SerialPort>,
+    /// Timeout currently programmed into the port.
+    timeout: Duration,
+}
+
+impl SerialLink {
+    pub fn new(port: Box<dyn serialport::SerialPort>) -> Self {
+        let timeout = port.timeout();
+
+        Self { port, timeout }
+    }
+}
+
+impl Transport for SerialLink {
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if timeout != self.timeout {
+            self.port.set_timeout(timeout).map_err(io::Error::other)?;
+            self.timeout = timeout;
+        }
+
+        // A quiet line already comes back as `TimedOut`, which is
+        // exactly the trait's contract
+        self.port.read(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.port.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
@@ -0,0 +1,351 @@
+//! An interactive protocol shell for poking at a device by hand.
+//!
+//! Each typed command is translated into one host message and sent
+//! as-is; whatever the device answers is decoded and printed. No state
+//! machine sits in between, which is the point - the shell will happily
+//! send a `cancel` with nothing in flight or an `end` without a start,
+//! exactly the exchanges worth watching when debugging the protocol.
+
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use messages::{MessageTypeHost, MessageTypeMcu, UpdateEnd, UpdateStart};
+
+use crate::{send_message, FrameReader, Stats, Transport, HOST_CAPABILITIES};
+
+/// How long a command's reply window stays open after the last frame;
+/// most answers are a single frame arriving well inside this.
+const REPLY_IDLE: Duration = Duration::from_millis(500);
+
+/// Default `listen` duration.
+const LISTEN_DEFAULT: Duration = Duration::from_secs(2);
+
+/// What one input line asks for.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Put this message on the wire and print the replies.
+    Send(MessageTypeHost),
+    /// Just print incoming frames for this long.
+    Listen(Duration),
+    Help,
+    Quit,
+    /// A blank line; do nothing.
+    Empty,
+}
+
+/// Parses one input line. `Err` carries the message to show the user,
+/// with the offending word in it.
+pub fn parse(line: &str) -> Result<Action, String> {
+    let mut words = line.split_whitespace();
+
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Ok(Action::Empty),
+    };
+
+    let args: Vec<&str> = words.collect();
+
+    let action = match command {
+        "help" | "?" => Action::Help,
+        "quit" | "exit" => Action::Quit,
+        "ping" => Action::Send(MessageTypeHost::Ping),
+        "tping" => {
+            let nonce = match args.first() {
+                Some(word) => parse_number(word, "nonce")?,
+                None => rand::random(),
+            };
+            Action::Send(MessageTypeHost::TimedPing(nonce))
+        }
+        "hello" => Action::Send(MessageTypeHost::Hello {
+            protocol_version: messages::PROTOCOL_VERSION,
+            capabilities: HOST_CAPABILITIES,
+        }),
+        "info" | "version" => Action::Send(MessageTypeHost::GetInfo),
+        "stats" => Action::Send(MessageTypeHost::GetStats {
+            reset: args.first() == Some(&"reset"),
+        }),
+        "start" => {
+            let size = match args.first() {
+                Some(word) => parse_number(word, "size")?,
+                None => return Err("start needs a size in bytes: start 4096".to_string()),
+            };
+            Action::Send(MessageTypeHost::UpdateStart(UpdateStart {
+                size,
+                nonce_prefix: None,
+                delta_base: None,
+                partition: None,
+                sha256: None,
+                segment_size: None,
+                resume: false,
+                progress: false,
+            }))
+        }
+        "end" => Action::Send(MessageTypeHost::UpdateEnd(UpdateEnd {
+            sha256: None,
+            signature: None,
+            reboot: false,
+        })),
+        "cancel" => Action::Send(MessageTypeHost::Cancel),
+        "adc" => match args.first() {
+            Some(&"stop") => Action::Send(MessageTypeHost::AdcStop),
+            Some(word) => Action::Send(MessageTypeHost::AdcStart {
+                interval_ms: parse_number(word, "interval")?,
+            }),
+            None => Action::Send(MessageTypeHost::AdcStart { interval_ms: 0 }),
+        },
+        "mark-valid" => Action::Send(MessageTypeHost::MarkValid),
+        "rollback" => Action::Send(MessageTypeHost::Rollback),
+        "reboot" => {
+            return Err(
+                "the protocol has no plain reboot; `rollback` reboots into the previous image"
+                    .to_string(),
+            )
+        }
+        "log-level" => {
+            let level = match args.first() {
+                Some(word) => parse_number(word, "level")?,
+                None => return Err("log-level needs 0-5: log-level 3".to_string()),
+            };
+            Action::Send(MessageTypeHost::SetLogLevel(level))
+        }
+        "trace" => match args.first() {
+            Some(&"on") => Action::Send(MessageTypeHost::SetTrace {
+                enabled: true,
+                force: args.get(1) == Some(&"force"),
+            }),
+            Some(&"off") => Action::Send(MessageTypeHost::SetTrace {
+                enabled: false,
+                force: false,
+            }),
+            Some(&"dump") => Action::Send(MessageTypeHost::DumpTrace),
+            _ => return Err("trace takes on, off or dump".to_string()),
+        },
+        "baud" => {
+            let rate = match args.first() {
+                Some(word) => parse_number(word, "rate")?,
+                None => return Err("baud needs a rate: baud 115200".to_string()),
+            };
+            Action::Send(MessageTypeHost::SetBaud(rate))
+        }
+        "read" => match args.as_slice() {
+            [offset, len, rest @ ..] => Action::Send(MessageTypeHost::ReadFlash {
+                partition: rest.first().map(|label| label.to_string()),
+                offset: parse_number(offset, "offset")?,
+                len: parse_number(len, "len")?,
+            }),
+            _ => return Err("read needs offset and length: read 0 256 [partition]".to_string()),
+        },
+        "erase" => match args.as_slice() {
+            [offset, len, rest @ ..] => Action::Send(MessageTypeHost::EraseRegion {
+                partition: rest.first().map(|label| label.to_string()),
+                offset: parse_number(offset, "offset")?,
+                len: parse_number(len, "len")?,
+            }),
+            _ => return Err("erase needs offset and length: erase 0 4096 [partition]".to_string()),
+        },
+        "hash" => match args.as_slice() {
+            [len, rest @ ..] => Action::Send(MessageTypeHost::HashFlash {
+                partition: rest.first().map(|label| label.to_string()),
+                len: parse_number(len, "len")?,
+            }),
+            _ => return Err("hash needs a length: hash 1048576 [partition]".to_string()),
+        },
+        "last-update" => Action::Send(MessageTypeHost::GetUpdateInfo),
+        "listen" => {
+            let seconds: u64 = match args.first() {
+                Some(word) => parse_number(word, "seconds")?,
+                None => return Ok(Action::Listen(LISTEN_DEFAULT)),
+            };
+            Action::Listen(Duration::from_secs(seconds))
+        }
+        other => return Err(format!("unknown command {:?}; try help", other)),
+    };
+
+    Ok(action)
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str, what: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("{:?} is not a valid {}", word, what))
+}
+
+const HELP: &str = "\
+  ping | tping [nonce]      liveness probe (plain / nonce-and-uptime)
+  hello                     open a session, print the device's HelloAck
+  info | version            ask for the device's Info block
+  stats [reset]             wire counters, optionally cleared after
+  start <size>              bare UpdateStart, no segments follow
+  end                       bare UpdateEnd (no digest, no reboot)
+  cancel                    cancel an in-flight update
+  adc [interval_ms|stop]    start or stop the ADC sample stream
+  mark-valid | rollback     confirm the running image / reboot into the old one
+  log-level <0-5>           cap the device's mirrored log records
+  trace on [force]|off|dump protocol tracing
+  baud <rate>               ask the device to switch its UART rate
+  read <offset> <len> [p]   read flash back (update slot, or partition p)
+  erase <offset> <len> [p]  erase a flash region
+  hash <len> [p]            SHA-256 of the first len bytes of a slot
+  last-update               the device's persisted update audit record
+  listen [seconds]          just print incoming frames for a while
+  help | quit";
+
+/// Runs the shell over `link` until `quit` or end of input. `input`
+/// is a parameter (rather than stdin hardwired) so scripted sessions
+/// can be piped in.
+pub fn run<S: Transport>(link: &mut S, input: impl BufRead) -> Result<()> {
+    let mut reader = FrameReader::new();
+    let mut stats = Stats::default();
+
+    println!("Protocol shell; help lists the commands, quit leaves");
+
+    let mut lines = input.lines();
+
+    loop {
+        print!("flasher> ");
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+
+        match parse(&line) {
+            Ok(Action::Empty) => (),
+            Ok(Action::Help) => println!("{}", HELP),
+            Ok(Action::Quit) => break,
+            Ok(Action::Listen(window)) => {
+                drain(link, &mut reader, &mut stats, window, window)?;
+            }
+            Ok(Action::Send(msg)) => {
+                println!("-> {}", msg.name());
+                send_message(link, &msg)?;
+                drain(link, &mut reader, &mut stats, REPLY_IDLE, Duration::MAX)?;
+            }
+            Err(complaint) => println!("{}", complaint),
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints decoded frames until the line has been quiet for `idle` or
+/// `total` has passed, whichever is first. Multi-frame answers (a
+/// read-back stream, a trace dump) keep the window open as long as
+/// frames keep arriving.
+fn drain<S: Transport>(
+    link: &mut S,
+    reader: &mut FrameReader,
+    stats: &mut Stats,
+    idle: Duration,
+    total: Duration,
+) -> Result<()> {
+    // `total` may be `Duration::MAX` (no overall cap), which would
+    // overflow a plain `Instant + Duration`
+    let deadline = Instant::now().checked_add(total);
+
+    loop {
+        let window = match deadline {
+            Some(deadline) => idle.min(deadline.saturating_duration_since(Instant::now())),
+            None => idle,
+        };
+        if window.is_zero() {
+            return Ok(());
+        }
+
+        let timeouts_before = stats.timeouts;
+        match reader.read_message(link, window, stats) {
+            Ok(msg) => println!("<- {}", describe(&msg)),
+            Err(err) => {
+                // Silence is the normal end of a reply window; a real
+                // io error is not
+                if stats.timeouts == timeouts_before {
+                    return Err(err);
+                }
+
+                stats.timeouts = timeouts_before;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// One line per frame. The bulky payloads are summarized instead of
+/// dumped; everything else leans on the wire types' `Debug`.
+fn describe(msg: &MessageTypeMcu) -> String {
+    match msg {
+        MessageTypeMcu::FlashData { offset, data, last } => format!(
+            "FlashData {{ offset: {}, len: {}, last: {} }}",
+            offset,
+            data.len(),
+            last
+        ),
+        MessageTypeMcu::Log(record) => format!(
+            "Log [{} {}] {}",
+            record.level_name(),
+            record.target,
+            record.message
+        ),
+        other => format!("{:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_commands_map_to_their_messages() {
+        assert_eq!(parse("ping"), Ok(Action::Send(MessageTypeHost::Ping)));
+        assert_eq!(parse("cancel"), Ok(Action::Send(MessageTypeHost::Cancel)));
+        assert_eq!(parse("version"), Ok(Action::Send(MessageTypeHost::GetInfo)));
+        assert_eq!(
+            parse("stats reset"),
+            Ok(Action::Send(MessageTypeHost::GetStats { reset: true }))
+        );
+    }
+
+    #[test]
+    fn arguments_are_parsed_and_validated() {
+        assert_eq!(
+            parse("start 4096"),
+            Ok(Action::Send(MessageTypeHost::UpdateStart(UpdateStart {
+                size: 4096,
+                nonce_prefix: None,
+                delta_base: None,
+                partition: None,
+                sha256: None,
+                segment_size: None,
+                resume: false,
+                progress: false,
+            })))
+        );
+        assert_eq!(
+            parse("read 0 256 nvs"),
+            Ok(Action::Send(MessageTypeHost::ReadFlash {
+                partition: Some("nvs".to_string()),
+                offset: 0,
+                len: 256,
+            }))
+        );
+
+        assert!(parse("start").is_err());
+        assert!(parse("start many").is_err());
+        assert!(parse("log-level").is_err());
+    }
+
+    #[test]
+    fn unknown_commands_point_at_help() {
+        let complaint = parse("frobnicate").unwrap_err();
+
+        assert!(complaint.contains("frobnicate"));
+        assert!(complaint.contains("help"));
+    }
+
+    #[test]
+    fn blank_lines_do_nothing() {
+        assert_eq!(parse(""), Ok(Action::Empty));
+        assert_eq!(parse("   "), Ok(Action::Empty));
+    }
+}
@@ -0,0 +1,699 @@
+//! Host-side stand-in for the device, used by the integration tests.
+//!
+//! The simulator speaks the device side of the protocol over any
+//! [`Transport`] link - the in-memory loopback from
+//! [`messages::transport::pair`] in tests - and reassembles the image in
+//! memory, so tests can assert that what the flasher sent is
+//! byte-identical to the input.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use messages::{
+    codec, transport::Transport, Caps, FailureReason, LinkStats, MessageTypeHost, MessageTypeMcu,
+    Status, UpdateRecord, UpdateStartStatus, SEGMENT_SIZE,
+};
+
+use crate::{compress, crypto};
+
+pub struct Simulator {
+    capabilities: u8,
+    /// Fail the first transmission of this segment id, to exercise retries.
+    fail_segment_once: Option<u16>,
+    /// Corrupt the first transmission of this encrypted segment id before
+    /// decrypting, to exercise authentication failures.
+    corrupt_segment_once: Option<u16>,
+    key: Option<[u8; crypto::KEY_LEN]>,
+    nonce_prefix: Option<[u8; messages::NONCE_PREFIX_LEN]>,
+    verifying_key: Option<ed25519_dalek::VerifyingKey>,
+    /// Image "running" on the simulated device, the source for delta copies.
+    base: Option<Vec<u8>>,
+    /// Known non-app partitions, label -> capacity.
+    partitions: Vec<(String, usize)>,
+    /// App version reported via `Info`; `None` simulates old firmware
+    /// that ignores `GetInfo`.
+    app_version: Option<String>,
+    /// OTA slot size reported via `Info`.
+    slot_size: Option<u32>,
+    /// Segment size advertised in `UpdateStartStatus`, as a device with
+    /// hardware flow control would.
+    max_segment_size: Option<u16>,
+    /// Sit on each plain segment this long before acking, answering pings
+    /// meanwhile, like a device with slow flash writes.
+    ack_delay: Option<Duration>,
+    /// Answer `Hello` with a `HelloAck`; `false` simulates firmware
+    /// that predates the handshake and ignores the frame.
+    answers_hello: bool,
+    /// Protocol version acked in `HelloAck`, for exercising the host's
+    /// mismatch gate against firmware from another generation.
+    protocol_version: u8,
+    /// Push `UpdateAborted` instead of acking this segment once, like
+    /// firmware whose inactivity timer (or worse) fired mid-transfer.
+    abort_at: Option<(u16, FailureReason)>,
+    /// Flip a byte at this offset of the reassembled image before the
+    /// final digest check, like a flash write that silently corrupted.
+    flip_byte_at: Option<usize>,
+    /// Reported via `Info` and cleared by `MarkValid`, like a device
+    /// whose freshly booted image still awaits its confirmation.
+    pending_verify: bool,
+    /// Bytes already "in flash" from an interrupted transfer, offered as
+    /// a resume point when the host asks for one.
+    resume_prefix: Option<Vec<u8>>,
+    /// What the update slot currently holds, hashed in answer to
+    /// `HashFlash`, like a device that completed an earlier transfer.
+    slot_contents: Option<Vec<u8>>,
+    /// Audit record answered to `GetUpdateInfo`; `None` simulates a
+    /// device that never completed an update.
+    last_update: Option<UpdateRecord>,
+    /// Wire counters reported via `GetStats`, like the firmware keeps.
+    /// `bytes_sent` stays zero: the simulator only meters its RX side,
+    /// which is all the tests assert on.
+    stats: LinkStats,
+    /// High-water mark of written segment ids, for counting duplicates
+    /// the way the firmware's tracker recognises them.
+    next_expected: u16,
+    /// Payload size the host announced in `UpdateStart`; positions the
+    /// writes the way the firmware does.
+    segment_size: usize,
+    image: Vec<u8>,
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self {
+            capabilities: 0,
+            fail_segment_once: None,
+            corrupt_segment_once: None,
+            key: None,
+            nonce_prefix: None,
+            verifying_key: None,
+            base: None,
+            partitions: Vec::new(),
+            app_version: None,
+            slot_size: None,
+            max_segment_size: None,
+            ack_delay: None,
+            answers_hello: true,
+            protocol_version: messages::PROTOCOL_VERSION,
+            abort_at: None,
+            flip_byte_at: None,
+            pending_verify: false,
+            resume_prefix: None,
+            slot_contents: None,
+            last_update: None,
+            stats: LinkStats::default(),
+            next_expected: 0,
+            segment_size: SEGMENT_SIZE,
+            image: Vec::new(),
+        }
+    }
+
+    pub fn with_capabilities(mut self, capabilities: u8) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_failed_segment(mut self, id: u16) -> Self {
+        self.fail_segment_once = Some(id);
+        self
+    }
+
+    pub fn with_corrupted_segment(mut self, id: u16) -> Self {
+        self.corrupt_segment_once = Some(id);
+        self
+    }
+
+    pub fn with_key(mut self, key: [u8; crypto::KEY_LEN]) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_verifying_key(mut self, key: ed25519_dalek::VerifyingKey) -> Self {
+        self.verifying_key = Some(key);
+        self
+    }
+
+    pub fn with_base(mut self, base: Vec<u8>) -> Self {
+        self.base = Some(base);
+        self
+    }
+
+    pub fn with_partition(mut self, label: &str, capacity: usize) -> Self {
+        self.partitions.push((label.to_string(), capacity));
+        self
+    }
+
+    pub fn with_app_version(mut self, version: &str) -> Self {
+        self.app_version = Some(version.to_string());
+        self
+    }
+
+    pub fn with_slot_size(mut self, slot_size: u32) -> Self {
+        self.slot_size = Some(slot_size);
+        self
+    }
+
+    pub fn with_max_segment_size(mut self, size: u16) -> Self {
+        self.max_segment_size = Some(size);
+        self
+    }
+
+    pub fn with_ack_delay(mut self, delay: Duration) -> Self {
+        self.ack_delay = Some(delay);
+        self
+    }
+
+    pub fn with_protocol_version(mut self, version: u8) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    pub fn without_hello(mut self) -> Self {
+        self.answers_hello = false;
+        self
+    }
+
+    pub fn with_abort_at(mut self, id: u16, reason: FailureReason) -> Self {
+        self.abort_at = Some((id, reason));
+        self
+    }
+
+    pub fn with_flipped_byte(mut self, offset: usize) -> Self {
+        self.flip_byte_at = Some(offset);
+        self
+    }
+
+    pub fn with_pending_verify(mut self) -> Self {
+        self.pending_verify = true;
+        self
+    }
+
+    pub fn with_resumable_prefix(mut self, prefix: Vec<u8>) -> Self {
+        self.resume_prefix = Some(prefix);
+        self
+    }
+
+    pub fn with_slot_contents(mut self, contents: Vec<u8>) -> Self {
+        self.slot_contents = Some(contents);
+        self
+    }
+
+    pub fn with_last_update(mut self, record: UpdateRecord) -> Self {
+        self.last_update = Some(record);
+        self
+    }
+
+    /// Runs the device side of one update, returning the reassembled image
+    /// once `UpdateEnd` arrives.
+    pub fn run<S: Transport>(mut self, link: &mut S) -> Result<Vec<u8>> {
+        // Bytes received but not yet parsed; frames can straddle reads
+        let mut rx_buf = Vec::new();
+
+        loop {
+            let msg = self.read_host_message(link, &mut rx_buf)?;
+
+            match msg {
+                MessageTypeHost::Hello { .. } => {
+                    // Firmware from before the handshake does not know
+                    // the variant and says nothing; the host's short
+                    // timeout covers that case
+                    if self.answers_hello {
+                        send_mcu_message(
+                            link,
+                            &MessageTypeMcu::HelloAck {
+                                protocol_version: self.protocol_version,
+                                capabilities: Caps::from_legacy(self.capabilities),
+                                max_segment_len: self
+                                    .max_segment_size
+                                    .unwrap_or(SEGMENT_SIZE as u16),
+                            },
+                        )?;
+                    }
+                }
+                MessageTypeHost::GetInfo => {
+                    // Old firmware does not answer GetInfo at all
+                    if let Some(app_version) = &self.app_version {
+                        send_mcu_message(
+                            link,
+                            &MessageTypeMcu::Info(messages::Info {
+                                protocol_version: messages::PROTOCOL_VERSION,
+                                app_version: app_version.clone(),
+                                slot_size: self.slot_size,
+                                project_name: "simulator".to_string(),
+                                build: String::new(),
+                                running_slot: None,
+                                update_slot: self.slot_size.map(|size| messages::SlotInfo {
+                                    label: "ota_1".to_string(),
+                                    offset: 0,
+                                    size,
+                                }),
+                                free_heap: 0,
+                                min_free_heap: 0,
+                                updater_stack_free: 0,
+                                desyncs: 0,
+                                reset_reason: 0,
+                                boot_count: None,
+                                pending_verify: self.pending_verify,
+                                serial_priority: 0,
+                                updater_priority: 0,
+                                serial_core: None,
+                                updater_core: None,
+                                self_test_passed: None,
+                            }),
+                        )?;
+                    }
+                }
+                MessageTypeHost::UpdateStart(start) => {
+                    self.image = Vec::with_capacity(start.size as usize);
+                    self.nonce_prefix = start.nonce_prefix;
+                    self.next_expected = 0;
+
+                    let mut status = match &start.delta_base {
+                        Some(base) => match &self.base {
+                            Some(running)
+                                if base.len as usize == running.len()
+                                    && base.hash == crate::image_hash(running) =>
+                            {
+                                Status::Ok
+                            }
+                            _ => Status::BaseMismatch,
+                        },
+                        None => Status::Ok,
+                    };
+
+                    if let Some(label) = &start.partition {
+                        status = match self.partitions.iter().find(|(l, _)| l == label) {
+                            Some((_, capacity)) if start.size as usize <= *capacity => Status::Ok,
+                            _ => Status::Failed,
+                        };
+                    }
+
+                    // A start without `resume` erases the checkpoint, like
+                    // the firmware starting the slot over
+                    let segment_size = start.segment_size.map(usize::from).unwrap_or(SEGMENT_SIZE);
+                    self.segment_size = segment_size;
+                    let resume_wanted = status == Status::Ok
+                        && start.resume
+                        && start.partition.is_none()
+                        && start.delta_base.is_none();
+
+                    let resume_offset = match self.resume_prefix.take() {
+                        Some(prefix) if resume_wanted && prefix.len() % segment_size == 0 => {
+                            self.next_expected = (prefix.len() / segment_size) as u16;
+                            let offset = prefix.len() as u32;
+                            self.image = prefix;
+                            Some(offset)
+                        }
+                        _ => None,
+                    };
+
+                    send_mcu_message(
+                        link,
+                        &MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                            status,
+                            capabilities: self.capabilities,
+                            max_segment_size: self.max_segment_size,
+                            resume_offset,
+                        }),
+                    )?;
+                }
+                MessageTypeHost::UpdateSegment(segment) => {
+                    // An aborting device never acks the segment: it
+                    // drops its state and pushes the notice instead
+                    if let Some(reason) = self.take_abort(segment.id) {
+                        self.image.clear();
+                        send_mcu_message(link, &MessageTypeMcu::UpdateAborted(reason))?;
+                        continue;
+                    }
+
+                    if let Some(delay) = self.ack_delay {
+                        self.busy_delay(link, &mut rx_buf, delay)?;
+                    }
+
+                    if self.take_failure(segment.id) {
+                        send_mcu_message(
+                            link,
+                            &MessageTypeMcu::UpdateSegmentStatus {
+                                id: segment.id,
+                                status: Status::Failed,
+                            },
+                        )?;
+                        continue;
+                    }
+
+                    self.store(segment.id, &segment.data);
+
+                    send_mcu_message(
+                        link,
+                        &MessageTypeMcu::UpdateSegmentStatus {
+                            id: segment.id,
+                            status: Status::Ok,
+                        },
+                    )?;
+                }
+                MessageTypeHost::UpdateSegmentCompressed(segment) => {
+                    if self.take_failure(segment.id) {
+                        send_mcu_message(
+                            link,
+                            &MessageTypeMcu::UpdateSegmentStatus {
+                                id: segment.id,
+                                status: Status::Failed,
+                            },
+                        )?;
+                        continue;
+                    }
+
+                    let raw = compress::decode(&segment.data, segment.raw_len as usize)?;
+                    self.store(segment.id, &raw);
+
+                    send_mcu_message(
+                        link,
+                        &MessageTypeMcu::UpdateSegmentStatus {
+                            id: segment.id,
+                            status: Status::Ok,
+                        },
+                    )?;
+                }
+                MessageTypeHost::UpdateSegmentEncrypted(mut segment) => {
+                    if self.corrupt_segment_once == Some(segment.id) {
+                        self.corrupt_segment_once = None;
+                        segment.data[0] ^= 0xff;
+                    }
+
+                    let key = self.key.as_ref().expect("simulator has no key configured");
+                    let prefix = self
+                        .nonce_prefix
+                        .as_ref()
+                        .expect("host did not announce a nonce prefix");
+
+                    let status =
+                        match crypto::decrypt_segment(key, prefix, segment.id, &segment.data) {
+                            Ok(plain) => {
+                                // Compressed before sealing, like the
+                                // firmware decodes it
+                                let raw = match segment.raw_len {
+                                    Some(raw_len) => compress::decode(&plain, raw_len as usize)?,
+                                    None => plain,
+                                };
+
+                                self.store(segment.id, &raw);
+                                Status::Ok
+                            }
+                            Err(_) => Status::Failed,
+                        };
+
+                    send_mcu_message(
+                        link,
+                        &MessageTypeMcu::UpdateSegmentStatus {
+                            id: segment.id,
+                            status,
+                        },
+                    )?;
+                }
+                MessageTypeHost::UpdateSegmentDelta(segment) => {
+                    let base = self.base.as_deref().unwrap_or(&[]);
+                    let raw = crate::delta::apply_delta(base, std::slice::from_ref(&segment.op));
+
+                    self.store(segment.id, &raw);
+
+                    send_mcu_message(
+                        link,
+                        &MessageTypeMcu::UpdateSegmentStatus {
+                            id: segment.id,
+                            status: Status::Ok,
+                        },
+                    )?;
+                }
+                MessageTypeHost::UpdateEnd(end) => {
+                    // Like firmware with a baked-in key: an unsigned or
+                    // badly signed image earns InvalidSignature
+                    if let Some(key) = &self.verifying_key {
+                        let verified = end.signature.as_deref().map(|signature| {
+                            crate::sign::verify_image(key, &self.image, signature)
+                        });
+
+                        match verified {
+                            Some(Ok(())) => (),
+                            Some(Err(err)) => {
+                                send_mcu_message(
+                                    link,
+                                    &MessageTypeMcu::UpdateEndStatus(Status::InvalidSignature),
+                                )?;
+                                return Err(err);
+                            }
+                            None => {
+                                send_mcu_message(
+                                    link,
+                                    &MessageTypeMcu::UpdateEndStatus(Status::InvalidSignature),
+                                )?;
+                                bail!("Update is not signed");
+                            }
+                        }
+                    }
+
+                    // The configured "flash corruption" happens between
+                    // the writes and the final check, where the digest
+                    // is the only thing that can catch it
+                    if let Some(offset) = self.flip_byte_at {
+                        self.image[offset] ^= 0x01;
+                    }
+
+                    if let Some(expected) = &end.sha256 {
+                        if &crate::image_hash(&self.image) != expected {
+                            send_mcu_message(
+                                link,
+                                &MessageTypeMcu::UpdateEndStatus(Status::InvalidImage),
+                            )?;
+                            bail!("Received image does not match the announced digest");
+                        }
+                    }
+
+                    // Real firmware acks before it reboots; the flasher
+                    // waits for this verdict
+                    send_mcu_message(link, &MessageTypeMcu::UpdateEndStatus(Status::Ok))?;
+
+                    return Ok(self.image);
+                }
+                MessageTypeHost::Ping => {
+                    send_mcu_message(link, &MessageTypeMcu::Pong)?;
+                }
+                // The simulator has no boot epoch, so zero stands in
+                // for the uptime the device would report
+                MessageTypeHost::TimedPing(nonce) => {
+                    send_mcu_message(
+                        link,
+                        &MessageTypeMcu::TimedPong {
+                            nonce,
+                            uptime_ms: 0,
+                        },
+                    )?;
+                }
+                MessageTypeHost::Cancel => {
+                    self.image.clear();
+                    send_mcu_message(link, &MessageTypeMcu::CancelStatus(Status::Ok))?;
+                }
+                MessageTypeHost::MarkValid => {
+                    self.pending_verify = false;
+                    send_mcu_message(link, &MessageTypeMcu::MarkValidStatus(Status::Ok))?;
+                }
+                MessageTypeHost::Rollback => {
+                    // An image awaiting its confirmation has a previous
+                    // slot to fall back to; a confirmed one does not
+                    let status = if self.pending_verify {
+                        Status::Ok
+                    } else {
+                        Status::Failed
+                    };
+                    send_mcu_message(link, &MessageTypeMcu::RollbackStatus(status))?;
+                }
+                MessageTypeHost::GetStats { reset } => {
+                    // Snapshot first, reset after, like the firmware:
+                    // the reply carries what the host asked to clear
+                    send_mcu_message(link, &MessageTypeMcu::Stats(self.stats.clone()))?;
+
+                    if reset {
+                        self.stats = LinkStats::default();
+                    }
+                }
+                MessageTypeHost::HashFlash { partition, len } => {
+                    // Only the update slot has simulated contents; a
+                    // named partition or an over-long range is refused
+                    // like on the device
+                    let reply = match &self.slot_contents {
+                        Some(contents) if partition.is_none() && len as usize <= contents.len() => {
+                            MessageTypeMcu::FlashHash {
+                                status: Status::Ok,
+                                sha256: Some(crate::image_hash(&contents[..len as usize])),
+                            }
+                        }
+                        _ => MessageTypeMcu::FlashHash {
+                            status: Status::Failed,
+                            sha256: None,
+                        },
+                    };
+
+                    send_mcu_message(link, &reply)?;
+                }
+                MessageTypeHost::GetUpdateInfo => {
+                    send_mcu_message(link, &MessageTypeMcu::UpdateInfo(self.last_update.clone()))?;
+                }
+                other => bail!("Simulator cannot handle {:?}", other),
+            }
+        }
+    }
+
+    fn take_abort(&mut self, id: u16) -> Option<FailureReason> {
+        match self.abort_at {
+            Some((abort_id, reason)) if abort_id == id => {
+                self.abort_at = None;
+                Some(reason)
+            }
+            _ => None,
+        }
+    }
+
+    fn take_failure(&mut self, id: u16) -> bool {
+        if self.fail_segment_once == Some(id) {
+            self.fail_segment_once = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn store(&mut self, id: u16, data: &[u8]) {
+        // An id below the high-water mark was already written once; a
+        // retransmit whose ack got lost
+        if id < self.next_expected {
+            self.stats.duplicate_segments = self.stats.duplicate_segments.saturating_add(1);
+        } else {
+            self.next_expected = id + 1;
+        }
+
+        let offset = id as usize * self.segment_size;
+
+        if self.image.len() < offset + data.len() {
+            self.image.resize(offset + data.len(), 0);
+        }
+
+        self.image[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Sits on the current message for `delay`, answering pings
+    /// meanwhile - the firmware's serial thread stays responsive while
+    /// a flash write is in progress, and the simulator should too.
+    fn busy_delay<S: Transport>(
+        &mut self,
+        link: &mut S,
+        rx_buf: &mut Vec<u8>,
+        delay: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + delay;
+
+        while let Some(msg) = self.read_host_message_until(link, rx_buf, deadline)? {
+            if msg == MessageTypeHost::Ping {
+                send_mcu_message(link, &MessageTypeMcu::Pong)?;
+            } else if let MessageTypeHost::TimedPing(nonce) = msg {
+                send_mcu_message(
+                    link,
+                    &MessageTypeMcu::TimedPong {
+                        nonce,
+                        uptime_ms: 0,
+                    },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_host_message<S: Transport>(
+        &mut self,
+        link: &mut S,
+        rx_buf: &mut Vec<u8>,
+    ) -> Result<MessageTypeHost> {
+        loop {
+            let deadline = Instant::now() + Duration::from_secs(60);
+
+            if let Some(msg) = self.read_host_message_until(link, rx_buf, deadline)? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Reads one host message, or `None` once `deadline` passes. A
+    /// frame with a failed checksum is counted, dropped, and answered
+    /// with a `Retry` pointing at the next expected segment - the
+    /// firmware's desync notice collapsed to a single drop, since a
+    /// scripted link never produces the noise bursts the real threshold
+    /// is tuned for.
+    fn read_host_message_until<S: Transport>(
+        &mut self,
+        link: &mut S,
+        accumulated: &mut Vec<u8>,
+        deadline: Instant,
+    ) -> Result<Option<MessageTypeHost>> {
+        let mut buf = [0_u8; 256];
+
+        loop {
+            match codec::take::<MessageTypeHost>(accumulated) {
+                Ok((payload, rest)) => {
+                    let consumed = accumulated.len() - rest.len();
+                    accumulated.drain(..consumed);
+
+                    self.stats.frames_received = self.stats.frames_received.saturating_add(1);
+
+                    return Ok(Some(payload));
+                }
+                Err(codec::DecodeError::Truncated) => (),
+                Err(codec::DecodeError::BadCrc { frame_len, .. }) => {
+                    accumulated.drain(..frame_len);
+
+                    self.stats.crc_errors = self.stats.crc_errors.saturating_add(1);
+                    self.stats.segments_retried = self.stats.segments_retried.saturating_add(1);
+
+                    send_mcu_message(
+                        link,
+                        &MessageTypeMcu::UpdateSegmentStatus {
+                            id: self.next_expected,
+                            status: Status::Retry,
+                        },
+                    )?;
+                }
+                Err(err) => bail!("Undecodable frame: {:?}", err),
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            match link.read_available(&mut buf, deadline - now) {
+                Ok(0) => bail!("Link closed by the host"),
+                Ok(n) => {
+                    self.stats.bytes_received = self.stats.bytes_received.saturating_add(n as u32);
+                    accumulated.extend_from_slice(&buf[..n]);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn send_mcu_message<S: Transport>(link: &mut S, msg: &MessageTypeMcu) -> Result<()> {
+    let frame = codec::encode(msg)?;
+
+    link.write_all(&frame)?;
+    link.flush()?;
+
+    Ok(())
+}
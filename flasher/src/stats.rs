@@ -0,0 +1,205 @@
+//! Transfer statistics, collected while flashing and reported at the end.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use messages::LinkStats;
+
+/// Everything that happened during one flash run. Threaded through the
+/// transfer loop instead of ad-hoc counters, and part of the library
+/// surface so non-CLI consumers get it too.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Stats {
+    pub image_size: usize,
+    /// Segment payload bytes actually put on the wire.
+    pub sent_bytes: usize,
+    pub segments: usize,
+    pub compressed_segments: usize,
+    /// Ids of segments that needed at least one retransmission.
+    pub retransmitted: Vec<u16>,
+    /// Received frames whose checksum verified.
+    pub frames_received: u32,
+    /// Bytes read off the link, frames and garbage alike.
+    pub received_bytes: usize,
+    /// Received frames dropped due to a bad CRC.
+    pub crc_errors: u32,
+    /// Received frames that did not decode at all.
+    pub decode_errors: u32,
+    /// Reply deadlines that expired.
+    pub timeouts: u32,
+    /// `Busy` replies honoured, each costing one `retry_after_ms` wait.
+    pub busy_waits: u32,
+    pub handshake_ms: u64,
+    pub transfer_ms: u64,
+    pub finalize_ms: u64,
+    /// Peak per-segment effective throughput, bytes/s.
+    pub peak_throughput: f64,
+    /// The device's own wire counters, fetched via `GetStats` at the
+    /// end of the transfer; `None` on firmware that predates the
+    /// exchange.
+    pub remote: Option<LinkStats>,
+}
+
+impl Stats {
+    pub fn retries(&self) -> u32 {
+        self.retransmitted.len() as u32
+    }
+
+    /// This side's counters as the shared wire struct, the mirror of
+    /// [`remote`](Self::remote). `bytes_sent` carries the segment
+    /// payload bytes (the dominant term; frame overhead is not
+    /// tracked), and `duplicate_segments` stays zero - recognising
+    /// duplicates is the writing side's job.
+    pub fn local_link(&self) -> LinkStats {
+        LinkStats {
+            frames_received: self.frames_received,
+            crc_errors: self.crc_errors,
+            decode_errors: self.decode_errors,
+            segments_retried: self.retries(),
+            duplicate_segments: 0,
+            busy_events: self.busy_waits,
+            bytes_received: u32::try_from(self.received_bytes).unwrap_or(u32::MAX),
+            bytes_sent: u32::try_from(self.sent_bytes).unwrap_or(u32::MAX),
+            // Flash metering is the writing side's job too
+            flash_writes: 0,
+            flash_write_micros: 0,
+        }
+    }
+
+    /// Wire bytes as a fraction of the image size; 1.0 means no gain.
+    pub fn compression_ratio(&self) -> f64 {
+        self.sent_bytes as f64 / self.image_size as f64
+    }
+
+    /// Wall-clock duration of the whole run, all phases combined.
+    pub fn total_ms(&self) -> u64 {
+        self.handshake_ms + self.transfer_ms + self.finalize_ms
+    }
+
+    /// Retransmitted segments as a fraction of the segment count.
+    pub fn retransmission_ratio(&self) -> f64 {
+        if self.segments == 0 {
+            return 0.0;
+        }
+
+        self.retransmitted.len() as f64 / self.segments as f64
+    }
+
+    /// Average effective throughput over the transfer phase, bytes/s.
+    pub fn average_throughput(&self) -> f64 {
+        if self.transfer_ms == 0 {
+            return 0.0;
+        }
+
+        self.sent_bytes as f64 / Duration::from_millis(self.transfer_ms).as_secs_f64()
+    }
+
+    pub(crate) fn record_segment(&mut self, wire_len: usize, elapsed: Duration) {
+        self.sent_bytes += wire_len;
+
+        let secs = elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.peak_throughput = self.peak_throughput.max(wire_len as f64 / secs);
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Image: {} bytes, {} on the wire in {} segments ({} compressed)",
+            self.image_size, self.sent_bytes, self.segments, self.compressed_segments
+        )?;
+        writeln!(
+            f,
+            "Phases: handshake {} ms, transfer {} ms, finalize {} ms ({:.1} s total)",
+            self.handshake_ms,
+            self.transfer_ms,
+            self.finalize_ms,
+            self.total_ms() as f64 / 1000.0
+        )?;
+        writeln!(
+            f,
+            "Throughput: {:.1} kB/s average, {:.1} kB/s peak",
+            self.average_throughput() / 1000.0,
+            self.peak_throughput / 1000.0
+        )?;
+        write!(
+            f,
+            "Errors: {} retransmissions{}, {} CRC errors, {} decode errors, {} timeouts, \
+             {} busy waits",
+            self.retries(),
+            if self.retransmitted.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    " ({:.1}% of segments, ids {:?})",
+                    100.0 * self.retransmission_ratio(),
+                    self.retransmitted
+                )
+            },
+            self.crc_errors,
+            self.decode_errors,
+            self.timeouts,
+            self.busy_waits
+        )?;
+
+        if let Some(remote) = &self.remote {
+            write!(
+                f,
+                "\nDevice: {} frames, {} CRC errors, {} decode errors, {} retries asked, \
+                 {} duplicates acked, {} busy replies",
+                remote.frames_received,
+                remote.crc_errors,
+                remote.decode_errors,
+                remote.segments_retried,
+                remote.duplicate_segments,
+                remote.busy_events
+            )?;
+
+            // Zero means firmware from before the write coalescing
+            if remote.flash_writes > 0 {
+                write!(
+                    f,
+                    "\nDevice flash: {} writes, {:.1} ms in the driver",
+                    remote.flash_writes,
+                    remote.flash_write_micros as f64 / 1000.0
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_ratio_handles_an_empty_run() {
+        assert_eq!(Stats::default().retransmission_ratio(), 0.0);
+    }
+
+    #[test]
+    fn the_summary_carries_duration_and_retransmission_ratio() {
+        let stats = Stats {
+            segments: 10,
+            retransmitted: vec![3],
+            handshake_ms: 100,
+            transfer_ms: 2_400,
+            finalize_ms: 500,
+            ..Default::default()
+        };
+
+        assert_eq!(stats.total_ms(), 3_000);
+
+        let text = stats.to_string();
+        assert!(text.contains("(3.0 s total)"), "summary: {}", text);
+        assert!(text.contains("(10.0% of segments"), "summary: {}", text);
+    }
+}
@@ -0,0 +1,77 @@
+//! TCP link to a device built with the firmware's `tcp_update` feature.
+//!
+//! The protocol over the socket is byte-for-byte the serial one, so the
+//! flash logic is reused as-is; this adapter only smooths over the I/O
+//! differences. In particular POSIX reports a socket read timeout as
+//! `WouldBlock`, while [`Transport`] callers expect the serial ports'
+//! `TimedOut` for "no bytes yet".
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use messages::transport::Transport;
+
+/// Port the firmware's update listener binds by default.
+pub const DEFAULT_PORT: u16 = 3232;
+
+pub struct TcpLink {
+    stream: TcpStream,
+    /// Read timeout currently set on the socket; reprogrammed only when
+    /// a caller's timeout changes.
+    timeout: Duration,
+}
+
+impl TcpLink {
+    /// Connects to `addr` (`host` or `host:port`; the default port is
+    /// [`DEFAULT_PORT`]).
+    pub fn connect(addr: &str) -> Result<Self> {
+        let addr = if addr.contains(':') {
+            addr.to_string()
+        } else {
+            format!("{}:{}", addr, DEFAULT_PORT)
+        };
+
+        let stream =
+            TcpStream::connect(&addr).with_context(|| format!("Cannot connect to {}", addr))?;
+
+        // Acks are a handful of bytes; waiting to fill a packet would
+        // add Nagle's 40 ms to every segment round-trip
+        stream.set_nodelay(true).ok();
+
+        let timeout = Duration::from_millis(100);
+        stream.set_read_timeout(Some(timeout))?;
+
+        Ok(Self { stream, timeout })
+    }
+}
+
+impl Transport for TcpLink {
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        // A zero socket timeout means "block forever" to the OS, the
+        // opposite of what a caller with an expired deadline wants
+        let timeout = timeout.max(Duration::from_millis(1));
+
+        if timeout != self.timeout {
+            self.stream.set_read_timeout(Some(timeout))?;
+            self.timeout = timeout;
+        }
+
+        match self.stream.read(buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+            }
+            other => other,
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.stream.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
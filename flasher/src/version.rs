@@ -0,0 +1,219 @@
+//! App/protocol version gating for the pre-flash handshake.
+
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+use messages::{Info, PROTOCOL_VERSION};
+
+/// Where `esp_app_desc_t` sits in an app image: right after the 24-byte
+/// image header and the first 8-byte segment header. Mirrors the
+/// firmware's anti-rollback parsing.
+const APP_DESC_OFFSET: usize = 32;
+
+/// `esp_app_desc_t.magic_word` of a well-formed descriptor.
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+
+/// Offset of the NUL-padded version string within the descriptor, and
+/// its fixed size.
+const APP_DESC_VERSION_OFFSET: usize = 16;
+const APP_DESC_VERSION_LEN: usize = 32;
+
+/// Reads the version string out of an app image's `esp_app_desc_t`;
+/// `None` when the bytes do not look like an app image at all (a raw
+/// partition image, say).
+pub fn image_version(image: &[u8]) -> Option<String> {
+    let desc = image.get(APP_DESC_OFFSET..)?;
+
+    let magic = u32::from_le_bytes(desc.get(..4)?.try_into().ok()?);
+    if magic != APP_DESC_MAGIC {
+        return None;
+    }
+
+    let field =
+        desc.get(APP_DESC_VERSION_OFFSET..APP_DESC_VERSION_OFFSET + APP_DESC_VERSION_LEN)?;
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+
+    String::from_utf8(field[..len].to_vec()).ok()
+}
+
+/// Parses a semver-ish version as it appears in the app descriptor:
+/// an optional leading `v`, dot-separated numbers, and anything after a
+/// `-` or `+` ignored. Returns `None` if no leading number is found.
+pub fn parse_version(s: &str) -> Option<(u32, u32, u32)> {
+    let s = s.trim().trim_start_matches('v');
+    let s = s.split(['-', '+']).next().unwrap_or("");
+
+    let mut parts = s.split('.').map(|part| part.parse::<u32>());
+
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+
+    Some((major, minor, patch))
+}
+
+/// Whether `actual` satisfies `required`; `None` when either side is
+/// unparseable.
+pub fn at_least(actual: &str, required: &str) -> Option<bool> {
+    Some(parse_version(actual)? >= parse_version(required)?)
+}
+
+/// Enforces the protocol version the device acked in the Hello
+/// exchange, before anything a mismatched generation might misparse
+/// goes over the line. `None` is firmware from before the exchange,
+/// which speaks the legacy subset every flasher still sends; the
+/// `--require-protocol` gate in [`check`] covers it.
+pub fn check_protocol(acked: Option<u8>, force: bool) -> Result<()> {
+    match acked {
+        Some(device) if device != PROTOCOL_VERSION => {
+            let who = if device < PROTOCOL_VERSION {
+                "the firmware is outdated"
+            } else {
+                "this flasher is outdated"
+            };
+
+            refuse(
+                force,
+                &format!(
+                    "Device speaks protocol version {} but this flasher speaks {}; {}",
+                    device, PROTOCOL_VERSION, who
+                ),
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Prints what the device reported and enforces the `--min-version` /
+/// `--require-protocol` gates; `force` downgrades refusals to warnings.
+pub fn check(
+    info: Option<&Info>,
+    min_version: Option<&str>,
+    require_protocol: Option<u8>,
+    force: bool,
+) -> Result<()> {
+    match info {
+        Some(info) => {
+            println!(
+                "Device: app version {}, protocol version {} (flasher speaks {})",
+                info.app_version, info.protocol_version, PROTOCOL_VERSION
+            );
+
+            if let Some(required) = require_protocol {
+                if info.protocol_version != required {
+                    let who = if info.protocol_version < required {
+                        "the firmware is outdated"
+                    } else {
+                        "this flasher is outdated"
+                    };
+
+                    refuse(
+                        force,
+                        &format!(
+                            "Device speaks protocol version {} but {} is required; {}",
+                            info.protocol_version, required, who
+                        ),
+                    )?;
+                }
+            }
+
+            if let Some(required) = min_version {
+                match at_least(&info.app_version, required) {
+                    Some(true) => (),
+                    Some(false) => refuse(
+                        force,
+                        &format!(
+                            "Device runs app version {} but at least {} is required",
+                            info.app_version, required
+                        ),
+                    )?,
+                    None => refuse(
+                        force,
+                        &format!(
+                            "Cannot compare app version {:?} against {:?}",
+                            info.app_version, required
+                        ),
+                    )?,
+                }
+            }
+        }
+        None => {
+            println!("Device did not report its version (old firmware?)");
+
+            if min_version.is_some() || require_protocol.is_some() {
+                refuse(force, "Device version unknown but a version gate was given")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn refuse(force: bool, msg: &str) -> Result<()> {
+    if force {
+        eprintln!("warning: {} (continuing due to --force)", msg);
+        Ok(())
+    } else {
+        bail!("{}; pass --force to flash anyway", msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_and_decorated_versions() {
+        assert_eq!(parse_version("0.25.0"), Some((0, 25, 0)));
+        assert_eq!(parse_version("v1.2.3"), Some((1, 2, 3)));
+        assert_eq!(parse_version("1.2.3-g1234abc"), Some((1, 2, 3)));
+        assert_eq!(parse_version("2.0"), Some((2, 0, 0)));
+        assert_eq!(parse_version("garbage"), None);
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(at_least("0.25.1", "0.25.0"), Some(true));
+        assert_eq!(at_least("0.25.0", "0.25.0"), Some(true));
+        assert_eq!(at_least("0.24.9", "0.25.0"), Some(false));
+        assert_eq!(at_least("1.0.0", "0.99.99"), Some(true));
+        assert_eq!(at_least("??", "0.1.0"), None);
+    }
+
+    #[test]
+    fn a_mismatched_protocol_ack_is_refused_up_front() {
+        assert!(check_protocol(Some(PROTOCOL_VERSION), false).is_ok());
+        // Old firmware never acks; the legacy fallback handles it
+        assert!(check_protocol(None, false).is_ok());
+
+        assert!(check_protocol(Some(PROTOCOL_VERSION + 1), false).is_err());
+        assert!(check_protocol(Some(PROTOCOL_VERSION + 1), true).is_ok());
+    }
+
+    /// Minimal app image: headers, then a descriptor carrying `version`.
+    fn image_with_version(version: &str) -> Vec<u8> {
+        let mut image = vec![0_u8; APP_DESC_OFFSET + 256];
+
+        image[APP_DESC_OFFSET..APP_DESC_OFFSET + 4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+
+        let at = APP_DESC_OFFSET + APP_DESC_VERSION_OFFSET;
+        image[at..at + version.len()].copy_from_slice(version.as_bytes());
+
+        image
+    }
+
+    #[test]
+    fn the_descriptor_version_is_read_out_of_an_image() {
+        assert_eq!(
+            image_version(&image_with_version("0.25.0")),
+            Some("0.25.0".to_string())
+        );
+    }
+
+    #[test]
+    fn bytes_without_a_descriptor_are_not_an_app_image() {
+        assert_eq!(image_version(&[0xff; 512]), None);
+        assert_eq!(image_version(b"short"), None);
+    }
+}
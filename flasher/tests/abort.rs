@@ -0,0 +1,78 @@
+//! Device-pushed aborts against the simulator.
+
+use std::thread;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::pair;
+use messages::{FailureReason, SEGMENT_SIZE};
+
+fn test_image() -> Vec<u8> {
+    vec![0x5a_u8; SEGMENT_SIZE * 4]
+}
+
+#[test]
+fn pushed_abort_reports_the_reason() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_abort_at(2, FailureReason::Timeout)
+            .run(&mut device);
+    });
+
+    let opts = FlashOpts {
+        no_compress: true,
+        ..Default::default()
+    };
+    let err = flash(&mut host, &test_image(), &opts).unwrap_err();
+
+    // The pushed reason, not a generic reply timeout
+    let msg = format!("{:#}", err);
+    assert!(msg.contains("aborted"), "unexpected error: {}", msg);
+    assert!(msg.contains("Timeout"), "unexpected error: {}", msg);
+}
+
+#[test]
+fn retry_session_restarts_after_a_transient_abort() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_abort_at(2, FailureReason::Timeout)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+
+    let opts = FlashOpts {
+        no_compress: true,
+        retry_session: true,
+        ..Default::default()
+    };
+    flash(&mut host, &image, &opts).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+}
+
+#[test]
+fn persistent_reasons_are_not_retried() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_abort_at(2, FailureReason::FlashError)
+            .run(&mut device);
+    });
+
+    let opts = FlashOpts {
+        no_compress: true,
+        retry_session: true,
+        ..Default::default()
+    };
+    let err = flash(&mut host, &test_image(), &opts).unwrap_err();
+
+    assert!(format!("{:#}", err).contains("FlashError"));
+}
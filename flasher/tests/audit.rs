@@ -0,0 +1,44 @@
+//! The `GetUpdateInfo` audit query against the device simulator.
+
+use std::thread;
+
+use flasher::simulator::Simulator;
+use flasher::{last_update, FlashOpts};
+
+use messages::transport::pair;
+use messages::UpdateRecord;
+
+#[test]
+fn a_recorded_update_is_reported() {
+    let (mut host, mut device) = pair();
+
+    let record = UpdateRecord {
+        sha256: [7; 32],
+        source: "uart".to_string(),
+        timestamp: 1_700_000_000,
+        segments: 421,
+        retries: 2,
+    };
+    let expected = record.clone();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().with_last_update(record).run(&mut device);
+    });
+
+    let reported = last_update(&mut host, &FlashOpts::default()).unwrap();
+
+    assert_eq!(reported, Some(expected));
+}
+
+#[test]
+fn a_device_without_a_record_reports_none() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    let reported = last_update(&mut host, &FlashOpts::default()).unwrap();
+
+    assert_eq!(reported, None);
+}
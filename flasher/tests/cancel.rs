@@ -0,0 +1,42 @@
+//! Cancel acknowledgement against the device simulator.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flasher::simulator::Simulator;
+use flasher::{cancel, FlashOpts, RetryPolicy};
+
+use messages::transport::pair;
+
+#[test]
+fn cancel_waits_for_the_ack() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    let started = Instant::now();
+
+    cancel(&mut host, &FlashOpts::default()).unwrap();
+
+    // Acked promptly, no blind wait involved
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn silent_firmware_falls_back_to_the_blind_wait() {
+    let (mut host, _device) = pair();
+
+    let opts = FlashOpts {
+        keepalive_interval: Some(Duration::from_millis(50)),
+        retry: RetryPolicy {
+            ack_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    // No ack ever arrives; the old-firmware path still succeeds
+    cancel(&mut host, &opts).unwrap();
+}
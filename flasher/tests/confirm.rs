@@ -0,0 +1,62 @@
+//! The confirm-after-boot exchange against the simulator.
+
+use std::thread;
+use std::time::Duration;
+
+use flasher::simulator::Simulator;
+use flasher::{mark_valid, read_message, rollback, send_message, FlashOpts};
+
+use messages::transport::pair;
+use messages::{Info, MessageTypeHost, MessageTypeMcu};
+
+/// `Info` as the device currently reports it.
+fn get_info(link: &mut messages::transport::Loopback) -> Info {
+    send_message(link, &MessageTypeHost::GetInfo).unwrap();
+
+    match read_message(link, Duration::from_secs(1)).unwrap() {
+        MessageTypeMcu::Info(info) => info,
+        other => panic!("expected Info, got {:?}", other),
+    }
+}
+
+#[test]
+fn mark_valid_clears_the_pending_verify() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_app_version("0.25.0")
+            .with_pending_verify()
+            .run(&mut device);
+    });
+
+    assert!(get_info(&mut host).pending_verify);
+
+    mark_valid(&mut host, &FlashOpts::default()).unwrap();
+
+    assert!(!get_info(&mut host).pending_verify);
+}
+
+#[test]
+fn rollback_is_acked_while_a_verify_is_pending() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().with_pending_verify().run(&mut device);
+    });
+
+    rollback(&mut host, &FlashOpts::default()).unwrap();
+}
+
+#[test]
+fn rollback_of_a_confirmed_image_is_refused() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    let err = rollback(&mut host, &FlashOpts::default()).unwrap_err();
+
+    assert!(format!("{:#}", err).contains("roll back"));
+}
@@ -0,0 +1,124 @@
+//! The doctor's checks against a simulator misbehaving in each
+//! specific way a bad connection does.
+
+use std::thread;
+use std::time::Duration;
+
+use flasher::doctor::{self, LineProbe};
+use flasher::simulator::Simulator;
+
+use messages::transport::{pair, Transport};
+
+/// Short enough to keep the negative probes fast; the real window only
+/// matters on hardware.
+const WINDOW: Duration = Duration::from_millis(200);
+
+#[test]
+fn a_dead_line_is_silent() {
+    let (mut host, device) = pair();
+
+    // Keep the device end alive but mute, like a powered-off board
+    let sim = thread::spawn(move || {
+        let _device = device;
+        thread::sleep(Duration::from_millis(400));
+    });
+
+    assert_eq!(
+        doctor::probe_line(&mut host, WINDOW).unwrap(),
+        LineProbe::Silent
+    );
+    sim.join().unwrap();
+}
+
+#[test]
+fn garbage_bytes_are_noise() {
+    let (mut host, mut device) = pair();
+
+    // A device at the wrong baud rate produces bytes that never frame
+    let sim = thread::spawn(move || {
+        device.write_all(&[0xAA; 64]).unwrap();
+        thread::sleep(Duration::from_millis(400));
+    });
+
+    assert_eq!(
+        doctor::probe_line(&mut host, WINDOW).unwrap(),
+        LineProbe::Noise
+    );
+    sim.join().unwrap();
+}
+
+#[test]
+fn a_healthy_line_yields_frames_and_pongs() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    assert_eq!(
+        doctor::probe_line(&mut host, WINDOW).unwrap(),
+        LineProbe::Frames
+    );
+    assert!(doctor::probe_pong(&mut host, WINDOW).unwrap());
+}
+
+#[test]
+fn boot_noise_before_the_first_frame_does_not_hide_it() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        // Boot-log chatter ahead of the first real reply
+        device.write_all(b"I (312) boot: Loaded app\r\n").unwrap();
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    assert_eq!(
+        doctor::probe_line(&mut host, WINDOW).unwrap(),
+        LineProbe::Frames
+    );
+}
+
+#[test]
+fn old_firmware_answers_ping_but_not_get_info() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    let report = doctor::run(&mut host, WINDOW).unwrap();
+
+    assert_eq!(report.line, LineProbe::Frames);
+    assert!(report.pong);
+    assert!(report.info.is_none());
+}
+
+#[test]
+fn info_carries_the_protocol_and_slot_checks() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_app_version("0.25.0")
+            .with_slot_size(0x18_0000)
+            .run(&mut device);
+    });
+
+    let info = doctor::probe_info(&mut host, WINDOW).unwrap().unwrap();
+
+    assert!(doctor::protocol_mismatch(&info).is_none());
+    assert!(!doctor::missing_ota_slot(&info));
+}
+
+#[test]
+fn a_device_without_slots_is_flagged() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().with_app_version("0.25.0").run(&mut device);
+    });
+
+    let info = doctor::probe_info(&mut host, WINDOW).unwrap().unwrap();
+
+    assert!(doctor::missing_ota_slot(&info));
+}
@@ -0,0 +1,139 @@
+//! Version handshake gating against the device simulator.
+
+use std::thread;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::pair;
+
+fn test_image() -> Vec<u8> {
+    (0_u32..1000).flat_map(|i| i.to_le_bytes()).collect()
+}
+
+fn min_version_opts(version: &str, force: bool) -> FlashOpts {
+    FlashOpts {
+        min_version: Some(version.to_string()),
+        force,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn older_firmware_is_refused() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().with_app_version("0.24.0").run(&mut device);
+    });
+
+    let err = flash(&mut host, &test_image(), &min_version_opts("0.25.0", false)).unwrap_err();
+
+    assert!(err.to_string().contains("--force"));
+}
+
+#[test]
+fn newer_firmware_passes_the_gate() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_app_version("0.25.1")
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+
+    flash(&mut host, &image, &min_version_opts("0.25.0", false)).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+}
+
+#[test]
+fn force_overrides_an_unknown_version() {
+    let (mut host, mut device) = pair();
+
+    // Old firmware: no Info reply at all
+    let sim = thread::spawn(move || Simulator::new().run(&mut device).unwrap());
+
+    let image = test_image();
+
+    flash(&mut host, &image, &min_version_opts("0.25.0", true)).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+}
+
+#[test]
+fn oversized_image_is_refused_before_any_segment() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_app_version("0.25.0")
+            .with_slot_size(1024)
+            .run(&mut device);
+    });
+
+    let err = flash(&mut host, &test_image(), &FlashOpts::default()).unwrap_err();
+
+    assert!(err.to_string().contains("update slot"));
+}
+
+#[test]
+fn firmware_without_hello_still_flashes() {
+    let (mut host, mut device) = pair();
+
+    // Firmware from before the Hello exchange ignores the frame; the
+    // host has to fall back to the legacy flow without a hitch
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_app_version("0.25.0")
+            .without_hello()
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+
+    flash(&mut host, &image, &FlashOpts::default()).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+}
+
+#[test]
+fn protocol_mismatch_is_refused() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().with_app_version("0.25.0").run(&mut device);
+    });
+
+    let opts = FlashOpts {
+        require_protocol: Some(messages::PROTOCOL_VERSION + 1),
+        ..Default::default()
+    };
+
+    let err = flash(&mut host, &test_image(), &opts).unwrap_err();
+
+    assert!(err.to_string().contains("protocol"));
+}
+
+#[test]
+fn a_future_protocol_generation_is_refused_at_the_hello() {
+    let (mut host, mut device) = pair();
+
+    // Firmware from a later generation acks with its own version; the
+    // host refuses right there, before sending anything the device
+    // might misparse - no --require-protocol flag needed
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_protocol_version(messages::PROTOCOL_VERSION + 1)
+            .run(&mut device);
+    });
+
+    let err = flash(&mut host, &test_image(), &FlashOpts::default()).unwrap_err();
+
+    assert!(err.to_string().contains("protocol version"));
+    assert!(err.to_string().contains("--force"));
+}
@@ -0,0 +1,67 @@
+//! Keepalive pings while the device is busy, against the simulator.
+
+use std::thread;
+use std::time::Duration;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts, RetryPolicy};
+
+use messages::transport::pair;
+use messages::SEGMENT_SIZE;
+
+fn test_image() -> Vec<u8> {
+    vec![0x5a_u8; SEGMENT_SIZE * 3]
+}
+
+/// A device acking slower than the response timeout survives as long as
+/// it answers pings, and the Pongs must not be mistaken for segment acks.
+#[test]
+fn slow_acks_survive_via_pings() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_app_version("0.25.0")
+            .with_ack_delay(Duration::from_millis(300))
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+
+    let opts = FlashOpts {
+        no_compress: true,
+        keepalive_interval: Some(Duration::from_millis(50)),
+        retry: RetryPolicy {
+            ack_timeout: Some(Duration::from_millis(150)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let stats = flash(&mut host, &image, &opts).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert!(stats.retransmitted.is_empty());
+    assert_eq!(stats.timeouts, 0);
+}
+
+/// Without keepalive answers the wait still ends: an unresponsive device
+/// is a timeout, not an infinite hang.
+#[test]
+fn unresponsive_device_still_times_out() {
+    let (mut host, _device) = pair();
+
+    let opts = FlashOpts {
+        keepalive_interval: Some(Duration::from_millis(50)),
+        retry: RetryPolicy {
+            ack_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let err = flash(&mut host, &test_image(), &opts).unwrap_err();
+
+    assert!(err.to_string().contains("Timed out"));
+}
@@ -0,0 +1,67 @@
+//! Flashing non-app partitions against the device simulator.
+
+use std::thread;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::pair;
+
+fn spiffs_image() -> Vec<u8> {
+    (0_u32..2000).flat_map(|i| i.to_le_bytes()).collect()
+}
+
+fn partition_opts(label: &str) -> FlashOpts {
+    FlashOpts {
+        partition: Some(label.to_string()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn partition_roundtrip() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_partition("storage", 64 * 1024)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = spiffs_image();
+
+    flash(&mut host, &image, &partition_opts("storage")).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+}
+
+#[test]
+fn unknown_label_is_refused() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_partition("storage", 64 * 1024)
+            .run(&mut device);
+    });
+
+    let err = flash(&mut host, &spiffs_image(), &partition_opts("wwwdata")).unwrap_err();
+
+    assert!(err.to_string().contains("refused"));
+}
+
+#[test]
+fn too_large_image_is_refused() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_partition("storage", 1024)
+            .run(&mut device);
+    });
+
+    let err = flash(&mut host, &spiffs_image(), &partition_opts("storage")).unwrap_err();
+
+    assert!(err.to_string().contains("refused"));
+}
@@ -0,0 +1,127 @@
+//! Mid-transfer disconnects against the device simulator.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use flasher::reconnect::ReconnectingLink;
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::{pair, Loopback, Transport};
+
+/// A link handle that "unplugs" after a number of successful reads: every
+/// operation from then on fails with `BrokenPipe`, like a dead file
+/// descriptor. The underlying loopback (and so the simulator) stays
+/// alive, as the real device does when only the USB hub hiccups.
+struct FlakyLink {
+    pipe: Arc<Mutex<Loopback>>,
+    /// Fail permanently once this many reads returned data; `usize::MAX`
+    /// for a healthy handle.
+    fail_after_reads: usize,
+    reads: usize,
+    dead: bool,
+}
+
+impl FlakyLink {
+    fn new(pipe: Arc<Mutex<Loopback>>, fail_after_reads: usize) -> Self {
+        Self {
+            pipe,
+            fail_after_reads,
+            reads: 0,
+            dead: false,
+        }
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        if self.dead {
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "simulated unplug",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Transport for FlakyLink {
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.check()?;
+
+        let n = self.pipe.lock().unwrap().read_available(buf, timeout)?;
+
+        self.reads += 1;
+        if self.reads >= self.fail_after_reads {
+            self.dead = true;
+        }
+
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.check()?;
+        self.pipe.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check()?;
+        self.pipe.lock().unwrap().flush()
+    }
+}
+
+fn test_image() -> Vec<u8> {
+    (0_u32..1000).flat_map(|i| i.to_le_bytes()).collect()
+}
+
+#[test]
+fn transfer_resumes_after_a_reconnect() {
+    let (host, mut device) = pair();
+    let pipe = Arc::new(Mutex::new(host));
+
+    let sim = thread::spawn(move || Simulator::new().run(&mut device).unwrap());
+
+    let image = test_image();
+
+    // The handle dies mid-transfer; the replacement is only "re-enumerated"
+    // on the second poll
+    let polls = AtomicUsize::new(0);
+    let factory_pipe = pipe.clone();
+    let mut link = ReconnectingLink::new(
+        FlakyLink::new(pipe, 10),
+        move || {
+            if polls.fetch_add(1, Ordering::Relaxed) == 0 {
+                None
+            } else {
+                Some(FlakyLink::new(factory_pipe.clone(), usize::MAX))
+            }
+        },
+        Duration::from_secs(2),
+    );
+
+    flash(&mut link, &image, &FlashOpts::default()).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(link.reconnects(), 1);
+}
+
+#[test]
+fn exhausted_reconnect_timeout_fails_the_transfer() {
+    let (host, mut device) = pair();
+    let pipe = Arc::new(Mutex::new(host));
+
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    // The port never comes back
+    let mut link = ReconnectingLink::new(
+        FlakyLink::new(pipe, 10),
+        || None,
+        Duration::from_millis(300),
+    );
+
+    flash(&mut link, &test_image(), &FlashOpts::default()).unwrap_err();
+}
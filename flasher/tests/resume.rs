@@ -0,0 +1,64 @@
+//! Resuming an interrupted transfer from the device's checkpoint.
+
+use std::thread;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::pair;
+use messages::SEGMENT_SIZE;
+
+/// An image of a few segments plus a trailing short one.
+fn test_image() -> Vec<u8> {
+    (0..5 * SEGMENT_SIZE + 123)
+        .map(|i| (i / 11) as u8)
+        .collect()
+}
+
+#[test]
+fn a_resumed_transfer_skips_the_segments_already_in_flash() {
+    let (mut host, mut device) = pair();
+
+    let image = test_image();
+    let prefix = image[..2 * SEGMENT_SIZE].to_vec();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_resumable_prefix(prefix)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let report = flash(
+        &mut host,
+        &image,
+        &FlashOpts {
+            resume: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(report.sent_bytes, image.len() - 2 * SEGMENT_SIZE);
+}
+
+#[test]
+fn without_the_flag_the_checkpoint_is_ignored() {
+    let (mut host, mut device) = pair();
+
+    let image = test_image();
+    let prefix = image[..2 * SEGMENT_SIZE].to_vec();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_resumable_prefix(prefix)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let report = flash(&mut host, &image, &FlashOpts::default()).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(report.sent_bytes, image.len());
+}
@@ -0,0 +1,262 @@
+//! End-to-end transfers of the flasher against the device simulator.
+
+use std::thread;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts, RetryPolicy};
+
+use messages::transport::pair;
+use messages::CAP_COMPRESSED_SEGMENTS;
+
+/// A compressible test image: repetitive, and deliberately not a multiple
+/// of the segment size so the trailing short segment is covered.
+fn test_image() -> Vec<u8> {
+    let mut image = Vec::new();
+
+    for i in 0_u32..5000 {
+        image.extend_from_slice(&(i / 7).to_le_bytes());
+    }
+    image.truncate(image.len() - 3);
+
+    image
+}
+
+#[test]
+fn plain_roundtrip() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || Simulator::new().run(&mut device).unwrap());
+
+    let image = test_image();
+    let report = flash(&mut host, &image, &FlashOpts::default()).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(report.compressed_segments, 0);
+    assert_eq!(report.sent_bytes, image.len());
+}
+
+#[test]
+fn compressed_roundtrip_is_byte_identical() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_capabilities(CAP_COMPRESSED_SEGMENTS)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+    let report = flash(&mut host, &image, &FlashOpts::default()).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert!(report.compressed_segments > 0);
+    assert!(report.sent_bytes < image.len());
+}
+
+#[test]
+fn no_compress_flag_forces_plain_segments() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_capabilities(CAP_COMPRESSED_SEGMENTS)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+    let report = flash(
+        &mut host,
+        &image,
+        &FlashOpts {
+            no_compress: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(report.compressed_segments, 0);
+}
+
+#[test]
+fn a_matching_running_version_is_skipped_when_asked() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().with_app_version("0.25.0").run(&mut device);
+    });
+
+    // Plant an app descriptor announcing the version the device runs
+    let mut image = test_image();
+    image[32..36].copy_from_slice(&0xabcd_5432_u32.to_le_bytes());
+    image[48..80].fill(0);
+    image[48..54].copy_from_slice(b"0.25.0");
+
+    let report = flash(
+        &mut host,
+        &image,
+        &FlashOpts {
+            skip_if_same: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(report.segments, 0);
+    assert_eq!(report.sent_bytes, 0);
+}
+
+#[test]
+fn a_chunk_size_override_shrinks_the_segments() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || Simulator::new().run(&mut device).unwrap());
+
+    let image = test_image();
+    let report = flash(
+        &mut host,
+        &image,
+        &FlashOpts {
+            chunk_size: Some(64),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(report.segments, image.len().div_ceil(64));
+}
+
+#[test]
+fn an_oversized_chunk_is_refused() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    let err = flash(
+        &mut host,
+        &test_image(),
+        &FlashOpts {
+            chunk_size: Some(2048),
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+
+    assert!(
+        format!("{:#}", err).contains("exceeds"),
+        "unexpected error: {:#}",
+        err
+    );
+}
+
+#[test]
+fn a_corrupted_write_is_caught_by_the_digest_and_reported() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        // One flipped byte in "flash": only the digest can see it
+        let _ = Simulator::new().with_flipped_byte(100).run(&mut device);
+    });
+
+    let err = flash(&mut host, &test_image(), &FlashOpts::default()).unwrap_err();
+
+    assert!(
+        format!("{:#}", err).contains("SHA-256"),
+        "unexpected error: {:#}",
+        err
+    );
+}
+
+#[test]
+fn a_windowed_transfer_is_byte_identical() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || Simulator::new().run(&mut device).unwrap());
+
+    let image = test_image();
+    let report = flash(
+        &mut host,
+        &image,
+        &FlashOpts {
+            window: 8,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(report.sent_bytes, image.len());
+}
+
+#[test]
+fn a_windowed_transfer_recovers_from_a_failed_segment() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_failed_segment(2)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+    let report = flash(
+        &mut host,
+        &image,
+        &FlashOpts {
+            window: 4,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert!(report.retries() >= 1);
+}
+
+#[test]
+fn an_exhausted_retry_budget_fails_the_transfer() {
+    let (mut host, mut device) = pair();
+
+    // The simulator refuses segment 2 only once, but a budget of zero
+    // makes that single refusal final
+    thread::spawn(move || {
+        let _ = Simulator::new().with_failed_segment(2).run(&mut device);
+    });
+
+    let opts = FlashOpts {
+        retry: RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let err = flash(&mut host, &test_image(), &opts).unwrap_err();
+
+    assert!(err.to_string().contains("after 0 retries"));
+}
+
+#[test]
+fn retried_compressed_segment_is_retransmitted_verbatim() {
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_capabilities(CAP_COMPRESSED_SEGMENTS)
+            .with_failed_segment(2)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+    let report = flash(&mut host, &image, &FlashOpts::default()).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+    assert_eq!(report.retries(), 1);
+}
@@ -0,0 +1,85 @@
+//! Signed transfers of the flasher against the device simulator.
+
+use std::thread;
+
+use ed25519_dalek::SigningKey;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::pair;
+use messages::CAP_SIGNATURE_REQUIRED;
+
+const SEED: [u8; 32] = [7; 32];
+
+fn test_image() -> Vec<u8> {
+    (0_u32..3000).flat_map(|i| i.to_le_bytes()).collect()
+}
+
+#[test]
+fn signed_transfer_verifies_on_the_device() {
+    let key = SigningKey::from_bytes(&SEED);
+    let verifying = key.verifying_key();
+
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_capabilities(CAP_SIGNATURE_REQUIRED)
+            .with_verifying_key(verifying)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let image = test_image();
+    let opts = FlashOpts {
+        sign_key: Some(key),
+        ..Default::default()
+    };
+
+    flash(&mut host, &image, &opts).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+}
+
+#[test]
+fn precomputed_signature_is_accepted() {
+    let key = SigningKey::from_bytes(&SEED);
+    let verifying = key.verifying_key();
+
+    let image = test_image();
+    let signature = flasher::sign::sign_image(&key, &image);
+
+    let (mut host, mut device) = pair();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_verifying_key(verifying)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let opts = FlashOpts {
+        signature: Some(signature),
+        ..Default::default()
+    };
+
+    flash(&mut host, &image, &opts).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+}
+
+#[test]
+fn unsigned_flash_is_refused_when_mandatory() {
+    let (mut host, mut device) = pair();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_capabilities(CAP_SIGNATURE_REQUIRED)
+            .run(&mut device);
+    });
+
+    let err = flash(&mut host, &test_image(), &FlashOpts::default()).unwrap_err();
+
+    assert!(err.to_string().contains("signed"));
+}
@@ -0,0 +1,148 @@
+//! End-of-run statistics against the device simulator.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::{pair, Loopback, Transport};
+use messages::{Checksum, MessageTypeHost, SEGMENT_SIZE};
+
+/// A link that flips one byte inside the first transmission of a given
+/// segment's frame: the structure still decodes, the checksum does not.
+struct CorruptingLink {
+    inner: Loopback,
+    corrupt_segment: Option<u16>,
+}
+
+impl Transport for CorruptingLink {
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.inner.read_available(buf, timeout)
+    }
+
+    fn write_all(&mut self, frame: &[u8]) -> io::Result<()> {
+        let id = match postcard::take_from_bytes::<Checksum<MessageTypeHost>>(frame) {
+            Ok((decoded, _)) => match decoded.payload {
+                MessageTypeHost::UpdateSegment(segment) => Some(segment.id),
+                _ => None,
+            },
+            Err(_) => None,
+        };
+
+        if id.is_some() && id == self.corrupt_segment {
+            self.corrupt_segment = None;
+
+            // The middle of the frame is segment data: the frame still
+            // parses, only the CRC check catches it
+            let mut corrupted = frame.to_vec();
+            let middle = corrupted.len() / 2;
+            corrupted[middle] ^= 0xff;
+
+            return self.inner.write_all(&corrupted);
+        }
+
+        self.inner.write_all(frame)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn counters_reflect_injected_retries() {
+    let (mut host, mut device) = pair();
+
+    // Incompressible image so segment count is easy to predict
+    let image: Vec<u8> = (0..SEGMENT_SIZE * 4)
+        .map(|i| (i as u32).wrapping_mul(2654435761) as u8)
+        .collect();
+
+    let sim = thread::spawn(move || {
+        Simulator::new()
+            .with_failed_segment(2)
+            .run(&mut device)
+            .unwrap()
+    });
+
+    let stats = flash(&mut host, &image, &FlashOpts::default()).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+
+    assert_eq!(stats.image_size, image.len());
+    assert_eq!(stats.segments, 4);
+    assert_eq!(stats.retransmitted, vec![2]);
+    assert_eq!(stats.retries(), 1);
+    // One segment sent twice
+    assert_eq!(stats.sent_bytes, image.len() + SEGMENT_SIZE);
+    assert_eq!(stats.crc_errors, 0);
+    assert_eq!(stats.decode_errors, 0);
+    assert!(stats.peak_throughput > 0.0);
+}
+
+#[test]
+fn clean_run_has_no_errors() {
+    let (mut host, mut device) = pair();
+
+    let image = vec![0xa5_u8; SEGMENT_SIZE * 2];
+
+    let sim = thread::spawn(move || Simulator::new().run(&mut device).unwrap());
+
+    let opts = FlashOpts {
+        no_compress: true,
+        ..Default::default()
+    };
+    let stats = flash(&mut host, &image, &opts).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+
+    assert!(stats.retransmitted.is_empty());
+    assert_eq!(stats.crc_errors, 0);
+    assert_eq!(stats.decode_errors, 0);
+    assert_eq!(stats.sent_bytes, image.len());
+
+    // The device's counters came back clean too
+    let remote = stats.remote.expect("simulator answers GetStats");
+    assert_eq!(remote.crc_errors, 0);
+    assert_eq!(remote.duplicate_segments, 0);
+    assert_eq!(remote.segments_retried, 0);
+    assert!(remote.frames_received > 0);
+    assert!(remote.bytes_received > 0);
+}
+
+#[test]
+fn both_sides_count_an_injected_corruption() {
+    let (host, mut device) = pair();
+    let mut host = CorruptingLink {
+        inner: host,
+        corrupt_segment: Some(2),
+    };
+
+    let image: Vec<u8> = (0..SEGMENT_SIZE * 4)
+        .map(|i| (i as u32).wrapping_mul(2654435761) as u8)
+        .collect();
+
+    let sim = thread::spawn(move || Simulator::new().run(&mut device).unwrap());
+
+    let opts = FlashOpts {
+        no_compress: true,
+        ..Default::default()
+    };
+    let stats = flash(&mut host, &image, &opts).unwrap();
+
+    assert_eq!(sim.join().unwrap(), image);
+
+    // The device dropped the corrupt frame and asked for a retry; the
+    // host's retransmission count matches what the device asked for
+    let remote = stats.remote.as_ref().expect("simulator answers GetStats");
+    assert_eq!(remote.crc_errors, 1);
+    assert_eq!(remote.segments_retried, 1);
+    assert_eq!(stats.retransmitted, vec![2]);
+    assert_eq!(stats.local_link().segments_retried, remote.segments_retried);
+
+    // The retransmission replaced the frame, never the written bytes
+    assert_eq!(remote.duplicate_segments, 0);
+    assert_eq!(stats.crc_errors, 0);
+}
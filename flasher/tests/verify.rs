@@ -0,0 +1,71 @@
+//! `--verify-only` against the device simulator.
+
+use std::thread;
+
+use flasher::simulator::Simulator;
+use flasher::{flash, FlashOpts};
+
+use messages::transport::pair;
+
+fn test_image() -> Vec<u8> {
+    (0_u32..1000).flat_map(|i| i.to_le_bytes()).collect()
+}
+
+fn verify_opts() -> FlashOpts {
+    FlashOpts {
+        verify_only: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn a_matching_slot_verifies_without_a_transfer() {
+    let (mut host, mut device) = pair();
+
+    let image = test_image();
+    let contents = image.clone();
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_slot_contents(contents)
+            .run(&mut device);
+    });
+
+    let stats = flash(&mut host, &image, &verify_opts()).unwrap();
+
+    // Verification sends no segments
+    assert_eq!(stats.segments, 0);
+    assert_eq!(stats.sent_bytes, 0);
+}
+
+#[test]
+fn a_mismatched_slot_fails_verification() {
+    let (mut host, mut device) = pair();
+
+    let mut contents = test_image();
+    contents[100] ^= 0xff;
+
+    thread::spawn(move || {
+        let _ = Simulator::new()
+            .with_slot_contents(contents)
+            .run(&mut device);
+    });
+
+    let err = flash(&mut host, &test_image(), &verify_opts()).unwrap_err();
+
+    assert!(err.to_string().contains("does not match"));
+}
+
+#[test]
+fn a_device_refusal_surfaces_as_an_error() {
+    let (mut host, mut device) = pair();
+
+    // No simulated slot contents at all: the device answers Failed
+    thread::spawn(move || {
+        let _ = Simulator::new().run(&mut device);
+    });
+
+    let err = flash(&mut host, &test_image(), &verify_opts()).unwrap_err();
+
+    assert!(err.to_string().contains("refused"));
+}
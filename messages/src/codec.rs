@@ -0,0 +1,178 @@
+//! Single-pass codec for the checksummed postcard frames.
+//!
+//! The [`Checksum`](crate::Checksum) envelope serializes its payload
+//! once to compute the CRC and again when the envelope itself goes to
+//! postcard, and `verify` serializes a third time on receive - with an
+//! `expect` at every step. [`encode`] and [`take`] work on the
+//! serialized bytes exactly once: the CRC is computed over the payload
+//! bytes the same pass produced (or consumed), nothing panics, and
+//! decode failures come back as a [`DecodeError`] saying what actually
+//! went wrong. The wire format is byte-for-byte the `Checksum`
+//! envelope, so the two forms interoperate freely.
+
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crc32;
+
+/// Why a frame did not decode. Only [`Truncated`](Self::Truncated) is
+/// worth waiting out - more bytes may complete the frame; everything
+/// else means the bytes at this position are not a frame and the reader
+/// should resynchronize past them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end before the payload and its CRC are complete.
+    Truncated,
+    /// An enum discriminant this build does not know - usually the
+    /// other side is a newer protocol generation.
+    UnknownVariant,
+    /// Structurally invalid in some other way: a bad varint, bogus
+    /// UTF-8, an out-of-range bool or option tag.
+    Malformed,
+    /// The frame parsed, but its checksum does not match the payload
+    /// bytes - corruption on the wire. `frame_len` is how many bytes
+    /// the corrupt frame occupied, so a reader can skip exactly that
+    /// frame and stay in sync with the ones behind it.
+    BadCrc {
+        expected: u32,
+        found: u32,
+        frame_len: usize,
+    },
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "frame is truncated"),
+            Self::UnknownVariant => write!(f, "unknown message discriminant"),
+            Self::Malformed => write!(f, "malformed frame"),
+            Self::BadCrc {
+                expected, found, ..
+            } => write!(
+                f,
+                "checksum mismatch: computed {:08x}, frame says {:08x}",
+                expected, found
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+fn classify(err: postcard::Error) -> DecodeError {
+    match err {
+        postcard::Error::DeserializeUnexpectedEnd => DecodeError::Truncated,
+        // serde-derive rejects an out-of-range discriminant through its
+        // custom error path, which postcard folds into SerdeDeCustom;
+        // for these message types that is the only way to land there
+        postcard::Error::DeserializeBadEnum | postcard::Error::SerdeDeCustom => {
+            DecodeError::UnknownVariant
+        }
+        _ => DecodeError::Malformed,
+    }
+}
+
+/// Serializes `payload` into a checksummed frame, ready for the wire.
+/// The only failure mode is postcard running out of memory; none of
+/// this crate's message types can fail to serialize.
+pub fn encode<T: Serialize>(payload: &T) -> Result<Vec<u8>, postcard::Error> {
+    let mut frame = postcard::to_allocvec(payload)?;
+    let crc = crc32(&frame);
+
+    // The envelope is the payload bytes followed by the CRC varint;
+    // appending it reuses the pass that produced the payload bytes
+    frame.extend(postcard::to_allocvec(&crc)?);
+
+    Ok(frame)
+}
+
+/// Decodes one frame off the front of `bytes`, returning the payload
+/// and whatever follows it. The CRC is checked against the very bytes
+/// the payload was parsed from, so nothing is serialized again.
+pub fn take<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<(T, &'a [u8]), DecodeError> {
+    let (payload, rest) = postcard::take_from_bytes::<T>(bytes).map_err(classify)?;
+    let expected = crc32(&bytes[..bytes.len() - rest.len()]);
+
+    let (found, rest) = postcard::take_from_bytes::<u32>(rest).map_err(classify)?;
+    if expected != found {
+        return Err(DecodeError::BadCrc {
+            expected,
+            found,
+            frame_len: bytes.len() - rest.len(),
+        });
+    }
+
+    Ok((payload, rest))
+}
+
+/// [`take`] for inputs carrying exactly one frame, like a payload the
+/// [`framing`](crate::framing) layer already delimited.
+pub fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, DecodeError> {
+    match take(bytes)? {
+        (payload, []) => Ok(payload),
+        _ => Err(DecodeError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Checksum, MessageTypeHost, MessageTypeMcu};
+
+    #[test]
+    fn the_codec_matches_the_checksum_envelope_byte_for_byte() {
+        let msg = MessageTypeHost::SetBaud(921_600);
+
+        let envelope = postcard::to_allocvec(&Checksum::new(msg.clone())).unwrap();
+        let frame = encode(&msg).unwrap();
+
+        assert_eq!(frame, envelope);
+        assert_eq!(decode::<MessageTypeHost>(&frame).unwrap(), msg);
+    }
+
+    #[test]
+    fn errors_say_what_went_wrong() {
+        let frame = encode(&MessageTypeMcu::Pong).unwrap();
+
+        assert_eq!(
+            decode::<MessageTypeMcu>(&frame[..frame.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+
+        let mut corrupt = frame.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            decode::<MessageTypeMcu>(&corrupt),
+            Err(DecodeError::BadCrc { .. })
+        ));
+
+        // A discriminant from a protocol generation that does not exist
+        assert_eq!(
+            decode::<MessageTypeMcu>(&[0xff, 0xff, 0x01]),
+            Err(DecodeError::UnknownVariant)
+        );
+    }
+
+    #[test]
+    fn take_leaves_the_following_frame_alone() {
+        let mut wire = encode(&MessageTypeMcu::Pong).unwrap();
+        wire.extend(
+            encode(&MessageTypeMcu::Adc(crate::AdcSample {
+                channel: 6,
+                seq: 1,
+                value: 1234,
+            }))
+            .unwrap(),
+        );
+
+        let (first, rest) = take::<MessageTypeMcu>(&wire).unwrap();
+        assert_eq!(first, MessageTypeMcu::Pong);
+
+        let (second, rest) = take::<MessageTypeMcu>(rest).unwrap();
+        assert!(matches!(second, MessageTypeMcu::Adc(_)));
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! ChaCha20-Poly1305 sealing of update segments.
+//!
+//! The cipher lives in this shared crate so the flasher encrypts through
+//! the very code path the device decrypts with, and the known-answer
+//! tests below pin both ends down on the host. ChaCha20-Poly1305 rather
+//! than mbedtls' AES because the implementation is pure Rust and
+//! `no_std`-capable, so the device side needs no ESP-IDF crypto bindings.
+
+use alloc::vec::Vec;
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::{NONCE_LEN, NONCE_PREFIX_LEN};
+
+/// Length of the segment encryption key.
+pub const KEY_LEN: usize = 32;
+
+/// Bytes the Poly1305 authentication tag adds to each sealed payload.
+pub const TAG_LEN: usize = 16;
+
+/// A sealed segment whose tag did not verify: forged, corrupted, or
+/// sealed under a different key or nonce prefix. The payload must be
+/// treated as garbage; none of it was decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthError;
+
+/// Builds the per-segment nonce from the update's random prefix and the
+/// segment id. Segment ids are unique within an update and the prefix
+/// is drawn fresh per update, so a (key, nonce) pair is never reused.
+pub fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], id: u16) -> [u8; NONCE_LEN] {
+    let mut nonce = [0_u8; NONCE_LEN];
+
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&(id as u64).to_le_bytes());
+
+    nonce
+}
+
+/// Seals one segment payload, appending the [`TAG_LEN`]-byte tag.
+pub fn seal_segment(
+    key: &[u8; KEY_LEN],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    id: u16,
+    plaintext: &[u8],
+) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce(prefix, id)), plaintext)
+        .expect("sealing an in-memory buffer cannot fail")
+}
+
+/// Authenticates and decrypts one sealed segment. On [`AuthError`] no
+/// plaintext is returned at all, so a caller cannot accidentally write
+/// unauthenticated bytes.
+pub fn open_segment(
+    key: &[u8; KEY_LEN],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    id: u16,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, AuthError> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(&nonce(prefix, id)), ciphertext)
+        .map_err(|_| AuthError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30, 31,
+    ];
+    const PREFIX: [u8; NONCE_PREFIX_LEN] = [0xa0, 0xa1, 0xa2, 0xa3];
+
+    #[test]
+    fn nonce_is_prefix_then_id_as_little_endian_u64() {
+        assert_eq!(
+            nonce(&PREFIX, 0x0201),
+            [0xa0, 0xa1, 0xa2, 0xa3, 0x01, 0x02, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn sealed_segments_round_trip() {
+        let sealed = seal_segment(&KEY, &PREFIX, 7, b"segment payload");
+
+        assert_eq!(sealed.len(), b"segment payload".len() + TAG_LEN);
+        assert_eq!(
+            open_segment(&KEY, &PREFIX, 7, &sealed).unwrap(),
+            b"segment payload"
+        );
+    }
+
+    // Known-answer vector: a change here means segments sealed by an
+    // older flasher no longer open on newer firmware or vice versa -
+    // neither the cipher nor the nonce derivation may drift.
+    #[test]
+    fn sealing_matches_the_known_answer() {
+        let sealed = seal_segment(&KEY, &PREFIX, 7, b"segment payload");
+
+        assert_eq!(
+            sealed,
+            [
+                0x1f, 0x1e, 0x00, 0x86, 0x9c, 0x9d, 0xee, 0xbb, 0xfb, 0xc7, 0xb7, 0x6c, 0x8f, 0xcb,
+                0xf2, 0x1a, 0x1b, 0x60, 0xf0, 0xb8, 0x1e, 0xd5, 0xb5, 0xfd, 0x1f, 0x6b, 0x7f, 0xc7,
+                0x6d, 0x4b, 0x3f,
+            ]
+        );
+    }
+
+    #[test]
+    fn a_flipped_bit_fails_authentication() {
+        let mut sealed = seal_segment(&KEY, &PREFIX, 7, b"segment payload");
+        sealed[0] ^= 1;
+
+        assert_eq!(open_segment(&KEY, &PREFIX, 7, &sealed), Err(AuthError));
+    }
+
+    #[test]
+    fn the_wrong_key_or_segment_id_fails_authentication() {
+        let sealed = seal_segment(&KEY, &PREFIX, 7, b"segment payload");
+
+        let mut other_key = KEY;
+        other_key[0] ^= 1;
+
+        assert_eq!(
+            open_segment(&other_key, &PREFIX, 7, &sealed),
+            Err(AuthError)
+        );
+        assert_eq!(open_segment(&KEY, &PREFIX, 8, &sealed), Err(AuthError));
+    }
+}
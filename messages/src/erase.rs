@@ -0,0 +1,110 @@
+//! Device-side erase validation, shared so it can be unit-tested on
+//! the host.
+//!
+//! `EraseRegion` hands the host raw sector-level control, so the
+//! checks around it are stricter than for a read-back: the range must
+//! be sector-aligned at both ends, lie inside the resolved partition,
+//! and the target must not be something the device cannot survive
+//! losing. Resolving a label needs the partition table and stays on
+//! the device; [`validate`] covers everything that does not.
+
+/// Flash sector size; erase offsets and lengths must be multiples of
+/// it, because the driver can only erase whole sectors anyway and
+/// silently widening the range would destroy neighbouring data.
+pub const ERASE_ALIGN: u32 = 4096;
+
+/// Why an erase request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseError {
+    /// The target is one the device must not lose - the running app;
+    /// the bootloader and partition table are not reachable by label
+    /// in the first place.
+    Protected,
+    /// `len` of zero.
+    Empty,
+    /// `offset` or `len` is not a multiple of [`ERASE_ALIGN`].
+    Unaligned,
+    /// `offset + len` leaves the partition.
+    OutOfBounds,
+}
+
+/// Validates one erase request against the resolved target;
+/// `protected` is the device's verdict on the target itself. Checked
+/// before anything else: a protected target must be refused as such
+/// even when the range is nonsense too.
+pub fn validate(
+    partition_size: u32,
+    protected: bool,
+    offset: u32,
+    len: u32,
+) -> Result<(), EraseError> {
+    if protected {
+        return Err(EraseError::Protected);
+    }
+
+    if len == 0 {
+        return Err(EraseError::Empty);
+    }
+
+    if !offset.is_multiple_of(ERASE_ALIGN) || !len.is_multiple_of(ERASE_ALIGN) {
+        return Err(EraseError::Unaligned);
+    }
+
+    // Widened so offset + len cannot wrap before the comparison
+    if u64::from(offset) + u64::from(len) > u64::from(partition_size) {
+        return Err(EraseError::OutOfBounds);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 16 * ERASE_ALIGN;
+
+    #[test]
+    fn a_sector_aligned_range_inside_the_partition_passes() {
+        assert_eq!(validate(SIZE, false, ERASE_ALIGN, 2 * ERASE_ALIGN), Ok(()));
+        assert_eq!(validate(SIZE, false, 0, SIZE), Ok(()));
+    }
+
+    #[test]
+    fn a_protected_target_is_refused_before_anything_else() {
+        // Even a range that would also be refused on its own merits
+        // reports the protection, not the range
+        assert_eq!(validate(SIZE, true, 3, 0), Err(EraseError::Protected));
+    }
+
+    #[test]
+    fn a_zero_length_erase_is_refused() {
+        assert_eq!(validate(SIZE, false, 0, 0), Err(EraseError::Empty));
+    }
+
+    #[test]
+    fn unaligned_offsets_and_lengths_are_refused() {
+        assert_eq!(
+            validate(SIZE, false, 512, ERASE_ALIGN),
+            Err(EraseError::Unaligned)
+        );
+        assert_eq!(
+            validate(SIZE, false, ERASE_ALIGN, ERASE_ALIGN + 16),
+            Err(EraseError::Unaligned)
+        );
+    }
+
+    #[test]
+    fn a_range_past_the_partition_is_refused() {
+        assert_eq!(
+            validate(SIZE, false, SIZE, ERASE_ALIGN),
+            Err(EraseError::OutOfBounds)
+        );
+
+        // offset + len wrapping u32 must not sneak back in bounds
+        assert_eq!(
+            validate(SIZE, false, u32::MAX - (ERASE_ALIGN - 1), ERASE_ALIGN),
+            Err(EraseError::OutOfBounds)
+        );
+    }
+}
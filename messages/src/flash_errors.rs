@@ -0,0 +1,86 @@
+//! Classification of flash write errors into "worth a resend" and "give
+//! up", shared so the policy can be unit-tested on the host.
+//!
+//! Segment data is coalesced into sector-sized blocks before it goes
+//! to flash, so a failed block spans many segments and no single
+//! resend can repair it: the device retries
+//! [`Transient`](WriteError::Transient) write errors itself and reports
+//! whatever survives as [`Status::FlashWrite`], after aborting the
+//! update. The resend path - [`Status::Retry`], the device still
+//! expecting the same segment - remains for the source-side reads of a
+//! delta copy, which fail before the segment is accounted anywhere.
+//! The codes below are raw ESP-IDF `esp_err_t` values
+//! (`esp_err.h` and the `spi_flash` component); duplicating the handful
+//! we care about keeps this crate free of an `esp-idf-sys` dependency,
+//! and the values are fixed in ESP-IDF's ABI.
+//!
+//! [`Status::Retry`]: crate::Status::Retry
+//! [`Status::FlashWrite`]: crate::Status::FlashWrite
+
+/// What a failed flash write means for the rest of the transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError {
+    /// The same operation may well succeed when repeated: the device
+    /// retries the failed block itself for writes, and keeps the update
+    /// open expecting a resend for source-side reads.
+    Transient,
+    /// Retrying the same bytes at the same address will keep failing;
+    /// the device aborts the update.
+    Fatal,
+}
+
+/// `ESP_ERR_NO_MEM`: an allocation inside the OTA/flash path failed.
+const NO_MEM: i32 = 0x101;
+
+/// `ESP_ERR_TIMEOUT`: the operation timed out waiting for a lock or
+/// the hardware.
+const TIMEOUT: i32 = 0x107;
+
+/// `ESP_ERR_FLASH_OP_TIMEOUT`: the flash chip did not finish the
+/// operation in time.
+const FLASH_OP_TIMEOUT: i32 = 0x6002;
+
+/// Decides whether a failed flash operation is worth repeating -
+/// on-device for writes, via a host resend for reads.
+///
+/// Timeouts are transient: the SPI flash is shared with everything else
+/// on the chip (including the cache), so contention now says nothing
+/// about the resend. So is memory exhaustion, which heap pressure from
+/// another task can cause and clear again. Everything else - invalid
+/// arguments, a write past the end of the partition, the chip refusing
+/// the operation outright - is deterministic, and unknown codes count
+/// as fatal too so a broken device cannot hold the host in a retry
+/// loop.
+pub fn classify_write_error(code: i32) -> WriteError {
+    match code {
+        TIMEOUT | FLASH_OP_TIMEOUT | NO_MEM => WriteError::Transient,
+        _ => WriteError::Fatal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_and_memory_pressure_are_transient() {
+        for code in [TIMEOUT, FLASH_OP_TIMEOUT, NO_MEM] {
+            assert_eq!(classify_write_error(code), WriteError::Transient);
+        }
+    }
+
+    #[test]
+    fn deterministic_failures_are_fatal() {
+        // ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_SIZE (write beyond the
+        // partition) and ESP_ERR_FLASH_OP_FAIL
+        for code in [0x102, 0x104, 0x6001] {
+            assert_eq!(classify_write_error(code), WriteError::Fatal);
+        }
+    }
+
+    #[test]
+    fn unknown_codes_are_fatal() {
+        assert_eq!(classify_write_error(-1), WriteError::Fatal);
+        assert_eq!(classify_write_error(0x7fff), WriteError::Fatal);
+    }
+}
@@ -0,0 +1,241 @@
+//! COBS framing, for links that want hard frame boundaries.
+//!
+//! The deployed wire format puts checksummed postcard frames on the line
+//! back to back, and after noise both ends resynchronize by scanning
+//! byte by byte - safe thanks to the checksum, but slow and fiddly. COBS
+//! (consistent overhead byte stuffing) removes every `0x00` from a
+//! frame's bytes for one byte of overhead per 254, so the `0x00`
+//! delimiter after each frame is unambiguous: a receiver that lost its
+//! place drops everything up to the next delimiter and is back in sync
+//! at frame granularity, whatever arrived in whatever read sizes.
+//!
+//! [`encode`] wraps one serialized frame; [`FrameDecoder`] is the
+//! streaming receive side, accumulating bytes and yielding complete
+//! frames while silently discarding chunks that do not decode. The
+//! payloads are still the checksummed postcard frames from this crate -
+//! the framing only settles where they begin and end.
+
+use alloc::vec::Vec;
+
+/// Terminates every encoded frame; never occurs inside one.
+pub const DELIMITER: u8 = 0x00;
+
+/// Longest encoded frame a decoder accumulates before declaring the
+/// delimiter lost and dropping the bytes; well above the largest
+/// segment frame either side sends.
+pub const MAX_FRAME: usize = 8 * 1024;
+
+/// Encodes one frame, delimiter included, ready for the wire.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    // One code byte per 254 payload bytes, plus the delimiter
+    let mut out = Vec::with_capacity(payload.len() + payload.len() / 254 + 2);
+
+    let mut code_at = out.len();
+    out.push(0);
+    let mut code: u8 = 1;
+
+    for &byte in payload {
+        if byte == 0 {
+            out[code_at] = code;
+            code_at = out.len();
+            out.push(0);
+            code = 1;
+        } else {
+            out.push(byte);
+            code += 1;
+
+            // A full block; open the next one
+            if code == 0xff {
+                out[code_at] = code;
+                code_at = out.len();
+                out.push(0);
+                code = 1;
+            }
+        }
+    }
+
+    out[code_at] = code;
+    out.push(DELIMITER);
+
+    out
+}
+
+/// Decodes one delimiter-free chunk; `None` means it was not produced
+/// by [`encode`].
+fn decode(chunk: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(chunk.len());
+    let mut at = 0;
+
+    while at < chunk.len() {
+        let code = chunk[at] as usize;
+
+        // A zero code cannot be encoded, and a block reaching past the
+        // end means the chunk was truncated
+        if code == 0 || at + code > chunk.len() {
+            return None;
+        }
+
+        out.extend_from_slice(&chunk[at + 1..at + code]);
+        at += code;
+
+        // Every non-maximal block but the last stands for a zero
+        if code < 0xff && at < chunk.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+/// Streaming receive side: feed it whatever each read returned, take
+/// complete frames out. Chunks that do not decode - noise, or the tail
+/// of a frame whose start was missed - are dropped, which is the whole
+/// resynchronization story.
+#[derive(Default)]
+pub struct FrameDecoder {
+    pending: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts the next bytes off the line, however many arrived.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+
+        // A stream without delimiters is noise at the wrong baud or
+        // worse; keep the memory bounded rather than the bytes
+        if self.pending.len() > MAX_FRAME && !self.pending.contains(&DELIMITER) {
+            self.pending.clear();
+        }
+    }
+
+    /// The next complete frame, if one is buffered. Call until `None`
+    /// after each [`push`](Self::push): one read can carry several
+    /// frames.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        while let Some(end) = self.pending.iter().position(|&byte| byte == DELIMITER) {
+            let chunk: Vec<u8> = self.pending.drain(..=end).take(end).collect();
+
+            // Back-to-back delimiters are idle padding, not a frame
+            if chunk.is_empty() {
+                continue;
+            }
+
+            if let Some(frame) = decode(&chunk) {
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(payload: &[u8]) -> Vec<u8> {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode(payload));
+
+        let frame = decoder.next_frame().expect("one frame in, one out");
+        assert!(decoder.next_frame().is_none());
+
+        frame
+    }
+
+    #[test]
+    fn payloads_survive_the_round_trip() {
+        assert_eq!(round_trip(b""), b"");
+        assert_eq!(round_trip(b"\x00"), b"\x00");
+        assert_eq!(round_trip(b"\x00\x00"), b"\x00\x00");
+        assert_eq!(round_trip(b"abc\x00def"), b"abc\x00def");
+        assert_eq!(round_trip(&[0xff; 1000]), vec![0xff; 1000]);
+
+        // Block-boundary sizes, where the code-byte bookkeeping lives
+        for len in [253, 254, 255, 508, 509] {
+            let payload: Vec<u8> = (0..len).map(|i| (i % 255) as u8 + 1).collect();
+            assert_eq!(round_trip(&payload), payload);
+        }
+    }
+
+    #[test]
+    fn encoded_frames_contain_no_delimiter_before_the_end() {
+        for payload in [&b"\x00\x01\x00\x02\x00"[..], &[0_u8; 600], b"plain"] {
+            let encoded = encode(payload);
+
+            assert_eq!(*encoded.last().unwrap(), DELIMITER);
+            assert!(!encoded[..encoded.len() - 1].contains(&DELIMITER));
+        }
+    }
+
+    #[test]
+    fn frames_reassemble_from_arbitrary_read_sizes() {
+        let payloads: [&[u8]; 3] = [b"first\x00frame", b"", b"third"];
+        // An empty payload still encodes to a non-empty frame
+        let wire: Vec<u8> = payloads.iter().flat_map(|p| encode(p)).collect();
+
+        // Byte-by-byte, like the slowest UART read imaginable
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+
+        for byte in wire {
+            decoder.push(&[byte]);
+            while let Some(frame) = decoder.next_frame() {
+                frames.push(frame);
+            }
+        }
+
+        assert_eq!(frames, payloads);
+    }
+
+    #[test]
+    fn noise_between_frames_is_dropped_and_sync_recovers() {
+        let mut decoder = FrameDecoder::new();
+
+        decoder.push(&encode(b"good"));
+        // A truncated frame: its head was lost to noise, the delimiter
+        // survived
+        decoder.push(&encode(b"mangled beyond recognition")[7..]);
+        // A zero code byte never comes out of the encoder
+        decoder.push(&[0x00, 0x00, 0x04, 0x00]);
+        decoder.push(&encode(b"after"));
+
+        assert_eq!(decoder.next_frame().unwrap(), b"good");
+        assert_eq!(decoder.next_frame().unwrap(), b"after");
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn a_partial_frame_waits_for_the_rest() {
+        let encoded = encode(b"split across reads");
+        let (head, tail) = encoded.split_at(5);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(head);
+        assert!(decoder.next_frame().is_none());
+
+        decoder.push(tail);
+        assert_eq!(decoder.next_frame().unwrap(), b"split across reads");
+    }
+
+    #[test]
+    fn a_delimiter_free_flood_does_not_grow_without_bound() {
+        let mut decoder = FrameDecoder::new();
+
+        for _ in 0..64 {
+            decoder.push(&[0xaa; 1024]);
+            assert!(decoder.next_frame().is_none());
+        }
+        assert!(decoder.pending.len() <= MAX_FRAME + 1024);
+
+        // The first delimiter closes whatever garbage is still pending;
+        // the frame after it comes through whole
+        decoder.push(&[DELIMITER]);
+        decoder.push(&encode(b"survivor"));
+        assert_eq!(decoder.next_frame().unwrap(), b"survivor");
+    }
+}
@@ -0,0 +1,972 @@
+//! Wire protocol shared between the firmware and the host-side `flasher` tool.
+//!
+//! Messages are serialized with `postcard` and wrapped in a [`Checksum`]
+//! envelope so either side can reject frames corrupted on the wire.
+//!
+//! The crate is `no_std` with the default `std` feature disabled, so
+//! bare-metal (esp-hal) firmware can reuse the protocol: the message
+//! types and the pure logic only need `alloc`. The modules that want an
+//! OS - [`transport`], [`trace`], [`mode`], [`selftest`] - come and go
+//! with the feature.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+pub mod codec;
+pub mod crypto;
+pub mod erase;
+pub mod flash_errors;
+pub mod framing;
+#[cfg(feature = "std")]
+pub mod mode;
+pub mod readback;
+pub mod segments;
+#[cfg(feature = "std")]
+pub mod selftest;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod transport;
+pub mod verify;
+pub mod version;
+
+/// Version of the update protocol spoken by this crate.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Payload bytes carried by a single `UpdateSegment`.
+pub const SEGMENT_SIZE: usize = 256;
+
+/// Segment payload the device advertises instead when hardware flow
+/// control rules out RX FIFO overruns on its side.
+pub const SEGMENT_SIZE_FLOW_CONTROLLED: usize = 1024;
+
+/// Worst-case wire bytes around a segment payload: the [`Checksum`]
+/// envelope's CRC (`u32`, 4), the message variant tag (1), the segment
+/// id (`u16`, 2), the payload length (varint, 3), a compressed
+/// segment's raw length (`u16`, 2) and an encrypted segment's Poly1305 tag
+/// riding inside the payload (16), with some slack on top.
+pub const SEGMENT_WIRE_OVERHEAD: usize = 32;
+
+/// Largest segment payload a receiver able to buffer `capacity` bytes
+/// of one frame can take, rounded down to a multiple of the classic
+/// [`SEGMENT_SIZE`] so write offsets keep their alignment properties.
+/// The device derives its advertised
+/// [`UpdateStartStatus::max_segment_size`] from this and its actual
+/// buffer sizes instead of hardcoding a second copy of either.
+pub const fn max_segment_payload(capacity: usize) -> usize {
+    (capacity - SEGMENT_WIRE_OVERHEAD) / SEGMENT_SIZE * SEGMENT_SIZE
+}
+
+/// Heatshrink parameters used for compressed segments. Both sides have to
+/// agree on these, so they live here rather than in the flasher.
+pub const HEATSHRINK_WINDOW_SZ2: u8 = 8;
+pub const HEATSHRINK_LOOKAHEAD_SZ2: u8 = 4;
+
+/// Capability bits advertised by the device in [`UpdateStartStatus`].
+pub const CAP_COMPRESSED_SEGMENTS: u8 = 1 << 0;
+pub const CAP_ENCRYPTED_SEGMENTS: u8 = 1 << 1;
+/// The device refuses updates whose `UpdateEnd` carries no valid signature.
+pub const CAP_SIGNATURE_REQUIRED: u8 = 1 << 2;
+/// The device can reconstruct an image from `UpdateSegmentDelta` messages.
+pub const CAP_DELTA_UPDATES: u8 = 1 << 3;
+
+/// Session capability bits exchanged in [`MessageTypeHost::Hello`] /
+/// [`MessageTypeMcu::HelloAck`]. A bitflags-style newtype over `u32`:
+/// readers test the bits they know and ignore the rest, so new bits can
+/// be added without breaking older peers. The low byte mirrors the
+/// legacy per-update `CAP_*` constants, which keeps the two capability
+/// vocabularies from drifting apart.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Caps(u32);
+
+impl Caps {
+    pub const COMPRESSED_SEGMENTS: Self = Self(CAP_COMPRESSED_SEGMENTS as u32);
+    pub const ENCRYPTED_SEGMENTS: Self = Self(CAP_ENCRYPTED_SEGMENTS as u32);
+    pub const SIGNATURE_REQUIRED: Self = Self(CAP_SIGNATURE_REQUIRED as u32);
+    pub const DELTA_UPDATES: Self = Self(CAP_DELTA_UPDATES as u32);
+    /// The device can offer a `resume_offset` for an interrupted image.
+    pub const RESUME: Self = Self(1 << 8);
+    /// The device can stream [`MessageTypeMcu::Progress`] frames.
+    pub const PROGRESS: Self = Self(1 << 9);
+    /// The device answers [`MessageTypeHost::GetStats`].
+    pub const STATS: Self = Self(1 << 10);
+    /// The device answers [`MessageTypeHost::ReadFlash`].
+    pub const READBACK: Self = Self(1 << 11);
+    /// The device answers [`MessageTypeHost::EraseRegion`].
+    pub const ERASE: Self = Self(1 << 12);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Every bit is kept, known or not: an unknown capability is
+    /// something to ignore, never an error.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Widens a legacy [`UpdateStartStatus`] capability byte.
+    pub const fn from_legacy(bits: u8) -> Self {
+        Self(bits as u32)
+    }
+
+    /// The bits the legacy `UpdateStartStatus.capabilities` byte can
+    /// carry.
+    pub const fn legacy_bits(self) -> u8 {
+        (self.0 & 0xff) as u8
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Caps {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for Caps {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Length of a SHA-256 digest.
+pub const HASH_LEN: usize = 32;
+
+/// Length of an Ed25519 detached signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Length of an Ed25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Segment cipher nonce layout: 4 random prefix bytes chosen per update,
+/// followed by the segment id as a little-endian u64 (see [`crypto`]).
+pub const NONCE_PREFIX_LEN: usize = 4;
+pub const NONCE_LEN: usize = 12;
+
+/// Messages sent by the host to the device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MessageTypeHost {
+    UpdateStart(UpdateStart),
+    UpdateSegment(UpdateSegment),
+    UpdateSegmentCompressed(UpdateSegmentCompressed),
+    UpdateSegmentEncrypted(UpdateSegmentEncrypted),
+    UpdateSegmentDelta(UpdateSegmentDelta),
+    UpdateEnd(UpdateEnd),
+    Cancel,
+    GetInfo,
+    SetBaud(u32),
+    Ping,
+    /// Marks the running image valid, cancelling a pending rollback; the
+    /// host-driven alternative to the firmware's own post-boot self-test.
+    MarkValid,
+    /// Invalidates the running image and reboots into the previous one.
+    Rollback,
+    /// Starts streaming ADC samples every `interval_ms` milliseconds;
+    /// 0 keeps the device's current interval.
+    AdcStart {
+        interval_ms: u32,
+    },
+    /// Stops the ADC sample stream.
+    AdcStop,
+    /// Caps the severity of log records mirrored to the host as
+    /// [`MessageTypeMcu::Log`] frames: 0 switches the mirror off, 1
+    /// passes only errors, 5 everything up to trace.
+    SetLogLevel(u8),
+    /// Liveness probe carrying a host-chosen nonce, echoed back in
+    /// [`MessageTypeMcu::TimedPong`] so a reply cannot be mistaken for
+    /// a stale one; the plain [`Ping`](Self::Ping)/`Pong` pair stays
+    /// for older peers.
+    TimedPing(u32),
+    /// Reads back flash for host-side verification: `len` bytes from
+    /// `offset` within the update slot, or within the named partition.
+    /// Answered with a run of [`MessageTypeMcu::FlashData`] frames in
+    /// offset order, or one [`MessageTypeMcu::ReadFlashStatus`] when
+    /// refused; see [`readback`] for the limits a request must respect.
+    ReadFlash {
+        partition: Option<String>,
+        offset: u32,
+        len: u32,
+    },
+    /// Erases `len` bytes at `offset` within the update slot, or
+    /// within the named partition; both must be multiples of
+    /// [`erase::ERASE_ALIGN`] and the range must lie inside the
+    /// partition. Answered with [`MessageTypeMcu::EraseStatus`];
+    /// refused while an update is in flight and for targets the device
+    /// cannot survive losing.
+    EraseRegion {
+        partition: Option<String>,
+        offset: u32,
+        len: u32,
+    },
+    /// Switches protocol tracing on or off; see [`trace`]. `force`
+    /// keeps it running through transfers, where it otherwise steps
+    /// aside to protect segment-ack bandwidth. Acknowledged with
+    /// [`MessageTypeMcu::TraceStatus`].
+    SetTrace {
+        enabled: bool,
+        force: bool,
+    },
+    /// Asks for the recorded trace ring: the device replies with one
+    /// `Log` frame per entry, oldest first, closed by a
+    /// [`MessageTypeMcu::TraceStatus`]. Works whether or not tracing is
+    /// currently on.
+    DumpTrace,
+    /// Asks for the device's wire counters, answered with
+    /// [`MessageTypeMcu::Stats`]. `reset` zeroes the counters after the
+    /// snapshot, so a host can meter one transfer instead of the whole
+    /// uptime.
+    GetStats {
+        reset: bool,
+    },
+    /// Opens a session: the host's protocol version and capability
+    /// bits, answered with [`MessageTypeMcu::HelloAck`]. Sent once
+    /// before anything else; firmware that predates the exchange stays
+    /// silent and the host falls back to sniffing the legacy
+    /// [`UpdateStartStatus`] byte.
+    Hello {
+        protocol_version: u8,
+        capabilities: Caps,
+    },
+    /// Asks the device to hash flash instead of streaming it: the
+    /// SHA-256 of the first `len` bytes of the update slot, or of the
+    /// named partition. Answered with [`MessageTypeMcu::FlashHash`].
+    /// One short reply where [`ReadFlash`](Self::ReadFlash) would send
+    /// the whole image back, so a host can verify a flashed binary in
+    /// seconds even over a slow link.
+    HashFlash {
+        partition: Option<String>,
+        len: u32,
+    },
+    /// Asks what the device's last successful update looked like,
+    /// answered with [`MessageTypeMcu::UpdateInfo`].
+    GetUpdateInfo,
+}
+
+impl MessageTypeHost {
+    /// Short variant name for trace entries and diagnostics; payloads -
+    /// which can be kilobytes of segment data - stay out of it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UpdateStart(_) => "UpdateStart",
+            Self::UpdateSegment(_) => "UpdateSegment",
+            Self::UpdateSegmentCompressed(_) => "UpdateSegmentCompressed",
+            Self::UpdateSegmentEncrypted(_) => "UpdateSegmentEncrypted",
+            Self::UpdateSegmentDelta(_) => "UpdateSegmentDelta",
+            Self::UpdateEnd(_) => "UpdateEnd",
+            Self::Cancel => "Cancel",
+            Self::GetInfo => "GetInfo",
+            Self::SetBaud(_) => "SetBaud",
+            Self::Ping => "Ping",
+            Self::MarkValid => "MarkValid",
+            Self::Rollback => "Rollback",
+            Self::AdcStart { .. } => "AdcStart",
+            Self::AdcStop => "AdcStop",
+            Self::SetLogLevel(_) => "SetLogLevel",
+            Self::TimedPing(_) => "TimedPing",
+            Self::ReadFlash { .. } => "ReadFlash",
+            Self::EraseRegion { .. } => "EraseRegion",
+            Self::SetTrace { .. } => "SetTrace",
+            Self::DumpTrace => "DumpTrace",
+            Self::GetStats { .. } => "GetStats",
+            Self::Hello { .. } => "Hello",
+            Self::HashFlash { .. } => "HashFlash",
+            Self::GetUpdateInfo => "GetUpdateInfo",
+        }
+    }
+}
+
+/// Messages sent by the device to the host.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MessageTypeMcu {
+    UpdateStartStatus(UpdateStartStatus),
+    UpdateSegmentStatus {
+        id: u16,
+        status: Status,
+    },
+    UpdateEndStatus(Status),
+    /// One ADC reading from the telemetry task.
+    Adc(AdcSample),
+    Info(Info),
+    /// Liveness reply to [`MessageTypeHost::Ping`].
+    Pong,
+    /// Acknowledges a [`MessageTypeHost::Cancel`], sent once any in-flight
+    /// update has actually been aborted.
+    CancelStatus(Status),
+    /// Reply to [`MessageTypeHost::MarkValid`].
+    MarkValidStatus(Status),
+    /// Reply to [`MessageTypeHost::Rollback`]; `Ok` means the device is
+    /// about to reboot into the previous image.
+    RollbackStatus(Status),
+    /// One log record mirrored from the device's console log, for
+    /// boards whose console UART is not wired up.
+    Log(LogRecord),
+    /// Reply to [`MessageTypeHost::SetBaud`], sent at the old rate.
+    /// `Ok` means the device switches once this frame has drained and
+    /// falls back to the old rate if no valid frame arrives at the new
+    /// one within its grace period.
+    SetBaudStatus(Status),
+    /// Reply to [`MessageTypeHost::TimedPing`] with the probe's nonce
+    /// and the milliseconds since the device booted.
+    TimedPong {
+        nonce: u32,
+        uptime_ms: u64,
+    },
+    /// Periodic transfer progress, sent only when the host asked for it
+    /// in [`UpdateStart::progress`]. Best-effort: the device skips a
+    /// frame rather than let it delay segment handling.
+    Progress {
+        bytes_written: u32,
+        phase: UpdatePhase,
+    },
+    /// Flow control: the device's inbound queue is full (typically a
+    /// long flash operation) and the frame this answers was dropped,
+    /// never queued. The host should wait roughly `retry_after_ms`
+    /// milliseconds and send the same frame again.
+    Busy {
+        retry_after_ms: u32,
+    },
+    /// One chunk of a [`MessageTypeHost::ReadFlash`] reply, in offset
+    /// order; `last` marks the final chunk of the request.
+    FlashData {
+        offset: u32,
+        data: Vec<u8>,
+        last: bool,
+    },
+    /// A refused or failed [`MessageTypeHost::ReadFlash`]. A read that
+    /// fails mid-stream ends with this instead of a `last` chunk.
+    ReadFlashStatus(Status),
+    /// Outcome of a [`MessageTypeHost::EraseRegion`], sent once the
+    /// whole range is erased or the request was refused.
+    EraseStatus(Status),
+    /// Acknowledges a [`MessageTypeHost::SetTrace`], and closes the run
+    /// of `Log` frames answering a [`MessageTypeHost::DumpTrace`].
+    TraceStatus(Status),
+    /// Reply to [`MessageTypeHost::GetStats`]: the device's wire
+    /// counters since boot, or since the last reset.
+    Stats(LinkStats),
+    /// Reply to [`MessageTypeHost::Hello`]: the device's protocol
+    /// version, its full capability set and the largest segment payload
+    /// it accepts on this link.
+    HelloAck {
+        protocol_version: u8,
+        capabilities: Caps,
+        max_segment_len: u16,
+    },
+    /// Push-style notice that the device abandoned the in-flight update
+    /// on its own - inactivity timeout, internal fault, shutdown - and
+    /// is idle again. May arrive at any point during a session; the
+    /// host should stop sending segments rather than collect
+    /// `WrongState` replies one by one.
+    UpdateAborted(FailureReason),
+    /// Reply to [`MessageTypeHost::HashFlash`]: the digest over the
+    /// requested range, or the status explaining its absence when the
+    /// request was refused or the read failed.
+    FlashHash {
+        status: Status,
+        sha256: Option<[u8; HASH_LEN]>,
+    },
+    /// Reply to [`MessageTypeHost::GetUpdateInfo`]: the persisted
+    /// [`UpdateRecord`], or `None` on a device that never completed an
+    /// update (or whose NVS is unavailable).
+    UpdateInfo(Option<UpdateRecord>),
+}
+
+impl MessageTypeMcu {
+    /// Short variant name for trace entries and diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UpdateStartStatus(_) => "UpdateStartStatus",
+            Self::UpdateSegmentStatus { .. } => "UpdateSegmentStatus",
+            Self::UpdateEndStatus(_) => "UpdateEndStatus",
+            Self::Adc(_) => "Adc",
+            Self::Info(_) => "Info",
+            Self::Pong => "Pong",
+            Self::CancelStatus(_) => "CancelStatus",
+            Self::MarkValidStatus(_) => "MarkValidStatus",
+            Self::RollbackStatus(_) => "RollbackStatus",
+            Self::Log(_) => "Log",
+            Self::SetBaudStatus(_) => "SetBaudStatus",
+            Self::TimedPong { .. } => "TimedPong",
+            Self::Progress { .. } => "Progress",
+            Self::Busy { .. } => "Busy",
+            Self::FlashData { .. } => "FlashData",
+            Self::ReadFlashStatus(_) => "ReadFlashStatus",
+            Self::EraseStatus(_) => "EraseStatus",
+            Self::TraceStatus(_) => "TraceStatus",
+            Self::Stats(_) => "Stats",
+            Self::HelloAck { .. } => "HelloAck",
+            Self::UpdateAborted(_) => "UpdateAborted",
+            Self::FlashHash { .. } => "FlashHash",
+            Self::UpdateInfo(_) => "UpdateInfo",
+        }
+    }
+}
+
+/// Where an update currently is, for the host's progress display; the
+/// distinction keeps the host from looking hung during the multi-second
+/// finalization.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdatePhase {
+    /// Segments are arriving and being written.
+    Receiving,
+    /// All bytes are in; the size/digest/signature checks are running.
+    Verifying,
+    /// Verification passed; the image is being finalized and activated.
+    Finalizing,
+    /// A host-requested [`EraseRegion`](MessageTypeHost::EraseRegion)
+    /// is grinding through its sectors; `bytes_written` counts the
+    /// bytes erased so far.
+    Erasing,
+}
+
+/// Why the device abandoned an update on its own initiative; carried
+/// by [`MessageTypeMcu::UpdateAborted`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// No host message within the device's inactivity window. The
+    /// device is idle again; a fresh `UpdateStart` may follow at once.
+    Timeout,
+    /// A flash operation failed between segment replies.
+    FlashError,
+    /// The updater is shutting down (reboot, task exit).
+    Shutdown,
+}
+
+impl FailureReason {
+    /// Whether a fresh `UpdateStart` right away has a chance: true for
+    /// reasons that clear on their own, false for persistent faults.
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::Timeout)
+    }
+}
+
+/// A single ADC reading. `seq` increments (wrapping) per channel so the
+/// host can detect dropped frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdcSample {
+    pub channel: u8,
+    pub seq: u16,
+    /// Reading in mV.
+    pub value: u16,
+}
+
+/// One log record from the device. `level` follows the `log` crate's
+/// numbering: 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: u8,
+    /// Module path the record originated from.
+    pub target: String,
+    pub message: String,
+}
+
+impl LogRecord {
+    /// The `log` crate's name for [`level`](Self::level), for display;
+    /// levels from a newer generation come back as `"?"`.
+    pub fn level_name(&self) -> &'static str {
+        match self.level {
+            1 => "ERROR",
+            2 => "WARN",
+            3 => "INFO",
+            4 => "DEBUG",
+            5 => "TRACE",
+            _ => "?",
+        }
+    }
+}
+
+/// Wire-level counters kept by each end of the link, for long-term
+/// link-quality monitoring. Every counter saturates at `u32::MAX`
+/// instead of wrapping, so a long-lived device can never report a noisy
+/// line as a clean one. Each side fills in what it can observe - the
+/// device counts the duplicates it recognises, the host the
+/// retransmissions it decides on - and leaves the rest at zero. Like
+/// [`Info`], fields are appended as the exchange grows, so a frame from
+/// older firmware simply ends early.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Frames received whose checksum verified.
+    pub frames_received: u32,
+    /// Frames dropped for a failed checksum.
+    pub crc_errors: u32,
+    /// Runs of bytes that did not deserialize as a frame at all.
+    pub decode_errors: u32,
+    /// Segments put on the wire more than once (host), or asked to be
+    /// resent with a `Retry` status (device).
+    pub segments_retried: u32,
+    /// Retransmitted segments recognised and re-acked without a second
+    /// write.
+    pub duplicate_segments: u32,
+    /// `Busy` backpressure replies sent (device) or honoured with a
+    /// wait (host).
+    pub busy_events: u32,
+    /// Bytes read off the link, frames and garbage alike.
+    pub bytes_received: u32,
+    /// Bytes written to the link.
+    pub bytes_sent: u32,
+    /// Flash driver calls issued for update data (device). Segments are
+    /// coalesced into sector-sized blocks before they go to flash, so
+    /// this stays far below the segment count.
+    pub flash_writes: u32,
+    /// Time spent inside those flash calls, in microseconds (device);
+    /// with the write count this puts a number on what the coalescing
+    /// buys.
+    pub flash_write_micros: u32,
+}
+
+/// Label, flash offset and size of a partition reported in [`Info`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SlotInfo {
+    pub label: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// Reply to `GetInfo`. Fields are appended as the exchange grows, so a
+/// frame from older firmware simply ends early.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Info {
+    pub protocol_version: u8,
+    /// Application version from the app descriptor, e.g. "0.25.0".
+    pub app_version: String,
+    /// Size in bytes of the OTA app slot the next update goes to; `None`
+    /// when the firmware cannot tell.
+    pub slot_size: Option<u32>,
+    /// Project name from the app descriptor.
+    pub project_name: String,
+    /// Build date and time of the running image.
+    pub build: String,
+    /// The slot the running app booted from.
+    pub running_slot: Option<SlotInfo>,
+    /// The slot the next update goes to.
+    pub update_slot: Option<SlotInfo>,
+    /// Free heap bytes at the time of the reply.
+    pub free_heap: u32,
+    /// Lowest the free heap has been since boot.
+    pub min_free_heap: u32,
+    /// Unused stack bytes of the updater task at its deepest, the
+    /// live counterpart of the marks logged after every transfer.
+    pub updater_stack_free: u32,
+    /// Times the device's RX stream lost frame sync and was flushed
+    /// since boot; a nonzero value points at a noisy or mismatched line.
+    pub desyncs: u32,
+    /// Raw `esp_reset_reason()` code of the current boot (1 power-on,
+    /// 3 software, 4 panic, 5-7 watchdogs, 9 brownout).
+    pub reset_reason: u32,
+    /// Boots counted in the device's NVS since first flash; `None` when
+    /// NVS is unavailable.
+    pub boot_count: Option<u32>,
+    /// Whether the running image is still pending verification after an
+    /// OTA; only ever true on rollback-enabled builds.
+    pub pending_verify: bool,
+    /// FreeRTOS priority the device's serial threads run at.
+    pub serial_priority: u8,
+    /// FreeRTOS priority the device's updater thread runs at.
+    pub updater_priority: u8,
+    /// Core the serial threads are pinned to; `None` when unpinned.
+    pub serial_core: Option<u8>,
+    /// Core the updater thread is pinned to; `None` when unpinned.
+    pub updater_core: Option<u8>,
+    /// Outcome of the post-boot self-test: `None` when it never ran
+    /// this boot, `Some(false)` only when a failed test's rollback did
+    /// not take.
+    pub self_test_passed: Option<bool>,
+}
+
+/// What the last successful update looked like, persisted in the
+/// device's NVS when a transfer completes and reported via
+/// [`MessageTypeMcu::UpdateInfo`], so a fielded device can be audited
+/// for what was flashed, when and how well the transfer went.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UpdateRecord {
+    /// SHA-256 of the complete image as written to flash.
+    pub sha256: [u8; HASH_LEN],
+    /// Link the image arrived over: `"uart"`, `"tcp"` or `"ble"`.
+    pub source: String,
+    /// Unix epoch seconds when the update completed. Only meaningful
+    /// on devices whose clock was set (SNTP); an unset clock counts
+    /// from 1970, which a reader can recognise and discount.
+    pub timestamp: u64,
+    /// Segments written, duplicates excluded.
+    pub segments: u32,
+    /// Retransmitted segments the device received during the transfer.
+    pub retries: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Failed,
+    /// The delta base announced by the host does not match what the device
+    /// is running; the host should fall back to a full transfer.
+    BaseMismatch,
+    /// The received image's size or digest does not match what the host
+    /// announced, or a compressed segment failed to decompress; the
+    /// update was aborted without activating anything.
+    InvalidImage,
+    /// The device's partition table has no OTA update slot; flashing an
+    /// OTA-capable table over the wire is the only cure, so retrying is
+    /// pointless.
+    NoOtaPartition,
+    /// A transient failure before the segment was accounted anywhere -
+    /// an oversized frame, or a source-side read error during a delta
+    /// copy (see [`flash_errors`]); the device did not advance its
+    /// expected segment id and the host should resend the same segment.
+    Retry,
+    /// A fatal flash write error; the device aborted the update and went
+    /// back to idle, so resending is pointless.
+    FlashWrite,
+    /// The `UpdateEnd` signature is missing while the device requires
+    /// one, or did not verify against the device's baked-in public key;
+    /// the update was aborted without activating anything.
+    InvalidSignature,
+    /// The incoming image's app descriptor reports a [`version`] older
+    /// than what the device is running (or its configured minimum), or
+    /// carries no parseable version while the device rejects those; the
+    /// update was aborted without activating anything.
+    VersionTooOld,
+    /// The request is valid but not right now - e.g. a flash read-back
+    /// while segments are still being written. Retry once the device is
+    /// idle again.
+    WrongState,
+}
+
+/// Announces an update of `size` bytes. When the host intends to send
+/// encrypted segments it picks a fresh nonce prefix for the update and
+/// announces it here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateStart {
+    pub size: u32,
+    pub nonce_prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    /// Present when the host wants to send a delta against this base image.
+    pub delta_base: Option<DeltaBase>,
+    /// Target partition label; `None` means the next OTA app slot.
+    pub partition: Option<String>,
+    /// SHA-256 of the complete image, announced up front so the device
+    /// can recognise an interrupted transfer of the same image.
+    pub sha256: Option<[u8; HASH_LEN]>,
+    /// Segment payload size the host will use; `None` means the
+    /// classic [`SEGMENT_SIZE`].
+    pub segment_size: Option<u16>,
+    /// The host understands resume replies and will skip the segments
+    /// before [`UpdateStartStatus::resume_offset`].
+    pub resume: bool,
+    /// The host wants periodic [`MessageTypeMcu::Progress`] frames
+    /// during the transfer and finalization.
+    pub progress: bool,
+}
+
+/// Identifies the image a delta was computed against.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeltaBase {
+    pub hash: [u8; HASH_LEN],
+    pub len: u32,
+}
+
+/// Reply to [`UpdateStart`]; `capabilities` carries the `CAP_*` bits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateStartStatus {
+    pub status: Status,
+    pub capabilities: u8,
+    /// Largest segment payload the device accepts for this update;
+    /// `None` means the classic [`SEGMENT_SIZE`].
+    pub max_segment_size: Option<u16>,
+    /// Byte offset where an interrupted transfer of the same image can
+    /// continue; the first `resume_offset / segment_size` segments are
+    /// already in flash and must be skipped.
+    pub resume_offset: Option<u32>,
+}
+
+/// One chunk of the image. Segment ids are consecutive, starting at 0.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateSegment {
+    pub id: u16,
+    pub data: Vec<u8>,
+}
+
+/// A heatshrink-compressed chunk; `raw_len` is the decompressed size.
+/// Each chunk is an independent heatshrink stream, so there is no
+/// decoder state to resynchronize after a retransmission.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateSegmentCompressed {
+    pub id: u16,
+    pub raw_len: u16,
+    pub data: Vec<u8>,
+}
+
+/// A [`crypto`]-sealed chunk; `data` is ciphertext plus the
+/// [`crypto::TAG_LEN`]-byte tag, and the nonce is reconstructed from the
+/// update's prefix and `id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateSegmentEncrypted {
+    pub id: u16,
+    /// Present when the plaintext is itself a heatshrink stream
+    /// expanding to this many bytes: the host compresses before
+    /// sealing, since ciphertext never compresses.
+    pub raw_len: Option<u16>,
+    pub data: Vec<u8>,
+}
+
+/// One delta instruction producing the next stretch of the new image,
+/// either verbatim bytes or a copy out of the base image.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateSegmentDelta {
+    pub id: u16,
+    pub op: DeltaOp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DeltaOp {
+    Copy { src_offset: u32, len: u32 },
+    Data(Vec<u8>),
+}
+
+/// Marks the end of the transfer. `signature` is a detached Ed25519
+/// signature ([`SIGNATURE_LEN`] bytes) over the complete image. App
+/// updates always reboot into the new image; for other partitions the
+/// device only reboots when `reboot` is set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateEnd {
+    pub signature: Option<Vec<u8>>,
+    pub reboot: bool,
+    /// SHA-256 over the complete image, so the device can catch double
+    /// writes and host-side corruption that per-segment CRCs cannot.
+    pub sha256: Option<[u8; HASH_LEN]>,
+}
+
+/// Envelope adding a CRC32 over the postcard-serialized payload.
+///
+/// Constructing and verifying through this type serializes the payload
+/// once per step; the [`codec`] module produces and parses the same
+/// wire bytes in a single pass and reports typed errors, so prefer it
+/// on new call sites. The struct remains the wire format's definition.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checksum<T> {
+    pub payload: T,
+    pub checksum: u32,
+}
+
+impl<T: Serialize> Checksum<T> {
+    pub fn new(payload: T) -> Self {
+        let bytes = postcard::to_allocvec(&payload).expect("payload serialization cannot fail");
+        let checksum = crc32(&bytes);
+
+        Self { payload, checksum }
+    }
+
+    pub fn verify(&self) -> bool {
+        let bytes =
+            postcard::to_allocvec(&self.payload).expect("payload serialization cannot fail");
+
+        crc32(&bytes) == self.checksum
+    }
+}
+
+/// Plain bitwise CRC32 (IEEE); small enough that a lookup table is not worth
+/// the flash space on the device.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finalize()
+}
+
+/// Incremental form of [`crc32`], for checksumming a stream that is not
+/// in memory all at once.
+#[derive(Debug, Clone)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { state: 0xffff_ffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state ^= byte as u32;
+            for _ in 0..8 {
+                self.state = if self.state & 1 != 0 {
+                    (self.state >> 1) ^ 0xedb8_8320
+                } else {
+                    self.state >> 1
+                };
+            }
+        }
+    }
+
+    /// The checksum of everything fed so far; the state is untouched,
+    /// so updating and finalizing again is fine.
+    pub fn finalize(&self) -> u32 {
+        !self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incremental_crc_matches_the_one_shot_form() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+
+        let mut crc = Crc32::new();
+        for chunk in data.chunks(7) {
+            crc.update(chunk);
+        }
+
+        assert_eq!(crc.finalize(), crc32(data));
+        // Finalizing is not destructive
+        assert_eq!(crc.finalize(), crc32(data));
+    }
+
+    #[test]
+    fn info_wire_format_is_stable() {
+        let info = Info {
+            protocol_version: 1,
+            app_version: "0.25.0".into(),
+            slot_size: Some(0x18_0000),
+            project_name: "demo".into(),
+            build: "Jan  1 1970 00:00:00".into(),
+            running_slot: Some(SlotInfo {
+                label: "ota_0".into(),
+                offset: 0x1_0000,
+                size: 0x18_0000,
+            }),
+            update_slot: None,
+            free_heap: 150_000,
+            min_free_heap: 100_000,
+            updater_stack_free: 2048,
+            desyncs: 3,
+            reset_reason: 4,
+            boot_count: Some(7),
+            pending_verify: true,
+            serial_priority: 6,
+            updater_priority: 5,
+            serial_core: Some(1),
+            updater_core: None,
+            self_test_passed: Some(true),
+        };
+
+        // Golden vector: a change here means old peers can no longer
+        // decode Info - append fields, never reorder or repurpose them
+        let golden = [
+            1, 6, b'0', b'.', b'2', b'5', b'.', b'0', 1, 0, 0, 24, 0, 4, b'd', b'e', b'm', b'o',
+            20, b'J', b'a', b'n', b' ', b' ', b'1', b' ', b'1', b'9', b'7', b'0', b' ', b'0', b'0',
+            b':', b'0', b'0', b':', b'0', b'0', 1, 5, b'o', b't', b'a', b'_', b'0', 0, 0, 1, 0, 0,
+            0, 24, 0, 0, 240, 73, 2, 0, 160, 134, 1, 0, 0, 8, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 1, 7,
+            0, 0, 0, 1, 6, 5, 1, 1, 0, 1, 1,
+        ];
+
+        assert_eq!(postcard::to_allocvec(&info).unwrap(), golden);
+    }
+
+    #[test]
+    fn hello_wire_format_is_stable() {
+        let hello = MessageTypeHost::Hello {
+            protocol_version: 1,
+            capabilities: Caps::COMPRESSED_SEGMENTS | Caps::DELTA_UPDATES | Caps::STATS,
+        };
+
+        // Golden vector: the handshake must stay decodable across every
+        // firmware generation that knows it at all
+        let golden = [21, 1, 9, 4, 0, 0];
+
+        assert_eq!(postcard::to_allocvec(&hello).unwrap(), golden);
+    }
+
+    #[test]
+    fn hello_ack_wire_format_is_stable() {
+        let ack = MessageTypeMcu::HelloAck {
+            protocol_version: 1,
+            capabilities: Caps::from_bits(0x1f0f),
+            max_segment_len: 1024,
+        };
+
+        let golden = [19, 1, 15, 31, 0, 0, 0, 4];
+
+        assert_eq!(postcard::to_allocvec(&ack).unwrap(), golden);
+    }
+
+    #[test]
+    fn unknown_capability_bits_survive_the_round_trip() {
+        // A peer from the future: its extra bits must come through for
+        // forwarding and logging, and must not trip the known checks
+        let caps = Caps::STATS | Caps::from_bits(1 << 31);
+
+        let bytes = postcard::to_allocvec(&caps).unwrap();
+        let decoded: Caps = postcard::from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded, caps);
+        assert!(decoded.contains(Caps::STATS));
+        assert!(!decoded.contains(Caps::RESUME));
+    }
+
+    #[test]
+    fn caps_low_byte_mirrors_the_legacy_constants() {
+        let caps = Caps::COMPRESSED_SEGMENTS | Caps::SIGNATURE_REQUIRED | Caps::RESUME;
+
+        assert_eq!(
+            caps.legacy_bits(),
+            CAP_COMPRESSED_SEGMENTS | CAP_SIGNATURE_REQUIRED
+        );
+        assert_eq!(
+            Caps::from_legacy(CAP_ENCRYPTED_SEGMENTS | CAP_DELTA_UPDATES),
+            Caps::ENCRYPTED_SEGMENTS | Caps::DELTA_UPDATES
+        );
+    }
+
+    #[test]
+    fn a_maximal_segment_frame_fits_the_capacity_it_was_derived_from() {
+        for capacity in [512_usize, 1024, 2048, 4096] {
+            let payload = max_segment_payload(capacity);
+
+            // Encrypted segments are the fattest on the wire: their
+            // data is the payload plus the authentication tag
+            let msg = MessageTypeHost::UpdateSegmentEncrypted(UpdateSegmentEncrypted {
+                id: u16::MAX,
+                raw_len: Some(u16::MAX),
+                data: vec![0xff; payload + crypto::TAG_LEN],
+            });
+
+            let frame = postcard::to_allocvec(&Checksum::new(msg)).unwrap();
+
+            assert!(
+                frame.len() <= capacity,
+                "{} byte frame for a {} byte receiver",
+                frame.len(),
+                capacity
+            );
+        }
+    }
+
+    #[test]
+    fn derived_payloads_stay_aligned_to_the_classic_segment_size() {
+        for capacity in [512_usize, 1000, 1024, 2048] {
+            assert_eq!(max_segment_payload(capacity) % SEGMENT_SIZE, 0);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Device-side activity flag, shared so the bookkeeping can be
+//! unit-tested on the host.
+//!
+//! The ADC stream and the log mirror share the update link's TX queue
+//! with the segment acks, so their traffic has to step aside while a
+//! transfer is in flight. Rather than every auxiliary task carrying its
+//! own suspend flag for the updater to flip, the updater publishes one
+//! coarse [`DeviceMode`] through a [`SharedMode`]: it flips to
+//! `Updating` when a transfer starts and back to `Idle` on end, cancel
+//! or timeout, and each task decides for itself what to withhold while
+//! `Updating`.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+/// Coarse device activity, as published by the updater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceMode {
+    Idle,
+    Updating,
+}
+
+impl DeviceMode {
+    fn code(self) -> u8 {
+        match self {
+            Self::Idle => 0,
+            Self::Updating => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            1 => Self::Updating,
+            _ => Self::Idle,
+        }
+    }
+}
+
+/// The flag itself, cloneable across threads: the updater sets it and
+/// the auxiliary tasks poll it. A fresh flag reads `Idle`.
+#[derive(Clone, Default)]
+pub struct SharedMode {
+    inner: Arc<AtomicU8>,
+}
+
+impl SharedMode {
+    pub fn set(&self, mode: DeviceMode) {
+        self.inner.store(mode.code(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> DeviceMode {
+        DeviceMode::from_code(self.inner.load(Ordering::Relaxed))
+    }
+
+    /// Shorthand for the only question the auxiliary tasks ask.
+    pub fn is_updating(&self) -> bool {
+        self.get() == DeviceMode::Updating
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_fresh_flag_reads_idle() {
+        let mode = SharedMode::default();
+
+        assert_eq!(mode.get(), DeviceMode::Idle);
+        assert!(!mode.is_updating());
+    }
+
+    #[test]
+    fn a_flip_is_visible_through_every_clone() {
+        let updater = SharedMode::default();
+        let task = updater.clone();
+
+        updater.set(DeviceMode::Updating);
+
+        assert!(task.is_updating());
+    }
+
+    #[test]
+    fn every_teardown_path_returns_to_idle() {
+        let mode = SharedMode::default();
+
+        // End, cancel and timeout all publish the same transition; the
+        // tasks cannot tell them apart and must not need to
+        for _teardown in ["end", "cancel", "timeout"] {
+            mode.set(DeviceMode::Updating);
+            assert!(mode.is_updating());
+
+            mode.set(DeviceMode::Idle);
+            assert_eq!(mode.get(), DeviceMode::Idle);
+        }
+    }
+}
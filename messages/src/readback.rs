@@ -0,0 +1,252 @@
+//! Device-side read-back chunking, shared so the splitting and
+//! sequencing can be unit-tested on the host.
+//!
+//! `ReadFlash` lets the host verify what actually landed in flash. The
+//! reply has to fit the UART TX budget, so a large read goes back as a
+//! run of chunks in offset order with the final one marked `last`.
+//! [`plan`] validates a request against the partition bounds and lays
+//! out the chunks; [`stream`] drives a plan over any [`FlashSource`],
+//! flash or in-memory.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Required alignment of a read-back offset. Word alignment is within
+/// what every flash and transparent-decryption config accepts, so
+/// insisting on it keeps the device's read path out of driver corner
+/// cases.
+pub const READ_ALIGN: u32 = 4;
+
+/// Why a read request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanError {
+    /// `len` of zero: a host asking for nothing is confused enough to
+    /// deserve an error over an empty success.
+    Empty,
+    /// `offset + len` leaves the partition.
+    OutOfBounds,
+    /// `offset` is not [`READ_ALIGN`]-aligned.
+    Unaligned,
+}
+
+/// One chunk of a planned read, in offset order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashChunk {
+    pub offset: u32,
+    pub len: u32,
+    /// Marks the final chunk, so the receiver knows the stream is
+    /// complete rather than cut short.
+    pub last: bool,
+}
+
+/// Validates a read request and lays out its chunks, each at most
+/// `max_chunk` bytes.
+pub fn plan(
+    partition_size: u32,
+    offset: u32,
+    len: u32,
+    max_chunk: u32,
+) -> Result<ReadPlan, PlanError> {
+    if len == 0 {
+        return Err(PlanError::Empty);
+    }
+
+    if !offset.is_multiple_of(READ_ALIGN) {
+        return Err(PlanError::Unaligned);
+    }
+
+    // Widened so offset + len cannot wrap before the comparison
+    if u64::from(offset) + u64::from(len) > u64::from(partition_size) {
+        return Err(PlanError::OutOfBounds);
+    }
+
+    Ok(ReadPlan {
+        offset,
+        remaining: len,
+        // A zero cap would never make progress; one byte per chunk is
+        // absurd but terminates
+        max_chunk: max_chunk.max(1),
+    })
+}
+
+/// Iterator over the chunks of one validated request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReadPlan {
+    offset: u32,
+    remaining: u32,
+    max_chunk: u32,
+}
+
+impl Iterator for ReadPlan {
+    type Item = FlashChunk;
+
+    fn next(&mut self) -> Option<FlashChunk> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let len = self.remaining.min(self.max_chunk);
+        let chunk = FlashChunk {
+            offset: self.offset,
+            len,
+            last: len == self.remaining,
+        };
+
+        self.offset += len;
+        self.remaining -= len;
+
+        Some(chunk)
+    }
+}
+
+/// Where the chunk bytes come from: partitions on the device, plain
+/// buffers in tests.
+pub trait FlashSource {
+    type Error;
+
+    /// Fills `buf` from `offset` within the partition.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Which side of a streamed read failed.
+#[derive(Debug)]
+pub enum StreamError<R, E> {
+    Read(R),
+    Emit(E),
+}
+
+/// Drives one validated plan over a source, handing each filled chunk
+/// to `emit` in offset order. The first failure of either side stops
+/// the stream; a partial stream simply never delivers a `last` chunk.
+pub fn stream<S, E>(
+    source: &mut S,
+    plan: ReadPlan,
+    mut emit: impl FnMut(FlashChunk, Vec<u8>) -> Result<(), E>,
+) -> Result<(), StreamError<S::Error, E>>
+where
+    S: FlashSource,
+{
+    for chunk in plan {
+        let mut data = vec![0_u8; chunk.len as usize];
+
+        source
+            .read(chunk.offset, &mut data)
+            .map_err(StreamError::Read)?;
+        emit(chunk, data).map_err(StreamError::Emit)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct SliceSource(Vec<u8>);
+
+    impl FlashSource for SliceSource {
+        type Error = &'static str;
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+            let start = offset as usize;
+            let end = start + buf.len();
+
+            match self.0.get(start..end) {
+                Some(slice) => {
+                    buf.copy_from_slice(slice);
+                    Ok(())
+                }
+                None => Err("read past the end"),
+            }
+        }
+    }
+
+    #[test]
+    fn a_zero_length_read_is_refused() {
+        assert_eq!(plan(4096, 0, 0, 256), Err(PlanError::Empty));
+    }
+
+    #[test]
+    fn a_read_past_the_partition_is_refused() {
+        assert_eq!(plan(4096, 4096, 1, 256), Err(PlanError::OutOfBounds));
+        assert_eq!(plan(4096, 4092, 8, 256), Err(PlanError::OutOfBounds));
+
+        // offset + len wrapping u32 must not sneak back in bounds
+        assert_eq!(
+            plan(4096, u32::MAX - 3, 8, 256),
+            Err(PlanError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn an_unaligned_offset_is_refused() {
+        assert_eq!(plan(4096, 3, 16, 256), Err(PlanError::Unaligned));
+    }
+
+    #[test]
+    fn chunks_cover_the_range_in_order_with_the_final_one_marked() {
+        let chunks: Vec<FlashChunk> = plan(4096, 4, 600, 256).unwrap().collect();
+
+        assert_eq!(
+            chunks,
+            [
+                FlashChunk {
+                    offset: 4,
+                    len: 256,
+                    last: false
+                },
+                FlashChunk {
+                    offset: 260,
+                    len: 256,
+                    last: false
+                },
+                FlashChunk {
+                    offset: 516,
+                    len: 88,
+                    last: true
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn an_exact_multiple_marks_the_final_full_chunk() {
+        let chunks: Vec<FlashChunk> = plan(4096, 0, 512, 256).unwrap().collect();
+
+        assert_eq!(chunks.len(), 2);
+        assert!(!chunks[0].last);
+        assert!(chunks[1].last);
+    }
+
+    #[test]
+    fn the_stream_hands_back_the_source_bytes() {
+        let mut source = SliceSource((0..=255).collect());
+        let mut received = Vec::new();
+
+        stream::<_, ()>(&mut source, plan(256, 4, 100, 32).unwrap(), |_, data| {
+            received.extend(data);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(received, source.0[4..104]);
+    }
+
+    #[test]
+    fn an_emit_failure_stops_the_stream() {
+        let mut source = SliceSource(vec![0; 256]);
+        let mut emitted = 0;
+
+        let result = stream(&mut source, plan(256, 0, 256, 64).unwrap(), |_, _| {
+            emitted += 1;
+            if emitted == 2 {
+                Err("queue gone")
+            } else {
+                Ok(())
+            }
+        });
+
+        assert!(matches!(result, Err(StreamError::Emit("queue gone"))));
+        assert_eq!(emitted, 2);
+    }
+}
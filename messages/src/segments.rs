@@ -0,0 +1,255 @@
+//! Device-side segment ordering, shared so the decision logic can be
+//! unit-tested on the host.
+//!
+//! Segments are written sequentially, so when the host retransmits one
+//! because its ack was lost, writing it again would corrupt the image.
+//! [`SegmentTracker`] classifies every incoming id: the expected one is
+//! written, the immediately preceding one is re-acked without touching
+//! flash, and anything else is rejected. [`apply`] ties the two
+//! together over any [`UpdateSink`], flash or in-memory.
+
+/// What to do with an incoming segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentAction {
+    /// The expected next segment; write it.
+    Write,
+    /// The segment most recently written, retransmitted because its ack
+    /// got lost; ack it again but do not write.
+    AckDuplicate,
+    /// Out of order beyond a single retransmit; something is badly
+    /// wrong with the transfer.
+    Reject,
+}
+
+/// Tracks the transfer position. Wire ids are 16 bits and wrap on long
+/// transfers, so the tracker keeps a widened position and compares in
+/// id space.
+#[derive(Default)]
+pub struct SegmentTracker {
+    next: u32,
+}
+
+impl SegmentTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tracker for a resumed transfer: the first `next` segments are
+    /// already in flash and the host skips them.
+    pub fn starting_at(next: u32) -> Self {
+        Self { next }
+    }
+
+    /// Classifies `id` against the current position. Does not advance -
+    /// call [`advance`](Self::advance) once the segment actually hit
+    /// flash, so a failed write keeps the position and the host's retry
+    /// of the same id is written rather than falsely acked.
+    pub fn classify(&self, id: u16) -> SegmentAction {
+        if id == self.next as u16 {
+            SegmentAction::Write
+        } else if self.next > 0 && id == (self.next - 1) as u16 {
+            SegmentAction::AckDuplicate
+        } else {
+            SegmentAction::Reject
+        }
+    }
+
+    /// Records that the expected segment was written successfully.
+    pub fn advance(&mut self) {
+        self.next += 1;
+    }
+
+    /// The wire id the tracker expects next; what the device points the
+    /// host at when the line loses sync mid-transfer.
+    pub fn expected(&self) -> u16 {
+        self.next as u16
+    }
+}
+
+/// Where segment bytes land once the tracker admits them. The firmware
+/// implements this over its flash targets - the OTA handle and the raw
+/// partition writer - while tests implement it over a plain buffer,
+/// which is what keeps the ordering logic checkable on the host.
+pub trait UpdateSink {
+    type Error;
+
+    /// Appends `data` at the sink's current position.
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Runs one incoming segment through the tracker's rules against a
+/// sink: the expected id is written and advances the position, a
+/// retransmit is reported without touching the sink, and anything else
+/// is rejected. A failed write keeps the position, so the host's retry
+/// of the same id is written rather than falsely acked.
+pub fn apply<S: UpdateSink>(
+    tracker: &mut SegmentTracker,
+    sink: &mut S,
+    id: u16,
+    data: &[u8],
+) -> Result<SegmentAction, S::Error> {
+    match tracker.classify(id) {
+        SegmentAction::Write => {
+            sink.write(data)?;
+            tracker.advance();
+            Ok(SegmentAction::Write)
+        }
+        other => Ok(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer standing in for flash.
+    struct InMemorySink(Vec<u8>);
+
+    impl UpdateSink for InMemorySink {
+        type Error = ();
+
+        fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+            self.0.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// Drives the tracker the way the firmware does, recording every
+    /// write into the in-memory sink.
+    struct MockedWriter {
+        tracker: SegmentTracker,
+        sink: InMemorySink,
+    }
+
+    impl MockedWriter {
+        fn new() -> Self {
+            Self {
+                tracker: SegmentTracker::new(),
+                sink: InMemorySink(Vec::new()),
+            }
+        }
+
+        fn written(&self) -> &[u8] {
+            &self.sink.0
+        }
+
+        /// Returns whether the segment was acked Ok.
+        fn receive(&mut self, id: u16, data: &[u8]) -> bool {
+            match apply(&mut self.tracker, &mut self.sink, id, data).unwrap() {
+                SegmentAction::Write | SegmentAction::AckDuplicate => true,
+                SegmentAction::Reject => false,
+            }
+        }
+    }
+
+    #[test]
+    fn in_order_segments_are_written_once() {
+        let mut writer = MockedWriter::new();
+
+        assert!(writer.receive(0, b"aa"));
+        assert!(writer.receive(1, b"bb"));
+        assert!(writer.receive(2, b"cc"));
+
+        assert_eq!(writer.written(), b"aabbcc");
+    }
+
+    #[test]
+    fn a_retransmit_is_acked_but_never_written_twice() {
+        let mut writer = MockedWriter::new();
+
+        assert!(writer.receive(0, b"aa"));
+        // The ack for 0 got lost; the host sends it again
+        assert!(writer.receive(0, b"aa"));
+        assert!(writer.receive(1, b"bb"));
+        assert!(writer.receive(1, b"bb"));
+        assert!(writer.receive(2, b"cc"));
+
+        assert_eq!(writer.written(), b"aabbcc");
+    }
+
+    #[test]
+    fn segments_further_away_are_rejected() {
+        let mut writer = MockedWriter::new();
+
+        assert!(writer.receive(0, b"aa"));
+        assert!(writer.receive(1, b"bb"));
+
+        // Neither the distant past nor the future is acceptable
+        assert!(!writer.receive(0, b"aa"));
+        assert!(!writer.receive(5, b"xx"));
+
+        assert_eq!(writer.written(), b"aabb");
+    }
+
+    #[test]
+    fn id_zero_is_never_a_duplicate_before_anything_was_written() {
+        let tracker = SegmentTracker::new();
+
+        assert_eq!(tracker.classify(0), SegmentAction::Write);
+        assert_eq!(tracker.classify(u16::MAX), SegmentAction::Reject);
+    }
+
+    #[test]
+    fn a_failed_write_does_not_turn_the_retry_into_a_duplicate() {
+        let mut tracker = SegmentTracker::new();
+
+        assert_eq!(tracker.classify(0), SegmentAction::Write);
+        // The write failed, so the position stays; the host retries id 0
+        // and it must be written, not falsely acked as a duplicate
+        assert_eq!(tracker.classify(0), SegmentAction::Write);
+
+        tracker.advance();
+        assert_eq!(tracker.classify(0), SegmentAction::AckDuplicate);
+        assert_eq!(tracker.classify(1), SegmentAction::Write);
+    }
+
+    #[test]
+    fn a_sink_error_surfaces_and_keeps_the_position() {
+        /// A sink whose flash is broken.
+        struct FailingSink;
+
+        impl UpdateSink for FailingSink {
+            type Error = &'static str;
+
+            fn write(&mut self, _data: &[u8]) -> Result<(), &'static str> {
+                Err("write failed")
+            }
+        }
+
+        let mut tracker = SegmentTracker::new();
+
+        assert_eq!(
+            apply(&mut tracker, &mut FailingSink, 0, b"aa"),
+            Err("write failed")
+        );
+        // The position stays, so the retry of id 0 is a write again
+        assert_eq!(tracker.classify(0), SegmentAction::Write);
+    }
+
+    #[test]
+    fn a_resumed_tracker_expects_the_segment_after_the_checkpoint() {
+        let mut tracker = SegmentTracker::starting_at(16);
+
+        assert_eq!(tracker.classify(0), SegmentAction::Reject);
+        assert_eq!(tracker.classify(15), SegmentAction::AckDuplicate);
+        assert_eq!(tracker.classify(16), SegmentAction::Write);
+
+        tracker.advance();
+        assert_eq!(tracker.classify(17), SegmentAction::Write);
+    }
+
+    #[test]
+    fn wire_ids_wrap_but_the_position_does_not() {
+        let mut tracker = SegmentTracker::new();
+
+        // Advance past the u16 range
+        for _ in 0..=u16::MAX as u32 {
+            tracker.advance();
+        }
+
+        // Position 65536 shows up as wire id 0 again
+        assert_eq!(tracker.classify(0), SegmentAction::Write);
+        assert_eq!(tracker.classify(u16::MAX), SegmentAction::AckDuplicate);
+        assert_eq!(tracker.classify(1), SegmentAction::Reject);
+    }
+}
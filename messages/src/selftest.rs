@@ -0,0 +1,210 @@
+//! Post-OTA self-test orchestration, shared so the verdict logic can
+//! be unit-tested on the host against a fake slot backend.
+//!
+//! A freshly booted image that is still pending verification gets one
+//! chance to prove itself: the application's [`SelfTest`] runs under a
+//! deadline, a pass marks the slot valid, and a failure - or a test
+//! that finishes past its deadline - rolls back to the previous image.
+//! The deadline only judges tests that finish; one that hangs outright
+//! is the task watchdog's department. The whole exercise has to fit
+//! inside whatever watchdog guards the boot path, because a reset here
+//! just boots the unverified image again.
+
+use std::time::{Duration, Instant};
+
+/// An application's proof that the new image can do its job - sensors
+/// respond, config parses. Run once, early, with the protocol stack
+/// already up.
+pub trait SelfTest {
+    fn run(&mut self) -> Result<(), SelfTestError>;
+}
+
+/// Closures work as one-off self-tests, so simple applications need no
+/// named type.
+impl<F: FnMut() -> Result<(), SelfTestError>> SelfTest for F {
+    fn run(&mut self) -> Result<(), SelfTestError> {
+        self()
+    }
+}
+
+/// Why a self-test did not pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// The test reported a failure of its own, with its reason.
+    Failed(String),
+    /// The test finished, but past the deadline; a new image that
+    /// takes this long to come up is treated as broken.
+    TimedOut,
+}
+
+/// The slot operations a verdict drives: `simple_ota` on the device,
+/// fakes in tests.
+pub trait SlotBackend {
+    type Error;
+
+    /// Whether the running image still awaits its verdict.
+    fn pending_verify(&mut self) -> Result<bool, Self::Error>;
+
+    /// Commits to the running image.
+    fn mark_valid(&mut self) -> Result<(), Self::Error>;
+
+    /// Reboots into the previous image; returning at all means the
+    /// rollback did not take.
+    fn rollback(&mut self) -> Result<(), Self::Error>;
+}
+
+/// What [`confirm`] decided.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The image was not pending verification; the test never ran.
+    NotPending,
+    /// The test passed in time and the slot was marked valid.
+    Passed,
+    /// The test did not pass and rollback was requested; observing
+    /// this value at all means the rollback failed to reboot.
+    Failed(SelfTestError),
+}
+
+/// Runs the post-boot confirmation: nothing to decide unless the image
+/// is pending, otherwise the test's result - judged against the
+/// deadline - is applied to the slot. `Err` carries the backend's own
+/// failure; the verdict could not be applied then.
+pub fn confirm<B, T>(backend: &mut B, test: &mut T, deadline: Duration) -> Result<Verdict, B::Error>
+where
+    B: SlotBackend,
+    T: SelfTest,
+{
+    if !backend.pending_verify()? {
+        return Ok(Verdict::NotPending);
+    }
+
+    let started = Instant::now();
+
+    let failure = match test.run() {
+        Ok(()) if started.elapsed() > deadline => Some(SelfTestError::TimedOut),
+        Ok(()) => None,
+        Err(err) => Some(err),
+    };
+
+    match failure {
+        None => {
+            backend.mark_valid()?;
+            Ok(Verdict::Passed)
+        }
+        Some(err) => {
+            backend.rollback()?;
+            Ok(Verdict::Failed(err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeSlot {
+        pending: bool,
+        marked_valid: bool,
+        rolled_back: bool,
+    }
+
+    impl SlotBackend for FakeSlot {
+        type Error = ();
+
+        fn pending_verify(&mut self) -> Result<bool, ()> {
+            Ok(self.pending)
+        }
+
+        fn mark_valid(&mut self) -> Result<(), ()> {
+            self.marked_valid = true;
+            Ok(())
+        }
+
+        fn rollback(&mut self) -> Result<(), ()> {
+            self.rolled_back = true;
+            Ok(())
+        }
+    }
+
+    const GENEROUS: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn nothing_pending_never_runs_the_test() {
+        let mut slot = FakeSlot::default();
+        let mut runs = 0;
+
+        let verdict = confirm(
+            &mut slot,
+            &mut || {
+                runs += 1;
+                Ok(())
+            },
+            GENEROUS,
+        )
+        .unwrap();
+
+        assert_eq!(verdict, Verdict::NotPending);
+        assert_eq!(runs, 0);
+        assert!(!slot.marked_valid);
+        assert!(!slot.rolled_back);
+    }
+
+    #[test]
+    fn a_pass_marks_the_slot_valid() {
+        let mut slot = FakeSlot {
+            pending: true,
+            ..Default::default()
+        };
+
+        let verdict = confirm(&mut slot, &mut || Ok(()), GENEROUS).unwrap();
+
+        assert_eq!(verdict, Verdict::Passed);
+        assert!(slot.marked_valid);
+        assert!(!slot.rolled_back);
+    }
+
+    #[test]
+    fn a_failure_rolls_back_with_its_reason() {
+        let mut slot = FakeSlot {
+            pending: true,
+            ..Default::default()
+        };
+
+        let verdict = confirm(
+            &mut slot,
+            &mut || Err(SelfTestError::Failed("sensor dead".to_string())),
+            GENEROUS,
+        )
+        .unwrap();
+
+        assert_eq!(
+            verdict,
+            Verdict::Failed(SelfTestError::Failed("sensor dead".to_string()))
+        );
+        assert!(!slot.marked_valid);
+        assert!(slot.rolled_back);
+    }
+
+    #[test]
+    fn a_late_finish_rolls_back_as_timed_out() {
+        let mut slot = FakeSlot {
+            pending: true,
+            ..Default::default()
+        };
+
+        // A zero deadline: any test that takes observable time is late
+        let verdict = confirm(
+            &mut slot,
+            &mut || {
+                std::thread::sleep(Duration::from_millis(5));
+                Ok(())
+            },
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        assert_eq!(verdict, Verdict::Failed(SelfTestError::TimedOut));
+        assert!(slot.rolled_back);
+    }
+}
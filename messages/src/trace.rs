@@ -0,0 +1,202 @@
+//! Device-side protocol tracing, host-testable like the rest of the
+//! bookkeeping.
+//!
+//! When interop goes wrong, the most useful witness is the device's own
+//! account of what it thinks it received and answered. The updater
+//! records one short line per frame into a [`TraceLog`] - a small ring,
+//! heap-bounded so the facility is safe to leave compiled in - and,
+//! while tracing is on, streams each line to the host as a `Log` frame
+//! under a [`Throttle`]. The ring survives the throttle: a dropped
+//! stream line is still there for a later `DumpTrace`.
+//!
+//! Tracing steps aside when a transfer starts - trace frames would
+//! compete with segment acks for the link - unless the host forced it
+//! on and accepts the cost.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Entries the ring holds before the oldest is evicted.
+pub const TRACE_DEPTH: usize = 16;
+
+/// Longest entry kept, in bytes; anything past it is cut. Bounds the
+/// ring's worst case at `TRACE_DEPTH * ENTRY_MAX` on the heap.
+pub const ENTRY_MAX: usize = 96;
+
+/// Raw bytes quoted from the front of an undecodable frame.
+pub const RAW_PREVIEW: usize = 8;
+
+/// Whether and how hard the device is tracing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceMode {
+    Off,
+    /// Tracing until the next transfer starts.
+    On,
+    /// Tracing through transfers too; the host accepted the bandwidth
+    /// cost.
+    Forced,
+}
+
+impl TraceMode {
+    /// The host's `SetTrace` request mapped onto a mode.
+    pub fn requested(enabled: bool, force: bool) -> Self {
+        match (enabled, force) {
+            (false, _) => Self::Off,
+            (true, false) => Self::On,
+            (true, true) => Self::Forced,
+        }
+    }
+
+    /// Called when a transfer starts: plain tracing yields the link,
+    /// forced tracing keeps it. Returns whether tracing switched off.
+    pub fn on_transfer_start(&mut self) -> bool {
+        if *self == Self::On {
+            *self = Self::Off;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        self != Self::Off
+    }
+}
+
+/// The ring of recorded entries. Pushing past [`TRACE_DEPTH`] evicts
+/// the oldest; entries longer than [`ENTRY_MAX`] are cut on the way in,
+/// so the heap cost has a hard ceiling regardless of what is recorded.
+#[derive(Default)]
+pub struct TraceLog {
+    entries: VecDeque<String>,
+}
+
+impl TraceLog {
+    pub fn record(&mut self, mut entry: String) {
+        if entry.len() > ENTRY_MAX {
+            // Truncate on a character boundary; entries are ASCII in
+            // practice but a stray multi-byte char must not panic here
+            let mut cut = ENTRY_MAX;
+            while !entry.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            entry.truncate(cut);
+        }
+
+        if self.entries.len() == TRACE_DEPTH {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(entry);
+    }
+
+    /// Recorded entries, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Rate limit for streamed trace frames: at most one per interval,
+/// judged against a caller-supplied clock so tests need no sleeping.
+/// Deliberately blunt - tracing is a debugging aid and must never crowd
+/// the link the way a chatty frame exchange could.
+pub struct Throttle {
+    min_interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last: None,
+        }
+    }
+
+    /// Whether a frame may go out at `now`; saying yes spends the slot.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.min_interval => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_request_maps_onto_the_three_modes() {
+        assert_eq!(TraceMode::requested(false, false), TraceMode::Off);
+        // Force without enable is just off; there is nothing to force
+        assert_eq!(TraceMode::requested(false, true), TraceMode::Off);
+        assert_eq!(TraceMode::requested(true, false), TraceMode::On);
+        assert_eq!(TraceMode::requested(true, true), TraceMode::Forced);
+    }
+
+    #[test]
+    fn a_transfer_silences_plain_tracing_but_not_forced() {
+        let mut plain = TraceMode::On;
+        assert!(plain.on_transfer_start());
+        assert_eq!(plain, TraceMode::Off);
+
+        let mut forced = TraceMode::Forced;
+        assert!(!forced.on_transfer_start());
+        assert!(forced.enabled());
+
+        let mut off = TraceMode::Off;
+        assert!(!off.on_transfer_start());
+    }
+
+    #[test]
+    fn the_ring_evicts_oldest_and_caps_entry_length() {
+        let mut log = TraceLog::default();
+
+        for i in 0..TRACE_DEPTH + 3 {
+            log.record(format!("entry {}", i));
+        }
+
+        assert_eq!(log.len(), TRACE_DEPTH);
+        // The three oldest are gone
+        assert_eq!(log.entries().next(), Some("entry 3"));
+
+        log.record("x".repeat(ENTRY_MAX * 2));
+        assert_eq!(log.entries().last().unwrap().len(), ENTRY_MAX);
+    }
+
+    #[test]
+    fn truncation_respects_character_boundaries() {
+        let mut log = TraceLog::default();
+
+        // A two-byte character straddling the cut must go entirely
+        log.record(format!("{}é", "x".repeat(ENTRY_MAX - 1)));
+
+        let entry = log.entries().next().unwrap();
+        assert_eq!(entry.len(), ENTRY_MAX - 1);
+        assert!(entry.chars().all(|c| c == 'x'));
+    }
+
+    #[test]
+    fn the_throttle_spends_one_slot_per_interval() {
+        let mut throttle = Throttle::new(Duration::from_millis(50));
+        let start = Instant::now();
+
+        assert!(throttle.allow(start));
+        assert!(!throttle.allow(start + Duration::from_millis(10)));
+        assert!(!throttle.allow(start + Duration::from_millis(49)));
+        assert!(throttle.allow(start + Duration::from_millis(50)));
+        assert!(!throttle.allow(start + Duration::from_millis(60)));
+    }
+}
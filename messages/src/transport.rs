@@ -0,0 +1,435 @@
+//! The byte-shoveling contract shared by every link the protocol runs
+//! over.
+//!
+//! The flasher talks to serial ports and TCP sockets, the firmware to
+//! UART drivers and the USB-Serial-JTAG peripheral, and each of them
+//! amounts to the same three operations: read whatever bytes arrived
+//! within a timeout, write a frame, flush it onto the wire. [`Transport`]
+//! names those operations once so the retry loops and message pumps can
+//! be written against the trait, and [`pair`] provides an in-memory
+//! loopback so they can be exercised on the host with no hardware in
+//! sight.
+
+use std::io;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// A bidirectional byte link carrying protocol frames.
+///
+/// Reads are timeout-bounded polls: a quiet link reports
+/// [`io::ErrorKind::TimedOut`], while `Ok(0)` means the peer closed the
+/// link for good. Writes are all-or-nothing; partial writes stay an
+/// implementation detail behind [`write_all`](Self::write_all).
+pub trait Transport {
+    /// Reads the bytes available within `timeout` into `buf`, returning
+    /// how many arrived. Returns as soon as there is anything to hand
+    /// over rather than waiting to fill the buffer.
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+
+    /// Writes all of `buf` to the link.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Pushes anything buffered onto the wire.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Adapts any `Read + Write` type - a pipe, a socket, a PTY - to
+/// [`Transport`], so host tools can drive the protocol over IO this
+/// crate has no dedicated link for.
+///
+/// Plain `Read` has no per-call timeout, so the caller's timeout cannot
+/// be forwarded: the wrapped IO keeps its own discipline. Configure its
+/// read timeout (as `TcpStream` and serial ports support) to at most
+/// what the protocol loops pass in; a reader that blocks forever stalls
+/// them. A read failing with [`io::ErrorKind::WouldBlock`] is reported
+/// as the [`io::ErrorKind::TimedOut`] the trait's contract expects.
+pub struct IoLink<T> {
+    inner: T,
+}
+
+impl<T: io::Read + io::Write> IoLink<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: io::Read + io::Write> Transport for IoLink<T> {
+    fn read_available(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+            }
+            other => other,
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Creates a connected pair of in-memory loopback ends; bytes written to
+/// one are read from the other. Reads honour the caller's timeout like a
+/// serial port would, and dropping an end closes the link for the peer.
+pub fn pair() -> (Loopback, Loopback) {
+    let (a_tx, a_rx) = mpsc::channel();
+    let (b_tx, b_rx) = mpsc::channel();
+
+    (
+        Loopback { tx: a_tx, rx: b_rx },
+        Loopback { tx: b_tx, rx: a_rx },
+    )
+}
+
+/// One end of the in-memory loopback created by [`pair`].
+pub struct Loopback {
+    tx: mpsc::Sender<u8>,
+    rx: mpsc::Receiver<u8>,
+}
+
+impl Transport for Loopback {
+    fn read_available(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        // Block for the first byte only; whatever else is already
+        // queued behind it is drained without waiting, like a serial
+        // port read returning a burst
+        let first = match self.rx.recv_timeout(timeout) {
+            Ok(byte) => byte,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+        };
+
+        buf[0] = first;
+        let mut n = 1;
+
+        while n < buf.len() {
+            match self.rx.try_recv() {
+                Ok(byte) => {
+                    buf[n] = byte;
+                    n += 1;
+                }
+                Err(_) => break,
+            }
+        }
+
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        for &byte in buf {
+            self.tx
+                .send(byte)
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "link closed"))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    use crate::segments::{apply, SegmentAction, SegmentTracker, UpdateSink};
+    use crate::{
+        Checksum, MessageTypeHost, MessageTypeMcu, Status, UpdateEnd, UpdateSegment, UpdateStart,
+        UpdateStartStatus,
+    };
+
+    #[test]
+    fn bytes_cross_the_pair_and_a_quiet_link_times_out() {
+        let (mut a, mut b) = pair();
+        let mut buf = [0_u8; 16];
+
+        a.write_all(b"hello").unwrap();
+        a.flush().unwrap();
+
+        assert_eq!(
+            b.read_available(&mut buf, Duration::from_secs(1)).unwrap(),
+            5
+        );
+        assert_eq!(&buf[..5], b"hello");
+
+        // Nothing more in flight: the read times out rather than blocking
+        let err = b
+            .read_available(&mut buf, Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn dropping_an_end_closes_the_link_after_the_buffered_bytes() {
+        let (mut a, mut b) = pair();
+        let mut buf = [0_u8; 16];
+
+        a.write_all(b"bye").unwrap();
+        drop(a);
+
+        // The bytes written before the drop still arrive
+        assert_eq!(
+            b.read_available(&mut buf, Duration::from_secs(1)).unwrap(),
+            3
+        );
+        // Then the link reports closed, not a timeout
+        assert_eq!(
+            b.read_available(&mut buf, Duration::from_secs(1)).unwrap(),
+            0
+        );
+
+        let err = b.write_all(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    /// `Read + Write` stand-in for [`IoLink`]: reads pop from a script,
+    /// writes accumulate.
+    struct ScriptedIo {
+        reads: std::collections::VecDeque<io::Result<Vec<u8>>>,
+        written: Vec<u8>,
+    }
+
+    impl io::Read for ScriptedIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(Ok(bytes)) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                Some(Err(err)) => Err(err),
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl io::Write for ScriptedIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn an_io_link_carries_bytes_and_maps_would_block_to_timed_out() {
+        let mut link = IoLink::new(ScriptedIo {
+            reads: vec![
+                Ok(b"reply".to_vec()),
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing yet")),
+            ]
+            .into(),
+            written: Vec::new(),
+        });
+        let mut buf = [0_u8; 16];
+
+        link.write_all(b"frame").unwrap();
+        link.flush().unwrap();
+        assert_eq!(link.get_ref().written, b"frame");
+
+        assert_eq!(
+            link.read_available(&mut buf, Duration::from_secs(1))
+                .unwrap(),
+            5
+        );
+        assert_eq!(&buf[..5], b"reply");
+
+        // The inner IO's own timeout surfaces as the trait's TimedOut
+        let err = link
+            .read_available(&mut buf, Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // And exhaustion as a closed link
+        assert_eq!(
+            link.read_available(&mut buf, Duration::from_secs(1))
+                .unwrap(),
+            0
+        );
+    }
+
+    /// Writes one checksummed frame to the link.
+    fn send<M: Serialize>(link: &mut impl Transport, msg: M) {
+        let frame = postcard::to_allocvec(&Checksum::new(msg)).unwrap();
+        link.write_all(&frame).unwrap();
+        link.flush().unwrap();
+    }
+
+    /// Reads the next checksummed frame from the link, accumulating
+    /// bytes across reads like both real sides do.
+    fn recv<M: Serialize + DeserializeOwned>(
+        link: &mut impl Transport,
+        accumulated: &mut Vec<u8>,
+    ) -> M {
+        let mut buf = [0_u8; 256];
+
+        loop {
+            match postcard::take_from_bytes::<Checksum<M>>(accumulated) {
+                Ok((frame, rest)) => {
+                    let consumed = accumulated.len() - rest.len();
+                    accumulated.drain(..consumed);
+
+                    assert!(frame.verify(), "frame failed its checksum");
+                    return frame.payload;
+                }
+                Err(postcard::Error::DeserializeUnexpectedEnd) => (),
+                Err(err) => panic!("undecodable frame: {:?}", err),
+            }
+
+            let n = link
+                .read_available(&mut buf, Duration::from_secs(5))
+                .unwrap();
+            assert_ne!(n, 0, "link closed mid-exchange");
+            accumulated.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// A buffer standing in for flash on the device side.
+    struct InMemorySink(Vec<u8>);
+
+    impl UpdateSink for InMemorySink {
+        type Error = ();
+
+        fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+            self.0.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// The device side of one transfer: [`SegmentTracker`] rules over an
+    /// in-memory sink, acking each frame the way the firmware does.
+    fn device_side(mut link: Loopback) -> Vec<u8> {
+        let mut accumulated = Vec::new();
+        let mut tracker = SegmentTracker::new();
+        let mut sink = InMemorySink(Vec::new());
+
+        loop {
+            match recv::<MessageTypeHost>(&mut link, &mut accumulated) {
+                MessageTypeHost::UpdateStart(_) => send(
+                    &mut link,
+                    MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                        status: Status::Ok,
+                        capabilities: 0,
+                        max_segment_size: None,
+                        resume_offset: None,
+                    }),
+                ),
+                MessageTypeHost::UpdateSegment(segment) => {
+                    let action = apply(&mut tracker, &mut sink, segment.id, &segment.data).unwrap();
+
+                    send(
+                        &mut link,
+                        MessageTypeMcu::UpdateSegmentStatus {
+                            id: segment.id,
+                            status: match action {
+                                SegmentAction::Write | SegmentAction::AckDuplicate => Status::Ok,
+                                SegmentAction::Reject => Status::Failed,
+                            },
+                        },
+                    );
+                }
+                MessageTypeHost::UpdateEnd(_) => {
+                    send(&mut link, MessageTypeMcu::UpdateEndStatus(Status::Ok));
+                    return sink.0;
+                }
+                other => panic!("device got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn a_segment_ack_exchange_crosses_the_loopback_intact() {
+        let (mut host, device) = pair();
+
+        let image: Vec<u8> = (0..2048_u32).map(|i| (i % 251) as u8).collect();
+        let segment_size = 512;
+
+        let device = thread::spawn(move || device_side(device));
+
+        let mut accumulated = Vec::new();
+
+        send(
+            &mut host,
+            MessageTypeHost::UpdateStart(UpdateStart {
+                size: image.len() as u32,
+                nonce_prefix: None,
+                delta_base: None,
+                partition: None,
+                sha256: None,
+                segment_size: Some(segment_size as u16),
+                resume: false,
+                progress: false,
+            }),
+        );
+        assert!(matches!(
+            recv::<MessageTypeMcu>(&mut host, &mut accumulated),
+            MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                status: Status::Ok,
+                ..
+            })
+        ));
+
+        for (id, chunk) in image.chunks(segment_size).enumerate() {
+            // Each segment goes out twice, as if the first ack was lost
+            // on the wire; the duplicate must be acked but not stored
+            for _ in 0..2 {
+                send(
+                    &mut host,
+                    MessageTypeHost::UpdateSegment(UpdateSegment {
+                        id: id as u16,
+                        data: chunk.to_vec(),
+                    }),
+                );
+
+                match recv::<MessageTypeMcu>(&mut host, &mut accumulated) {
+                    MessageTypeMcu::UpdateSegmentStatus {
+                        id: acked,
+                        status: Status::Ok,
+                    } => assert_eq!(acked, id as u16),
+                    other => panic!("expected an Ok ack, got {:?}", other),
+                }
+            }
+        }
+
+        send(
+            &mut host,
+            MessageTypeHost::UpdateEnd(UpdateEnd {
+                signature: None,
+                reboot: false,
+                sha256: None,
+            }),
+        );
+        assert!(matches!(
+            recv::<MessageTypeMcu>(&mut host, &mut accumulated),
+            MessageTypeMcu::UpdateEndStatus(Status::Ok)
+        ));
+
+        assert_eq!(device.join().unwrap(), image);
+    }
+}
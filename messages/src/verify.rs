@@ -0,0 +1,222 @@
+//! End-to-end image verification, shared so the device-side bookkeeping
+//! can be unit-tested on the host.
+//!
+//! Per-segment CRCs catch line noise but not a segment written twice, a
+//! lost write, or a corrupt file on the host. [`ImageCheck`] feeds every
+//! byte written to flash into a running SHA-256 and compares size and
+//! digest against what the host announced before anything is activated.
+//! [`verify_signature`] then checks the Ed25519 signature over that same
+//! digest; signing the digest rather than the image is what lets the
+//! streaming device verify without ever holding the image in RAM.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::{HASH_LEN, PUBLIC_KEY_LEN};
+
+/// Why a received image was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    Size { expected: u32, written: u64 },
+    Digest,
+}
+
+/// Running bookkeeping over every byte handed to the flash writer. The
+/// hash is incremental, so RAM usage stays flat regardless of image size.
+pub struct ImageCheck {
+    hasher: Sha256,
+    written: u64,
+    expected_size: u32,
+}
+
+impl ImageCheck {
+    /// `expected_size` is the image size announced in `UpdateStart`.
+    pub fn new(expected_size: u32) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            written: 0,
+            expected_size,
+        }
+    }
+
+    /// Accounts for `data` having been written to the target.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.written += data.len() as u64;
+    }
+
+    /// Bytes accounted for so far; the device's progress reports read
+    /// this rather than keeping a second counter that could drift.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Final check against the host-announced values; `expected_sha256`
+    /// is `None` when talking to a host that predates the digest field,
+    /// which leaves only the size check. Returns the computed digest so
+    /// a signature over it can be checked next.
+    pub fn verify(
+        self,
+        expected_sha256: Option<&[u8; HASH_LEN]>,
+    ) -> Result<[u8; HASH_LEN], ImageError> {
+        if self.written != u64::from(self.expected_size) {
+            return Err(ImageError::Size {
+                expected: self.expected_size,
+                written: self.written,
+            });
+        }
+
+        let digest: [u8; HASH_LEN] = self.hasher.finalize().into();
+
+        if let Some(expected) = expected_sha256 {
+            if &digest != expected {
+                return Err(ImageError::Digest);
+            }
+        }
+
+        Ok(digest)
+    }
+}
+
+/// A detached signature that did not verify: missing bytes, a malformed
+/// key, or an image signed with a different key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureError;
+
+/// Verifies the detached Ed25519 signature over an image's SHA-256
+/// digest, as computed by [`ImageCheck::verify`] on the device and by
+/// the flasher's signer on the host.
+pub fn verify_signature(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    digest: &[u8; HASH_LEN],
+    signature: &[u8],
+) -> Result<(), SignatureError> {
+    let key = VerifyingKey::from_bytes(public_key).map_err(|_| SignatureError)?;
+    let signature = Signature::from_slice(signature).map_err(|_| SignatureError)?;
+
+    key.verify_strict(digest, &signature)
+        .map_err(|_| SignatureError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn digest_of(data: &[u8]) -> [u8; HASH_LEN] {
+        Sha256::digest(data).into()
+    }
+
+    // The RFC 8032 test 1 key; the signature covers the SHA-256 digest
+    // of an empty image. The flasher's signer test pins the same bytes,
+    // so the two ends cannot drift apart unnoticed.
+    const SEED: [u8; 32] = [
+        0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c,
+        0xc4, 0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae,
+        0x7f, 0x60,
+    ];
+
+    const EMPTY_IMAGE_SIGNATURE: [u8; 64] = [
+        0x48, 0xa9, 0x6e, 0x8f, 0x6c, 0xa1, 0x18, 0xb3, 0x91, 0xbc, 0xec, 0x11, 0xde, 0xa1, 0x65,
+        0xd4, 0xec, 0xbc, 0xbb, 0x81, 0xf6, 0x99, 0xbe, 0xf1, 0x53, 0xed, 0xee, 0x8a, 0x63, 0xe4,
+        0x04, 0x68, 0xb6, 0x88, 0x73, 0x0c, 0x1b, 0xa7, 0x46, 0x7b, 0xfb, 0x11, 0x4b, 0x2c, 0x0a,
+        0x5a, 0x87, 0xb5, 0xf0, 0x7b, 0x14, 0x59, 0x7a, 0x25, 0x35, 0xd3, 0xf7, 0x2c, 0x07, 0xb8,
+        0xab, 0x1c, 0x3c, 0x07,
+    ];
+
+    #[test]
+    fn accepts_a_matching_image_in_any_chunking() {
+        let image = b"0123456789abcdef0123456789abcdef";
+        let expected = digest_of(image);
+
+        let mut check = ImageCheck::new(image.len() as u32);
+        for chunk in image.chunks(7) {
+            check.update(chunk);
+        }
+
+        check.verify(Some(&expected)).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_short_or_double_write() {
+        let image = b"0123456789abcdef";
+
+        let mut check = ImageCheck::new(image.len() as u32);
+        check.update(&image[..8]);
+
+        assert_eq!(
+            check.verify(None),
+            Err(ImageError::Size {
+                expected: 16,
+                written: 8
+            })
+        );
+
+        let mut check = ImageCheck::new(image.len() as u32);
+        check.update(image);
+        check.update(&image[8..]);
+
+        assert!(matches!(check.verify(None), Err(ImageError::Size { .. })));
+    }
+
+    #[test]
+    fn rejects_a_wrong_digest() {
+        let image = b"0123456789abcdef";
+
+        let mut check = ImageCheck::new(image.len() as u32);
+        check.update(image);
+
+        assert_eq!(
+            check.verify(Some(&digest_of(b"something else entirely"))),
+            Err(ImageError::Digest)
+        );
+    }
+
+    #[test]
+    fn size_check_alone_passes_for_old_hosts() {
+        let image = b"0123456789abcdef";
+
+        let mut check = ImageCheck::new(image.len() as u32);
+        check.update(image);
+
+        check.verify(None).unwrap();
+    }
+
+    #[test]
+    fn signature_over_the_digest_matches_the_known_answer() {
+        let key = SigningKey::from_bytes(&SEED);
+        let digest = digest_of(b"");
+        let signature = key.sign(&digest).to_bytes();
+
+        assert_eq!(signature, EMPTY_IMAGE_SIGNATURE);
+        verify_signature(key.verifying_key().as_bytes(), &digest, &signature).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_tampered_signature_or_the_wrong_digest() {
+        let key = SigningKey::from_bytes(&SEED);
+        let digest = digest_of(b"firmware");
+        let mut signature = key.sign(&digest).to_bytes();
+
+        let public = key.verifying_key().to_bytes();
+
+        verify_signature(&public, &digest, &signature).unwrap();
+        assert_eq!(
+            verify_signature(&public, &digest_of(b"firmwarf"), &signature),
+            Err(SignatureError)
+        );
+
+        signature[0] ^= 1;
+        assert_eq!(
+            verify_signature(&public, &digest, &signature),
+            Err(SignatureError)
+        );
+
+        // Truncated or garbage key material degrades to the same error
+        assert_eq!(
+            verify_signature(&public, &digest, &signature[..32]),
+            Err(SignatureError)
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Semver-ish firmware version parsing, shared so the device's
+//! anti-rollback comparison can be unit-tested on the host.
+//!
+//! The app descriptor's version string is whatever the build put there -
+//! `CARGO_PKG_VERSION`, a `git describe`, a hand-rolled tag - so the
+//! parser accepts a leading `v` and ignores pre-release or build
+//! suffixes (`1.2.3-rc1`, `1.2.3+gabcdef`) rather than implementing all
+//! of semver. Ordering is plain numeric major/minor/patch; a suffix
+//! never influences it.
+
+/// A parsed `major.minor.patch` version; derived ordering compares the
+/// components numerically, so `1.2.10` beats `1.2.9`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Parses a version string, or `None` for anything that does not start
+/// with one to three dot-separated numbers. Missing components count as
+/// zero, so `"1.2"` is `1.2.0`.
+pub fn parse(s: &str) -> Option<Version> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix('v')
+        .or_else(|| s.strip_prefix('V'))
+        .unwrap_or(s);
+
+    // Everything after a pre-release or build marker is ignored
+    let core = s.split(['-', '+']).next()?;
+
+    let mut components = core.split('.');
+    let mut parsed = [0_u32; 3];
+
+    for slot in parsed.iter_mut() {
+        match components.next() {
+            Some(part) => *slot = part.parse().ok()?,
+            None => break,
+        }
+    }
+
+    if components.next().is_some() {
+        return None;
+    }
+
+    Some(Version {
+        major: parsed[0],
+        minor: parsed[1],
+        patch: parsed[2],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(major: u32, minor: u32, patch: u32) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    #[test]
+    fn parses_the_common_shapes() {
+        assert_eq!(parse("1.2.3"), Some(v(1, 2, 3)));
+        assert_eq!(parse("v0.25.0"), Some(v(0, 25, 0)));
+        assert_eq!(parse("1.2"), Some(v(1, 2, 0)));
+        assert_eq!(parse("2"), Some(v(2, 0, 0)));
+        assert_eq!(parse(" 1.2.3 "), Some(v(1, 2, 3)));
+        assert_eq!(v(1, 2, 3).to_string(), "1.2.3");
+    }
+
+    #[test]
+    fn ignores_prerelease_and_build_suffixes() {
+        assert_eq!(parse("1.2.3-rc1"), Some(v(1, 2, 3)));
+        assert_eq!(parse("v1.2.3-4-gabcdef"), Some(v(1, 2, 3)));
+        assert_eq!(parse("1.2.3+20260828"), Some(v(1, 2, 3)));
+    }
+
+    #[test]
+    fn rejects_everything_else() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("firmware"), None);
+        assert_eq!(parse("1.2.3.4"), None);
+        assert_eq!(parse("1..2"), None);
+        assert_eq!(parse("1.2.x"), None);
+    }
+
+    #[test]
+    fn orders_numerically_not_lexically() {
+        assert!(parse("1.2.10") > parse("1.2.9"));
+        assert!(parse("2.0.0") > parse("1.9.9"));
+        assert!(parse("0.25.0") > parse("0.9.9"));
+        assert_eq!(parse("v1.2.3-rc1"), parse("1.2.3"));
+    }
+}
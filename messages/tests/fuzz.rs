@@ -0,0 +1,198 @@
+//! Property suite for the codec: arbitrary bytes, mutated frames and
+//! round-trips of arbitrary valid messages.
+//!
+//! The decoder sits right behind the UART on the device, where any byte
+//! sequence at all can arrive; a panic there reboots the MCU. These
+//! properties pin down that [`codec::take`] and [`codec::decode`]
+//! always return - with an error for garbage, with the original message
+//! for an intact frame - and never panic, whatever the input.
+
+use proptest::prelude::*;
+
+use messages::{
+    codec, Caps, DeltaBase, DeltaOp, MessageTypeHost, MessageTypeMcu, UpdateEnd, UpdateSegment,
+    UpdateSegmentCompressed, UpdateSegmentDelta, UpdateSegmentEncrypted, UpdateStart, HASH_LEN,
+    NONCE_PREFIX_LEN,
+};
+
+fn partition() -> impl Strategy<Value = Option<String>> {
+    proptest::option::of("[a-z0-9_]{1,16}")
+}
+
+fn payload() -> impl Strategy<Value = Vec<u8>> {
+    proptest::collection::vec(any::<u8>(), 0..256)
+}
+
+fn delta_op() -> impl Strategy<Value = DeltaOp> {
+    prop_oneof![
+        (any::<u32>(), any::<u32>())
+            .prop_map(|(src_offset, len)| DeltaOp::Copy { src_offset, len }),
+        payload().prop_map(DeltaOp::Data),
+    ]
+}
+
+fn update_start() -> impl Strategy<Value = UpdateStart> {
+    (
+        any::<u32>(),
+        proptest::option::of(any::<[u8; NONCE_PREFIX_LEN]>()),
+        proptest::option::of(
+            (any::<[u8; HASH_LEN]>(), any::<u32>()).prop_map(|(hash, len)| DeltaBase { hash, len }),
+        ),
+        partition(),
+        proptest::option::of(any::<[u8; HASH_LEN]>()),
+        proptest::option::of(any::<u16>()),
+        any::<bool>(),
+        any::<bool>(),
+    )
+        .prop_map(
+            |(
+                size,
+                nonce_prefix,
+                delta_base,
+                partition,
+                sha256,
+                segment_size,
+                resume,
+                progress,
+            )| {
+                UpdateStart {
+                    size,
+                    nonce_prefix,
+                    delta_base,
+                    partition,
+                    sha256,
+                    segment_size,
+                    resume,
+                    progress,
+                }
+            },
+        )
+}
+
+/// Every host message variant, with arbitrary field values.
+fn host_message() -> impl Strategy<Value = MessageTypeHost> {
+    prop_oneof![
+        update_start().prop_map(MessageTypeHost::UpdateStart),
+        (any::<u16>(), payload())
+            .prop_map(|(id, data)| MessageTypeHost::UpdateSegment(UpdateSegment { id, data })),
+        (any::<u16>(), any::<u16>(), payload()).prop_map(|(id, raw_len, data)| {
+            MessageTypeHost::UpdateSegmentCompressed(UpdateSegmentCompressed { id, raw_len, data })
+        }),
+        (any::<u16>(), proptest::option::of(any::<u16>()), payload()).prop_map(
+            |(id, raw_len, data)| {
+                MessageTypeHost::UpdateSegmentEncrypted(UpdateSegmentEncrypted {
+                    id,
+                    raw_len,
+                    data,
+                })
+            }
+        ),
+        (any::<u16>(), delta_op()).prop_map(|(id, op)| MessageTypeHost::UpdateSegmentDelta(
+            UpdateSegmentDelta { id, op }
+        )),
+        (
+            proptest::option::of(payload()),
+            any::<bool>(),
+            proptest::option::of(any::<[u8; HASH_LEN]>()),
+        )
+            .prop_map(|(signature, reboot, sha256)| {
+                MessageTypeHost::UpdateEnd(UpdateEnd {
+                    signature,
+                    reboot,
+                    sha256,
+                })
+            }),
+        Just(MessageTypeHost::Cancel),
+        Just(MessageTypeHost::GetInfo),
+        any::<u32>().prop_map(MessageTypeHost::SetBaud),
+        Just(MessageTypeHost::Ping),
+        Just(MessageTypeHost::MarkValid),
+        Just(MessageTypeHost::Rollback),
+        any::<u32>().prop_map(|interval_ms| MessageTypeHost::AdcStart { interval_ms }),
+        Just(MessageTypeHost::AdcStop),
+        any::<u8>().prop_map(MessageTypeHost::SetLogLevel),
+        any::<u32>().prop_map(MessageTypeHost::TimedPing),
+        (partition(), any::<u32>(), any::<u32>()).prop_map(|(partition, offset, len)| {
+            MessageTypeHost::ReadFlash {
+                partition,
+                offset,
+                len,
+            }
+        }),
+        (partition(), any::<u32>(), any::<u32>()).prop_map(|(partition, offset, len)| {
+            MessageTypeHost::EraseRegion {
+                partition,
+                offset,
+                len,
+            }
+        }),
+        (any::<bool>(), any::<bool>())
+            .prop_map(|(enabled, force)| MessageTypeHost::SetTrace { enabled, force }),
+        Just(MessageTypeHost::DumpTrace),
+        any::<bool>().prop_map(|reset| MessageTypeHost::GetStats { reset }),
+        (any::<u8>(), any::<u32>()).prop_map(|(protocol_version, bits)| MessageTypeHost::Hello {
+            protocol_version,
+            capabilities: Caps::from_bits(bits),
+        }),
+        (partition(), any::<u32>())
+            .prop_map(|(partition, len)| MessageTypeHost::HashFlash { partition, len }),
+        Just(MessageTypeHost::GetUpdateInfo),
+    ]
+}
+
+proptest! {
+    /// Arbitrary bytes - line noise, a peer speaking something else
+    /// entirely - decode to an error or a message, never a panic. Both
+    /// directions are covered: the device parses host frames, the
+    /// flasher parses device frames.
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let _ = codec::take::<MessageTypeHost>(&bytes);
+        let _ = codec::take::<MessageTypeMcu>(&bytes);
+        let _ = codec::decode::<MessageTypeHost>(&bytes);
+        let _ = codec::decode::<MessageTypeMcu>(&bytes);
+    }
+
+    /// Any strict prefix of a valid frame is reported as an error -
+    /// usually `Truncated` - and never parses as a complete frame.
+    #[test]
+    fn truncated_frames_are_errors(msg in host_message(), cut in 1_usize..64) {
+        let frame = codec::encode(&msg).unwrap();
+        let cut = cut.min(frame.len());
+
+        prop_assert!(codec::decode::<MessageTypeHost>(&frame[..frame.len() - cut]).is_err());
+    }
+
+    /// A single corrupted byte never yields a message: the CRC32 is
+    /// guaranteed to catch any error burst of up to 32 bits, and a
+    /// corruption that derails the parse itself surfaces as one of the
+    /// other decode errors.
+    #[test]
+    fn corrupted_frames_are_errors(
+        msg in host_message(),
+        position in any::<prop::sample::Index>(),
+        mask in 1_u8..=255,
+    ) {
+        let mut frame = codec::encode(&msg).unwrap();
+        let position = position.index(frame.len());
+        frame[position] ^= mask;
+
+        prop_assert!(codec::decode::<MessageTypeHost>(&frame).is_err());
+    }
+
+    /// Every valid message survives the wire: encode then decode is the
+    /// identity, and `take` leaves bytes behind the frame untouched.
+    #[test]
+    fn valid_messages_round_trip(msg in host_message(), trailing in payload()) {
+        let frame = codec::encode(&msg).unwrap();
+
+        prop_assert_eq!(codec::decode::<MessageTypeHost>(&frame).unwrap(), msg.clone());
+
+        let mut wire = frame;
+        wire.extend_from_slice(&trailing);
+
+        let (decoded, rest) = codec::take::<MessageTypeHost>(&wire).unwrap();
+        prop_assert_eq!(decoded, msg);
+        prop_assert_eq!(rest, &trailing[..]);
+    }
+}
@@ -0,0 +1,140 @@
+//! Streams ADC readings to the host as `MessageTypeMcu::Adc` frames.
+//!
+//! A dedicated thread samples one ADC1 channel at a configurable
+//! interval and queues each reading on the update link. The stream
+//! pauses itself while the shared [`mode`](messages::mode) flag reads
+//! `Updating` so samples never interleave with segment acks, and a
+//! full TX queue drops the sample rather than blocking the sampler.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use embedded_hal::adc::{Channel, OneShot};
+
+use esp_idf_hal::adc;
+
+use log::*;
+
+use messages::mode::SharedMode;
+use messages::{AdcSample, MessageTypeMcu};
+
+use crate::uart_update::McuSender;
+
+/// Default sampling interval; the host can pick another via `AdcStart`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a stopped or suspended stream checks whether to resume.
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// Stack size of the sampling thread.
+const STACK_SIZE: usize = 4096;
+
+/// Shared switch for the sample stream, cloneable across threads: the
+/// host flips it with `AdcStart`/`AdcStop`, and the device mode pauses
+/// it while a transfer is in flight.
+#[derive(Clone)]
+pub struct Control {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    running: AtomicBool,
+    mode: SharedMode,
+    interval_ms: AtomicU32,
+}
+
+impl Control {
+    /// `running` is the boot-time default; the host can override it at
+    /// any time. `mode` is the updater-owned device mode the stream
+    /// defers to.
+    pub fn new(running: bool, mode: SharedMode) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                running: AtomicBool::new(running),
+                mode,
+                interval_ms: AtomicU32::new(DEFAULT_INTERVAL.as_millis() as u32),
+            }),
+        }
+    }
+
+    /// Starts (or re-times) the stream; an interval of 0 keeps the
+    /// current one.
+    pub fn start(&self, interval_ms: u32) {
+        if interval_ms > 0 {
+            self.inner.interval_ms.store(interval_ms, Ordering::Relaxed);
+        }
+
+        self.inner.running.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        self.inner.running.store(false, Ordering::Relaxed);
+    }
+
+    fn should_sample(&self) -> bool {
+        self.inner.running.load(Ordering::Relaxed) && !self.inner.mode.is_updating()
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.inner.interval_ms.load(Ordering::Relaxed) as u64)
+    }
+}
+
+/// Spawns the sampling thread on ADC1. `AN` is the pin's attenuation
+/// (e.g. `Atten11dB`), fixed by the caller's `into_analog_atten_*`
+/// conversion. `channel` tags the samples so the host can tell streams
+/// apart; the demo wires GPIO34, which is ADC1 channel 6.
+pub fn spawn<AN, PIN>(
+    adc1: adc::ADC1,
+    mut pin: PIN,
+    channel: u8,
+    control: Control,
+    sender: McuSender,
+) -> anyhow::Result<()>
+where
+    AN: adc::Analog<adc::ADC1>,
+    PIN: Channel<AN, ID = u8> + Send + 'static,
+{
+    let mut powered_adc = adc::PoweredAdc::new(adc1, adc::config::Config::new().calibration(true))?;
+
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut seq: u16 = 0;
+
+            loop {
+                if !control.should_sample() {
+                    thread::sleep(IDLE_POLL);
+                    continue;
+                }
+
+                match nb::block!(powered_adc.read(&mut pin)) {
+                    Ok(value) => {
+                        let sample = AdcSample {
+                            channel,
+                            seq,
+                            value,
+                        };
+
+                        // The sequence number advances even when the queue
+                        // is full, so a dropped sample shows up as a gap on
+                        // the host instead of going unnoticed
+                        seq = seq.wrapping_add(1);
+
+                        if !sender.try_send(MessageTypeMcu::Adc(sample)) {
+                            debug!("TX queue congested, dropping ADC sample");
+                        }
+                    }
+                    Err(err) => warn!("ADC read failed: {}", err),
+                }
+
+                thread::sleep(control.interval());
+            }
+        })?;
+
+    info!("ADC telemetry started on channel {}", channel);
+
+    Ok(())
+}
@@ -0,0 +1,443 @@
+//! BLE transport for the update protocol (`ble_update` feature), for
+//! battery devices without Wi-Fi that get their updates from a phone.
+//!
+//! A Bluedroid GATT server exposes the Nordic UART Service layout -
+//! one write characteristic for host -> MCU frames and one notify
+//! characteristic for MCU -> host frames - so any off-the-shelf NUS
+//! client can speak to it. Frames are the same postcard + [`Checksum`]
+//! envelopes as on the UART: the central fragments them to the
+//! negotiated MTU and this bridge reassembles with the usual framing
+//! layer before injecting into the updater via [`HostLink`], and
+//! notifications going the other way are fragmented the same way.
+//! Since every ATT write is acknowledged at the transport layer, the
+//! updater advertises the large flow-controlled segment size on this
+//! link regardless of the MTU - the MTU only affects throughput.
+//!
+//! Security is "bonding required": characteristic access needs an
+//! encrypted link and pairing is kicked off on connect. Protecting the
+//! image itself stays with the encrypted-segment and signature work.
+//! A central vanishing mid-transfer stops producing messages, so the
+//! update dies through the updater's usual inactivity timeout.
+//!
+//! Needs `CONFIG_BT_ENABLED` and `CONFIG_BT_BLUEDROID_ENABLED` in
+//! sdkconfig (see the commented lines in `sdkconfig.defaults`).
+
+use core::ptr;
+use core::slice;
+
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use log::*;
+
+use esp_idf_sys::*;
+
+use messages::{codec, MessageTypeHost, MessageTypeMcu};
+
+use crate::uart_update::{HostLink, MAX_REASSEMBLY};
+
+// Both bridges would fight over the single alternate-transport reply
+// slot in the updater; a build wanting both needs per-bridge slots
+// first.
+#[cfg(feature = "tcp_update")]
+compile_error!("tcp_update and ble_update cannot be enabled together (yet)");
+
+/// GATT application id; arbitrary, we register exactly one.
+const APP_ID: u16 = 0;
+
+/// Handles the service needs: the service itself, two characteristics
+/// (declaration + value each) and the notify characteristic's CCCD.
+const HANDLE_COUNT: u16 = 8;
+
+/// The largest MTU Bluedroid supports; the central negotiates downward
+/// from here.
+const MAX_MTU: u16 = 517;
+
+/// Stack size of the notify thread.
+const STACK_SIZE: usize = 6144;
+
+/// Depth of the reply queue, mirroring the UART command queue.
+const REPLY_QUEUE_DEPTH: usize = 32;
+
+/// Nordic UART Service UUID bytes (little-endian), parameterized on the
+/// short id: 1 = service, 2 = write (host -> MCU), 3 = notify.
+const fn nus_uuid(short: u8) -> [u8; 16] {
+    [
+        0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, short, 0x00, 0x40,
+        0x6e,
+    ]
+}
+
+const SERVICE_UUID: [u8; 16] = nus_uuid(1);
+const WRITE_UUID: [u8; 16] = nus_uuid(2);
+const NOTIFY_UUID: [u8; 16] = nus_uuid(3);
+
+/// Raw advertising payload: general-discoverable flags plus the
+/// complete device name.
+const ADV_DATA: [u8; 20] = [
+    0x02, 0x01, 0x06, // flags: LE general discoverable, no BR/EDR
+    0x10, 0x09, b'r', b'u', b's', b't', b'-', b'e', b's', b'p', b'3', b'2', b'-', b'd', b'e', b'm',
+    b'o',
+];
+
+// Connection state shared between the Bluedroid callback task (writer)
+// and the notify thread (reader). All small enough to be atomics.
+static GATTS_IF: AtomicU8 = AtomicU8::new(ESP_GATT_IF_NONE as u8);
+static CONN_ID: AtomicU16 = AtomicU16::new(0);
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static MTU: AtomicU16 = AtomicU16::new(23);
+static SERVICE_HANDLE: AtomicU16 = AtomicU16::new(0);
+static WRITE_HANDLE: AtomicU16 = AtomicU16::new(0);
+static NOTIFY_HANDLE: AtomicU16 = AtomicU16::new(0);
+
+// Written once in `spawn` before the stack starts delivering events;
+// afterwards only the Bluedroid callback task touches them, so the
+// unsynchronized access below is single-threaded in practice.
+static mut LINK: Option<HostLink> = None;
+static mut REASSEMBLY: Vec<u8> = Vec::new();
+
+/// Brings the BLE stack up and starts advertising. `link` should be
+/// tagged [`Link::Ble`](crate::uart_update::Link) so replies come back
+/// over the air.
+pub fn spawn(link: HostLink) -> anyhow::Result<()> {
+    let (reply_tx, reply_rx) = mpsc::sync_channel::<MessageTypeMcu>(REPLY_QUEUE_DEPTH);
+
+    // Unlike the per-connection TCP registration, the BLE bridge holds
+    // its reply queue for its lifetime and drops frames itself while no
+    // central is connected
+    link.connect(reply_tx);
+
+    unsafe {
+        LINK = Some(link);
+    }
+
+    init_stack()?;
+
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || notify_loop(reply_rx))?;
+
+    info!("BLE update service advertising");
+
+    Ok(())
+}
+
+fn init_stack() -> Result<(), EspError> {
+    unsafe {
+        // Classic BT is never used; hand its controller RAM back
+        esp!(esp_bt_controller_mem_release(
+            esp_bt_mode_t_ESP_BT_MODE_CLASSIC_BT
+        ))?;
+
+        // BT_CONTROLLER_INIT_CONFIG_DEFAULT() is a C macro bindgen
+        // cannot export; these are its values for an ESP32 with the
+        // default sdkconfig (components/bt/include/esp_bt.h, IDF 4.x)
+        let mut bt_cfg = esp_bt_controller_config_t {
+            controller_task_stack_size: 3584,
+            controller_task_prio: 23,
+            hci_uart_no: 1,
+            hci_uart_baudrate: 921_600,
+            scan_duplicate_mode: 0,
+            scan_duplicate_type: 0,
+            normal_adv_size: 20,
+            mesh_adv_size: 0,
+            send_adv_reserved_size: 1000,
+            controller_debug_flag: 0,
+            mode: esp_bt_mode_t_ESP_BT_MODE_BLE as u8,
+            ble_max_conn: 3,
+            bt_max_acl_conn: 0,
+            bt_sco_datapath: 0,
+            auto_latency: false,
+            bt_legacy_auth_vs_evt: false,
+            bt_max_sync_conn: 0,
+            ble_sca: 1,
+            pcm_role: 0,
+            pcm_polar: 0,
+            hli: false,
+            magic: ESP_BT_CONTROLLER_CONFIG_MAGIC_VAL,
+        };
+
+        esp!(esp_bt_controller_init(&mut bt_cfg))?;
+        esp!(esp_bt_controller_enable(esp_bt_mode_t_ESP_BT_MODE_BLE))?;
+        esp!(esp_bluedroid_init())?;
+        esp!(esp_bluedroid_enable())?;
+
+        esp!(esp_ble_gatts_register_callback(Some(gatts_handler)))?;
+        esp!(esp_ble_gap_register_callback(Some(gap_handler)))?;
+
+        // Bond with whatever the peer can manage, no keypad or display
+        // on this end
+        let mut auth_req = ESP_LE_AUTH_REQ_SC_BOND as u8;
+        esp!(esp_ble_gap_set_security_param(
+            esp_ble_sm_param_t_ESP_BLE_SM_AUTHEN_REQ_MODE,
+            &mut auth_req as *mut _ as *mut c_types::c_void,
+            1,
+        ))?;
+
+        let mut io_cap = ESP_IO_CAP_NONE as u8;
+        esp!(esp_ble_gap_set_security_param(
+            esp_ble_sm_param_t_ESP_BLE_SM_IOCAP_MODE,
+            &mut io_cap as *mut _ as *mut c_types::c_void,
+            1,
+        ))?;
+
+        esp!(esp_ble_gatts_app_register(APP_ID))?;
+        esp!(esp_ble_gatt_set_local_mtu(MAX_MTU))?;
+    }
+
+    Ok(())
+}
+
+fn uuid128(bytes: [u8; 16]) -> esp_bt_uuid_t {
+    esp_bt_uuid_t {
+        len: ESP_UUID_LEN_128 as u16,
+        uuid: esp_bt_uuid_t__bindgen_ty_1 { uuid128: bytes },
+    }
+}
+
+fn adv_params() -> esp_ble_adv_params_t {
+    esp_ble_adv_params_t {
+        adv_int_min: 0x20,
+        adv_int_max: 0x40,
+        adv_type: esp_ble_adv_type_t_ADV_TYPE_IND,
+        own_addr_type: esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC,
+        peer_addr: [0; 6],
+        peer_addr_type: esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC,
+        channel_map: esp_ble_adv_channel_t_ADV_CHNL_ALL,
+        adv_filter_policy: esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_ANY_CON_ANY,
+    }
+}
+
+unsafe extern "C" fn gatts_handler(
+    event: esp_gatts_cb_event_t,
+    gatts_if: esp_gatt_if_t,
+    param: *mut esp_ble_gatts_cb_param_t,
+) {
+    match event {
+        esp_gatts_cb_event_t_ESP_GATTS_REG_EVT => {
+            GATTS_IF.store(gatts_if, Ordering::SeqCst);
+
+            esp_ble_gap_set_device_name(b"rust-esp32-demo\0".as_ptr() as *const _);
+            esp_ble_gap_config_adv_data_raw(ADV_DATA.as_ptr() as *mut _, ADV_DATA.len() as _);
+
+            let mut service_id = esp_gatt_srvc_id_t {
+                is_primary: true,
+                id: esp_gatt_id_t {
+                    inst_id: 0,
+                    uuid: uuid128(SERVICE_UUID),
+                },
+            };
+
+            esp_ble_gatts_create_service(gatts_if, &mut service_id, HANDLE_COUNT);
+        }
+        esp_gatts_cb_event_t_ESP_GATTS_CREATE_EVT => {
+            let service_handle = (*param).create.service_handle;
+            SERVICE_HANDLE.store(service_handle, Ordering::SeqCst);
+
+            esp_ble_gatts_start_service(service_handle);
+
+            // The notify characteristic and its CCCD follow from the
+            // ADD_CHAR events; Bluedroid wants them added one by one
+            let mut uuid = uuid128(WRITE_UUID);
+            esp_ble_gatts_add_char(
+                service_handle,
+                &mut uuid,
+                (ESP_GATT_PERM_WRITE_ENCRYPTED) as u16,
+                (ESP_GATT_CHAR_PROP_BIT_WRITE | ESP_GATT_CHAR_PROP_BIT_WRITE_NR) as u8,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+        }
+        esp_gatts_cb_event_t_ESP_GATTS_ADD_CHAR_EVT => {
+            let added = &(*param).add_char;
+
+            if added.char_uuid.uuid.uuid128 == WRITE_UUID {
+                WRITE_HANDLE.store(added.attr_handle, Ordering::SeqCst);
+
+                let mut uuid = uuid128(NOTIFY_UUID);
+                esp_ble_gatts_add_char(
+                    SERVICE_HANDLE.load(Ordering::SeqCst),
+                    &mut uuid,
+                    (ESP_GATT_PERM_READ_ENCRYPTED) as u16,
+                    (ESP_GATT_CHAR_PROP_BIT_NOTIFY) as u8,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                );
+            } else {
+                NOTIFY_HANDLE.store(added.attr_handle, Ordering::SeqCst);
+
+                let mut cccd = esp_bt_uuid_t {
+                    len: ESP_UUID_LEN_16 as u16,
+                    uuid: esp_bt_uuid_t__bindgen_ty_1 {
+                        uuid16: ESP_GATT_UUID_CHAR_CLIENT_CONFIG as u16,
+                    },
+                };
+
+                esp_ble_gatts_add_char_descr(
+                    SERVICE_HANDLE.load(Ordering::SeqCst),
+                    &mut cccd,
+                    (ESP_GATT_PERM_READ | ESP_GATT_PERM_WRITE) as u16,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                );
+            }
+        }
+        esp_gatts_cb_event_t_ESP_GATTS_CONNECT_EVT => {
+            let connect = &mut (*param).connect;
+
+            info!("BLE central connected");
+
+            CONN_ID.store(connect.conn_id, Ordering::SeqCst);
+            MTU.store(23, Ordering::SeqCst);
+            REASSEMBLY.clear();
+            CONNECTED.store(true, Ordering::SeqCst);
+
+            // Bonding required: pair (or re-encrypt with stored keys)
+            // right away instead of waiting for the first access
+            esp_ble_set_encryption(
+                connect.remote_bda.as_mut_ptr(),
+                esp_ble_sec_act_t_ESP_BLE_SEC_ENCRYPT_MITM,
+            );
+        }
+        esp_gatts_cb_event_t_ESP_GATTS_MTU_EVT => {
+            MTU.store((*param).mtu.mtu, Ordering::SeqCst);
+        }
+        esp_gatts_cb_event_t_ESP_GATTS_WRITE_EVT => {
+            let write = &(*param).write;
+
+            if write.handle == WRITE_HANDLE.load(Ordering::SeqCst) && !write.is_prep {
+                receive(slice::from_raw_parts(write.value, write.len as usize));
+            }
+
+            if write.need_rsp {
+                esp_ble_gatts_send_response(
+                    gatts_if,
+                    write.conn_id,
+                    write.trans_id,
+                    esp_gatt_status_t_ESP_GATT_OK,
+                    ptr::null_mut(),
+                );
+            }
+        }
+        esp_gatts_cb_event_t_ESP_GATTS_DISCONNECT_EVT => {
+            // A central gone mid-transfer stops producing messages and
+            // the updater's inactivity timeout aborts the update
+            info!("BLE central disconnected");
+
+            CONNECTED.store(false, Ordering::SeqCst);
+            REASSEMBLY.clear();
+
+            esp_ble_gap_start_advertising(&mut adv_params());
+        }
+        _ => (),
+    }
+}
+
+unsafe extern "C" fn gap_handler(
+    event: esp_gap_ble_cb_event_t,
+    param: *mut esp_ble_gap_cb_param_t,
+) {
+    match event {
+        esp_gap_ble_cb_event_t_ESP_GAP_BLE_ADV_DATA_RAW_SET_COMPLETE_EVT => {
+            esp_ble_gap_start_advertising(&mut adv_params());
+        }
+        esp_gap_ble_cb_event_t_ESP_GAP_BLE_AUTH_CMPL_EVT => {
+            if (*param).ble_security.auth_cmpl.success {
+                info!("BLE bonding complete");
+            } else {
+                warn!("BLE bonding failed");
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Runs on the Bluedroid callback task: reassembles frames out of the
+/// MTU-sized writes with the same rules as the other transports.
+unsafe fn receive(data: &[u8]) {
+    let link = match LINK.as_ref() {
+        Some(link) => link,
+        None => return,
+    };
+
+    REASSEMBLY.extend_from_slice(data);
+
+    loop {
+        match codec::take::<MessageTypeHost>(&REASSEMBLY) {
+            Ok((payload, rest)) => {
+                let consumed = REASSEMBLY.len() - rest.len();
+                REASSEMBLY.drain(..consumed);
+
+                if !link.inject(payload) {
+                    warn!("Updater gone, dropping frame");
+                }
+            }
+            Err(codec::DecodeError::BadCrc { frame_len, .. }) => {
+                warn!("Dropping frame with bad checksum");
+                REASSEMBLY.drain(..frame_len);
+            }
+            Err(codec::DecodeError::Truncated) => break,
+            Err(err) => {
+                warn!("Dropping undecodable bytes: {:?}", err);
+                REASSEMBLY.clear();
+                break;
+            }
+        }
+    }
+
+    if REASSEMBLY.len() > MAX_REASSEMBLY {
+        warn!(
+            "Reassembly buffer overflow, dropping {} bytes",
+            REASSEMBLY.len()
+        );
+        REASSEMBLY.clear();
+    }
+}
+
+/// Fragments outgoing frames to the negotiated MTU and ships them as
+/// notifications; the central's framing layer reassembles.
+fn notify_loop(reply_rx: mpsc::Receiver<MessageTypeMcu>) {
+    while let Ok(msg) = reply_rx.recv() {
+        if !CONNECTED.load(Ordering::SeqCst) {
+            // Out-of-band frames with nobody listening, same as a UART
+            // host that went away
+            continue;
+        }
+
+        let frame = match codec::encode(&msg) {
+            Ok(frame) => frame,
+            Err(err) => {
+                warn!("Dropping unserializable frame: {:?}", err);
+                continue;
+            }
+        };
+
+        // A notification carries at most MTU - 3 payload bytes
+        let chunk = (MTU.load(Ordering::SeqCst) as usize)
+            .saturating_sub(3)
+            .max(1);
+
+        let gatts_if = GATTS_IF.load(Ordering::SeqCst);
+        let conn_id = CONN_ID.load(Ordering::SeqCst);
+        let handle = NOTIFY_HANDLE.load(Ordering::SeqCst);
+
+        for piece in frame.chunks(chunk) {
+            let sent = unsafe {
+                esp!(esp_ble_gatts_send_indicate(
+                    gatts_if,
+                    conn_id,
+                    handle,
+                    piece.len() as u16,
+                    piece.as_ptr() as *mut _,
+                    false,
+                ))
+            };
+
+            if sent.is_err() {
+                // Connection went away under us; drop the rest
+                break;
+            }
+        }
+    }
+}
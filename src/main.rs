@@ -95,6 +95,17 @@ use st7789;
 
 use epd_waveshare::{epd4in2::*, graphics::VarDisplay, prelude::*};
 
+mod adc_telemetry;
+#[cfg(feature = "ble_update")]
+mod ble_update;
+mod protocol_log;
+mod resume;
+mod simple_ota;
+mod status_led;
+#[cfg(feature = "tcp_update")]
+mod tcp_update;
+mod uart_update;
+
 #[allow(dead_code)]
 #[cfg(not(feature = "qemu"))]
 const SSID: &str = env!("RUST_ESP32_STD_DEMO_WIFI_SSID");
@@ -124,8 +135,15 @@ fn main() -> Result<()> {
     #[cfg(not(esp_idf_version = "4.3"))]
     test_fs()?;
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // One coarse flag the update service flips around a transfer; the
+    // log mirror and the ADC stream watch it to stay off the link
+    #[allow(unused)]
+    let device_mode = messages::mode::SharedMode::default();
+
+    // Bind the log crate to the ESP Logging facilities, plus a mirror
+    // that streams records to the host once the update link is up
+    #[allow(unused)]
+    let logging = protocol_log::init(device_mode.clone());
 
     // Get backtraces from anyhow; only works for Xtensa arch currently
     // TODO: No longer working with ESP-IDF 4.3.1+
@@ -144,6 +162,132 @@ fn main() -> Result<()> {
     #[allow(unused)]
     let default_nvs = Arc::new(EspDefaultNvs::new()?);
 
+    // Count the boot and say why we reset before anything below gets a
+    // chance to fail; the store is handed on to the update service,
+    // which keeps the resume checkpoint in the same NVS namespace
+    #[allow(unused)]
+    let mut resume_store = resume::Store::new(default_nvs.clone());
+    uart_update::log_boot_diagnostics(resume_store.count_boot());
+
+    #[cfg(any(esp32, esp32s2, esp32s3))]
+    #[allow(unused)]
+    let telemetry = adc_telemetry::Control::new(true, device_mode.clone());
+
+    // The devkit's onboard LED mirrors the update state; boards that
+    // use GPIO2 for something else pass StatusLed::disabled() instead
+    #[cfg(esp32)]
+    #[allow(unused)]
+    let led = status_led::spawn(pins.gpio2.into_output()?)?;
+    #[cfg(not(esp32))]
+    #[allow(unused)]
+    let led = status_led::StatusLed::disabled();
+
+    // No CTS/RTS wired on the demo board; Config::default() keeps
+    // flow control off to match
+    #[cfg(all(
+        any(esp32, esp32s2, esp32s3),
+        not(any(
+            feature = "uart0_update",
+            feature = "usb_serial_update",
+            feature = "usb_cdc_update"
+        ))
+    ))]
+    let serial_pins: esp_idf_hal::serial::Pins<_, _> = esp_idf_hal::serial::Pins {
+        tx: pins.gpio32,
+        rx: pins.gpio33,
+        cts: None,
+        rts: None,
+    };
+
+    #[cfg(all(
+        any(esp32, esp32s2, esp32s3),
+        not(any(
+            feature = "uart0_update",
+            feature = "usb_serial_update",
+            feature = "usb_cdc_update"
+        ))
+    ))]
+    #[allow(unused)]
+    let (updater, mcu_sender, host_link) = uart_update::spawn(
+        peripherals.uart1,
+        serial_pins,
+        update_config(),
+        device_mode,
+        telemetry.clone(),
+        logging,
+        led,
+        resume_store,
+    )?;
+
+    // The console UART doubles as the update link; spawn silences the
+    // console so the two cannot collide on the wire
+    #[cfg(all(esp32, feature = "uart0_update"))]
+    let serial_pins: esp_idf_hal::serial::Pins<_, _> = esp_idf_hal::serial::Pins {
+        tx: pins.gpio1,
+        rx: pins.gpio3,
+        cts: None,
+        rts: None,
+    };
+
+    #[cfg(all(esp32, feature = "uart0_update"))]
+    #[allow(unused)]
+    let (updater, mcu_sender, host_link) = uart_update::spawn(
+        peripherals.uart0,
+        serial_pins,
+        update_config(),
+        device_mode,
+        telemetry.clone(),
+        logging,
+        led,
+        resume_store,
+    )?;
+
+    // S3 devkits whose only connector is the native USB socket
+    #[cfg(all(esp32s3, feature = "usb_serial_update"))]
+    #[allow(unused)]
+    let (updater, mcu_sender, host_link) = uart_update::spawn_usb_serial_jtag(
+        update_config(),
+        device_mode,
+        telemetry.clone(),
+        logging,
+        led,
+        resume_store,
+    )?;
+
+    // S2/S3 boards driving the USB OTG port as a TinyUSB CDC-ACM
+    // device; the S2 has no USB-Serial-JTAG, so this is its USB option
+    #[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+    #[allow(unused)]
+    let (updater, mcu_sender, host_link) = uart_update::spawn_usb_cdc(
+        update_config(),
+        device_mode,
+        telemetry.clone(),
+        logging,
+        led,
+        resume_store,
+    )?;
+
+    // Updates over the air for boards whose UART is buried in the
+    // enclosure; the S2 has no radio for this (and no Bluedroid)
+    #[cfg(all(any(esp32, esp32s3), feature = "ble_update"))]
+    ble_update::spawn(host_link.tagged(uart_update::Link::Ble))?;
+
+    // The A2 readings stream to the host over the update link now,
+    // instead of cluttering the console log
+    #[cfg(esp32)]
+    adc_telemetry::spawn(
+        peripherals.adc1,
+        pins.gpio34.into_analog_atten_11db()?,
+        6, // GPIO34 is ADC1 channel 6
+        telemetry,
+        mcu_sender,
+    )?;
+
+    // Reaching this line is the demo's whole self-test: the update
+    // service spawned and nothing above bailed out
+    #[cfg(any(esp32, esp32s2, esp32s3))]
+    uart_update::confirm_running_image(uart_update::StackCameUp, uart_update::SELF_TEST_DEADLINE);
+
     #[cfg(feature = "ttgo")]
     ttgo_hello_world(
         pins.gpio4,
@@ -215,8 +359,14 @@ fn main() -> Result<()> {
         default_nvs.clone(),
     )?;
 
+    // With the network up, updates are accepted over TCP too, feeding
+    // the same updater as the UART
+    #[cfg(feature = "tcp_update")]
+    tcp_update::spawn(tcp_update::PORT, host_link)?;
+
     #[allow(clippy::redundant_clone)]
     #[cfg(feature = "qemu")]
+    #[allow(unused)]
     let eth = eth_configure(Box::new(EspEth::new_openeth(
         netif_stack.clone(),
         sys_loop_stack.clone(),
@@ -224,6 +374,7 @@ fn main() -> Result<()> {
 
     #[allow(clippy::redundant_clone)]
     #[cfg(feature = "ip101")]
+    #[allow(unused)]
     let eth = eth_configure(Box::new(EspEth::new_rmii(
         netif_stack.clone(),
         sys_loop_stack.clone(),
@@ -244,6 +395,7 @@ fn main() -> Result<()> {
     )?))?;
 
     #[cfg(feature = "w5500")]
+    #[allow(unused)]
     let eth = eth_configure(Box::new(EspEth::new_spi(
         netif_stack.clone(),
         sys_loop_stack.clone(),
@@ -298,70 +450,90 @@ fn main() -> Result<()> {
         led_power.set_low()?;
     }
 
-    let mut wait = mutex.0.lock().unwrap();
-
-    #[cfg(esp32)]
-    let mut hall_sensor = peripherals.hall_sensor;
+    // The S2 demo ends on request: the /ulp_start page posts a cycle
+    // count, the peripherals are torn down and the ULP takes over
+    #[cfg(esp32s2)]
+    {
+        let mut wait = mutex.0.lock().unwrap();
+
+        let cycles = loop {
+            if let Some(cycles) = *wait {
+                break cycles;
+            } else {
+                wait = mutex
+                    .1
+                    .wait_timeout(wait, Duration::from_secs(1))
+                    .unwrap()
+                    .0;
+            }
+        };
 
-    #[cfg(esp32)]
-    let mut a2 = pins.gpio34.into_analog_atten_11db()?;
-    #[cfg(any(esp32s2, esp32s3))]
-    let mut a2 = pins.gpio2.into_analog_atten_11db()?;
-    #[cfg(esp32c3)]
-    let mut a2 = pins.gpio2.into_analog_atten_11db()?;
+        for s in 0..3 {
+            info!("Shutting down in {} secs", 3 - s);
+            thread::sleep(Duration::from_secs(1));
+        }
 
-    let mut powered_adc1 = adc::PoweredAdc::new(
-        peripherals.adc1,
-        adc::config::Config::new().calibration(true),
-    )?;
+        drop(httpd);
+        info!("Httpd stopped");
 
-    #[allow(unused)]
-    let cycles = loop {
-        if let Some(cycles) = *wait {
-            break cycles;
-        } else {
-            wait = mutex
-                .1
-                .wait_timeout(wait, Duration::from_secs(1))
-                .unwrap()
-                .0;
-
-            #[cfg(esp32)]
-            log::info!(
-                "Hall sensor reading: {}mV",
-                powered_adc1.read(&mut hall_sensor).unwrap()
-            );
-            log::info!(
-                "A2 sensor reading: {}mV",
-                powered_adc1.read(&mut a2).unwrap()
-            );
+        #[cfg(not(feature = "qemu"))]
+        {
+            drop(wifi);
+            info!("Wifi stopped");
         }
-    };
-
-    for s in 0..3 {
-        info!("Shutting down in {} secs", 3 - s);
-        thread::sleep(Duration::from_secs(1));
-    }
 
-    drop(httpd);
-    info!("Httpd stopped");
+        start_ulp(peripherals.ulp, cycles)?;
 
-    #[cfg(not(feature = "qemu"))]
-    {
-        drop(wifi);
-        info!("Wifi stopped");
+        return Ok(());
     }
 
-    #[cfg(any(feature = "qemu", feature = "w5500", feature = "ip101"))]
+    // Everything else is a long-running device: park on the update
+    // service instead of sleeping in a loop, so a thread that dies -
+    // by panic or by a torn channel - comes back as this function's
+    // error instead of being swallowed
+    #[cfg(not(esp32s2))]
     {
-        let _eth_peripherals = eth.release()?;
-        info!("Eth stopped");
+        updater.join()?;
+
+        bail!("The update service stopped unexpectedly");
     }
+}
 
-    #[cfg(esp32s2)]
-    start_ulp(peripherals.ulp, cycles)?;
+/// The updater configuration shared by every update transport. An
+/// Ed25519 verifying key can be embedded at build time:
+///
+/// ```text
+/// UPDATE_VERIFYING_KEY=<64 hex digits> cargo build ...
+/// ```
+///
+/// which also makes signatures mandatory - a build that embeds a key
+/// has no reason to accept unsigned images. A malformed value panics
+/// the boot loudly instead of silently shipping an unverified build.
+#[cfg(any(esp32, esp32s2, esp32s3))]
+fn update_config() -> uart_update::Config {
+    let mut config = uart_update::Config::default();
+
+    if let Some(hex) = option_env!("UPDATE_VERIFYING_KEY") {
+        let mut key = [0_u8; messages::PUBLIC_KEY_LEN];
+
+        assert_eq!(
+            hex.len(),
+            2 * key.len(),
+            "UPDATE_VERIFYING_KEY must be {} hex digits",
+            2 * key.len()
+        );
+        for (i, chunk) in hex.as_bytes().chunks(2).enumerate() {
+            key[i] = std::str::from_utf8(chunk)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .expect("UPDATE_VERIFYING_KEY must be hex digits only");
+        }
 
-    Ok(())
+        config.verifying_key = Some(key);
+        config.require_signature = true;
+    }
+
+    config
 }
 
 #[allow(clippy::vec_init_then_push)]
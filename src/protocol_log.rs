@@ -0,0 +1,151 @@
+//! Mirrors `log` crate output to the host as `MessageTypeMcu::Log`
+//! frames, for boards whose console UART is not wired up.
+//!
+//! Every record still goes to the console via `EspLogger`; the mirror
+//! is an add-on that switches on once [`Control::attach`] wires it to
+//! the update link. The mirror never blocks: records are dropped while
+//! no link exists or when the TX queue is congested, and only warnings
+//! and errors pass while the shared [`mode`](messages::mode) flag
+//! reads `Updating`, so logging from the serial thread itself cannot
+//! deadlock on the very queue it drains.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::log::EspLogger;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use messages::mode::SharedMode;
+use messages::{LogRecord, MessageTypeMcu};
+
+use crate::uart_update::McuSender;
+
+/// Boot-time mirror severity; the host can change it with `SetLogLevel`.
+const DEFAULT_MIRROR_LEVEL: Level = Level::Info;
+
+/// Shared switchboard of the mirror, cloneable across threads: the
+/// updater applies the host's `SetLogLevel`, the device mode throttles
+/// the mirror around a transfer, and the update service attaches its
+/// TX queue once the serial threads are up.
+#[derive(Clone)]
+pub struct Control {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    sender: Mutex<Option<McuSender>>,
+    /// Maximum mirrored severity in the `log` crate's numbering
+    /// (1 = error .. 5 = trace); 0 switches the mirror off.
+    level: AtomicU8,
+    mode: SharedMode,
+}
+
+impl Control {
+    /// Wires the mirror to the update link. Records logged before this
+    /// point only reach the console.
+    pub fn attach(&self, sender: McuSender) {
+        *self.inner.sender.lock().unwrap() = Some(sender);
+    }
+
+    /// Applies the host's runtime severity cap and widens the global
+    /// `log` gate accordingly; 0 switches the mirror off.
+    pub fn set_level(&self, level: u8) {
+        self.inner.level.store(level, Ordering::Relaxed);
+
+        // The global gate must pass everything either backend wants;
+        // both filter for themselves below it
+        log::set_max_level(EspLogger.get_max_level().max(level_filter(level)));
+    }
+
+    fn mirror(&self, record: &Record) {
+        if record.level() as u8 > self.inner.level.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // While a transfer is in flight, only warnings and errors are
+        // worth spending ack bandwidth on; everything below is dropped
+        // rather than interleaved with segment traffic
+        if self.inner.mode.is_updating() && record.level() > Level::Warn {
+            return;
+        }
+
+        // try_lock, not lock: a record emitted while another thread is
+        // attaching must be dropped, never waited on - the logger runs
+        // on every thread, the serial one included
+        let sender = match self.inner.sender.try_lock() {
+            Ok(sender) => sender,
+            Err(_) => return,
+        };
+
+        if let Some(sender) = sender.as_ref() {
+            // A full queue drops the record; reporting that through the
+            // logger would just recurse
+            sender.try_send(MessageTypeMcu::Log(LogRecord {
+                level: record.level() as u8,
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+            }));
+        }
+    }
+}
+
+fn level_filter(level: u8) -> LevelFilter {
+    match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// The console logger plus the protocol mirror behind one `log` facade.
+struct ProtocolLogger {
+    console: EspLogger,
+    control: Control,
+}
+
+impl Log for ProtocolLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata)
+            || metadata.level() as u8 <= self.control.inner.level.load(Ordering::Relaxed)
+    }
+
+    fn log(&self, record: &Record) {
+        // EspLogger applies its own (per-target) filtering internally
+        self.console.log(record);
+        self.control.mirror(record);
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+/// Installs the combined console and protocol logger; called once from
+/// `main` in place of `EspLogger::initialize_default()`. `mode` is the
+/// updater-owned device mode the mirror defers to. Returns the handle
+/// the update service uses to attach the link and apply host requests.
+pub fn init(mode: SharedMode) -> Control {
+    let control = Control {
+        inner: Arc::new(Inner {
+            sender: Mutex::new(None),
+            level: AtomicU8::new(0),
+            mode,
+        }),
+    };
+
+    let logger = ProtocolLogger {
+        console: EspLogger,
+        control: control.clone(),
+    };
+
+    log::set_boxed_logger(Box::new(logger)).expect("a logger is already installed");
+
+    // Also sets the global max level
+    control.set_level(DEFAULT_MIRROR_LEVEL as u8);
+
+    control
+}
@@ -0,0 +1,160 @@
+//! Persists minimal transfer state in NVS so an interrupted update can
+//! continue where it stopped instead of restarting from segment zero.
+//!
+//! The updater records the announced image hash and size when a
+//! transfer starts and, every few segments, the count of bytes safely
+//! in flash together with a CRC over them. Checkpoints are only taken
+//! on flash sector boundaries, so a resumed transfer can erase from
+//! the checkpoint onward without clipping kept data. The checkpoint is
+//! advisory: before offering the host an offset, the updater reads the
+//! partial image back and compares the CRC, and anything stale or torn
+//! falls back to a full transfer.
+
+use std::sync::Arc;
+
+use embedded_svc::storage::Storage;
+
+use esp_idf_svc::nvs::EspDefaultNvs;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+
+use log::*;
+
+use serde::{Deserialize, Serialize};
+
+use messages::{UpdateRecord, HASH_LEN};
+
+/// NVS namespace and key holding the checkpoint blob.
+const NAMESPACE: &str = "uart_update";
+const KEY: &str = "resume";
+
+/// Key holding the last successful update's [`UpdateRecord`], in the
+/// same namespace for the same single-handle reason.
+const LAST_UPDATE_KEY: &str = "last_update";
+
+/// Key holding the boot counter, in the same namespace so the update
+/// machinery keeps a single NVS handle.
+const BOOT_COUNT_KEY: &str = "boot_count";
+
+/// Flash sector size; checkpoint offsets are always a multiple of it.
+pub const SECTOR_SIZE: usize = 4096;
+
+/// Everything needed to recognise and continue an interrupted transfer.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// SHA-256 of the complete image, as announced by the host.
+    pub sha256: [u8; HASH_LEN],
+    pub size: u32,
+    /// Bytes safely in flash, a multiple of [`SECTOR_SIZE`].
+    pub offset: u32,
+    /// CRC32 of those bytes as written, to catch a stale or torn slot.
+    pub crc: u32,
+}
+
+/// Checkpoint storage. A device whose NVS cannot be opened gets a
+/// store that forgets everything - resume quietly disabled, updates
+/// otherwise unaffected.
+pub struct Store {
+    storage: Option<EspNvsStorage>,
+    /// Cached by [`count_boot`](Self::count_boot) so the `Info` reply
+    /// does not go back to NVS on every `GetInfo`.
+    boot_count: Option<u32>,
+}
+
+impl Store {
+    pub fn new(nvs: Arc<EspDefaultNvs>) -> Self {
+        let storage = match EspNvsStorage::new_default(nvs, NAMESPACE, true) {
+            Ok(storage) => Some(storage),
+            Err(err) => {
+                warn!("Cannot open NVS, transfers will not be resumable: {}", err);
+                None
+            }
+        };
+
+        Self {
+            storage,
+            boot_count: None,
+        }
+    }
+
+    /// Counts the current boot and returns the new total, starting at 1
+    /// on a fresh device. Called once, early in startup; a store whose
+    /// NVS is unavailable (or holds a garbled counter) returns `None`
+    /// and the diagnostics report the count as unknown.
+    pub fn count_boot(&mut self) -> Option<u32> {
+        let storage = self.storage.as_mut()?;
+
+        let previous = match storage.get_raw(BOOT_COUNT_KEY) {
+            Ok(Some(blob)) => postcard::from_bytes::<u32>(&blob).ok()?,
+            Ok(None) => 0,
+            Err(err) => {
+                warn!("Cannot read the boot counter: {}", err);
+                return None;
+            }
+        };
+
+        let count = previous.saturating_add(1);
+        let blob = postcard::to_allocvec(&count).expect("counter serialization cannot fail");
+
+        if let Err(err) = storage.put_raw(BOOT_COUNT_KEY, blob) {
+            warn!("Cannot save the boot counter: {}", err);
+            return None;
+        }
+
+        self.boot_count = Some(count);
+
+        Some(count)
+    }
+
+    /// The count recorded by [`count_boot`](Self::count_boot) this
+    /// boot; `None` before it ran or when NVS was unavailable.
+    pub fn boot_count(&self) -> Option<u32> {
+        self.boot_count
+    }
+
+    pub fn load(&self) -> Option<Checkpoint> {
+        let blob = self.storage.as_ref()?.get_raw(KEY).ok()??;
+
+        postcard::from_bytes(&blob).ok()
+    }
+
+    pub fn save(&mut self, checkpoint: &Checkpoint) {
+        if let Some(storage) = self.storage.as_mut() {
+            let blob =
+                postcard::to_allocvec(checkpoint).expect("checkpoint serialization cannot fail");
+
+            if let Err(err) = storage.put_raw(KEY, blob) {
+                warn!("Cannot save the resume checkpoint: {}", err);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if let Some(storage) = self.storage.as_mut() {
+            if let Err(err) = storage.remove(KEY) {
+                warn!("Cannot clear the resume checkpoint: {}", err);
+            }
+        }
+    }
+
+    /// Persists what a just-completed update looked like, replacing the
+    /// previous record; an audit trail of one, which is what the flash
+    /// budget of an NVS page is worth here.
+    pub fn save_update(&mut self, record: &UpdateRecord) {
+        if let Some(storage) = self.storage.as_mut() {
+            let blob = postcard::to_allocvec(record).expect("record serialization cannot fail");
+
+            if let Err(err) = storage.put_raw(LAST_UPDATE_KEY, blob) {
+                warn!("Cannot save the update record: {}", err);
+            }
+        }
+    }
+
+    /// The record of the last successful update; `None` on a device
+    /// that never completed one, or whose NVS is unavailable or holds
+    /// a blob from an incompatible build.
+    pub fn last_update(&self) -> Option<UpdateRecord> {
+        let blob = self.storage.as_ref()?.get_raw(LAST_UPDATE_KEY).ok()??;
+
+        postcard::from_bytes(&blob).ok()
+    }
+}
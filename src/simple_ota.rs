@@ -0,0 +1,541 @@
+//! Minimal wrapper around the raw ESP-IDF OTA API.
+//!
+//! The serial update flow only needs a handful of calls, so rather than
+//! pulling in a full OTA abstraction we talk to `esp_idf_sys` directly.
+
+use core::ptr;
+use std::ffi::{CStr, CString};
+
+use esp_idf_sys::*;
+
+/// The IDF error codes carry the real story, so the variants that wrap
+/// one put it into their message; a plain `{}` in a log line or an
+/// `anyhow` chain reads sensibly without digging through Debug output.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no OTA update partition in the partition table")]
+    NoUpdatePartition,
+    #[error("the running partition cannot be determined")]
+    NoRunningPartition,
+    #[error("no partition with the requested label")]
+    UnknownPartition,
+    /// Writing this partition is not allowed (e.g. the running app).
+    #[error("writing this partition is not allowed")]
+    ProtectedPartition,
+    #[error("the image does not fit the target partition")]
+    ImageTooLarge,
+    #[error("starting the OTA update failed: {0}")]
+    Begin(EspError),
+    #[error("erasing the partition failed: {0}")]
+    Erase(EspError),
+    #[error("reading flash failed: {0}")]
+    Read(EspError),
+    #[error("writing flash failed: {0}")]
+    Write(EspError),
+    #[error("finalizing the OTA update failed: {0}")]
+    End(EspError),
+    #[error("switching the boot partition failed: {0}")]
+    SetBootPartition(EspError),
+    #[error("querying the OTA state failed: {0}")]
+    GetState(EspError),
+    #[error("marking the running image valid failed: {0}")]
+    MarkValid(EspError),
+    #[error("rolling back to the previous image failed: {0}")]
+    Rollback(EspError),
+}
+
+/// An in-flight update into the next OTA slot.
+pub struct OtaUpdate {
+    handle: esp_ota_handle_t,
+    partition: *const esp_partition_t,
+}
+
+// The partition pointer references a static table inside the ESP-IDF
+unsafe impl Send for OtaUpdate {}
+
+impl OtaUpdate {
+    /// Starts an update into the next OTA slot.
+    pub fn begin() -> Result<Self, Error> {
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+        if partition.is_null() {
+            return Err(Error::NoUpdatePartition);
+        }
+
+        let mut handle: esp_ota_handle_t = 0;
+        esp!(unsafe { esp_ota_begin(partition, OTA_SIZE_UNKNOWN as usize, &mut handle) })
+            .map_err(Error::Begin)?;
+
+        Ok(Self { handle, partition })
+    }
+
+    /// Appends `data` to the OTA slot.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        esp!(unsafe { esp_ota_write(self.handle, data.as_ptr() as *const _, data.len() as _) })
+            .map_err(Error::Write)?;
+
+        Ok(())
+    }
+
+    /// Finalizes the update and activates the new app for the next boot.
+    /// Restarting is the caller's business - it may still have a status
+    /// message to flush out the UART first.
+    pub fn complete(self) -> Result<(), Error> {
+        esp!(unsafe { esp_ota_end(self.handle) }).map_err(Error::End)?;
+        esp!(unsafe { esp_ota_set_boot_partition(self.partition) })
+            .map_err(Error::SetBootPartition)?;
+
+        Ok(())
+    }
+
+    /// Aborts the update, releasing the OTA handle.
+    pub fn abort(self) {
+        unsafe { esp_ota_abort(self.handle) };
+    }
+}
+
+/// Sectors handed to one `esp_partition_erase_range` call while
+/// preparing a write target. Erasing a full image range takes seconds,
+/// longer than the task watchdog allows in one stretch; chunking lets
+/// the caller feed it in between.
+const ERASE_CHUNK: usize = 64 * 1024;
+
+/// Erases `len` bytes at `offset` in [`ERASE_CHUNK`] steps, calling
+/// `between` after each - the updater thread feeds its watchdog there.
+fn erase_chunked(
+    partition: *const esp_partition_t,
+    offset: usize,
+    len: usize,
+    mut between: impl FnMut(),
+) -> Result<(), Error> {
+    let mut erased = 0;
+
+    while erased < len {
+        let chunk = (len - erased).min(ERASE_CHUNK);
+
+        esp!(unsafe { esp_partition_erase_range(partition, (offset + erased) as _, chunk as _) })
+            .map_err(Error::Erase)?;
+
+        erased += chunk;
+        between();
+    }
+
+    Ok(())
+}
+
+/// An in-flight write into an arbitrary (non-app) partition, looked up by
+/// label. Bypasses the OTA machinery: the range is erased up front and the
+/// data written sequentially, with no boot partition switch at the end.
+pub struct PartitionUpdate {
+    partition: *const esp_partition_t,
+    size: usize,
+    written: usize,
+}
+
+// Like OtaUpdate, the pointer references the static partition table
+unsafe impl Send for PartitionUpdate {}
+
+impl PartitionUpdate {
+    /// Looks up `label` and prepares it for an image of `size` bytes,
+    /// calling `between` after each erased chunk so the caller's
+    /// watchdog stays fed.
+    /// Writing the running app is refused; the bootloader and the
+    /// partition table are not reachable in the first place, since they
+    /// live below the first table entry and the label lookup only knows
+    /// table entries.
+    pub fn begin(label: &str, size: usize, between: impl FnMut()) -> Result<Self, Error> {
+        let label_c = CString::new(label).map_err(|_| Error::UnknownPartition)?;
+
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_ANY,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                label_c.as_ptr(),
+            )
+        };
+
+        if partition.is_null() {
+            return Err(Error::UnknownPartition);
+        }
+
+        let running = unsafe { esp_ota_get_running_partition() };
+        if partition == running {
+            return Err(Error::ProtectedPartition);
+        }
+
+        let capacity = unsafe { (*partition).size } as usize;
+        if size > capacity {
+            return Err(Error::ImageTooLarge);
+        }
+
+        // Erase the whole range we are about to write, rounded up to the
+        // flash sector size
+        let sector = 4096;
+        let erase_len = (size + sector - 1) / sector * sector;
+
+        erase_chunked(partition, 0, erase_len, between)?;
+
+        Ok(Self {
+            partition,
+            size,
+            written: 0,
+        })
+    }
+
+    /// Appends `data` to the partition.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.written + data.len() > self.size {
+            return Err(Error::ImageTooLarge);
+        }
+
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition,
+                self.written as _,
+                data.as_ptr() as *const _,
+                data.len() as _,
+            )
+        })
+        .map_err(Error::Write)?;
+
+        self.written += data.len();
+
+        Ok(())
+    }
+}
+
+/// A resumable write into the next OTA app slot, bypassing the OTA
+/// handle API: `esp_ota_begin` erases the whole slot, which would
+/// destroy the partial image a resumed transfer continues from. The
+/// slot is instead erased from the resume boundary onward and written
+/// sequentially like a plain partition, and the finished image is
+/// activated with `esp_ota_set_boot_partition` - which validates the
+/// image header - once the transfer verifies.
+pub struct SlotUpdate {
+    partition: *const esp_partition_t,
+    size: usize,
+    written: usize,
+}
+
+// Like OtaUpdate, the pointer references the static partition table
+unsafe impl Send for SlotUpdate {}
+
+impl SlotUpdate {
+    /// Prepares the next update slot for an image of `size` bytes,
+    /// keeping the first `resume_offset` bytes (which must sit on a
+    /// flash sector boundary) and erasing the rest of the image range,
+    /// with `between` called after each erased chunk like in
+    /// [`PartitionUpdate::begin`].
+    pub fn begin(size: usize, resume_offset: usize, between: impl FnMut()) -> Result<Self, Error> {
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+        if partition.is_null() {
+            return Err(Error::NoUpdatePartition);
+        }
+
+        let capacity = unsafe { (*partition).size } as usize;
+        if size > capacity {
+            return Err(Error::ImageTooLarge);
+        }
+
+        let sector = 4096;
+        let erase_len = (size + sector - 1) / sector * sector - resume_offset;
+
+        erase_chunked(partition, resume_offset, erase_len, between)?;
+
+        Ok(Self {
+            partition,
+            size,
+            written: resume_offset,
+        })
+    }
+
+    /// Appends `data` to the slot, after the kept bytes.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.written + data.len() > self.size {
+            return Err(Error::ImageTooLarge);
+        }
+
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition,
+                self.written as _,
+                data.as_ptr() as *const _,
+                data.len() as _,
+            )
+        })
+        .map_err(Error::Write)?;
+
+        self.written += data.len();
+
+        Ok(())
+    }
+
+    /// Activates the finished image for the next boot.
+    pub fn complete(self) -> Result<(), Error> {
+        esp!(unsafe { esp_ota_set_boot_partition(self.partition) })
+            .map_err(Error::SetBootPartition)?;
+
+        Ok(())
+    }
+}
+
+/// Size in bytes of the OTA slot the next update would go to, or `None`
+/// on partition tables without one (e.g. the default single-factory-app
+/// table).
+pub fn update_slot_size() -> Option<u32> {
+    update_slot().map(|slot| slot.size)
+}
+
+/// Label, flash offset and size of a partition, for the host's `GetInfo`.
+pub struct SlotDesc {
+    pub label: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+fn describe(partition: *const esp_partition_t) -> SlotDesc {
+    unsafe {
+        SlotDesc {
+            label: CStr::from_ptr((*partition).label.as_ptr() as *const _)
+                .to_string_lossy()
+                .into_owned(),
+            offset: (*partition).address,
+            size: (*partition).size,
+        }
+    }
+}
+
+/// The slot the running app booted from; a lookup only, so safe while
+/// an update handle is open.
+pub fn running_slot() -> Option<SlotDesc> {
+    let partition = unsafe { esp_ota_get_running_partition() };
+
+    if partition.is_null() {
+        None
+    } else {
+        Some(describe(partition))
+    }
+}
+
+/// The slot the next update goes to, or `None` on partition tables
+/// without one; a lookup only, so safe while an update handle is open.
+pub fn update_slot() -> Option<SlotDesc> {
+    let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+
+    if partition.is_null() {
+        None
+    } else {
+        Some(describe(partition))
+    }
+}
+
+/// Reads `buf.len()` bytes at `offset` from the next OTA update slot;
+/// used to check a partial image before resuming onto it.
+pub fn read_update_slot(offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+    let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+    if partition.is_null() {
+        return Err(Error::NoUpdatePartition);
+    }
+
+    esp!(unsafe {
+        esp_partition_read(
+            partition,
+            offset as _,
+            buf.as_mut_ptr() as *mut _,
+            buf.len() as _,
+        )
+    })
+    .map_err(Error::Read)?;
+
+    Ok(())
+}
+
+/// Reads `buf.len()` bytes at `offset` from the currently running app
+/// partition; the source side of a delta update.
+pub fn read_running(offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+    let running = unsafe { esp_ota_get_running_partition() };
+    if running.is_null() {
+        return Err(Error::NoRunningPartition);
+    }
+
+    esp!(unsafe {
+        esp_partition_read(
+            running,
+            offset as _,
+            buf.as_mut_ptr() as *mut _,
+            buf.len() as _,
+        )
+    })
+    .map_err(Error::Read)?;
+
+    Ok(())
+}
+
+/// Reads `buf.len()` bytes at `offset` from the partition named
+/// `label`; the read-back side of a `--partition` write. Reading is
+/// harmless, so unlike [`PartitionUpdate::begin`] even the running app
+/// is allowed.
+pub fn read_partition(label: &str, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+    let label_c = CString::new(label).map_err(|_| Error::UnknownPartition)?;
+
+    let partition = unsafe {
+        esp_partition_find_first(
+            esp_partition_type_t_ESP_PARTITION_TYPE_ANY,
+            esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            label_c.as_ptr(),
+        )
+    };
+
+    if partition.is_null() {
+        return Err(Error::UnknownPartition);
+    }
+
+    esp!(unsafe {
+        esp_partition_read(
+            partition,
+            offset as _,
+            buf.as_mut_ptr() as *mut _,
+            buf.len() as _,
+        )
+    })
+    .map_err(Error::Read)?;
+
+    Ok(())
+}
+
+/// Size of the erase target named `label` (the update slot when
+/// `None`) together with the protection verdict: `true` means the
+/// target is the running app, which must never be erased over the
+/// wire. The bootloader and partition table sit below the first table
+/// entry, so a label lookup cannot reach them at all. `None` when no
+/// such partition exists.
+pub fn erase_target(label: Option<&str>) -> Option<(u32, bool)> {
+    let partition = find_partition(label)?;
+    let running = unsafe { esp_ota_get_running_partition() };
+
+    Some((unsafe { (*partition).size }, partition == running))
+}
+
+/// Erases `len` bytes at `offset` within the partition named `label`
+/// (the update slot when `None`) in one driver call; the caller
+/// validates and chunks the range. The running app is refused here
+/// again as the last line of defence.
+pub fn erase_region(label: Option<&str>, offset: u32, len: u32) -> Result<(), Error> {
+    let partition = match label {
+        Some(_) => find_partition(label).ok_or(Error::UnknownPartition)?,
+        None => find_partition(None).ok_or(Error::NoUpdatePartition)?,
+    };
+
+    let running = unsafe { esp_ota_get_running_partition() };
+    if partition == running {
+        return Err(Error::ProtectedPartition);
+    }
+
+    esp!(unsafe { esp_partition_erase_range(partition, offset as _, len as _) })
+        .map_err(Error::Erase)?;
+
+    Ok(())
+}
+
+/// Looks up a partition by label, or the next OTA update slot for
+/// `None`; null is mapped to `None` so callers never touch a raw null.
+fn find_partition(label: Option<&str>) -> Option<*const esp_partition_t> {
+    let partition = match label {
+        Some(label) => {
+            let label_c = CString::new(label).ok()?;
+
+            unsafe {
+                esp_partition_find_first(
+                    esp_partition_type_t_ESP_PARTITION_TYPE_ANY,
+                    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                    label_c.as_ptr(),
+                )
+            }
+        }
+        None => unsafe { esp_ota_get_next_update_partition(ptr::null()) },
+    };
+
+    if partition.is_null() {
+        None
+    } else {
+        Some(partition)
+    }
+}
+
+/// Size in bytes of the partition named `label`, for bounds-checking a
+/// read-back; `None` when no such partition exists.
+pub fn partition_size(label: &str) -> Option<u32> {
+    let label_c = CString::new(label).ok()?;
+
+    let partition = unsafe {
+        esp_partition_find_first(
+            esp_partition_type_t_ESP_PARTITION_TYPE_ANY,
+            esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            label_c.as_ptr(),
+        )
+    };
+
+    if partition.is_null() {
+        None
+    } else {
+        Some(unsafe { (*partition).size })
+    }
+}
+
+/// Whether the running image is still pending verification from a
+/// previous OTA. Only ever true on builds with
+/// `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`; elsewhere the state is
+/// `VALID` or `UNDEFINED` and this returns false.
+pub fn pending_verify() -> Result<bool, Error> {
+    let running = unsafe { esp_ota_get_running_partition() };
+    if running.is_null() {
+        return Err(Error::NoRunningPartition);
+    }
+
+    let mut state: esp_ota_img_states_t = 0;
+    esp!(unsafe { esp_ota_get_state_partition(running, &mut state) }).map_err(Error::GetState)?;
+
+    Ok(state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY)
+}
+
+/// Marks the running image valid, cancelling any pending rollback.
+pub fn mark_valid() -> Result<(), Error> {
+    esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() }).map_err(Error::MarkValid)?;
+
+    Ok(())
+}
+
+/// Whether the other slot holds a valid image to roll back to. False on
+/// builds without rollback support, so callers can report a failure
+/// instead of tripping over the call below.
+pub fn rollback_possible() -> bool {
+    unsafe { esp_ota_check_rollback_is_possible() }
+}
+
+/// Invalidates the running image and reboots into the previous one.
+/// Only returns on failure (e.g. rollback is not enabled in sdkconfig).
+pub fn rollback() -> Result<(), Error> {
+    esp!(unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() }).map_err(Error::Rollback)?;
+
+    Ok(())
+}
+
+/// SHA-256 over the first `len` bytes of the running app partition, used
+/// to check a delta base before accepting the transfer.
+pub fn running_sha256(len: usize) -> Result<[u8; 32], Error> {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    let mut buf = [0_u8; 256];
+    let mut offset = 0;
+
+    while offset < len {
+        let chunk = (len - offset).min(buf.len());
+
+        read_running(offset, &mut buf[..chunk])?;
+        hasher.update(&buf[..chunk]);
+
+        offset += chunk;
+    }
+
+    Ok(hasher.finalize().into())
+}
@@ -0,0 +1,145 @@
+//! Drives an optional status LED through the update lifecycle, so a
+//! technician can tell what the box is doing without a laptop.
+//!
+//! The updater only ever stores the pattern it wants; a tiny dedicated
+//! thread renders it, so no blink timing ever stalls segment handling.
+//! Boards without the extra wire use [`StatusLed::disabled`] and run
+//! the exact same updater code with every `show` call swallowed.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use embedded_hal::digital::v2::OutputPin;
+
+use log::*;
+
+/// One pattern step; every pattern is a multiple of this.
+const TICK: Duration = Duration::from_millis(50);
+
+/// Stack size of the blinker thread.
+const STACK_SIZE: usize = 4096;
+
+/// What the LED is showing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// Slow blink: powered up and waiting for a host.
+    Idle = 0,
+    /// Fast blink: segments are streaming in.
+    Receiving = 1,
+    /// Solid: finalizing and verifying - do not pull the plug.
+    Finalizing = 2,
+    /// Three quick blinks, played once, then back to [`Idle`](Self::Idle).
+    Failure = 3,
+    /// Dark, e.g. just before the restart into a new image.
+    Off = 4,
+}
+
+impl Pattern {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Receiving,
+            2 => Self::Finalizing,
+            3 => Self::Failure,
+            4 => Self::Off,
+            _ => Self::Idle,
+        }
+    }
+}
+
+/// Cloneable handle the updater feeds on its state transitions. A
+/// handle without a driver thread behind it ignores every `show`.
+#[derive(Clone)]
+pub struct StatusLed {
+    inner: Option<Arc<AtomicU8>>,
+}
+
+impl StatusLed {
+    /// Handle for boards without a status LED.
+    pub fn disabled() -> Self {
+        Self { inner: None }
+    }
+
+    pub fn show(&self, pattern: Pattern) {
+        if let Some(inner) = &self.inner {
+            inner.store(pattern as u8, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Spawns the blinker thread on `pin` - the demo wires the devkit's
+/// onboard LED on GPIO2 - and returns the handle to feed it with.
+pub fn spawn<PIN>(mut pin: PIN) -> anyhow::Result<StatusLed>
+where
+    PIN: OutputPin + Send + 'static,
+    PIN::Error: std::fmt::Debug,
+{
+    let state = Arc::new(AtomicU8::new(Pattern::Idle as u8));
+
+    let led = StatusLed {
+        inner: Some(state.clone()),
+    };
+
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut previous = Pattern::Idle;
+            let mut phase: u32 = 0;
+            let mut lit = None;
+
+            loop {
+                let pattern = Pattern::from_u8(state.load(Ordering::Relaxed));
+
+                // Every pattern starts from its beginning
+                if pattern != previous {
+                    previous = pattern;
+                    phase = 0;
+                }
+
+                let on = match pattern {
+                    // 1 s period
+                    Pattern::Idle => phase % 20 < 10,
+                    // 200 ms period
+                    Pattern::Receiving => phase % 4 < 2,
+                    Pattern::Finalizing => true,
+                    // Three quick blinks and a beat of darkness, once
+                    Pattern::Failure => {
+                        if phase >= 20 {
+                            // Only fall back to Idle if nobody has asked
+                            // for something else in the meantime
+                            state
+                                .compare_exchange(
+                                    Pattern::Failure as u8,
+                                    Pattern::Idle as u8,
+                                    Ordering::Relaxed,
+                                    Ordering::Relaxed,
+                                )
+                                .ok();
+                        }
+
+                        phase % 4 < 2 && phase < 12
+                    }
+                    Pattern::Off => false,
+                };
+
+                // Only touch the pin on edges, so a wedged driver cannot
+                // flood the log either
+                if lit != Some(on) {
+                    let result = if on { pin.set_high() } else { pin.set_low() };
+
+                    match result {
+                        Ok(()) => lit = Some(on),
+                        Err(err) => warn!("Cannot drive the status LED: {:?}", err),
+                    }
+                }
+
+                phase = phase.wrapping_add(1);
+                thread::sleep(TICK);
+            }
+        })?;
+
+    info!("Status LED driver started");
+
+    Ok(led)
+}
@@ -0,0 +1,149 @@
+//! TCP transport for the update protocol (`tcp_update` feature), for
+//! devices installed where the UART is unreachable but Wi-Fi is not.
+//!
+//! The bridge listens on a plain TCP port and speaks the exact frames
+//! the flasher sends over the UART: received bytes are reassembled,
+//! checksum-verified and injected into the same updater the serial
+//! thread feeds (via [`HostLink`]), and outgoing frames are written
+//! back to the socket. Wi-Fi itself is brought up by `main` like for
+//! the rest of the demo; this module only owns the listener.
+//!
+//! One connection is served at a time - the next host waits in the
+//! accept backlog until the current one hangs up. A connection dropped
+//! mid-update simply stops producing messages, so the transfer dies
+//! through the updater's usual inactivity timeout, same as a UART host
+//! going silent.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+use log::*;
+
+use messages::{codec, MessageTypeHost, MessageTypeMcu};
+
+use crate::uart_update::{HostLink, BUF_SIZE, MAX_REASSEMBLY};
+
+/// Default port of the update listener; the same one `espota` uses, as
+/// this is the device's OTA port in the same spirit.
+pub const PORT: u16 = 3232;
+
+/// Stack size of the listener and writer threads; lighter duty than the
+/// serial thread since lwIP buffers the line for us.
+const STACK_SIZE: usize = 6144;
+
+/// Depth of the per-connection reply queue, mirroring the UART's
+/// command queue: telemetry senders drop on congestion, the updater
+/// never has more than a couple of frames in flight.
+const REPLY_QUEUE_DEPTH: usize = 32;
+
+/// Binds the listener and spawns the accept loop. Call once Wi-Fi (or
+/// Ethernet) is up; binding needs the TCP/IP stack initialized.
+pub fn spawn(port: u16, link: HostLink) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+
+    info!("TCP update service listening on port {}", port);
+
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || accept_loop(listener, link))?;
+
+    Ok(())
+}
+
+fn accept_loop(listener: TcpListener, link: HostLink) {
+    loop {
+        let (stream, peer) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("TCP accept failed: {}", err);
+                continue;
+            }
+        };
+
+        info!("Update connection from {}", peer);
+
+        match serve(&stream, &link) {
+            Ok(()) => info!("Update host {} hung up", peer),
+            Err(err) => info!("Update connection to {} lost: {}", peer, err),
+        }
+
+        // Drops the reply queue, which also stops the writer thread
+        link.disconnect();
+    }
+}
+
+/// Shuffles frames for one connection until it closes. Incoming frames
+/// reuse the serial thread's reassembly rules: undecodable bytes are
+/// dropped, a buffer growing past any legal frame is cleared.
+fn serve(stream: &TcpStream, link: &HostLink) -> std::io::Result<()> {
+    let (reply_tx, reply_rx) = mpsc::sync_channel::<MessageTypeMcu>(REPLY_QUEUE_DEPTH);
+    link.connect(reply_tx);
+
+    // Socket writes can stall on a congested link, so they get their
+    // own thread rather than holding up frame parsing
+    let mut writer = stream.try_clone()?;
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            while let Ok(msg) = reply_rx.recv() {
+                let frame = match codec::encode(&msg) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        warn!("Dropping unserializable frame: {:?}", err);
+                        continue;
+                    }
+                };
+
+                if writer.write_all(&frame).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    let mut reader = stream.try_clone()?;
+    let mut buf = vec![0_u8; BUF_SIZE];
+    let mut accumulated: Vec<u8> = Vec::new();
+
+    loop {
+        let received = reader.read(&mut buf)?;
+        if received == 0 {
+            return Ok(());
+        }
+
+        accumulated.extend_from_slice(&buf[..received]);
+
+        loop {
+            match codec::take::<MessageTypeHost>(&accumulated) {
+                Ok((payload, rest)) => {
+                    let consumed = accumulated.len() - rest.len();
+                    accumulated.drain(..consumed);
+
+                    if !link.inject(payload) {
+                        info!("Updater gone, closing the update connection");
+                        return Ok(());
+                    }
+                }
+                Err(codec::DecodeError::BadCrc { frame_len, .. }) => {
+                    warn!("Dropping frame with bad checksum");
+                    accumulated.drain(..frame_len);
+                }
+                Err(codec::DecodeError::Truncated) => break,
+                Err(err) => {
+                    warn!("Dropping undecodable bytes: {:?}", err);
+                    accumulated.clear();
+                    break;
+                }
+            }
+        }
+
+        if accumulated.len() > MAX_REASSEMBLY {
+            warn!(
+                "Reassembly buffer overflow, dropping {} bytes",
+                accumulated.len()
+            );
+            accumulated.clear();
+        }
+    }
+}
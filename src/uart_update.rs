@@ -0,0 +1,4356 @@
+//! Firmware update over a serial link, driven by the host-side `flasher`
+//! tool (see `flasher/` in the repository root). The byte transport is
+//! pluggable ([`LinkRx`]/[`LinkTx`]): any UART controller via [`spawn`],
+//! or the native USB-Serial-JTAG port via [`spawn_usb_serial_jtag`] on
+//! devkits whose only connector is the USB socket.
+//!
+//! Three threads are spawned: a serial thread that owns the link's RX half
+//! and reassembles frames, a TX thread that owns the other half and writes
+//! replies the moment they are queued, and an updater thread that drives
+//! the OTA machinery. They talk via channels so a slow flash write never
+//! stalls the serial receive path, and so ack latency never depends on the
+//! host going quiet.
+
+use core::ptr;
+
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::delay;
+use esp_idf_hal::gpio;
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::serial;
+
+use embedded_hal::serial::{Read as _, Write as _};
+
+use log::*;
+
+use messages::{
+    codec, crypto, erase,
+    flash_errors::{classify_write_error, WriteError},
+    mode::{DeviceMode, SharedMode},
+    readback,
+    segments::{SegmentAction, SegmentTracker, UpdateSink},
+    selftest::{self, SelfTest, SelfTestError, SlotBackend, Verdict},
+    trace::{self, Throttle, TraceLog, TraceMode},
+    transport::Transport,
+    verify::{self, ImageCheck},
+    version, Caps, Crc32, DeltaOp, FailureReason, Info, LinkStats, LogRecord, MessageTypeHost,
+    MessageTypeMcu, SlotInfo, Status, UpdatePhase, UpdateRecord, UpdateStart, UpdateStartStatus,
+    CAP_COMPRESSED_SEGMENTS, CAP_DELTA_UPDATES, CAP_ENCRYPTED_SEGMENTS, CAP_SIGNATURE_REQUIRED,
+    HASH_LEN, NONCE_PREFIX_LEN, PROTOCOL_VERSION, PUBLIC_KEY_LEN, SEGMENT_SIZE,
+};
+use smlang::statemachine;
+use updater_core::DESYNC_THRESHOLD;
+
+use crate::adc_telemetry;
+use crate::protocol_log;
+use crate::resume;
+use crate::simple_ota::{self, OtaUpdate, PartitionUpdate, SlotUpdate};
+use crate::status_led::{Pattern, StatusLed};
+
+/// Default baud rate of the update link.
+pub const BAUD_RATE: u32 = 921_600;
+
+/// Default stack size of the serial thread. Measured with
+/// [`stack_high_water`] on a WROOM devkit at just under 3 KiB used
+/// during a compressed transfer (the read buffer itself lives on the
+/// heap); the rest is margin for the ESP-IDF logging path.
+pub const SERIAL_STACK_SIZE: usize = 6144;
+
+/// Default stack size of the updater thread. Peak usage sits in
+/// finalization - SHA-256 over the slot plus the read-back buffers of
+/// the delta and resume paths - measured at just under 4 KiB.
+pub const UPDATER_STACK_SIZE: usize = 8192;
+
+/// Default stack size of the serial TX thread. It only serializes
+/// frames (which live on the heap) and writes bytes out; the margin is
+/// mostly for the ESP-IDF logging path.
+pub const SERIAL_TX_STACK_SIZE: usize = 4096;
+
+/// Size of the UART receive scratch buffer.
+pub const BUF_SIZE: usize = 1024;
+
+/// Upper bound on the reassembly buffer; no legal frame comes close, so
+/// anything beyond this is garbage that must not grow the heap forever.
+pub(crate) const MAX_REASSEMBLY: usize = 2 * BUF_SIZE;
+
+/// Largest segment payload any transport can actually deliver to the
+/// updater in one frame, derived from the reassembly capacity and the
+/// wire overhead so it tracks [`BUF_SIZE`] instead of being hardcoded a
+/// second time. This is what `UpdateStartStatus` advertises on links
+/// that can take more than the classic segment size.
+pub(crate) const RECEIVE_CAPACITY: usize = messages::max_segment_payload(MAX_REASSEMBLY);
+
+/// Rates the update UART can be switched to by `SetBaud`; everything
+/// here divides cleanly out of the APB clock on all three supported
+/// chips.
+pub const SUPPORTED_BAUD_RATES: [u32; 6] =
+    [115_200, 230_400, 460_800, 921_600, 1_500_000, 2_000_000];
+
+/// How long after a baud switch the device waits for the first valid
+/// frame at the new rate before falling back to the old one, so a
+/// failed host switch cannot strand the device.
+const BAUD_GRACE: Duration = Duration::from_secs(3);
+
+/// How long `WaitingForData` survives without any valid host message
+/// before the in-flight update is aborted. Long enough for the host's
+/// own retry pauses, short enough that a dead host cannot wedge OTA
+/// until the next power cycle.
+pub const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Grace period between the final status leaving the UART and the
+/// restart, so the host's UART has settled before the line glitches.
+pub const RESTART_DELAY: Duration = Duration::from_millis(250);
+
+/// Bounded wait for the serial TX thread to confirm its queue is
+/// drained; restarting with a wedged TX thread beats never restarting.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the updater thread wakes from its channel wait to feed the
+/// task watchdog; well below the default 5 second WDT timeout.
+const WDT_FEED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upper bound on one blocking link read. Short enough that a shutdown
+/// request and the WDT feed never wait long, long enough that an idle
+/// serial thread spends its life asleep in the driver.
+const RX_WAIT: Duration = Duration::from_millis(20);
+
+/// Depth of the updater/telemetry -> serial queue. Bounded so telemetry
+/// senders can drop on congestion instead of growing the heap; the
+/// updater itself never has more than a couple of frames in flight.
+const COMMAND_QUEUE_DEPTH: usize = 32;
+
+/// Depth of the host -> updater queue: a handful of max-size segments.
+/// Bounded so a host pushing segments while the updater sits in a long
+/// flash erase gets `Busy` backpressure instead of buffering megabytes
+/// on the heap.
+const HOST_QUEUE_DEPTH: usize = 4;
+
+/// Retry hint in a [`MessageTypeMcu::Busy`] reply. Long enough that the
+/// host is not hammering a stalled queue, short enough not to dent
+/// throughput when the stall was a single erase.
+const BUSY_RETRY_MS: u32 = 50;
+
+/// Floor between two streamed trace frames. Aggressive on purpose:
+/// tracing is a debugging aid, and the ring keeps what the throttle
+/// drops for a later `DumpTrace`.
+const TRACE_MIN_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Payload of one `FlashData` frame: the classic segment size, so a
+/// read-back reply obeys the same TX budget as everything else the
+/// device sends.
+const READ_CHUNK: u32 = 256;
+
+/// Upper bound on one `ReadFlash` request. Streaming is paced by the
+/// UART and the updater only feeds the watchdog between messages, so
+/// one request must finish well inside the WDT timeout even at modest
+/// baud rates; a host wanting more issues several requests.
+const READ_MAX: u32 = 64 * 1024;
+
+/// Bytes read per driver call while answering a `HashFlash`. Nothing
+/// goes back over the wire until the digest is done, so the chunk can
+/// be much larger than [`READ_CHUNK`]; the WDT is fed between calls.
+const HASH_CHUNK: u32 = 4096;
+
+/// Sectors handed to one `esp_partition_erase_range` call while
+/// grinding through an `EraseRegion`: big enough to make progress,
+/// small enough that the WDT is fed and the host sees signs of life
+/// between calls.
+const ERASE_CHUNK: u32 = 64 * 1024;
+
+/// Pings arriving closer together than this are dropped unanswered, so
+/// a misbehaving host flooding the link cannot keep the updater busy
+/// echoing instead of writing segments.
+const PING_RATE_LIMIT: Duration = Duration::from_millis(10);
+
+/// Where `esp_app_desc_t` sits in an app image: right after the 24-byte
+/// image header and the first 8-byte segment header.
+const APP_DESC_OFFSET: usize = 32;
+
+/// `esp_app_desc_t.magic_word` of a well-formed descriptor.
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+
+/// Offset of the NUL-padded version string within the descriptor, and
+/// its fixed size.
+const APP_DESC_VERSION_OFFSET: usize = 16;
+const APP_DESC_VERSION_LEN: usize = 32;
+
+/// Image bytes to capture before the anti-rollback check can run:
+/// everything through the end of the descriptor's version field.
+const IMAGE_HEAD_LEN: usize = APP_DESC_OFFSET + APP_DESC_VERSION_OFFSET + APP_DESC_VERSION_LEN;
+
+/// Times the RX stream was declared desynchronized and flushed since
+/// boot. Written by the serial thread, read by `GetInfo` on the updater
+/// thread, hence atomic; reported in [`Info`] so chronic line problems
+/// are visible from the host.
+static DESYNCS: AtomicU32 = AtomicU32::new(0);
+
+/// Wire counters reported via [`MessageTypeHost::GetStats`]. Written by
+/// the serial threads and the updater, read (and optionally reset) on
+/// the updater thread, hence atomics. Additions saturate so a counter
+/// that hits the ceiling stays pinned there instead of wrapping back to
+/// "clean". The byte counters cover the serial link, where line quality
+/// is the open question; TCP and BLE bring their own integrity.
+struct LinkCounters {
+    frames_received: AtomicU32,
+    crc_errors: AtomicU32,
+    decode_errors: AtomicU32,
+    segments_retried: AtomicU32,
+    duplicate_segments: AtomicU32,
+    busy_events: AtomicU32,
+    bytes_received: AtomicU32,
+    bytes_sent: AtomicU32,
+    flash_writes: AtomicU32,
+    flash_write_micros: AtomicU32,
+}
+
+impl LinkCounters {
+    const fn new() -> Self {
+        Self {
+            frames_received: AtomicU32::new(0),
+            crc_errors: AtomicU32::new(0),
+            decode_errors: AtomicU32::new(0),
+            segments_retried: AtomicU32::new(0),
+            duplicate_segments: AtomicU32::new(0),
+            busy_events: AtomicU32::new(0),
+            bytes_received: AtomicU32::new(0),
+            bytes_sent: AtomicU32::new(0),
+            flash_writes: AtomicU32::new(0),
+            flash_write_micros: AtomicU32::new(0),
+        }
+    }
+
+    /// Saturating add; one `fetch_update` loop, no allocation, cheap
+    /// enough for the per-read hot path.
+    fn bump(counter: &AtomicU32, n: u32) {
+        counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                Some(value.saturating_add(n))
+            })
+            .ok();
+    }
+
+    /// Counts the retry requests flowing out, whatever decided them -
+    /// a transient write error, an oversized segment, a desync notice.
+    fn note_reply(&self, msg: &MessageTypeMcu) {
+        if let MessageTypeMcu::UpdateSegmentStatus {
+            status: Status::Retry,
+            ..
+        } = msg
+        {
+            Self::bump(&self.segments_retried, 1);
+        }
+    }
+
+    /// Accounts one flash driver call on the segment path; the timing
+    /// saturates with the counter, so a long uptime cannot wrap it into
+    /// looking fast.
+    fn note_flash_write(&self, elapsed: Duration) {
+        Self::bump(&self.flash_writes, 1);
+        Self::bump(
+            &self.flash_write_micros,
+            u32::try_from(elapsed.as_micros()).unwrap_or(u32::MAX),
+        );
+    }
+
+    fn snapshot(&self) -> LinkStats {
+        LinkStats {
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            crc_errors: self.crc_errors.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            segments_retried: self.segments_retried.load(Ordering::Relaxed),
+            duplicate_segments: self.duplicate_segments.load(Ordering::Relaxed),
+            busy_events: self.busy_events.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            flash_writes: self.flash_writes.load(Ordering::Relaxed),
+            flash_write_micros: self.flash_write_micros.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Not atomic as a whole: a frame landing mid-reset can survive in
+    /// one counter. Monitoring tolerates an off-by-one; a lock would
+    /// put contention on the segment hot path.
+    fn reset(&self) {
+        self.frames_received.store(0, Ordering::Relaxed);
+        self.crc_errors.store(0, Ordering::Relaxed);
+        self.decode_errors.store(0, Ordering::Relaxed);
+        self.segments_retried.store(0, Ordering::Relaxed);
+        self.duplicate_segments.store(0, Ordering::Relaxed);
+        self.busy_events.store(0, Ordering::Relaxed);
+        self.bytes_received.store(0, Ordering::Relaxed);
+        self.bytes_sent.store(0, Ordering::Relaxed);
+        self.flash_writes.store(0, Ordering::Relaxed);
+        self.flash_write_micros.store(0, Ordering::Relaxed);
+    }
+}
+
+static LINK_STATS: LinkCounters = LinkCounters::new();
+
+/// Outcome of this boot's post-OTA self-test, for the `Info` reply:
+/// 0 never ran, 1 passed, 2 failed but the rollback did not take.
+/// Written once by [`confirm_running_image`] on the main thread, read
+/// by `GetInfo` on the updater thread, hence atomic.
+static SELF_TEST: AtomicU8 = AtomicU8::new(0);
+
+// The full update flow. Every host message maps to an event, and an
+// event that is invalid in the current state earns the host a Failed
+// reply instead of silently mutating anything.
+statemachine! {
+    transitions: {
+        *Idle + UpdateStarted = WaitingForData,
+        Idle + Cancelled = Idle,
+        // A host that crashed after the start handshake re-runs and its
+        // fresh UpdateStart lands mid-transfer: an implicit
+        // cancel-and-restart, never a wedge (see the UpdateStart arm)
+        WaitingForData + UpdateStarted = WaitingForData,
+        WaitingForData + StartFailed = Idle,
+        WaitingForData + SegmentReceived = WaitingForData,
+        WaitingForData + UpdateEndReceived = Finalizing,
+        WaitingForData + Cancelled = Idle,
+        WaitingForData + TimedOut = Idle,
+        Finalizing + FinalizeOk = Idle,
+        Finalizing + FinalizeFailed = Idle,
+    }
+}
+
+/// The transport a host message arrived on, so its reply goes back the
+/// same way. The updater itself is transport-agnostic; the `UpdateStart`
+/// arm refusing a restart from a different transport is what keeps a TCP
+/// host from hijacking a transfer running over the UART and vice versa.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Uart,
+    Tcp,
+    Ble,
+}
+
+impl Link {
+    /// Short name recorded in the update audit trail.
+    fn name(self) -> &'static str {
+        match self {
+            Link::Uart => "uart",
+            Link::Tcp => "tcp",
+            Link::Ble => "ble",
+        }
+    }
+}
+
+/// Everything the transitions operate on: the in-flight update and the
+/// transfer counters, owned here rather than as loose variables in the
+/// updater loop.
+pub struct Context {
+    update: Option<ActiveUpdate>,
+    segments_written: u32,
+    duplicates: u32,
+    /// Transport of the in-flight update, for replies the updater sends
+    /// without a triggering message (the inactivity timeout).
+    link: Link,
+}
+
+impl Context {
+    fn new() -> Self {
+        Self {
+            update: None,
+            segments_written: 0,
+            duplicates: 0,
+            link: Link::Uart,
+        }
+    }
+}
+
+impl StateMachineContext for Context {}
+
+/// What the updater hands to the serial TX thread: a frame to transmit, or
+/// a request to signal back once everything queued before it has actually
+/// left the UART.
+enum SerialCommand {
+    Send(MessageTypeMcu),
+    Drained(mpsc::Sender<()>),
+    /// Reconfigures the UART; everything queued before this must have
+    /// been drained at the old rate via [`SerialCommand::Drained`].
+    SetBaud(u32),
+}
+
+/// One item on the host -> updater channel: a decoded message tagged
+/// with its transport, or the serial thread's note that the UART stream
+/// lost frame sync and everything pending was flushed.
+enum Inbound {
+    Message(MessageTypeHost),
+    Desync,
+}
+
+/// Reply queue of the currently connected alternate-transport host,
+/// registered and torn down per connection by the `tcp_update` or
+/// `ble_update` bridge; `None` between connections (and always, on
+/// serial-only builds). One slot for all bridges: only one of them can
+/// hold a connection at a time.
+type AltReplySlot = Arc<Mutex<Option<mpsc::SyncSender<MessageTypeMcu>>>>;
+
+/// Cloneable handle for queueing frames to the host from outside the
+/// updater, e.g. the ADC telemetry thread. `try_send` drops the frame
+/// when the queue is full rather than blocking the caller.
+#[derive(Clone)]
+pub struct McuSender {
+    uart: mpsc::SyncSender<SerialCommand>,
+    alt: AltReplySlot,
+}
+
+impl McuSender {
+    pub fn try_send(&self, msg: MessageTypeMcu) -> bool {
+        // Out-of-band frames (telemetry, mirrored log records) go to
+        // every attached transport; replies are routed per request by
+        // the updater instead
+        if let Some(alt) = self.alt.lock().unwrap().as_ref() {
+            alt.try_send(msg.clone()).ok();
+        }
+
+        self.uart.try_send(SerialCommand::Send(msg)).is_ok()
+    }
+}
+
+/// Injection point for an alternate transport speaking the same
+/// protocol (the `tcp_update` and `ble_update` bridges): messages
+/// pushed here reach the same updater the UART feeds, and their replies
+/// flow back to the queue registered for the connection.
+#[derive(Clone)]
+pub struct HostLink {
+    link: Link,
+    host_msg_tx: mpsc::SyncSender<(Link, Inbound)>,
+    alt_reply: AltReplySlot,
+}
+
+impl HostLink {
+    /// The same injection point tagged for a different transport, so
+    /// each bridge's messages are answered over its own connection.
+    pub fn tagged(&self, link: Link) -> Self {
+        Self {
+            link,
+            ..self.clone()
+        }
+    }
+
+    /// Hands one host message to the updater; `false` once the updater
+    /// is gone. Blocks while the updater's queue is full - for a stream
+    /// transport the connection itself is the backpressure, and the
+    /// bridge thread has nothing better to do than wait.
+    pub fn inject(&self, msg: MessageTypeHost) -> bool {
+        self.host_msg_tx
+            .send((self.link, Inbound::Message(msg)))
+            .is_ok()
+    }
+
+    /// Registers the reply queue of a freshly accepted connection,
+    /// replacing whatever a previous connection left behind.
+    pub fn connect(&self, reply_tx: mpsc::SyncSender<MessageTypeMcu>) {
+        *self.alt_reply.lock().unwrap() = Some(reply_tx);
+    }
+
+    /// Drops the connection's reply queue; replies still in flight are
+    /// discarded, which is all one can do for a host that hung up.
+    pub fn disconnect(&self) {
+        *self.alt_reply.lock().unwrap() = None;
+    }
+}
+
+/// Shared handle to the protocol trace (see [`messages::trace`]): the
+/// serial thread records what it decoded, the [`ReplyRouter`] records
+/// what went out, and the updater owns the mode. The `enabled` flag
+/// mirrors the mode so the per-frame fast path is one relaxed load
+/// instead of a lock.
+#[derive(Clone)]
+struct Tracer {
+    enabled: Arc<AtomicBool>,
+    state: Arc<Mutex<TraceState>>,
+}
+
+struct TraceState {
+    mode: TraceMode,
+    log: TraceLog,
+    throttle: Throttle,
+}
+
+impl Tracer {
+    fn new() -> Self {
+        Self {
+            enabled: Arc::new(AtomicBool::new(false)),
+            state: Arc::new(Mutex::new(TraceState {
+                mode: TraceMode::Off,
+                log: TraceLog::default(),
+                throttle: Throttle::new(TRACE_MIN_INTERVAL),
+            })),
+        }
+    }
+
+    fn set_mode(&self, mode: TraceMode) {
+        let mut state = self.state.lock().unwrap();
+        state.mode = mode;
+        self.enabled.store(mode.enabled(), Ordering::Relaxed);
+    }
+
+    /// Steps plain tracing aside for the transfer; returns whether it
+    /// actually switched off.
+    fn on_transfer_start(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let disabled = state.mode.on_transfer_start();
+        self.enabled.store(state.mode.enabled(), Ordering::Relaxed);
+        disabled
+    }
+
+    /// Records one entry when tracing is on: always into the ring, and
+    /// out to the host as a `Log` frame when the throttle allows. The
+    /// closure keeps the formatting cost off the path while tracing is
+    /// off.
+    fn record(&self, entry: impl FnOnce() -> String, sender: &mpsc::SyncSender<SerialCommand>) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let entry = entry();
+        let mut state = self.state.lock().unwrap();
+        state.log.record(entry.clone());
+
+        if state.throttle.allow(Instant::now()) {
+            sender
+                .try_send(SerialCommand::Send(MessageTypeMcu::Log(LogRecord {
+                    level: 5,
+                    target: "trace".into(),
+                    message: entry,
+                })))
+                .ok();
+        }
+    }
+
+    /// The ring's contents, oldest first, for a `DumpTrace`.
+    fn snapshot(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .log
+            .entries()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+/// Routes one outgoing message to the transport its request arrived on.
+struct ReplyRouter {
+    uart: mpsc::SyncSender<SerialCommand>,
+    alt: AltReplySlot,
+    tracer: Tracer,
+}
+
+impl ReplyRouter {
+    /// Traces one outgoing frame. The mirrored streams - log records,
+    /// ADC samples - are not replies and would only drown the ring;
+    /// tracing the trace's own `Log` frames would feed back on itself.
+    fn trace(&self, msg: &MessageTypeMcu) {
+        if matches!(msg, MessageTypeMcu::Log(_) | MessageTypeMcu::Adc(_)) {
+            return;
+        }
+
+        self.tracer
+            .record(|| format!("tx {}", msg.name()), &self.uart);
+    }
+
+    /// `Err` means the serial TX thread is gone and the updater should
+    /// stop. A vanished TCP or BLE connection only loses the reply -
+    /// the host's retry and the updater's inactivity timeout take it
+    /// from there.
+    fn send(&self, link: Link, msg: MessageTypeMcu) -> Result<(), mpsc::SendError<SerialCommand>> {
+        self.trace(&msg);
+        LINK_STATS.note_reply(&msg);
+
+        match link {
+            Link::Uart => self.uart.send(SerialCommand::Send(msg)),
+            Link::Tcp | Link::Ble => {
+                if let Some(alt) = self.alt.lock().unwrap().as_ref() {
+                    alt.try_send(msg).ok();
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Best-effort send for frames that must never block segment
+    /// handling; a full queue simply loses the frame.
+    fn try_send(&self, link: Link, msg: MessageTypeMcu) {
+        self.trace(&msg);
+        LINK_STATS.note_reply(&msg);
+
+        match link {
+            Link::Uart => {
+                self.uart.try_send(SerialCommand::Send(msg)).ok();
+            }
+            Link::Tcp | Link::Ble => {
+                if let Some(alt) = self.alt.lock().unwrap().as_ref() {
+                    alt.try_send(msg).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Scope guard setting the esp_pthread spawn configuration - FreeRTOS
+/// priority and optional core pin - picked up by `thread::Builder`
+/// spawns while it lives. Dropping it restores what was set before (or
+/// the ESP-IDF defaults if nothing was), so the configuration cannot
+/// leak into threads the application spawns later. Stack sizes keep
+/// coming from the builder; the pthread attribute wins over the
+/// configured default.
+struct SpawnConfig {
+    previous: Option<esp_idf_sys::esp_pthread_cfg_t>,
+}
+
+impl SpawnConfig {
+    fn apply(priority: u8, core: Option<u8>) -> Self {
+        let mut previous = esp_idf_sys::esp_pthread_cfg_t::default();
+        let previous =
+            esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_pthread_get_cfg(&mut previous) })
+                .ok()
+                .map(|()| previous);
+
+        let mut cfg = unsafe { esp_idf_sys::esp_pthread_get_default_config() };
+        cfg.prio = priority.into();
+        cfg.pin_to_core = core.map(i32::from).unwrap_or(NO_AFFINITY);
+
+        if let Err(err) = esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_pthread_set_cfg(&cfg) }) {
+            warn!("Cannot set the thread spawn configuration: {}", err);
+        }
+
+        Self { previous }
+    }
+}
+
+impl Drop for SpawnConfig {
+    fn drop(&mut self) {
+        let restore = self
+            .previous
+            .unwrap_or_else(|| unsafe { esp_idf_sys::esp_pthread_get_default_config() });
+
+        if let Err(err) = esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_pthread_set_cfg(&restore) }) {
+            warn!("Cannot restore the thread spawn configuration: {}", err);
+        }
+    }
+}
+
+/// FreeRTOS `tskNO_AFFINITY` as `pin_to_core` wants it: run on
+/// whichever core the scheduler picks.
+const NO_AFFINITY: i32 = esp_idf_sys::tskNO_AFFINITY as i32;
+
+/// Subscription of the calling thread to the ESP-IDF task watchdog,
+/// deregistering on drop so every thread exit path is covered.
+/// Registration fails benignly when the WDT is not initialized in
+/// sdkconfig; `feed` is a no-op then.
+struct WdtSubscription {
+    subscribed: bool,
+}
+
+impl WdtSubscription {
+    fn subscribe() -> Self {
+        let subscribed =
+            match esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_task_wdt_add(ptr::null_mut()) }) {
+                Ok(()) => true,
+                Err(err) => {
+                    // Expected when the task WDT is disabled in sdkconfig
+                    info!("Task WDT not available for this thread: {}", err);
+                    false
+                }
+            };
+
+        Self { subscribed }
+    }
+
+    fn feed(&self) {
+        if self.subscribed {
+            if let Err(err) = esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_task_wdt_reset() }) {
+                warn!("Cannot feed the task WDT: {}", err);
+            }
+        }
+    }
+}
+
+impl Drop for WdtSubscription {
+    fn drop(&mut self) {
+        if self.subscribed {
+            if let Err(err) =
+                esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_task_wdt_delete(ptr::null_mut()) })
+            {
+                warn!("Cannot deregister from the task WDT: {}", err);
+            }
+        }
+    }
+}
+
+/// Where segment data ends up: the next OTA app slot, the same slot
+/// written raw for a resumed transfer, or an arbitrary partition
+/// looked up by label.
+enum Target {
+    App(OtaUpdate),
+    Slot(SlotUpdate),
+    Partition(PartitionUpdate),
+}
+
+// The flash side of the sink abstraction the segment ordering logic is
+// tested against on the host (see `messages::segments`)
+impl UpdateSink for Target {
+    type Error = simple_ota::Error;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), simple_ota::Error> {
+        match self {
+            Target::App(update) => update.write(data),
+            Target::Slot(update) => update.write(data),
+            Target::Partition(update) => update.write(data),
+        }
+    }
+}
+
+/// An update in progress: the flash target plus the running size/digest
+/// bookkeeping checked against the host's `UpdateEnd` before anything is
+/// activated.
+struct ActiveUpdate {
+    target: Target,
+    check: ImageCheck,
+    tracker: SegmentTracker,
+    /// Resume bookkeeping; `None` when the transfer cannot be resumed
+    /// (custom partitions, or a host that announced no image hash).
+    resume: Option<ResumeTracking>,
+    /// Nonce prefix announced in `UpdateStart`; `None` for cleartext
+    /// transfers, where encrypted segments are then rejected.
+    nonce_prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    /// Capture of the image head for the anti-rollback check. `None`
+    /// once the check has run, for partition targets (arbitrary data
+    /// has no descriptor), and for resumed transfers - the original
+    /// attempt checked these bytes before its first checkpoint.
+    descriptor: Option<DescriptorCapture>,
+    /// Whether the host asked for periodic `Progress` frames.
+    progress: bool,
+    /// Coalesces segment payloads into flash-sector-sized blocks before
+    /// they reach the driver: one call per ~110-byte segment paid the
+    /// driver's per-call overhead dozens of times per sector. Flushed
+    /// exactly when full, so a sector-aligned write position always
+    /// means the buffer is empty (what [`maybe_checkpoint`] relies on);
+    /// the tail short of a boundary goes out at `UpdateEnd`.
+    buffer: Vec<u8>,
+}
+
+/// Accumulates the head of an incoming image so the app descriptor can
+/// be inspected after the first segment(s), before much is on flash.
+struct DescriptorCapture {
+    head: [u8; IMAGE_HEAD_LEN],
+    filled: usize,
+}
+
+impl DescriptorCapture {
+    fn new() -> Self {
+        Self {
+            head: [0; IMAGE_HEAD_LEN],
+            filled: 0,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        let take = data.len().min(IMAGE_HEAD_LEN - self.filled);
+
+        self.head[self.filled..self.filled + take].copy_from_slice(&data[..take]);
+        self.filled += take;
+    }
+
+    fn complete(&self) -> bool {
+        self.filled == IMAGE_HEAD_LEN
+    }
+}
+
+/// What a checkpoint is cut from: the announced image identity plus a
+/// running CRC over every byte written so far.
+struct ResumeTracking {
+    sha256: [u8; HASH_LEN],
+    size: u32,
+    crc: Crc32,
+    written: u32,
+    segments_since_checkpoint: u32,
+}
+
+/// Times a transient flash error is retried on the spot before the
+/// update is torn down; see [`ActiveUpdate::flush`].
+const FLUSH_RETRIES: u32 = 3;
+
+/// Pause between those attempts, enough for whatever holds the flash -
+/// the cache, another task's NVS commit - to let go.
+const FLUSH_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+impl ActiveUpdate {
+    /// Buffers `data` toward the target and accounts for it in the image
+    /// check and the resume CRC, so they can never drift apart. Bytes
+    /// reach flash a sector at a time through [`flush`](Self::flush);
+    /// an error out of here is a flush failure and tears the update
+    /// down, since the failed block spans segments that were already
+    /// acked and no resend can reproduce them.
+    fn write(&mut self, mut data: &[u8]) -> Result<(), simple_ota::Error> {
+        self.check.update(data);
+
+        if let Some(tracking) = &mut self.resume {
+            tracking.crc.update(data);
+            tracking.written += data.len() as u32;
+        }
+
+        if let Some(capture) = &mut self.descriptor {
+            capture.feed(data);
+        }
+
+        while !data.is_empty() {
+            let take = data.len().min(resume::SECTOR_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.buffer.len() == resume::SECTOR_SIZE {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hands the buffered bytes to the flash driver: a full sector when
+    /// the buffer fills, the remainder at `UpdateEnd`. Transient errors
+    /// are retried here rather than bounced to the host - a resent
+    /// segment could not repair a block that spans many - and the
+    /// driver time is metered for the `GetStats` reply.
+    fn flush(&mut self) -> Result<(), simple_ota::Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut attempt = 0;
+        let result = loop {
+            let started = Instant::now();
+            let result = self.target.write(&self.buffer);
+            LINK_STATS.note_flash_write(started.elapsed());
+
+            match result {
+                Err(simple_ota::Error::Write(inner))
+                    if attempt < FLUSH_RETRIES
+                        && classify_write_error(inner.code()) == WriteError::Transient =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "Transient flash write error ({}), attempt {} of {}",
+                        inner, attempt, FLUSH_RETRIES
+                    );
+                    thread::sleep(FLUSH_RETRY_DELAY);
+                }
+                other => break other,
+            }
+        };
+
+        self.buffer.clear();
+        result
+    }
+
+    /// Returns the captured image head exactly once, as soon as enough
+    /// bytes have landed to contain the descriptor's version field.
+    fn image_head(&mut self) -> Option<[u8; IMAGE_HEAD_LEN]> {
+        if self.descriptor.as_ref()?.complete() {
+            self.descriptor.take().map(|capture| capture.head)
+        } else {
+            None
+        }
+    }
+}
+
+/// Key material and policy shared by every transfer, copied out of
+/// [`Config`] for the updater thread so it does not grow one parameter
+/// per key.
+#[derive(Clone, Copy)]
+struct Security {
+    update_key: Option<[u8; crypto::KEY_LEN]>,
+    verifying_key: Option<[u8; PUBLIC_KEY_LEN]>,
+    require_signature: bool,
+    rollback_protection: RollbackProtection,
+    allow_unversioned: bool,
+    min_version: Option<version::Version>,
+}
+
+/// The scheduling knobs as configured, copied out of [`Config`] so the
+/// `Info` reply can report what the threads actually run at.
+#[derive(Clone, Copy)]
+struct Scheduling {
+    serial_priority: u8,
+    updater_priority: u8,
+    serial_core: Option<u8>,
+    updater_core: Option<u8>,
+}
+
+/// What happens when the anti-rollback check dislikes an incoming image.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RollbackProtection {
+    /// Refuse the update with `VersionTooOld` and abort it.
+    Enforce,
+    /// Log the violation and carry on; for development builds that
+    /// downgrade all day.
+    WarnOnly,
+}
+
+/// Tunables of the update service; the UART instance and pins are passed
+/// to [`spawn`] directly since their types carry the wiring. `Default`
+/// reproduces the demo's setup.
+pub struct Config {
+    pub baudrate: u32,
+    /// Stack sizes of the three threads. The defaults carry a margin
+    /// over the measured high-water marks; the marks are logged after
+    /// every transfer, so a different sdkconfig (bigger log buffers,
+    /// stack smashing protection) can be re-measured and tuned from
+    /// here without editing source.
+    pub serial_stack_size: usize,
+    pub serial_tx_stack_size: usize,
+    pub updater_stack_size: usize,
+    /// FreeRTOS priority of the serial RX and TX threads. One above the
+    /// ESP-IDF pthread default of 5, so ack latency stays steady when
+    /// the application runs busy threads (a display, say) at the
+    /// default; higher would start crowding out the IDF's own drivers.
+    pub serial_priority: u8,
+    /// FreeRTOS priority of the updater thread. Flash writes block in
+    /// the driver anyway, so the default priority is plenty.
+    pub updater_priority: u8,
+    /// Core the serial threads are pinned to; `None` lets the scheduler
+    /// migrate them. Pinning them away from the application's busiest
+    /// core is the heavier hammer if priorities alone do not settle the
+    /// ack latency.
+    pub serial_core: Option<u8>,
+    /// Core the updater thread is pinned to; `None` leaves it unpinned.
+    pub updater_core: Option<u8>,
+    /// Hardware flow control of the update UART. `CTSRTS` needs the
+    /// matching pins wired up in [`spawn`] and lets the device accept
+    /// larger segments without RX FIFO overruns.
+    pub flow_control: serial::config::FlowControl,
+    /// RX fill level (bytes) at which RTS is deasserted; only
+    /// meaningful with flow control enabled. Kept well below the
+    /// 128 byte FIFO so bytes already in flight still fit.
+    pub rts_threshold: u8,
+    /// Segments between resume checkpoints in NVS. Larger intervals
+    /// wear the NVS less but lose more progress on a power cut; a
+    /// checkpoint is additionally deferred until the write position
+    /// sits on a flash sector boundary.
+    pub checkpoint_interval: u32,
+    /// Segments between [`MessageTypeMcu::Progress`] frames when the
+    /// host asked for progress reporting. At the default baud rate
+    /// segments land in the mid hundreds per second, so 64 keeps the
+    /// reporting to a few frames per second.
+    pub progress_interval: u32,
+    /// Key for [`crypto`]-sealed segments, baked into the firmware by
+    /// the application (NVS or efuse storage can slot in here later).
+    /// `None` rejects encrypted updates outright.
+    pub update_key: Option<[u8; crypto::KEY_LEN]>,
+    /// Ed25519 public key the `UpdateEnd` signature is checked against,
+    /// baked in like [`update_key`](Self::update_key). With `None` any
+    /// signature is ignored - the demo works without keys.
+    pub verifying_key: Option<[u8; PUBLIC_KEY_LEN]>,
+    /// Refuse unsigned updates outright, advertised to the host as
+    /// [`CAP_SIGNATURE_REQUIRED`]. Only useful together with a
+    /// verifying key; set, without one, every update is refused.
+    pub require_signature: bool,
+    /// Anti-rollback: an app image whose descriptor reports a version
+    /// older than the running image (or [`min_version`](Self::min_version))
+    /// is refused with `VersionTooOld`. `WarnOnly` keeps the check but
+    /// only logs it.
+    pub rollback_protection: RollbackProtection,
+    /// Accept app images whose descriptor carries no parseable version.
+    /// Off by default, since an unversioned image would slip past the
+    /// rollback check entirely.
+    pub allow_unversioned: bool,
+    /// Version floor below which updates are refused even when the
+    /// running image is older still, baked in by the application (NVS
+    /// storage can slot in here later, like the keys above).
+    pub min_version: Option<version::Version>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            baudrate: BAUD_RATE,
+            serial_stack_size: SERIAL_STACK_SIZE,
+            serial_tx_stack_size: SERIAL_TX_STACK_SIZE,
+            updater_stack_size: UPDATER_STACK_SIZE,
+            serial_priority: 6,
+            updater_priority: 5,
+            serial_core: None,
+            updater_core: None,
+            flow_control: serial::config::FlowControl::None,
+            rts_threshold: 100,
+            checkpoint_interval: 64,
+            progress_interval: 64,
+            update_key: None,
+            verifying_key: None,
+            require_signature: false,
+            rollback_protection: RollbackProtection::Enforce,
+            allow_unversioned: false,
+            min_version: None,
+        }
+    }
+}
+
+impl Config {
+    /// Rejects values the service cannot run with, before any driver or
+    /// thread is touched. The progress interval divides a segment count
+    /// mid-transfer, so zero would panic in the updater thread; the RTS
+    /// threshold must leave room in the 128 byte RX FIFO for bytes
+    /// already in flight.
+    fn validate(&self) -> Result<(), ServiceError> {
+        if self.checkpoint_interval == 0 {
+            return Err(ServiceError::InvalidConfig(
+                "checkpoint_interval must be at least 1",
+            ));
+        }
+
+        if self.progress_interval == 0 {
+            return Err(ServiceError::InvalidConfig(
+                "progress_interval must be at least 1",
+            ));
+        }
+
+        if !matches!(self.flow_control, serial::config::FlowControl::None)
+            && !(1..=127).contains(&self.rts_threshold)
+        {
+            return Err(ServiceError::InvalidConfig(
+                "rts_threshold must be between 1 and 127",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Coarse update-service state, mirrored out of the updater thread so
+/// the application can poll it without a channel round trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdaterState {
+    Idle,
+    Receiving,
+    Finalizing,
+}
+
+impl UpdaterState {
+    fn from_code(code: u8) -> Self {
+        match code {
+            1 => Self::Receiving,
+            2 => Self::Finalizing,
+            _ => Self::Idle,
+        }
+    }
+
+    fn code(states: &States) -> u8 {
+        match states {
+            States::Idle => 0,
+            States::WaitingForData => 1,
+            States::Finalizing => 2,
+        }
+    }
+}
+
+/// Failures of the update service itself: bringing it up, checking its
+/// configuration, or waiting on its threads. Everything protocol-level
+/// is reported to the host in-band; only these reach the application,
+/// and through `std::error::Error` they print as a readable cause chain
+/// when `main` gives up on them.
+#[derive(Debug, thiserror::Error)]
+pub enum ServiceError {
+    /// The link driver could not be brought up.
+    #[error("initializing the update transport failed: {0}")]
+    Transport(esp_idf_sys::EspError),
+    /// A service thread could not be spawned.
+    #[error("spawning the {name} thread failed")]
+    Spawn {
+        name: &'static str,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A [`Config`] field holds a value the service cannot run with.
+    #[error("invalid update service config: {0}")]
+    InvalidConfig(&'static str),
+    /// A service thread died; the panic message went to the log.
+    #[error("the {0} thread panicked")]
+    ThreadPanicked(&'static str),
+}
+
+/// The running update service. Dropping the handle detaches the threads
+/// and the service keeps running for the life of the program;
+/// [`join`](Self::join) parks the caller on it instead - the demo's
+/// mode of operation - and [`shutdown`](Self::shutdown) exists for
+/// applications that need to tear the stack down and get the UART back.
+pub struct UpdaterHandle {
+    shutdown: Arc<AtomicBool>,
+    state: Arc<AtomicU8>,
+    serial_rx: thread::JoinHandle<()>,
+    serial_tx: thread::JoinHandle<()>,
+    updater: thread::JoinHandle<()>,
+}
+
+impl UpdaterHandle {
+    /// The state machine's current state, updated by the updater thread
+    /// after every message.
+    pub fn state(&self) -> UpdaterState {
+        UpdaterState::from_code(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Stops all three threads and waits for them to finish. An update
+    /// in flight is aborted - the OTA slot is released and the host's
+    /// next segment goes unanswered, leaving it to its retry path.
+    pub fn shutdown(self) -> Result<(), ServiceError> {
+        self.shutdown.store(true, Ordering::Relaxed);
+
+        Self::join_all([
+            ("updater", self.updater),
+            ("serial RX", self.serial_rx),
+            ("serial TX", self.serial_tx),
+        ])
+    }
+
+    /// Parks the caller until the service's threads exit - normally
+    /// never, since they run for the life of the device. The handle was
+    /// consumed without [`shutdown`](Self::shutdown), so any return is
+    /// abnormal: a panic comes back as the error, and even a clean exit
+    /// means a torn channel somewhere. Lets `main` wait on the service
+    /// instead of a bare sleep loop that would swallow thread deaths.
+    pub fn join(self) -> Result<(), ServiceError> {
+        Self::join_all([
+            ("updater", self.updater),
+            ("serial RX", self.serial_rx),
+            ("serial TX", self.serial_tx),
+        ])
+    }
+
+    fn join_all(handles: [(&'static str, thread::JoinHandle<()>); 3]) -> Result<(), ServiceError> {
+        for (name, handle) in handles {
+            handle
+                .join()
+                .map_err(|_| ServiceError::ThreadPanicked(name))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Spawns the serial and updater threads on the given UART; the demo
+/// wires UART1 with TX on GPIO32 and RX on GPIO33 and no flow-control
+/// pins. `mode` is the shared device mode the updater publishes;
+/// `telemetry` and the `logging` mirror watch it to stand aside while
+/// a transfer is in flight and are switched by the host's
+/// `AdcStart`/`AdcStop` and `SetLogLevel`; the
+/// mirror is attached to the link here. `led` is fed on the update
+/// state transitions; boards without one pass [`StatusLed::disabled`].
+/// Returns a handle over the two threads plus a sender for out-of-band
+/// frames such as the telemetry samples themselves.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn<UART, TX, RX, CTS, RTS>(
+    uart: UART,
+    pins: serial::Pins<TX, RX, CTS, RTS>,
+    config: Config,
+    mode: SharedMode,
+    telemetry: adc_telemetry::Control,
+    logging: protocol_log::Control,
+    led: StatusLed,
+    resume_store: resume::Store,
+) -> Result<(UpdaterHandle, McuSender, HostLink), ServiceError>
+where
+    UART: serial::Uart + Send + 'static,
+    TX: gpio::OutputPin,
+    RX: gpio::InputPin,
+    CTS: gpio::InputPin,
+    RTS: gpio::OutputPin,
+{
+    // The console and the protocol cannot share a wire: a stray printf
+    // in the middle of a frame desynchronizes the host. The protocol's
+    // own Log mirror keeps the records visible.
+    if UART::port() == esp_idf_sys::uart_port_t_UART_NUM_0 {
+        info!("UART0 carries the update link; silencing console output");
+        silence_console();
+    }
+
+    let serial_config = serial::config::Config::default()
+        .baudrate(Hertz(config.baudrate))
+        .flow_control(config.flow_control)
+        .flow_control_rts_threshold(config.rts_threshold);
+
+    let serial = serial::Serial::new(uart, pins, serial_config).map_err(ServiceError::Transport)?;
+
+    // Without flow control, anything beyond the classic segment size
+    // risks overrunning the RX FIFO while the updater is busy in flash
+    let uart_max_segment = match config.flow_control {
+        serial::config::FlowControl::None => None,
+        _ => Some(RECEIVE_CAPACITY as u16),
+    };
+
+    let (serial_tx, serial_rx) = serial.split();
+
+    spawn_with_link(
+        UartRx { rx: serial_rx },
+        UartTx { tx: serial_tx },
+        uart_max_segment,
+        config,
+        mode,
+        telemetry,
+        logging,
+        led,
+        resume_store,
+    )
+}
+
+/// Spawns the update service on the native USB-Serial-JTAG port, for
+/// devkits whose only connector is the USB socket. USB carries its own
+/// flow control, so the larger negotiated segments are always safe;
+/// `SetBaud` is acknowledged and ignored, since the port's line rate is
+/// nominal.
+#[cfg(esp32s3)]
+pub fn spawn_usb_serial_jtag(
+    config: Config,
+    mode: SharedMode,
+    telemetry: adc_telemetry::Control,
+    logging: protocol_log::Control,
+    led: StatusLed,
+    resume_store: resume::Store,
+) -> Result<(UpdaterHandle, McuSender, HostLink), ServiceError> {
+    let mut driver_config = esp_idf_sys::usb_serial_jtag_driver_config_t {
+        tx_buffer_size: BUF_SIZE as _,
+        rx_buffer_size: BUF_SIZE as _,
+    };
+
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::usb_serial_jtag_driver_install(&mut driver_config) })
+        .map_err(ServiceError::Transport)?;
+
+    spawn_with_link(
+        UsbSerialRx,
+        UsbSerialTx,
+        Some(RECEIVE_CAPACITY as u16),
+        config,
+        mode,
+        telemetry,
+        logging,
+        led,
+        resume_store,
+    )
+}
+
+/// Spawns the update service on the USB OTG port as a TinyUSB CDC-ACM
+/// device, for the S2 - which has no USB-Serial-JTAG - and for S3
+/// builds that want the OTG controller. The device enumerates with
+/// Espressif's own VID/PID (`303a:4001`), which is what lets the
+/// flasher pick it out among UART bridge chips. Like the
+/// USB-Serial-JTAG link, USB carries its own flow control and the line
+/// rate is nominal.
+#[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+pub fn spawn_usb_cdc(
+    config: Config,
+    mode: SharedMode,
+    telemetry: adc_telemetry::Control,
+    logging: protocol_log::Control,
+    led: StatusLed,
+    resume_store: resume::Store,
+) -> Result<(UpdaterHandle, McuSender, HostLink), ServiceError> {
+    // Defaults throughout: TinyUSB's stock descriptors carry the
+    // Espressif VID/PID the flasher looks for
+    let tusb_config = esp_idf_sys::tinyusb_config_t::default();
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::tinyusb_driver_install(&tusb_config) })
+        .map_err(ServiceError::Transport)?;
+
+    let acm_config = esp_idf_sys::tinyusb_config_cdcacm_t {
+        usb_dev: esp_idf_sys::tinyusb_usbdev_t_TINYUSB_USBDEV_0,
+        cdc_port: USB_CDC_PORT,
+        rx_unread_buf_sz: BUF_SIZE as _,
+        ..Default::default()
+    };
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::tusb_cdc_acm_init(&acm_config) })
+        .map_err(ServiceError::Transport)?;
+
+    spawn_with_link(
+        UsbCdcRx,
+        UsbCdcTx,
+        Some(RECEIVE_CAPACITY as u16),
+        config,
+        mode,
+        telemetry,
+        logging,
+        led,
+        resume_store,
+    )
+}
+
+/// Detaches the IDF console so its text cannot corrupt protocol frames
+/// when the update link runs on the console's own UART. Local printf
+/// output is dropped; host-side visibility survives through the
+/// protocol's Log mirror.
+fn silence_console() {
+    unsafe extern "C" fn discard(
+        _format: *const std::os::raw::c_char,
+        _args: esp_idf_sys::va_list,
+    ) -> std::os::raw::c_int {
+        0
+    }
+
+    unsafe { esp_idf_sys::esp_log_set_vprintf(Some(discard)) };
+}
+
+/// The transport-independent half of [`spawn`]: wires the queues and
+/// the three threads around an already-open byte link. Public so an
+/// application with its own transport - RS-485, a radio bridge - can
+/// run the stack over it; `uart_max_segment` is the link's verdict on
+/// the larger negotiated segments (see [`Config::flow_control`]).
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_with_link<RXL, TXL>(
+    link_rx: RXL,
+    link_tx: TXL,
+    uart_max_segment: Option<u16>,
+    config: Config,
+    mode: SharedMode,
+    telemetry: adc_telemetry::Control,
+    logging: protocol_log::Control,
+    led: StatusLed,
+    resume_store: resume::Store,
+) -> Result<(UpdaterHandle, McuSender, HostLink), ServiceError>
+where
+    RXL: LinkRx + 'static,
+    TXL: LinkTx + 'static,
+{
+    config.validate()?;
+
+    // Host -> updater and updater -> host queues; messages are tagged
+    // with the transport they came in on so replies go back the same way
+    let (host_msg_tx, host_msg_rx) = mpsc::sync_channel::<(Link, Inbound)>(HOST_QUEUE_DEPTH);
+    let (mcu_msg_tx, mcu_msg_rx) = mpsc::sync_channel::<SerialCommand>(COMMAND_QUEUE_DEPTH);
+
+    let alt_reply: AltReplySlot = Arc::new(Mutex::new(None));
+
+    let sender = McuSender {
+        uart: mcu_msg_tx.clone(),
+        alt: alt_reply.clone(),
+    };
+
+    let host_link = HostLink {
+        link: Link::Tcp,
+        host_msg_tx: host_msg_tx.clone(),
+        alt_reply: alt_reply.clone(),
+    };
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let state = Arc::new(AtomicU8::new(0));
+
+    // Off until the host asks; both serial threads and the updater's
+    // reply router record into the same ring
+    let tracer = Tracer::new();
+
+    let scheduling = Scheduling {
+        serial_priority: config.serial_priority,
+        updater_priority: config.updater_priority,
+        serial_core: config.serial_core,
+        updater_core: config.updater_core,
+    };
+
+    // Scoped so the priorities apply to exactly these spawns and are
+    // reset before the application spawns anything of its own
+    let serial_spawn = SpawnConfig::apply(config.serial_priority, config.serial_core);
+
+    let rx_shutdown = shutdown.clone();
+    // Its own TX handle, for the Busy replies a full updater queue earns
+    let rx_mcu_tx = mcu_msg_tx.clone();
+    let rx_tracer = tracer.clone();
+    let rx_thread = thread::Builder::new()
+        .stack_size(config.serial_stack_size)
+        .spawn(move || serial_thread(link_rx, host_msg_tx, rx_mcu_tx, rx_tracer, rx_shutdown))
+        .map_err(|source| ServiceError::Spawn {
+            name: "serial RX",
+            source,
+        })?;
+
+    // The TX half gets its own thread blocking on the command queue, so
+    // an ack leaves the moment it is queued instead of waiting for the
+    // host to go quiet on the RX side
+    let tx_shutdown = shutdown.clone();
+    let tx_thread = thread::Builder::new()
+        .stack_size(config.serial_tx_stack_size)
+        .spawn(move || serial_tx_thread(link_tx, mcu_msg_rx, tx_shutdown))
+        .map_err(|source| ServiceError::Spawn {
+            name: "serial TX",
+            source,
+        })?;
+
+    drop(serial_spawn);
+
+    // From here on log records reach the host too
+    logging.attach(sender.clone());
+
+    // Boards flashed with the default single-app partition table cannot
+    // take updates; say so once at startup instead of leaving the user
+    // to puzzle over refused UpdateStarts. The service stays up - other
+    // host commands (telemetry, info, partition writes) still work.
+    if simple_ota::update_slot_size().is_none() {
+        warn!(
+            "No OTA update slot; app updates will be refused until an \
+             OTA-capable partition table (CONFIG_PARTITION_TABLE_TWO_OTA) is flashed"
+        );
+    }
+
+    let checkpoint_interval = config.checkpoint_interval;
+    let progress_interval = config.progress_interval;
+    let baudrate = config.baudrate;
+    let security = Security {
+        update_key: config.update_key,
+        verifying_key: config.verifying_key,
+        require_signature: config.require_signature,
+        rollback_protection: config.rollback_protection,
+        allow_unversioned: config.allow_unversioned,
+        min_version: config.min_version,
+    };
+
+    let replies = ReplyRouter {
+        uart: mcu_msg_tx,
+        alt: alt_reply,
+        tracer,
+    };
+
+    let updater_spawn = SpawnConfig::apply(config.updater_priority, config.updater_core);
+
+    let updater_shutdown = shutdown.clone();
+    let updater_state = state.clone();
+    let updater = thread::Builder::new()
+        .stack_size(config.updater_stack_size)
+        .spawn(move || {
+            updater_thread(
+                host_msg_rx,
+                replies,
+                mode,
+                telemetry,
+                logging,
+                led,
+                uart_max_segment,
+                resume_store,
+                checkpoint_interval,
+                progress_interval,
+                baudrate,
+                security,
+                scheduling,
+                updater_shutdown,
+                updater_state,
+            )
+        })
+        .map_err(|source| ServiceError::Spawn {
+            name: "updater",
+            source,
+        })?;
+
+    drop(updater_spawn);
+
+    info!("Serial update service started");
+
+    let handle = UpdaterHandle {
+        shutdown,
+        state,
+        serial_rx: rx_thread,
+        serial_tx: tx_thread,
+        updater,
+    };
+
+    Ok((handle, sender, host_link))
+}
+
+/// The real OTA slot behind [`selftest::confirm`]; tests in the
+/// `messages` crate drive the same orchestration with a fake.
+struct OtaBackend;
+
+impl SlotBackend for OtaBackend {
+    type Error = simple_ota::Error;
+
+    fn pending_verify(&mut self) -> Result<bool, simple_ota::Error> {
+        simple_ota::pending_verify()
+    }
+
+    fn mark_valid(&mut self) -> Result<(), simple_ota::Error> {
+        simple_ota::mark_valid()
+    }
+
+    fn rollback(&mut self) -> Result<(), simple_ota::Error> {
+        simple_ota::rollback()
+    }
+}
+
+/// The default self-test: getting called at all already proves the
+/// protocol stack came up and the UART threads spawned, which is all
+/// this demo has to verify. Applications substitute their own
+/// [`SelfTest`] with real checks - sensors respond, config parses.
+pub struct StackCameUp;
+
+impl SelfTest for StackCameUp {
+    fn run(&mut self) -> Result<(), SelfTestError> {
+        Ok(())
+    }
+}
+
+/// How long a self-test may take before a clean finish still counts as
+/// a failure. Kept well under the stock 5 s task watchdog: the whole
+/// confirmation must complete before any watchdog-driven reset, because
+/// a reset here just boots the unverified image again without a verdict.
+pub const SELF_TEST_DEADLINE: Duration = Duration::from_secs(3);
+
+/// Post-boot rollback handling, called once from `main` after the update
+/// service is up. If the running image is still pending verification from
+/// a previous OTA (builds with `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`),
+/// runs `self_test` under `deadline` and either marks the slot valid or
+/// rolls back to the previous image. The outcome lands in the `Info`
+/// reply so the host can see it without scraping logs.
+pub fn confirm_running_image<T: SelfTest>(mut self_test: T, deadline: Duration) {
+    match selftest::confirm(&mut OtaBackend, &mut self_test, deadline) {
+        Ok(Verdict::NotPending) => (),
+        Ok(Verdict::Passed) => {
+            SELF_TEST.store(1, Ordering::Relaxed);
+            info!("Self-test passed, running image marked valid");
+        }
+        Ok(Verdict::Failed(err)) => {
+            // Rollback reboots on success; reaching this arm means there
+            // was nothing to roll back to and the device keeps limping
+            // along on the image it has
+            SELF_TEST.store(2, Ordering::Relaxed);
+            warn!("Self-test failed ({:?}) but rollback did not take", err);
+        }
+        Err(err) => warn!("Cannot apply the self-test verdict: {}", err),
+    }
+}
+
+/// Least stack headroom (in bytes - the xtensa FreeRTOS port sizes
+/// stacks in bytes, not words) the calling thread has ever had. Logged
+/// after every transfer so the [`SERIAL_STACK_SIZE`] and
+/// [`UPDATER_STACK_SIZE`] defaults stay honest against real workloads.
+pub fn stack_high_water() -> u32 {
+    unsafe { esp_idf_sys::uxTaskGetStackHighWaterMark(ptr::null_mut()) }
+}
+
+/// RX half of the byte link the update protocol runs on. The serial
+/// thread only ever needs "give me what arrived"; FIFOs, ring buffers
+/// and notification schemes stay inside the implementation, which is
+/// what lets UART1, UART0 and the native USB-Serial-JTAG port plug in
+/// without the updater or the message plumbing noticing.
+pub trait LinkRx: Send {
+    /// Waits up to [`RX_WAIT`] for traffic and returns how many bytes
+    /// landed in `buf`; `0` is a quiet line (or a driver error the
+    /// implementation has already logged). The bound keeps the serial
+    /// thread's shutdown and watchdog checks running while the line is
+    /// idle.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Drops whatever the driver has received but not yet handed out;
+    /// called when the stream is declared desynchronized.
+    fn flush_input(&mut self);
+}
+
+/// TX half of the byte link, owned by the TX thread.
+pub trait LinkTx: Send {
+    /// Writes one encoded frame; `false` means a driver error (already
+    /// logged) tore it - the host's checksum rejects the torn frame and
+    /// its retry path resends it.
+    fn write(&mut self, frame: &[u8]) -> bool;
+
+    /// Blocks until everything written has left the device, as far as
+    /// the driver can tell.
+    fn flush(&mut self);
+
+    /// Applies a new line rate. Links without one (the USB port, whose
+    /// rate is a fiction the host may set freely) log and ignore it.
+    fn set_baud(&mut self, rate: u32);
+}
+
+/// The classic UART transport, over whichever controller [`spawn`] was
+/// handed.
+struct UartRx<UART: serial::Uart> {
+    rx: serial::Rx<UART>,
+}
+
+impl<UART: serial::Uart + Send> LinkRx for UartRx<UART> {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        // Sleep in the driver until the first byte arrives instead of
+        // spinning on count()
+        let first = unsafe {
+            esp_idf_sys::uart_read_bytes(
+                UART::port(),
+                buf.as_mut_ptr() as *mut _,
+                1,
+                delay::TickType::from(RX_WAIT).0,
+            )
+        };
+
+        if first < 0 {
+            warn!("UART read failed: {}", first);
+            return 0;
+        }
+
+        if first == 0 {
+            return 0;
+        }
+
+        // Whatever the burst queued behind the first byte. The driver's
+        // RX ring may be larger than `buf`; the leftover comes back on
+        // the next call, immediately, since the ring is non-empty
+        let pending = self.rx.count().unwrap_or(0) as usize;
+        let chunk = read_chunk(pending, buf.len() - 1);
+
+        for slot in buf[1..].iter_mut().take(chunk) {
+            *slot = nb::block!(self.rx.read()).unwrap();
+        }
+
+        1 + chunk
+    }
+
+    fn flush_input(&mut self) {
+        if let Err(err) = esp_idf_sys::esp!(unsafe { esp_idf_sys::uart_flush_input(UART::port()) })
+        {
+            warn!("Cannot flush the UART RX buffer: {}", err);
+        }
+    }
+}
+
+struct UartTx<UART: serial::Uart> {
+    tx: serial::Tx<UART>,
+}
+
+impl<UART: serial::Uart + Send> LinkTx for UartTx<UART> {
+    fn write(&mut self, frame: &[u8]) -> bool {
+        for &byte in frame {
+            if let Err(err) = nb::block!(self.tx.write(byte)) {
+                warn!(
+                    "UART write failed, dropping the rest of the frame: {:?}",
+                    err
+                );
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn flush(&mut self) {
+        nb::block!(self.tx.flush()).ok();
+    }
+
+    fn set_baud(&mut self, rate: u32) {
+        match esp_idf_sys::esp!(unsafe { esp_idf_sys::uart_set_baudrate(UART::port(), rate) }) {
+            Ok(()) => info!("UART reconfigured to {} baud", rate),
+            Err(err) => warn!("Cannot set {} baud: {}", rate, err),
+        }
+    }
+}
+
+/// The native USB-Serial-JTAG port, for devkits whose only connector is
+/// the USB socket. The driver has no "count then read" pair - its read
+/// takes a timeout and returns the burst it has buffered - so the trait
+/// maps onto a single call. The USB protocol carries its own flow
+/// control, and there is nothing to flush or retune on the TX side.
+#[cfg(esp32s3)]
+struct UsbSerialRx;
+
+#[cfg(esp32s3)]
+impl LinkRx for UsbSerialRx {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let got = unsafe {
+            esp_idf_sys::usb_serial_jtag_read_bytes(
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as _,
+                delay::TickType::from(RX_WAIT).0,
+            )
+        };
+
+        if got < 0 {
+            warn!("USB-Serial-JTAG read failed: {}", got);
+            return 0;
+        }
+
+        got as usize
+    }
+
+    fn flush_input(&mut self) {
+        // The driver has no flush call; drain with a zero timeout until
+        // it runs dry instead
+        let mut scratch = [0_u8; 64];
+
+        while unsafe {
+            esp_idf_sys::usb_serial_jtag_read_bytes(
+                scratch.as_mut_ptr() as *mut _,
+                scratch.len() as _,
+                0,
+            )
+        } > 0
+        {}
+    }
+}
+
+#[cfg(esp32s3)]
+struct UsbSerialTx;
+
+#[cfg(esp32s3)]
+impl LinkTx for UsbSerialTx {
+    fn write(&mut self, frame: &[u8]) -> bool {
+        let wrote = unsafe {
+            esp_idf_sys::usb_serial_jtag_write_bytes(
+                frame.as_ptr() as *const _,
+                frame.len() as _,
+                delay::BLOCK,
+            )
+        };
+
+        if wrote < 0 || (wrote as usize) < frame.len() {
+            warn!(
+                "USB-Serial-JTAG write wrote {} of {} bytes",
+                wrote,
+                frame.len()
+            );
+            return false;
+        }
+
+        true
+    }
+
+    fn flush(&mut self) {
+        // The driver exposes no TX-done query; `Drained` is only
+        // load-bearing around baud switches, which this link ignores
+    }
+
+    fn set_baud(&mut self, rate: u32) {
+        info!(
+            "USB-Serial-JTAG link has no line rate; ignoring {} baud",
+            rate
+        );
+    }
+}
+
+/// The CDC-ACM interface the update link claims; the rest of the
+/// composite device stays free for other interfaces.
+#[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+const USB_CDC_PORT: esp_idf_sys::tinyusb_cdcacm_itf_t =
+    esp_idf_sys::tinyusb_cdcacm_itf_t_TINYUSB_CDC_ACM_0;
+
+/// Empty-FIFO rounds (of [`RX_WAIT`] each) a CDC write survives before
+/// the frame is declared lost; about a second with nobody reading.
+#[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+const USB_CDC_TX_STALL_LIMIT: u32 = 50;
+
+/// The TinyUSB CDC-ACM port. Its read never blocks - it hands out
+/// whatever the unread buffer holds - so the RX half sleeps [`RX_WAIT`]
+/// itself when the buffer runs dry, keeping the trait's "wait a little"
+/// contract without spinning.
+#[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+struct UsbCdcRx;
+
+#[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+impl LinkRx for UsbCdcRx {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut got: esp_idf_sys::size_t = 0;
+
+        let result = unsafe {
+            esp_idf_sys::tinyusb_cdcacm_read(
+                USB_CDC_PORT,
+                buf.as_mut_ptr(),
+                buf.len() as _,
+                &mut got,
+            )
+        };
+
+        if let Err(err) = esp_idf_sys::esp!(result) {
+            warn!("USB CDC read failed: {}", err);
+            return 0;
+        }
+
+        if got == 0 {
+            std::thread::sleep(RX_WAIT);
+        }
+
+        got as usize
+    }
+
+    fn flush_input(&mut self) {
+        // No flush call in the driver; drain the unread buffer dry
+        let mut scratch = [0_u8; 64];
+        let mut got: esp_idf_sys::size_t = 0;
+
+        while unsafe {
+            esp_idf_sys::tinyusb_cdcacm_read(
+                USB_CDC_PORT,
+                scratch.as_mut_ptr(),
+                scratch.len() as _,
+                &mut got,
+            )
+        } == esp_idf_sys::ESP_OK
+            && got > 0
+        {}
+    }
+}
+
+#[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+struct UsbCdcTx;
+
+#[cfg(all(any(esp32s2, esp32s3), feature = "usb_cdc_update"))]
+impl LinkTx for UsbCdcTx {
+    fn write(&mut self, frame: &[u8]) -> bool {
+        // The queue call takes what fits its FIFO; flush between rounds
+        // until the whole frame is gone. A host that stopped reading
+        // stalls the FIFO for good - give up on the frame rather than
+        // wedging the TX thread, like a UART write error would.
+        let mut queued = 0;
+        let mut stalls = 0;
+
+        while queued < frame.len() {
+            let took = unsafe {
+                esp_idf_sys::tinyusb_cdcacm_write_queue(
+                    USB_CDC_PORT,
+                    frame[queued..].as_ptr(),
+                    (frame.len() - queued) as _,
+                )
+            };
+
+            if took == 0 {
+                stalls += 1;
+                if stalls > USB_CDC_TX_STALL_LIMIT {
+                    warn!("USB CDC write stalled, dropping the frame");
+                    return false;
+                }
+
+                self.flush();
+                std::thread::sleep(RX_WAIT);
+                continue;
+            }
+
+            stalls = 0;
+            queued += took as usize;
+        }
+
+        self.flush();
+        true
+    }
+
+    fn flush(&mut self) {
+        let result = unsafe {
+            esp_idf_sys::tinyusb_cdcacm_write_flush(USB_CDC_PORT, delay::TickType::from(RX_WAIT).0)
+        };
+
+        if let Err(err) = esp_idf_sys::esp!(result) {
+            // A host that stopped reading fills the FIFO; the frame is
+            // torn and the host-side checksum and retry sort it out
+            warn!("USB CDC flush failed: {}", err);
+        }
+    }
+
+    fn set_baud(&mut self, rate: u32) {
+        info!("USB CDC link has no line rate; ignoring {} baud", rate);
+    }
+}
+
+/// Any end of the shared [`Transport`] abstraction works as either link
+/// half: the RX and TX threads each get their own end (a socket clone,
+/// one side of a loopback pair) and [`spawn_with_link`] takes it from
+/// there. The driver-specific links above stay hand-written because the
+/// IDF splits them into RX/TX halves with their own quirks; everything
+/// host-shaped comes in through this bridge.
+impl<T: Transport + Send> LinkRx for T {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        match self.read_available(buf, RX_WAIT) {
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => 0,
+            Err(err) => {
+                warn!("Transport read failed: {}", err);
+                0
+            }
+        }
+    }
+
+    fn flush_input(&mut self) {
+        // No driver buffer to drop; drain whatever is queued instead
+        let mut scratch = [0_u8; 64];
+
+        while matches!(self.read_available(&mut scratch, Duration::ZERO), Ok(1..)) {}
+    }
+}
+
+impl<T: Transport + Send> LinkTx for T {
+    fn write(&mut self, frame: &[u8]) -> bool {
+        if let Err(err) = self.write_all(frame) {
+            warn!("Transport write failed: {}", err);
+            return false;
+        }
+
+        true
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = Transport::flush(self) {
+            warn!("Transport flush failed: {}", err);
+        }
+    }
+
+    fn set_baud(&mut self, rate: u32) {
+        info!("Transport link has no line rate; ignoring {} baud", rate);
+    }
+}
+
+fn serial_thread<RXL: LinkRx>(
+    mut rx: RXL,
+    host_msg_tx: mpsc::SyncSender<(Link, Inbound)>,
+    mcu_tx: mpsc::SyncSender<SerialCommand>,
+    tracer: Tracer,
+    shutdown: Arc<AtomicBool>,
+) {
+    // On the heap: a whole kilobyte of scratch would otherwise dominate
+    // this thread's stack budget
+    let mut buf = vec![0_u8; BUF_SIZE];
+
+    // Bytes received but not yet parsed: at high baud rates a message
+    // regularly straddles two reads, and a burst can hold several.
+    let mut accumulated: Vec<u8> = Vec::new();
+
+    let wdt = WdtSubscription::subscribe();
+
+    // Consecutive parse attempts that produced garbage rather than a
+    // frame or an "incomplete" verdict. Persists across reads: noise can
+    // trickle in one read at a time, and it still has to trip the flush.
+    let mut failures: u32 = 0;
+
+    loop {
+        // The link's read wait bounds how long a shutdown request sits
+        // unseen
+        if shutdown.load(Ordering::Relaxed) {
+            info!("Shutdown requested, stopping the serial thread");
+            return;
+        }
+
+        wdt.feed();
+
+        let got = rx.read(&mut buf);
+
+        if got > 0 {
+            LinkCounters::bump(&LINK_STATS.bytes_received, got as u32);
+            accumulated.extend_from_slice(&buf[..got]);
+
+            // Forward every complete message in the buffer, keeping the
+            // tail bytes for the next read to finish
+            loop {
+                match codec::take::<MessageTypeHost>(&accumulated) {
+                    Ok((payload, rest)) => {
+                        let consumed = accumulated.len() - rest.len();
+                        accumulated.drain(..consumed);
+
+                        failures = 0;
+                        LinkCounters::bump(&LINK_STATS.frames_received, 1);
+
+                        tracer.record(|| format!("rx {} {}B", payload.name(), consumed), &mcu_tx);
+
+                        // The transfer that just ended is this
+                        // thread's peak workload; note where the
+                        // mark sits before handing the frame on
+                        if matches!(payload, MessageTypeHost::UpdateEnd(_)) {
+                            info!(
+                                "Serial thread stack high-water mark: {} bytes free",
+                                stack_high_water()
+                            );
+                        }
+
+                        if !queue_inbound(payload, &host_msg_tx, &mcu_tx) {
+                            info!("Updater gone, stopping the serial thread");
+                            return;
+                        }
+                    }
+                    Err(codec::DecodeError::BadCrc { frame_len, .. }) => {
+                        accumulated.drain(..frame_len);
+
+                        warn!("Dropping frame with bad checksum");
+                        LinkCounters::bump(&LINK_STATS.crc_errors, 1);
+                        tracer.record(|| format!("rx {}B bad checksum", frame_len), &mcu_tx);
+                        failures += 1;
+                    }
+                    // Not enough bytes yet; more are on the way
+                    Err(codec::DecodeError::Truncated) => break,
+                    Err(err) => {
+                        // The stream has no frame delimiters, so sync is
+                        // regained by scanning: skip one byte and try to
+                        // parse again at the next
+                        debug!("Skipping an undecodable byte: {:?}", err);
+
+                        // One trace entry (and one counted decode
+                        // error) per garbage run, not per scanned byte;
+                        // the preview shows where the stream went off
+                        // the rails
+                        if failures == 0 {
+                            LinkCounters::bump(&LINK_STATS.decode_errors, 1);
+                            tracer.record(
+                                || {
+                                    let n = accumulated.len().min(trace::RAW_PREVIEW);
+                                    format!("rx? {:?} {:02x?}", err, &accumulated[..n])
+                                },
+                                &mcu_tx,
+                            );
+                        }
+
+                        accumulated.drain(..1);
+                        failures += 1;
+                    }
+                }
+
+                // Scanning byte by byte recovers from a short noise
+                // burst, but a stream this far gone - a baud mismatch,
+                // a host that is not speaking the protocol - only ever
+                // produces more garbage. Drop it all and start clean.
+                if failures >= DESYNC_THRESHOLD {
+                    warn!(
+                        "RX stream desynchronized ({} consecutive bad parses), flushing",
+                        failures
+                    );
+
+                    rx.flush_input();
+
+                    accumulated.clear();
+                    failures = 0;
+                    DESYNCS.fetch_add(1, Ordering::Relaxed);
+
+                    // Let the updater point the host at the segment it
+                    // still expects instead of leaving it to time out.
+                    // A blocking send: the flush already cost whatever
+                    // was in flight, the notice must not be lost too.
+                    if host_msg_tx.send((Link::Uart, Inbound::Desync)).is_err() {
+                        info!("Updater gone, stopping the serial thread");
+                        return;
+                    }
+
+                    break;
+                }
+            }
+
+            // Garbage that never completes a frame must not grow the
+            // buffer forever; dropping it loses at most one segment and
+            // the host's retry path re-sends it
+            if accumulated.len() > MAX_REASSEMBLY {
+                warn!(
+                    "Reassembly buffer overflow, dropping {} bytes",
+                    accumulated.len()
+                );
+                accumulated.clear();
+            }
+        }
+    }
+}
+
+/// How many bytes the next driver read should pull: everything pending,
+/// capped at the scratch buffer. Kept as a pure function so the
+/// clamping is checkable by inspection; the driver's count is a ring
+/// fill level, not a promise that it fits [`BUF_SIZE`].
+fn read_chunk(pending: usize, capacity: usize) -> usize {
+    pending.min(capacity)
+}
+
+/// Queues one decoded frame for the updater; `false` once the updater is
+/// gone. The queue is bounded, and a full one means the updater sits in
+/// a long flash operation: the frame is dropped and answered with
+/// `Busy` so the host resends later instead of the device buffering
+/// megabytes of segments on the heap. A `Cancel` is the exception - it
+/// must get through precisely when the queue is jammed, and the updater
+/// drains the queue in bounded time, so blocking for a slot is safe.
+fn queue_inbound(
+    msg: MessageTypeHost,
+    host_msg_tx: &mpsc::SyncSender<(Link, Inbound)>,
+    mcu_tx: &mpsc::SyncSender<SerialCommand>,
+) -> bool {
+    if matches!(msg, MessageTypeHost::Cancel) {
+        return host_msg_tx
+            .send((Link::Uart, Inbound::Message(msg)))
+            .is_ok();
+    }
+
+    match host_msg_tx.try_send((Link::Uart, Inbound::Message(msg))) {
+        Ok(()) => true,
+        Err(mpsc::TrySendError::Full(_)) => {
+            debug!("Updater queue full, answering Busy");
+            LinkCounters::bump(&LINK_STATS.busy_events, 1);
+
+            // Best effort: if the TX queue is congested too, the host's
+            // reply timeout covers the retry
+            mcu_tx
+                .try_send(SerialCommand::Send(MessageTypeMcu::Busy {
+                    retry_after_ms: BUSY_RETRY_MS,
+                }))
+                .ok();
+
+            true
+        }
+        Err(mpsc::TrySendError::Disconnected(_)) => false,
+    }
+}
+
+/// Owns the TX half of the link: blocks on the command queue and writes
+/// each frame the moment it is queued, so an ack's latency no longer
+/// depends on the host going quiet on the RX side. Commands are still
+/// processed in order by one thread, which is what lets `Drained` mean
+/// what it says.
+fn serial_tx_thread<TXL: LinkTx>(
+    mut tx: TXL,
+    mcu_msg_rx: mpsc::Receiver<SerialCommand>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let wdt = WdtSubscription::subscribe();
+
+    // A failed write tears at most the frame it hit - the host's
+    // checksum rejects the torn frame and its retry path resends it -
+    // but a line that keeps failing should be visible in the log
+    let mut write_errors: u32 = 0;
+
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            info!("Shutdown requested, stopping the serial TX thread");
+            return;
+        }
+
+        wdt.feed();
+
+        match mcu_msg_rx.recv_timeout(WDT_FEED_INTERVAL) {
+            Ok(SerialCommand::Send(msg)) => {
+                // None of our reply types can fail to serialize; if one
+                // ever does, dropping the frame beats panicking the TX
+                // thread mid-update
+                let frame = match codec::encode(&msg) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        warn!("Dropping unserializable frame: {:?}", err);
+                        continue;
+                    }
+                };
+
+                LinkCounters::bump(&LINK_STATS.bytes_sent, frame.len() as u32);
+
+                if !tx.write(&frame) {
+                    write_errors += 1;
+                    warn!("Serial write errors so far: {}", write_errors);
+                }
+            }
+            Ok(SerialCommand::Drained(ack)) => {
+                // Every earlier frame was written with blocking writes
+                // by this very thread, so once we get here the queue
+                // really is drained
+                tx.flush();
+                ack.send(()).ok();
+            }
+            Ok(SerialCommand::SetBaud(rate)) => tx.set_baud(rate),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                info!("Updater gone, stopping the serial TX thread");
+                return;
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn updater_thread(
+    host_msg_rx: mpsc::Receiver<(Link, Inbound)>,
+    replies: ReplyRouter,
+    mode: SharedMode,
+    telemetry: adc_telemetry::Control,
+    logging: protocol_log::Control,
+    led: StatusLed,
+    uart_max_segment: Option<u16>,
+    mut resume_store: resume::Store,
+    checkpoint_interval: u32,
+    progress_interval: u32,
+    initial_baud: u32,
+    security: Security,
+    scheduling: Scheduling,
+    shutdown: Arc<AtomicBool>,
+    state: Arc<AtomicU8>,
+) {
+    let mut sm = StateMachine::new(Context::new());
+    let mut last_activity = Instant::now();
+    let mut last_ping_reply: Option<Instant> = None;
+
+    // The rate to fall back to if the host never shows up after a baud
+    // switch, with the deadline for its first valid frame
+    let mut current_baud = initial_baud;
+    let mut baud_revert: Option<(u32, Instant)> = None;
+
+    let wdt = WdtSubscription::subscribe();
+
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            info!("Shutdown requested, stopping the updater");
+            break;
+        }
+
+        // Mirror the state for the handle; the store sits at the top of
+        // the loop, so it is current the moment the previous message's
+        // processing finished
+        state.store(UpdaterState::code(sm.state()), Ordering::Relaxed);
+
+        // Wake often enough to feed the WDT even when the channel is
+        // quiet; the inactivity timeout only runs while an update is in
+        // flight, and every valid host message - Ping and Cancel
+        // included - resets it.
+        let (link, inbound) = match host_msg_rx.recv_timeout(WDT_FEED_INTERVAL) {
+            Ok(msg) => {
+                last_activity = Instant::now();
+                msg
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                wdt.feed();
+
+                // Nothing valid arrived at the new rate in time; the
+                // host is presumably still at the old one
+                if let Some((previous, deadline)) = baud_revert {
+                    if Instant::now() >= deadline {
+                        warn!(
+                            "No host message since the baud change, falling back to {} baud",
+                            previous
+                        );
+
+                        baud_revert = None;
+                        current_baud = previous;
+
+                        if replies.uart.send(SerialCommand::SetBaud(previous)).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                if sm.context().update.is_some() && last_activity.elapsed() >= INACTIVITY_TIMEOUT {
+                    warn!(
+                        "No host message for {:?}, aborting the update",
+                        INACTIVITY_TIMEOUT
+                    );
+
+                    if let Some(ActiveUpdate {
+                        target: Target::App(update),
+                        ..
+                    }) = sm.context_mut().update.take()
+                    {
+                        update.abort();
+                    }
+
+                    sm.process_event(Events::TimedOut).ok();
+                    mode.set(DeviceMode::Idle);
+                    led.show(Pattern::Failure);
+
+                    // In case the host is still listening, push the
+                    // abort and its reason rather than leaving it to
+                    // time out against a device that went idle
+                    if replies
+                        .send(
+                            sm.context().link,
+                            MessageTypeMcu::UpdateAborted(FailureReason::Timeout),
+                        )
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                info!("Serial thread gone, stopping the updater");
+                break;
+            }
+        };
+
+        // A desync is not a host message: the serial thread flushed the
+        // line and whatever the host sent is gone. If a transfer is in
+        // flight, point the host at the segment the device still expects
+        // - its Retry handling re-sends exactly that id - instead of
+        // leaving it to wait out its reply timeout.
+        let msg = match inbound {
+            Inbound::Message(msg) => msg,
+            Inbound::Desync => {
+                if let Some(active) = &sm.context().update {
+                    let expected = active.tracker.expected();
+
+                    warn!(
+                        "RX desync during a transfer, asking the host to resume at segment {}",
+                        expected
+                    );
+
+                    if replies
+                        .send(
+                            link,
+                            MessageTypeMcu::UpdateSegmentStatus {
+                                id: expected,
+                                status: Status::Retry,
+                            },
+                        )
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+
+                wdt.feed();
+                continue;
+            }
+        };
+
+        // Any valid frame that made it off the UART proves the host
+        // really speaks the new rate
+        if matches!(link, Link::Uart) && baud_revert.take().is_some() {
+            info!("Host confirmed the new baud rate");
+        }
+
+        // Baud switching is a property of the UART itself, so it lives
+        // here next to the serial queue and the fallback timer rather
+        // than in the transport-agnostic handler
+        if let MessageTypeHost::SetBaud(rate) = msg {
+            match set_baud(rate, link, &sm, &replies, current_baud) {
+                Ok(Some(previous)) => {
+                    baud_revert = Some((previous, Instant::now() + BAUD_GRACE));
+                    current_baud = rate;
+                }
+                Ok(None) => (),
+                Err(_) => break,
+            }
+
+            wdt.feed();
+            continue;
+        }
+
+        if handle_message(
+            msg,
+            link,
+            &mut sm,
+            &replies,
+            &mode,
+            &telemetry,
+            &logging,
+            &led,
+            uart_max_segment,
+            &mut resume_store,
+            checkpoint_interval,
+            progress_interval,
+            &mut last_ping_reply,
+            &security,
+            &scheduling,
+            &wdt,
+        )
+        .is_err()
+        {
+            break;
+        }
+
+        // Segment writes, finalization and verification all happen in
+        // handle_message; a feed per message keeps the WDT quiet through
+        // the longest flash operations
+        wdt.feed();
+    }
+
+    // However the loop ended - shutdown or the serial thread going away
+    // - an open OTA handle must not outlive the thread that owns it
+    let link = sm.context().link;
+    if let Some(ActiveUpdate {
+        target: Target::App(update),
+        ..
+    }) = sm.context_mut().update.take()
+    {
+        info!("Aborting the in-progress update");
+        update.abort();
+
+        // Best effort: on a clean shutdown the serial thread is still
+        // there to deliver the notice; if it is gone, so is the link
+        replies
+            .send(link, MessageTypeMcu::UpdateAborted(FailureReason::Shutdown))
+            .ok();
+    }
+
+    mode.set(DeviceMode::Idle);
+    led.show(Pattern::Off);
+    state.store(UpdaterState::code(&States::Idle), Ordering::Relaxed);
+
+    info!("Updater stopped");
+}
+
+/// The per-update `CAP_*` byte advertised in `UpdateStartStatus`, and
+/// the low byte of the session capabilities in `HelloAck`. Decryption
+/// is only on the table when a key was baked in; heatshrink
+/// decompression needs nothing negotiated beyond the parameters both
+/// sides already share.
+fn legacy_capabilities(security: &Security) -> u8 {
+    let mut capabilities = CAP_COMPRESSED_SEGMENTS | CAP_DELTA_UPDATES;
+    if security.update_key.is_some() {
+        capabilities |= CAP_ENCRYPTED_SEGMENTS;
+    }
+    if security.require_signature {
+        capabilities |= CAP_SIGNATURE_REQUIRED;
+    }
+
+    capabilities
+}
+
+/// The full session capability set for `HelloAck`: the legacy byte plus
+/// everything this firmware supports beyond it.
+fn session_capabilities(security: &Security) -> Caps {
+    Caps::from_legacy(legacy_capabilities(security))
+        | Caps::RESUME
+        | Caps::PROGRESS
+        | Caps::STATS
+        | Caps::READBACK
+        | Caps::ERASE
+}
+
+/// Handles one host message by driving it through the state machine;
+/// an event that is invalid in the current state is answered with a
+/// `Failed` status. Device-side failures are likewise reported to the
+/// host so it can retry - a board without an OTA partition table must
+/// not panic the thread on the first `UpdateStart`. `Err` means the
+/// serial thread is gone and the updater should stop.
+#[allow(clippy::too_many_arguments)]
+fn handle_message(
+    msg: MessageTypeHost,
+    link: Link,
+    sm: &mut StateMachine<Context>,
+    replies: &ReplyRouter,
+    mode: &SharedMode,
+    telemetry: &adc_telemetry::Control,
+    logging: &protocol_log::Control,
+    led: &StatusLed,
+    uart_max_segment: Option<u16>,
+    resume_store: &mut resume::Store,
+    checkpoint_interval: u32,
+    progress_interval: u32,
+    last_ping_reply: &mut Option<Instant>,
+    security: &Security,
+    scheduling: &Scheduling,
+    wdt: &WdtSubscription,
+) -> Result<(), mpsc::SendError<SerialCommand>> {
+    // Commands that neither read nor touch the update state are
+    // answered first, without going anywhere near the state machine, so
+    // a ping cannot perturb a transfer in flight
+    if handle_stateless_message(
+        &msg,
+        link,
+        replies,
+        telemetry,
+        logging,
+        resume_store,
+        scheduling,
+        last_ping_reply,
+    )? {
+        return Ok(());
+    }
+
+    match msg {
+        MessageTypeHost::UpdateStart(start) => {
+            // What this transport can take per segment: the UART only
+            // copes with large segments under hardware flow control,
+            // while TCP and BLE ack at the transport layer and can
+            // always take them
+            let max_segment = match link {
+                Link::Uart => uart_max_segment,
+                Link::Tcp | Link::Ble => Some(RECEIVE_CAPACITY as u16),
+            };
+
+            let capabilities = legacy_capabilities(security);
+
+            // A fresh UpdateStart mid-transfer means the host restarted
+            // after the handshake (its Cancel, if any, got lost): abort
+            // the half-done transfer and let the new session take over,
+            // an implicit cancel-and-restart. Only on the same transport
+            // though - a second host on another link gets refused, not
+            // the session it would be stealing.
+            if sm.context().update.is_some() {
+                if sm.context().link != link {
+                    warn!("UpdateStart while an update runs on another transport");
+
+                    replies.send(
+                        link,
+                        MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                            status: Status::Failed,
+                            capabilities,
+                            max_segment_size: max_segment,
+                            resume_offset: None,
+                        }),
+                    )?;
+
+                    return Ok(());
+                }
+
+                warn!("UpdateStart mid-transfer; aborting the previous session");
+
+                let ctx = sm.context_mut();
+                if let Some(ActiveUpdate {
+                    target: Target::App(update),
+                    ..
+                }) = ctx.update.take()
+                {
+                    update.abort();
+                }
+                ctx.segments_written = 0;
+                ctx.duplicates = 0;
+                // The resume checkpoint is kept: whatever reached flash
+                // before the host died is still good, and the fresh
+                // start below may pick it up
+            }
+
+            if sm.process_event(Events::UpdateStarted).is_err() {
+                warn!("UpdateStart while another update is in progress");
+
+                replies.send(
+                    link,
+                    MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                        status: Status::Failed,
+                        capabilities,
+                        max_segment_size: max_segment,
+                        resume_offset: None,
+                    }),
+                )?;
+
+                return Ok(());
+            }
+
+            info!(
+                "Update started, image size: {} bytes, target: {}",
+                start.size,
+                start.partition.as_deref().unwrap_or("app")
+            );
+            // Bracketed with the low-water line at the end of the
+            // transfer; the bounded inbound queue is what keeps the
+            // difference flat under host-side segment pressure
+            info!("Free heap at transfer start: {} bytes", unsafe {
+                esp_idf_sys::esp_get_free_heap_size()
+            });
+
+            // Published for the auxiliary tasks: the ADC stream pauses
+            // and the log mirror drops below-warning records so neither
+            // interleaves with the ack traffic during the transfer
+            mode.set(DeviceMode::Updating);
+
+            if replies.tracer.on_transfer_start() {
+                info!("Protocol trace suspended for the transfer");
+            }
+
+            // An encrypted transfer announces its nonce prefix up front;
+            // refusing it here is clearer than failing every segment
+            let mut status = if start.nonce_prefix.is_some() && security.update_key.is_none() {
+                warn!("Encrypted update refused: no decryption key configured");
+                Status::Failed
+            } else {
+                // A delta only makes sense against the exact base the
+                // host diffed; otherwise ask for a full transfer instead.
+                match &start.delta_base {
+                    Some(base) => match simple_ota::running_sha256(base.len as usize) {
+                        Ok(running) if running == base.hash => Status::Ok,
+                        Ok(_) => {
+                            warn!("Delta base mismatch, requesting a full transfer");
+                            Status::BaseMismatch
+                        }
+                        Err(err) => {
+                            warn!("Cannot hash the running image: {}", err);
+                            Status::Failed
+                        }
+                    },
+                    None => Status::Ok,
+                }
+            };
+
+            let mut resume_offset = None;
+
+            if status == Status::Ok {
+                // A checkpointed transfer of this very image can pick
+                // up after the bytes already in flash - but only when
+                // the host said it knows how to skip them
+                let resumed =
+                    if start.resume && start.partition.is_none() && start.delta_base.is_none() {
+                        try_resume(&start, resume_store, wdt)
+                    } else {
+                        None
+                    };
+
+                let active = match resumed {
+                    Some((active, offset)) => {
+                        info!("Resuming the interrupted transfer at byte {}", offset);
+                        resume_offset = Some(offset);
+                        Ok(active)
+                    }
+                    None => {
+                        // The begin-time erase is the longest single
+                        // stretch on this thread; start it with a full
+                        // watchdog budget. esp_ota_begin erases inside
+                        // the IDF where nothing can feed, while the
+                        // partition path feeds between chunks.
+                        wdt.feed();
+
+                        let target = match &start.partition {
+                            Some(label) => {
+                                PartitionUpdate::begin(label, start.size as usize, || wdt.feed())
+                                    .map(Target::Partition)
+                            }
+                            None => OtaUpdate::begin().map(Target::App),
+                        };
+
+                        target.map(|target| {
+                            // Only app transfers with an announced hash
+                            // are worth checkpointing; delta segments
+                            // have no stable byte offsets to come back to
+                            let resume = match (&target, start.sha256, &start.delta_base) {
+                                (Target::Partition(_), _, _) | (_, None, _) | (_, _, Some(_)) => {
+                                    None
+                                }
+                                (_, Some(sha256), None) => Some(ResumeTracking {
+                                    sha256,
+                                    size: start.size,
+                                    crc: Crc32::new(),
+                                    written: 0,
+                                    segments_since_checkpoint: 0,
+                                }),
+                            };
+
+                            // Only app images carry a descriptor worth
+                            // holding against the rollback policy
+                            let descriptor = match &target {
+                                Target::App(_) => Some(DescriptorCapture::new()),
+                                Target::Slot(_) | Target::Partition(_) => None,
+                            };
+
+                            ActiveUpdate {
+                                target,
+                                check: ImageCheck::new(start.size),
+                                tracker: SegmentTracker::new(),
+                                resume,
+                                nonce_prefix: start.nonce_prefix,
+                                descriptor,
+                                progress: start.progress,
+                                buffer: Vec::with_capacity(resume::SECTOR_SIZE),
+                            }
+                        })
+                    }
+                };
+
+                match active {
+                    Ok(active) => {
+                        // Record the identity up front so a transfer
+                        // dying before its first checkpoint still
+                        // leaves consistent state behind
+                        if let Some(tracking) = &active.resume {
+                            resume_store.save(&resume::Checkpoint {
+                                sha256: tracking.sha256,
+                                size: tracking.size,
+                                offset: tracking.written,
+                                crc: tracking.crc.finalize(),
+                            });
+                        }
+
+                        let ctx = sm.context_mut();
+
+                        ctx.update = Some(active);
+                        ctx.segments_written = 0;
+                        ctx.duplicates = 0;
+                        ctx.link = link;
+
+                        led.show(Pattern::Receiving);
+                    }
+                    Err(simple_ota::Error::NoUpdatePartition) => {
+                        warn!(
+                            "No OTA update slot; select an OTA-capable partition table \
+                             (CONFIG_PARTITION_TABLE_TWO_OTA) and reflash over USB"
+                        );
+                        status = Status::NoOtaPartition;
+                    }
+                    Err(err) => {
+                        warn!("Cannot start update: {}", err);
+                        status = Status::Failed;
+                    }
+                }
+            }
+
+            if status != Status::Ok {
+                // Nothing actually started; fall back to Idle
+                sm.process_event(Events::StartFailed).ok();
+                mode.set(DeviceMode::Idle);
+                led.show(Pattern::Failure);
+            }
+
+            replies.send(
+                link,
+                MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                    status,
+                    capabilities,
+                    max_segment_size: max_segment,
+                    resume_offset,
+                }),
+            )?;
+        }
+        MessageTypeHost::UpdateSegment(segment) => {
+            let status = if sm.process_event(Events::SegmentReceived).is_err() {
+                warn!("Segment {} without an update in progress", segment.id);
+                Status::Failed
+            } else {
+                let ctx = sm.context_mut();
+
+                // A retransmit whose ack got lost is acked again without
+                // touching flash; writing it twice would corrupt the image
+                match ctx.update.as_mut() {
+                    // Bigger than anything we ever advertise: nothing
+                    // downstream is sized for it, so ask for a resend
+                    // rather than writing it anyway (the serial thread
+                    // has already flushed whatever overflowed its
+                    // reassembly buffer outright)
+                    Some(_) if segment.data.len() > RECEIVE_CAPACITY => {
+                        warn!(
+                            "Segment {} carries {} bytes, over the advertised {}",
+                            segment.id,
+                            segment.data.len(),
+                            RECEIVE_CAPACITY
+                        );
+                        Status::Retry
+                    }
+                    Some(active) => match active.tracker.classify(segment.id) {
+                        SegmentAction::Write => match active.write(&segment.data) {
+                            Ok(()) => segment_written(
+                                active,
+                                &mut ctx.segments_written,
+                                resume_store,
+                                checkpoint_interval,
+                                security,
+                                replies,
+                                link,
+                                progress_interval,
+                            ),
+                            Err(err) => {
+                                // A flush failure: the lost block spans
+                                // already-acked segments, so this tears
+                                // the update down rather than asking
+                                // for a resend
+                                let status = write_failure_status(&err);
+                                warn!(
+                                    "Segment {} write failed: {} -> {:?}",
+                                    segment.id, err, status
+                                );
+                                status
+                            }
+                        },
+                        SegmentAction::AckDuplicate => {
+                            debug!("Segment {} already written, acking again", segment.id);
+                            ctx.duplicates += 1;
+                            LinkCounters::bump(&LINK_STATS.duplicate_segments, 1);
+                            Status::Ok
+                        }
+                        SegmentAction::Reject => {
+                            warn!("Segment {} out of order", segment.id);
+                            Status::Failed
+                        }
+                    },
+                    None => {
+                        warn!("Segment {} without an update in progress", segment.id);
+                        Status::Failed
+                    }
+                }
+            };
+
+            if fatal_segment_status(status) {
+                abort_failed_update(sm, mode, led);
+            }
+
+            replies.send(
+                link,
+                MessageTypeMcu::UpdateSegmentStatus {
+                    id: segment.id,
+                    status,
+                },
+            )?;
+        }
+        MessageTypeHost::UpdateSegmentCompressed(segment) => {
+            // Compressed segments are independent heatshrink streams,
+            // so retransmits decode from a fresh decoder and duplicates
+            // are acked exactly like plain segments. A block that does
+            // not decode is corrupt
+            // on the host, not on the wire (the CRC already passed);
+            // resending it reproduces the same garbage, so the update
+            // is torn down instead of retried
+            let mut failed_decode = false;
+
+            let status = if sm.process_event(Events::SegmentReceived).is_err() {
+                warn!("Segment {} without an update in progress", segment.id);
+                Status::Failed
+            } else {
+                let ctx = sm.context_mut();
+
+                match ctx.update.as_mut() {
+                    Some(_) if segment.data.len() > RECEIVE_CAPACITY => {
+                        warn!(
+                            "Segment {} carries {} bytes, over the advertised {}",
+                            segment.id,
+                            segment.data.len(),
+                            RECEIVE_CAPACITY
+                        );
+                        Status::Retry
+                    }
+                    // `raw_len` bounds the decode buffer, so it gets
+                    // the same ceiling as the wire data before any
+                    // allocation happens
+                    Some(_) if segment.raw_len as usize > RECEIVE_CAPACITY => {
+                        warn!(
+                            "Segment {} expands to {} bytes, over the advertised {}",
+                            segment.id, segment.raw_len, RECEIVE_CAPACITY
+                        );
+                        failed_decode = true;
+                        Status::InvalidImage
+                    }
+                    Some(active) => match active.tracker.classify(segment.id) {
+                        SegmentAction::Write => {
+                            match decompress_segment(segment.id, segment.raw_len, &segment.data) {
+                                Some(raw) => match active.write(&raw) {
+                                    Ok(()) => segment_written(
+                                        active,
+                                        &mut ctx.segments_written,
+                                        resume_store,
+                                        checkpoint_interval,
+                                        security,
+                                        replies,
+                                        link,
+                                        progress_interval,
+                                    ),
+                                    Err(err) => {
+                                        let status = write_failure_status(&err);
+                                        warn!(
+                                            "Segment {} write failed: {} -> {:?}",
+                                            segment.id, err, status
+                                        );
+                                        status
+                                    }
+                                },
+                                None => {
+                                    failed_decode = true;
+                                    Status::InvalidImage
+                                }
+                            }
+                        }
+                        SegmentAction::AckDuplicate => {
+                            debug!("Segment {} already written, acking again", segment.id);
+                            ctx.duplicates += 1;
+                            LinkCounters::bump(&LINK_STATS.duplicate_segments, 1);
+                            Status::Ok
+                        }
+                        SegmentAction::Reject => {
+                            warn!("Segment {} out of order", segment.id);
+                            Status::Failed
+                        }
+                    },
+                    None => {
+                        warn!("Segment {} without an update in progress", segment.id);
+                        Status::Failed
+                    }
+                }
+            };
+
+            if fatal_segment_status(status) || failed_decode {
+                abort_failed_update(sm, mode, led);
+            }
+
+            replies.send(
+                link,
+                MessageTypeMcu::UpdateSegmentStatus {
+                    id: segment.id,
+                    status,
+                },
+            )?;
+        }
+        MessageTypeHost::UpdateSegmentEncrypted(segment) => {
+            // Tracks the FlashWrite convention below: a segment that
+            // yields no authenticated plaintext tears the whole update
+            // down, since a host (or man in the middle) producing bad
+            // tags cannot be retried into producing good ones
+            let mut failed_auth = false;
+            // Authenticated plaintext that does not decompress is corrupt
+            // on the host, exactly like a bad compressed segment
+            let mut failed_decode = false;
+
+            let status = if sm.process_event(Events::SegmentReceived).is_err() {
+                warn!("Segment {} without an update in progress", segment.id);
+                Status::Failed
+            } else {
+                let ctx = sm.context_mut();
+
+                match ctx.update.as_mut() {
+                    Some(_) if segment.data.len() > RECEIVE_CAPACITY => {
+                        warn!(
+                            "Segment {} carries {} bytes, over the advertised {}",
+                            segment.id,
+                            segment.data.len(),
+                            RECEIVE_CAPACITY
+                        );
+                        Status::Retry
+                    }
+                    // Same ceiling for the decode buffer as the
+                    // compressed path, checked before any allocation
+                    Some(_)
+                        if segment
+                            .raw_len
+                            .map_or(false, |n| n as usize > RECEIVE_CAPACITY) =>
+                    {
+                        warn!(
+                            "Segment {} expands to {:?} bytes, over the advertised {}",
+                            segment.id, segment.raw_len, RECEIVE_CAPACITY
+                        );
+                        failed_decode = true;
+                        Status::InvalidImage
+                    }
+                    Some(active) => match active.tracker.classify(segment.id) {
+                        SegmentAction::Write => {
+                            match open_segment(&security.update_key, active.nonce_prefix, &segment)
+                            {
+                                // Compressed before sealing: the
+                                // authenticated bytes still have to
+                                // decode before they can be written
+                                Some(plaintext) => {
+                                    let raw = match segment.raw_len {
+                                        Some(raw_len) => {
+                                            decompress_segment(segment.id, raw_len, &plaintext)
+                                        }
+                                        None => Some(plaintext),
+                                    };
+
+                                    match raw {
+                                        Some(raw) => match active.write(&raw) {
+                                            Ok(()) => segment_written(
+                                                active,
+                                                &mut ctx.segments_written,
+                                                resume_store,
+                                                checkpoint_interval,
+                                                security,
+                                                replies,
+                                                link,
+                                                progress_interval,
+                                            ),
+                                            Err(err) => {
+                                                let status = write_failure_status(&err);
+                                                warn!(
+                                                    "Segment {} write failed: {} -> {:?}",
+                                                    segment.id, err, status
+                                                );
+                                                status
+                                            }
+                                        },
+                                        None => {
+                                            failed_decode = true;
+                                            Status::InvalidImage
+                                        }
+                                    }
+                                }
+                                None => {
+                                    failed_auth = true;
+                                    Status::Failed
+                                }
+                            }
+                        }
+                        SegmentAction::AckDuplicate => {
+                            debug!("Segment {} already written, acking again", segment.id);
+                            ctx.duplicates += 1;
+                            LinkCounters::bump(&LINK_STATS.duplicate_segments, 1);
+                            Status::Ok
+                        }
+                        SegmentAction::Reject => {
+                            warn!("Segment {} out of order", segment.id);
+                            Status::Failed
+                        }
+                    },
+                    None => {
+                        warn!("Segment {} without an update in progress", segment.id);
+                        Status::Failed
+                    }
+                }
+            };
+
+            if fatal_segment_status(status) || failed_auth || failed_decode {
+                abort_failed_update(sm, mode, led);
+            }
+
+            replies.send(
+                link,
+                MessageTypeMcu::UpdateSegmentStatus {
+                    id: segment.id,
+                    status,
+                },
+            )?;
+        }
+        MessageTypeHost::UpdateSegmentDelta(segment) => {
+            let status = if sm.process_event(Events::SegmentReceived).is_err() {
+                warn!("Segment {} without an update in progress", segment.id);
+                Status::Failed
+            } else {
+                let ctx = sm.context_mut();
+
+                match ctx.update.as_mut() {
+                    Some(active) => match active.tracker.classify(segment.id) {
+                        SegmentAction::Write => match apply_delta(active, &segment.op) {
+                            Ok(()) => segment_written(
+                                active,
+                                &mut ctx.segments_written,
+                                resume_store,
+                                checkpoint_interval,
+                                security,
+                                replies,
+                                link,
+                                progress_interval,
+                            ),
+                            Err(err) => {
+                                let status = write_failure_status(&err);
+                                warn!(
+                                    "Delta segment {} failed: {} -> {:?}",
+                                    segment.id, err, status
+                                );
+                                status
+                            }
+                        },
+                        SegmentAction::AckDuplicate => {
+                            debug!("Segment {} already applied, acking again", segment.id);
+                            ctx.duplicates += 1;
+                            LinkCounters::bump(&LINK_STATS.duplicate_segments, 1);
+                            Status::Ok
+                        }
+                        SegmentAction::Reject => {
+                            warn!("Segment {} out of order", segment.id);
+                            Status::Failed
+                        }
+                    },
+                    None => {
+                        warn!("Segment {} without an update in progress", segment.id);
+                        Status::Failed
+                    }
+                }
+            };
+
+            if fatal_segment_status(status) {
+                abort_failed_update(sm, mode, led);
+            }
+
+            replies.send(
+                link,
+                MessageTypeMcu::UpdateSegmentStatus {
+                    id: segment.id,
+                    status,
+                },
+            )?;
+        }
+        MessageTypeHost::UpdateEnd(end) => {
+            if sm.process_event(Events::UpdateEndReceived).is_err() {
+                warn!("UpdateEnd without an update in progress");
+
+                replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::Failed))?;
+
+                return Ok(());
+            }
+
+            // Solid while flash is finalized and verified
+            led.show(Pattern::Finalizing);
+
+            let ctx = sm.context_mut();
+
+            info!(
+                "Transfer done: {} segments written, {} duplicate retransmits",
+                ctx.segments_written, ctx.duplicates
+            );
+            info!(
+                "Updater stack high-water mark: {} bytes free",
+                stack_high_water()
+            );
+            info!(
+                "Free heap at transfer end: {} bytes, low-water mark: {} bytes",
+                unsafe { esp_idf_sys::esp_get_free_heap_size() },
+                unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() }
+            );
+
+            // The transfer is over whichever way finalization goes, and
+            // so is the checkpoint - it has nothing left to resume onto
+            mode.set(DeviceMode::Idle);
+            resume_store.clear();
+
+            let segments_written = ctx.segments_written;
+            let duplicates = ctx.duplicates;
+
+            // The audit record for NVS, cut once the digest walk below
+            // has proven what is actually in flash; it is only
+            // persisted on the success paths
+            let mut record: Option<UpdateRecord> = None;
+
+            // Whatever the coalescing buffer still holds short of a
+            // sector boundary has to be on flash before anything below
+            // verifies or activates it
+            if let Some(mut active) = ctx.update.take() {
+                match active.flush() {
+                    Ok(()) => ctx.update = Some(active),
+                    Err(err) => {
+                        warn!("Cannot write the buffered image tail: {}", err);
+
+                        if let Target::App(app) = active.target {
+                            app.abort();
+                        }
+
+                        sm.process_event(Events::FinalizeFailed).ok();
+                        led.show(Pattern::Failure);
+
+                        replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::FlashWrite))?;
+
+                        return Ok(());
+                    }
+                }
+            }
+
+            // The image is only activated once every received byte has
+            // been accounted for; per-segment CRCs do not catch a segment
+            // written twice or an image that was corrupt on the host.
+            let target = match ctx.update.take() {
+                Some(ActiveUpdate {
+                    target,
+                    check,
+                    progress,
+                    ..
+                }) => {
+                    // Both the digest walk and complete() below can take
+                    // seconds; the phase frames are what keeps the
+                    // host's display from looking hung through them
+                    let bytes_written = check.written() as u32;
+
+                    if progress {
+                        replies.try_send(
+                            link,
+                            MessageTypeMcu::Progress {
+                                bytes_written,
+                                phase: UpdatePhase::Verifying,
+                            },
+                        );
+                    }
+
+                    match check.verify(end.sha256.as_ref()) {
+                        // The digest only proves the bytes arrived
+                        // intact; the signature over it proves who
+                        // produced them
+                        Ok(digest)
+                            if !signature_ok(security, &digest, end.signature.as_deref()) =>
+                        {
+                            if let Target::App(app) = target {
+                                app.abort();
+                            }
+
+                            sm.process_event(Events::FinalizeFailed).ok();
+                            led.show(Pattern::Failure);
+
+                            replies.send(
+                                link,
+                                MessageTypeMcu::UpdateEndStatus(Status::InvalidSignature),
+                            )?;
+
+                            return Ok(());
+                        }
+                        Ok(digest) => {
+                            if progress {
+                                replies.try_send(
+                                    link,
+                                    MessageTypeMcu::Progress {
+                                        bytes_written,
+                                        phase: UpdatePhase::Finalizing,
+                                    },
+                                );
+                            }
+
+                            record = Some(UpdateRecord {
+                                sha256: digest,
+                                source: link.name().into(),
+                                timestamp: unix_time(),
+                                segments: segments_written,
+                                retries: duplicates,
+                            });
+
+                            Some(target)
+                        }
+                        Err(err) => {
+                            warn!("Received image failed verification: {:?}", err);
+
+                            if let Target::App(app) = target {
+                                app.abort();
+                            }
+
+                            sm.process_event(Events::FinalizeFailed).ok();
+                            led.show(Pattern::Failure);
+
+                            replies.send(
+                                link,
+                                MessageTypeMcu::UpdateEndStatus(Status::InvalidImage),
+                            )?;
+
+                            return Ok(());
+                        }
+                    }
+                }
+                // Unreachable once Finalizing was entered, but a missing
+                // handle must stay a Failed reply, not a panic
+                None => None,
+            };
+
+            match target {
+                Some(Target::App(app)) => match app.complete() {
+                    Ok(()) => {
+                        info!("Update complete, restarting");
+
+                        sm.process_event(Events::FinalizeOk).ok();
+                        led.show(Pattern::Off);
+
+                        if let Some(record) = &record {
+                            resume_store.save_update(record);
+                        }
+
+                        replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::Ok))?;
+
+                        restart_after_drain(&replies.uart)?;
+                    }
+                    Err(err) => {
+                        warn!("Cannot finalize the update: {}", err);
+
+                        sm.process_event(Events::FinalizeFailed).ok();
+                        led.show(Pattern::Failure);
+
+                        replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::Failed))?;
+                    }
+                },
+                Some(Target::Slot(slot)) => match slot.complete() {
+                    Ok(()) => {
+                        info!("Resumed update complete, restarting");
+
+                        sm.process_event(Events::FinalizeOk).ok();
+                        led.show(Pattern::Off);
+
+                        if let Some(record) = &record {
+                            resume_store.save_update(record);
+                        }
+
+                        replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::Ok))?;
+
+                        restart_after_drain(&replies.uart)?;
+                    }
+                    Err(err) => {
+                        warn!("Cannot finalize the update: {}", err);
+
+                        sm.process_event(Events::FinalizeFailed).ok();
+                        led.show(Pattern::Failure);
+
+                        replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::Failed))?;
+                    }
+                },
+                Some(Target::Partition(_)) => {
+                    info!("Partition write complete");
+
+                    sm.process_event(Events::FinalizeOk).ok();
+
+                    if let Some(record) = &record {
+                        resume_store.save_update(record);
+                    }
+                    led.show(if end.reboot {
+                        Pattern::Off
+                    } else {
+                        Pattern::Idle
+                    });
+
+                    replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::Ok))?;
+
+                    if end.reboot {
+                        restart_after_drain(&replies.uart)?;
+                    }
+                }
+                None => {
+                    warn!("UpdateEnd without an update in progress");
+
+                    sm.process_event(Events::FinalizeFailed).ok();
+                    led.show(Pattern::Failure);
+
+                    replies.send(link, MessageTypeMcu::UpdateEndStatus(Status::Failed))?;
+                }
+            }
+        }
+        MessageTypeHost::Cancel => {
+            info!("Update cancelled by the host");
+
+            // Messages are handled in order on this thread, so a Cancel
+            // queued behind a flash write is only answered once that
+            // write - and the abort below - have actually finished.
+            if let Some(ActiveUpdate {
+                target: Target::App(update),
+                ..
+            }) = sm.context_mut().update.take()
+            {
+                update.abort();
+            }
+
+            sm.process_event(Events::Cancelled).ok();
+            mode.set(DeviceMode::Idle);
+            led.show(Pattern::Idle);
+
+            // A cancel is deliberate; resuming onto its leftovers later
+            // would only surprise the host
+            resume_store.clear();
+
+            // Acked in every state; with nothing in flight the cancel
+            // is a no-op that still deserves its confirmation
+            replies.send(link, MessageTypeMcu::CancelStatus(Status::Ok))?;
+        }
+        MessageTypeHost::MarkValid => {
+            let status = match simple_ota::mark_valid() {
+                Ok(()) => {
+                    info!("Running image marked valid on host request");
+                    Status::Ok
+                }
+                Err(err) => {
+                    warn!("Cannot mark the running image valid: {}", err);
+                    Status::Failed
+                }
+            };
+
+            replies.send(link, MessageTypeMcu::MarkValidStatus(status))?;
+        }
+        MessageTypeHost::Rollback => {
+            // The successful call reboots and never returns, so check
+            // feasibility first and get the ack out before committing
+            if simple_ota::rollback_possible() {
+                info!("Rolling back to the previous image on host request");
+
+                replies.send(link, MessageTypeMcu::RollbackStatus(Status::Ok))?;
+                drain_serial(&replies.uart)?;
+
+                if let Err(err) = simple_ota::rollback() {
+                    warn!("Rollback failed: {}", err);
+                }
+            } else {
+                warn!("Rollback is not possible; no valid image in the other slot");
+
+                replies.send(link, MessageTypeMcu::RollbackStatus(Status::Failed))?;
+            }
+        }
+        MessageTypeHost::ReadFlash {
+            partition,
+            offset,
+            len,
+        } => {
+            read_flash(partition, offset, len, sm, link, replies)?;
+        }
+        MessageTypeHost::HashFlash { partition, len } => {
+            hash_flash(partition, len, sm, link, replies, wdt)?;
+        }
+        MessageTypeHost::EraseRegion {
+            partition,
+            offset,
+            len,
+        } => {
+            erase_flash(partition, offset, len, sm, link, replies, wdt)?;
+        }
+        MessageTypeHost::Hello {
+            protocol_version,
+            capabilities,
+        } => {
+            // Unknown host bits are ignored by construction; the reply
+            // advertises everything this build supports so the host can
+            // gate its options up front instead of trying and failing
+            debug!(
+                "Hello: host protocol {}, capabilities {:#x}",
+                protocol_version,
+                capabilities.bits()
+            );
+
+            // The ack below carries our version either way; the host
+            // owns the decision to stop, this is for the device log
+            if protocol_version != PROTOCOL_VERSION {
+                warn!(
+                    "Host speaks protocol version {}, this firmware speaks {}",
+                    protocol_version, PROTOCOL_VERSION
+                );
+            }
+
+            let max_segment = match link {
+                Link::Uart => uart_max_segment,
+                Link::Tcp | Link::Ble => Some(RECEIVE_CAPACITY as u16),
+            };
+
+            replies.send(
+                link,
+                MessageTypeMcu::HelloAck {
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: session_capabilities(security),
+                    max_segment_len: max_segment.unwrap_or(SEGMENT_SIZE as u16),
+                },
+            )?;
+        }
+        other => debug!("Unhandled message: {:?}", other),
+    }
+
+    Ok(())
+}
+
+/// Answers the commands that are valid in every state and touch nothing
+/// the state machine owns - liveness probes, inventory and the
+/// telemetry/logging switches. Returns `true` when the message was
+/// consumed here; Cancel, MarkValid and Rollback are always accepted
+/// too but mutate state or reboot, so they stay with the state-driven
+/// handling. `Err` means the serial thread is gone.
+#[allow(clippy::too_many_arguments)]
+fn handle_stateless_message(
+    msg: &MessageTypeHost,
+    link: Link,
+    replies: &ReplyRouter,
+    telemetry: &adc_telemetry::Control,
+    logging: &protocol_log::Control,
+    resume_store: &resume::Store,
+    scheduling: &Scheduling,
+    last_ping_reply: &mut Option<Instant>,
+) -> Result<bool, mpsc::SendError<SerialCommand>> {
+    match *msg {
+        MessageTypeHost::Ping => {
+            if ping_allowed(last_ping_reply) {
+                replies.send(link, MessageTypeMcu::Pong)?;
+            }
+        }
+        MessageTypeHost::TimedPing(nonce) => {
+            if ping_allowed(last_ping_reply) {
+                let uptime_ms = (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64;
+
+                replies.send(link, MessageTypeMcu::TimedPong { nonce, uptime_ms })?;
+            }
+        }
+        MessageTypeHost::GetInfo => {
+            replies.send(
+                link,
+                MessageTypeMcu::Info(device_info(resume_store.boot_count(), scheduling)),
+            )?;
+        }
+        MessageTypeHost::AdcStart { interval_ms } => {
+            debug!("ADC stream started by the host ({} ms)", interval_ms);
+            telemetry.start(interval_ms);
+        }
+        MessageTypeHost::AdcStop => {
+            debug!("ADC stream stopped by the host");
+            telemetry.stop();
+        }
+        MessageTypeHost::SetLogLevel(level) => {
+            logging.set_level(level);
+            debug!("Log mirror level set to {} by the host", level);
+        }
+        MessageTypeHost::SetTrace { enabled, force } => {
+            let mode = TraceMode::requested(enabled, force);
+            info!("Protocol trace set to {:?} by the host", mode);
+            replies.tracer.set_mode(mode);
+            replies.send(link, MessageTypeMcu::TraceStatus(Status::Ok))?;
+        }
+        MessageTypeHost::GetUpdateInfo => {
+            replies.send(link, MessageTypeMcu::UpdateInfo(resume_store.last_update()))?;
+        }
+        MessageTypeHost::GetStats { reset } => {
+            replies.send(link, MessageTypeMcu::Stats(LINK_STATS.snapshot()))?;
+
+            // Snapshot first, reset after: the reply carries the
+            // counters the host asked to clear, not an empty set
+            if reset {
+                LINK_STATS.reset();
+            }
+        }
+        MessageTypeHost::DumpTrace => {
+            // The ring goes out as ordinary trace-level Log frames, one
+            // per entry, bypassing the throttle: a dump was asked for
+            for entry in replies.tracer.snapshot() {
+                replies.send(
+                    link,
+                    MessageTypeMcu::Log(LogRecord {
+                        level: 5,
+                        target: "trace".into(),
+                        message: entry,
+                    }),
+                )?;
+            }
+            replies.send(link, MessageTypeMcu::TraceStatus(Status::Ok))?;
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Seconds since the Unix epoch, stamped into the update audit record.
+/// The clock counts from 1970 on devices that never saw SNTP; the
+/// record's reader can recognise such a stamp and discount it.
+fn unix_time() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Applies [`PING_RATE_LIMIT`] to both ping flavours: a probe arriving
+/// too soon after the last answered one is silently dropped. The host
+/// treats a ping without a reply as a timeout anyway, and its next
+/// probe will be far enough out to pass.
+fn ping_allowed(last_ping_reply: &mut Option<Instant>) -> bool {
+    match last_ping_reply {
+        Some(last) if last.elapsed() < PING_RATE_LIMIT => {
+            debug!("Dropping a ping inside the rate limit");
+            false
+        }
+        _ => {
+            *last_ping_reply = Some(Instant::now());
+            true
+        }
+    }
+}
+
+/// The device end of [`readback`]: chunk bytes come from the update
+/// slot or a named partition.
+struct PartitionSource {
+    partition: Option<String>,
+}
+
+impl readback::FlashSource for PartitionSource {
+    type Error = simple_ota::Error;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        match &self.partition {
+            Some(label) => simple_ota::read_partition(label, offset as usize, buf),
+            None => simple_ota::read_update_slot(offset as usize, buf),
+        }
+    }
+}
+
+/// Answers one `ReadFlash`: bounds-checks the request against the
+/// target partition, then streams the chunks. Only legal while the
+/// updater is idle, which covers both a fresh boot and an update that
+/// finished but has not rebooted; during a transfer the slot being
+/// read is the slot being written, so the host gets `WrongState` and
+/// retries once the device is idle. `Err` means the serial thread is
+/// gone.
+fn read_flash(
+    partition: Option<String>,
+    offset: u32,
+    len: u32,
+    sm: &StateMachine<Context>,
+    link: Link,
+    replies: &ReplyRouter,
+) -> Result<(), mpsc::SendError<SerialCommand>> {
+    if !matches!(sm.state(), States::Idle) {
+        warn!("ReadFlash during an update, refusing");
+        return replies.send(link, MessageTypeMcu::ReadFlashStatus(Status::WrongState));
+    }
+
+    if len > READ_MAX {
+        warn!(
+            "ReadFlash of {} bytes exceeds the {} byte cap",
+            len, READ_MAX
+        );
+        return replies.send(link, MessageTypeMcu::ReadFlashStatus(Status::Failed));
+    }
+
+    let size = match &partition {
+        Some(label) => simple_ota::partition_size(label),
+        None => simple_ota::update_slot_size(),
+    };
+
+    let size = match size {
+        Some(size) => size,
+        None => {
+            warn!(
+                "ReadFlash: no partition named {}",
+                partition.as_deref().unwrap_or("(update slot)")
+            );
+            return replies.send(link, MessageTypeMcu::ReadFlashStatus(Status::Failed));
+        }
+    };
+
+    let plan = match readback::plan(size, offset, len, READ_CHUNK) {
+        Ok(plan) => plan,
+        Err(err) => {
+            warn!("ReadFlash request refused: {:?}", err);
+            return replies.send(link, MessageTypeMcu::ReadFlashStatus(Status::Failed));
+        }
+    };
+
+    debug!(
+        "Read-back of {} bytes at offset {} from {}",
+        len,
+        offset,
+        partition.as_deref().unwrap_or("the update slot")
+    );
+
+    let mut source = PartitionSource { partition };
+
+    match readback::stream(&mut source, plan, |chunk, data| {
+        replies.send(
+            link,
+            MessageTypeMcu::FlashData {
+                offset: chunk.offset,
+                data,
+                last: chunk.last,
+            },
+        )
+    }) {
+        Ok(()) => Ok(()),
+        Err(readback::StreamError::Read(err)) => {
+            // The host never sees a `last` chunk; the status tells it
+            // why the stream stopped
+            warn!("Read-back failed: {}", err);
+            replies.send(link, MessageTypeMcu::ReadFlashStatus(Status::Failed))
+        }
+        Err(readback::StreamError::Emit(err)) => Err(err),
+    }
+}
+
+/// Answers one `HashFlash`: resolves the target, validates the range
+/// and digests it in [`HASH_CHUNK`] pieces, feeding the WDT between
+/// driver calls. Unlike [`read_flash`] there is no [`READ_MAX`]-style
+/// cap: nothing streams back until the digest is done, so hashing a
+/// whole slot finishes well inside the WDT window. Same state rule as
+/// [`read_flash`]. `Err` means the serial thread is gone.
+fn hash_flash(
+    partition: Option<String>,
+    len: u32,
+    sm: &StateMachine<Context>,
+    link: Link,
+    replies: &ReplyRouter,
+    wdt: &WdtSubscription,
+) -> Result<(), mpsc::SendError<SerialCommand>> {
+    use sha2::{Digest, Sha256};
+
+    if !matches!(sm.state(), States::Idle) {
+        warn!("HashFlash during an update, refusing");
+        return replies.send(
+            link,
+            MessageTypeMcu::FlashHash {
+                status: Status::WrongState,
+                sha256: None,
+            },
+        );
+    }
+
+    let size = match &partition {
+        Some(label) => simple_ota::partition_size(label),
+        None => simple_ota::update_slot_size(),
+    };
+
+    let size = match size {
+        Some(size) => size,
+        None => {
+            warn!(
+                "HashFlash: no partition named {}",
+                partition.as_deref().unwrap_or("(update slot)")
+            );
+            return replies.send(
+                link,
+                MessageTypeMcu::FlashHash {
+                    status: Status::Failed,
+                    sha256: None,
+                },
+            );
+        }
+    };
+
+    let plan = match readback::plan(size, 0, len, HASH_CHUNK) {
+        Ok(plan) => plan,
+        Err(err) => {
+            warn!("HashFlash request refused: {:?}", err);
+            return replies.send(
+                link,
+                MessageTypeMcu::FlashHash {
+                    status: Status::Failed,
+                    sha256: None,
+                },
+            );
+        }
+    };
+
+    info!(
+        "Hashing {} bytes of {}",
+        len,
+        partition.as_deref().unwrap_or("the update slot")
+    );
+
+    let mut source = PartitionSource { partition };
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0_u8; HASH_CHUNK as usize];
+
+    for chunk in plan {
+        let buf = &mut buf[..chunk.len as usize];
+
+        if let Err(err) = readback::FlashSource::read(&mut source, chunk.offset, buf) {
+            warn!("HashFlash read failed: {}", err);
+            return replies.send(
+                link,
+                MessageTypeMcu::FlashHash {
+                    status: Status::Failed,
+                    sha256: None,
+                },
+            );
+        }
+
+        hasher.update(&buf[..]);
+        wdt.feed();
+    }
+
+    replies.send(
+        link,
+        MessageTypeMcu::FlashHash {
+            status: Status::Ok,
+            sha256: Some(hasher.finalize().into()),
+        },
+    )
+}
+
+/// Answers one `EraseRegion`: resolves the target, validates the range
+/// (sector alignment, bounds, nothing the device cannot survive
+/// losing) and grinds through it in [`ERASE_CHUNK`] pieces, feeding
+/// the WDT between driver calls and emitting best-effort progress so
+/// the host's keepalive sees signs of life through a multi-second
+/// erase. Same state rule as [`read_flash`]. `Err` means the serial
+/// thread is gone.
+fn erase_flash(
+    partition: Option<String>,
+    offset: u32,
+    len: u32,
+    sm: &StateMachine<Context>,
+    link: Link,
+    replies: &ReplyRouter,
+    wdt: &WdtSubscription,
+) -> Result<(), mpsc::SendError<SerialCommand>> {
+    if !matches!(sm.state(), States::Idle) {
+        warn!("EraseRegion during an update, refusing");
+        return replies.send(link, MessageTypeMcu::EraseStatus(Status::WrongState));
+    }
+
+    let (size, protected) = match simple_ota::erase_target(partition.as_deref()) {
+        Some(target) => target,
+        None => {
+            warn!(
+                "EraseRegion: no partition named {}",
+                partition.as_deref().unwrap_or("(update slot)")
+            );
+            return replies.send(link, MessageTypeMcu::EraseStatus(Status::Failed));
+        }
+    };
+
+    if let Err(err) = erase::validate(size, protected, offset, len) {
+        warn!("EraseRegion refused: {:?}", err);
+        return replies.send(link, MessageTypeMcu::EraseStatus(Status::Failed));
+    }
+
+    info!(
+        "Erasing {} bytes at offset {} of {}",
+        len,
+        offset,
+        partition.as_deref().unwrap_or("the update slot")
+    );
+
+    let mut erased = 0;
+
+    while erased < len {
+        let chunk = (len - erased).min(ERASE_CHUNK);
+
+        if let Err(err) = simple_ota::erase_region(partition.as_deref(), offset + erased, chunk) {
+            warn!("Erase failed: {}", err);
+            return replies.send(link, MessageTypeMcu::EraseStatus(Status::Failed));
+        }
+
+        erased += chunk;
+        wdt.feed();
+
+        // Best-effort signs of life; a full TX queue just skips one
+        replies.try_send(
+            link,
+            MessageTypeMcu::Progress {
+                bytes_written: erased,
+                phase: UpdatePhase::Erasing,
+            },
+        );
+    }
+
+    replies.send(link, MessageTypeMcu::EraseStatus(Status::Ok))
+}
+
+/// Handles a `SetBaud`: validates the rate, acks at the old rate, waits
+/// for the ack to actually leave the UART and only then reconfigures.
+/// Returns the rate to fall back to when the switch took place, `None`
+/// when nothing changed.
+fn set_baud(
+    rate: u32,
+    link: Link,
+    sm: &StateMachine<Context>,
+    replies: &ReplyRouter,
+    current: u32,
+) -> Result<Option<u32>, mpsc::SendError<SerialCommand>> {
+    // Switching mid-transfer would race the segment stream against the
+    // reconfiguration; the host can switch before its UpdateStart
+    if sm.context().update.is_some() {
+        warn!("Refusing a baud change during an update");
+        replies.send(link, MessageTypeMcu::SetBaudStatus(Status::Failed))?;
+        return Ok(None);
+    }
+
+    // Nothing to reconfigure for a TCP or BLE host
+    if !matches!(link, Link::Uart) {
+        warn!("SetBaud over a non-UART link");
+        replies.send(link, MessageTypeMcu::SetBaudStatus(Status::Failed))?;
+        return Ok(None);
+    }
+
+    if !SUPPORTED_BAUD_RATES.contains(&rate) {
+        warn!("Unsupported baud rate {}", rate);
+        replies.send(link, MessageTypeMcu::SetBaudStatus(Status::Failed))?;
+        return Ok(None);
+    }
+
+    if rate == current {
+        replies.send(link, MessageTypeMcu::SetBaudStatus(Status::Ok))?;
+        return Ok(None);
+    }
+
+    info!(
+        "Switching from {} to {} baud on host request",
+        current, rate
+    );
+
+    // The ack has to reach the host at the old rate; the drain blocks
+    // until the TX thread has pushed the last bit out of the FIFO, not
+    // merely handed it to the hardware
+    replies.send(link, MessageTypeMcu::SetBaudStatus(Status::Ok))?;
+    drain_serial(&replies.uart)?;
+
+    replies.uart.send(SerialCommand::SetBaud(rate))?;
+
+    Ok(Some(current))
+}
+
+/// Waits until the serial TX thread confirms everything queued so far
+/// has left the UART, then gives the host's side a short grace period
+/// to settle before the line glitches from a reboot.
+fn drain_serial(
+    mcu_msg_tx: &mpsc::SyncSender<SerialCommand>,
+) -> Result<(), mpsc::SendError<SerialCommand>> {
+    let (ack_tx, ack_rx) = mpsc::channel();
+
+    mcu_msg_tx.send(SerialCommand::Drained(ack_tx))?;
+    ack_rx.recv_timeout(DRAIN_TIMEOUT).ok();
+
+    thread::sleep(RESTART_DELAY);
+
+    Ok(())
+}
+
+/// Drains the serial queue, then restarts. Only returns if the serial
+/// TX thread is already gone - and then a restart without the ack is
+/// moot.
+fn restart_after_drain(
+    mcu_msg_tx: &mpsc::SyncSender<SerialCommand>,
+) -> Result<(), mpsc::SendError<SerialCommand>> {
+    drain_serial(mcu_msg_tx)?;
+
+    unsafe { esp_idf_sys::esp_restart() };
+}
+
+/// Applies one delta instruction to the open update, pulling copy ranges
+/// out of the running image in segment-sized pieces.
+fn apply_delta(active: &mut ActiveUpdate, op: &DeltaOp) -> Result<(), simple_ota::Error> {
+    match op {
+        DeltaOp::Copy { src_offset, len } => {
+            let mut buf = [0_u8; SEGMENT_SIZE];
+            let mut copied = 0;
+
+            while copied < *len as usize {
+                let chunk = (*len as usize - copied).min(buf.len());
+
+                simple_ota::read_running(*src_offset as usize + copied, &mut buf[..chunk])?;
+                active.write(&buf[..chunk])?;
+
+                copied += chunk;
+            }
+
+            Ok(())
+        }
+        DeltaOp::Data(data) => active.write(data),
+    }
+}
+
+/// Checks a stored checkpoint against the announced image and against
+/// the bytes actually in the update slot, returning a ready-to-write
+/// update plus the offset to report to the host when everything lines
+/// up. Any mismatch - a different image, an offset the host's segment
+/// size cannot hit, a CRC saying the slot was touched since - falls
+/// back to `None` and a full transfer.
+fn try_resume(
+    start: &UpdateStart,
+    store: &resume::Store,
+    wdt: &WdtSubscription,
+) -> Option<(ActiveUpdate, u32)> {
+    let sha256 = start.sha256?;
+    let saved = store.load()?;
+
+    if saved.sha256 != sha256 || saved.size != start.size || saved.offset == 0 {
+        return None;
+    }
+
+    let segment_size = start.segment_size.map(usize::from).unwrap_or(SEGMENT_SIZE);
+    if saved.offset as usize % segment_size != 0 {
+        return None;
+    }
+
+    // Read the kept bytes back and make sure they still are what the
+    // checkpoint recorded; flash is cheap to read, and resuming onto a
+    // stale image would only fail at the final verification
+    let mut check = ImageCheck::new(start.size);
+    let mut crc = Crc32::new();
+    let mut buf = [0_u8; SEGMENT_SIZE];
+    let mut offset = 0;
+
+    while offset < saved.offset as usize {
+        let chunk = (saved.offset as usize - offset).min(buf.len());
+
+        simple_ota::read_update_slot(offset, &mut buf[..chunk]).ok()?;
+        check.update(&buf[..chunk]);
+        crc.update(&buf[..chunk]);
+
+        offset += chunk;
+    }
+
+    if crc.finalize() != saved.crc {
+        info!("Stale partial image in the update slot, starting over");
+        return None;
+    }
+
+    let target = SlotUpdate::begin(start.size as usize, saved.offset as usize, || wdt.feed())
+        .map(Target::Slot)
+        .ok()?;
+
+    let active = ActiveUpdate {
+        target,
+        check,
+        tracker: SegmentTracker::starting_at(saved.offset / segment_size as u32),
+        resume: Some(ResumeTracking {
+            sha256,
+            size: start.size,
+            crc,
+            written: saved.offset,
+            segments_since_checkpoint: 0,
+        }),
+        nonce_prefix: start.nonce_prefix,
+        descriptor: None,
+        progress: start.progress,
+        buffer: Vec::with_capacity(resume::SECTOR_SIZE),
+    };
+
+    Some((active, saved.offset))
+}
+
+/// Gathers the device inventory for a `GetInfo` reply. Everything here
+/// is read-only - partition lookups, the running image's descriptor,
+/// heap counters - so answering during an in-flight transfer cannot
+/// disturb the open update handle.
+fn device_info(boot_count: Option<u32>, scheduling: &Scheduling) -> Info {
+    // The descriptor sits in the running image's flash, so the pointer
+    // stays valid for as long as we run
+    let app = unsafe { &*esp_idf_sys::esp_ota_get_app_description() };
+
+    let update_slot = simple_ota::update_slot();
+
+    Info {
+        protocol_version: PROTOCOL_VERSION,
+        app_version: cstr_field(&app.version),
+        slot_size: update_slot.as_ref().map(|slot| slot.size),
+        project_name: cstr_field(&app.project_name),
+        build: format!("{} {}", cstr_field(&app.date), cstr_field(&app.time)),
+        running_slot: simple_ota::running_slot().map(slot_info),
+        update_slot: update_slot.map(slot_info),
+        free_heap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+        min_free_heap: unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() },
+        updater_stack_free: stack_high_water(),
+        desyncs: DESYNCS.load(Ordering::Relaxed),
+        reset_reason: reset_reason().0,
+        boot_count,
+        pending_verify: simple_ota::pending_verify().unwrap_or(false),
+        serial_priority: scheduling.serial_priority,
+        updater_priority: scheduling.updater_priority,
+        serial_core: scheduling.serial_core,
+        updater_core: scheduling.updater_core,
+        self_test_passed: match SELF_TEST.load(Ordering::Relaxed) {
+            1 => Some(true),
+            2 => Some(false),
+            _ => None,
+        },
+    }
+}
+
+/// Raw `esp_reset_reason()` code of the current boot with a name for
+/// the log; the code goes out in `Info` as-is.
+fn reset_reason() -> (u32, &'static str) {
+    let code = unsafe { esp_idf_sys::esp_reset_reason() };
+
+    #[allow(non_upper_case_globals)]
+    let name = match code {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "power-on",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "external pin",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "other watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep sleep wakeup",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => "SDIO",
+        _ => "unknown",
+    };
+
+    (code as u32, name)
+}
+
+/// Logs why the device reset and how often it has booted, once at
+/// startup. The same values go out in the `Info` reply, but a unit that
+/// dies before answering `GetInfo` still leaves them on the console.
+pub fn log_boot_diagnostics(boot_count: Option<u32>) {
+    let (code, name) = reset_reason();
+
+    let boots = match boot_count {
+        Some(count) => format!("boot #{}", count),
+        None => "boot count unknown".to_string(),
+    };
+
+    info!(
+        "Reset reason: {} ({}), {}, image pending verify: {}",
+        name,
+        code,
+        boots,
+        simple_ota::pending_verify().unwrap_or(false)
+    );
+}
+
+fn slot_info(slot: simple_ota::SlotDesc) -> SlotInfo {
+    SlotInfo {
+        label: slot.label,
+        offset: slot.offset,
+        size: slot.size,
+    }
+}
+
+/// One NUL-terminated fixed-size text field out of `esp_app_desc_t`.
+fn cstr_field(field: &[esp_idf_sys::c_types::c_char]) -> String {
+    unsafe { CStr::from_ptr(field.as_ptr() as *const _) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Decompresses one heatshrink-compressed block, or says why not.
+/// Shared by the compressed and the compressed-then-sealed segment
+/// paths; `id` is only for the log line. The output buffer is sized
+/// from `raw_len` up front - the caller has already bounded it against
+/// [`RECEIVE_CAPACITY`] - so a pathological expansion cannot grow past
+/// what the plain-segment path would have accepted anyway. `None` means
+/// the block is corrupt: the flasher's encoder mirrors this decoder, so
+/// whatever it produced on the host is what failed here, and a
+/// retransmission would reproduce it.
+fn decompress_segment(id: u16, raw_len: u16, data: &[u8]) -> Option<Vec<u8>> {
+    let raw_len = raw_len as usize;
+
+    let config = heatshrink::Config::new(
+        messages::HEATSHRINK_WINDOW_SZ2,
+        messages::HEATSHRINK_LOOKAHEAD_SZ2,
+    )
+    .expect("valid heatshrink parameters");
+
+    // One spare byte: heatshrink 0.2 reports `OutputFull` when the
+    // output lands exactly at the end of the buffer
+    let mut out = vec![0_u8; raw_len + 1];
+
+    match heatshrink::decode(data, &mut out, &config) {
+        Ok(used) if used.len() == raw_len => {
+            out.truncate(raw_len);
+            Some(out)
+        }
+        Ok(used) => {
+            warn!(
+                "Segment {} decompressed to {} bytes, expected {}",
+                id,
+                used.len(),
+                raw_len
+            );
+            None
+        }
+        Err(err) => {
+            warn!("Segment {} failed to decompress: {:?}", id, err);
+            None
+        }
+    }
+}
+
+/// Authenticates and decrypts one sealed segment, or says why not.
+/// `None` means no plaintext exists to write: the update was started
+/// without a nonce prefix, no key is configured (the `UpdateStart` gate
+/// catches that pairing, but a lone encrypted segment can still turn up
+/// in a cleartext transfer), or the tag did not verify.
+fn open_segment(
+    key: &Option<[u8; crypto::KEY_LEN]>,
+    nonce_prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    segment: &messages::UpdateSegmentEncrypted,
+) -> Option<Vec<u8>> {
+    let (key, prefix) = match (key, nonce_prefix) {
+        (Some(key), Some(prefix)) => (key, prefix),
+        (None, _) => {
+            warn!(
+                "Segment {} is encrypted but no decryption key is configured",
+                segment.id
+            );
+            return None;
+        }
+        (_, None) => {
+            warn!(
+                "Segment {} is encrypted but the update announced no nonce prefix",
+                segment.id
+            );
+            return None;
+        }
+    };
+
+    match crypto::open_segment(key, &prefix, segment.id, &segment.data) {
+        Ok(plaintext) => Some(plaintext),
+        Err(crypto::AuthError) => {
+            warn!(
+                "Segment {} failed authentication, aborting the update",
+                segment.id
+            );
+            None
+        }
+    }
+}
+
+/// Applies the signature policy to a finished image's digest. Without a
+/// verifying key any signature is ignored; with one, a present
+/// signature must verify, and a missing one is only tolerated unless
+/// signatures are required. Requiring signatures without a key refuses
+/// every update - failing loudly beats pretending to enforce anything.
+fn signature_ok(security: &Security, digest: &[u8; HASH_LEN], signature: Option<&[u8]>) -> bool {
+    let key = match security.verifying_key {
+        Some(key) => key,
+        None => {
+            if security.require_signature {
+                warn!("Signatures are required but no verifying key is configured");
+                return false;
+            }
+
+            return true;
+        }
+    };
+
+    let signature = match signature {
+        Some(signature) => signature,
+        None => {
+            if security.require_signature {
+                warn!("Unsigned update refused: signatures are required");
+                return false;
+            }
+
+            debug!("Unsigned update accepted; signatures are optional");
+            return true;
+        }
+    };
+
+    match verify::verify_signature(&key, digest, signature) {
+        Ok(()) => {
+            info!("Image signature verified");
+            true
+        }
+        Err(verify::SignatureError) => {
+            warn!("Image signature verification failed");
+            false
+        }
+    }
+}
+
+/// Maps a failed segment write to the status the host sees. Write
+/// errors are always fatal here: they surface out of a coalesced flush
+/// whose block spans segments the host already got acks for, so no
+/// single resend can repair one (transient codes were already retried
+/// inside [`ActiveUpdate::flush`]). Only the source-side reads of a
+/// delta copy can still earn a `Retry` - they fail before the segment
+/// is accounted anywhere (see `messages::flash_errors` for the
+/// mapping); everything the `simple_ota` layer detects itself - size
+/// checks, partition lookups - is deterministic and fatal.
+fn write_failure_status(err: &simple_ota::Error) -> Status {
+    let code = match err {
+        simple_ota::Error::Read(inner) => inner.code(),
+        _ => return Status::FlashWrite,
+    };
+
+    match classify_write_error(code) {
+        WriteError::Transient => Status::Retry,
+        WriteError::Fatal => Status::FlashWrite,
+    }
+}
+
+/// A fatal write failure, a segment that failed authentication or the
+/// anti-rollback check ends
+/// the update before the status is sent: release the OTA slot and fall
+/// back to idle, so the host's next `UpdateStart` finds a clean slate.
+/// The resume checkpoint is kept - everything up to the last checkpoint
+/// really is on flash and was authenticated before it got there - in
+/// case a later attempt of the same image can pick it up.
+fn abort_failed_update(sm: &mut StateMachine<Context>, mode: &SharedMode, led: &StatusLed) {
+    if let Some(ActiveUpdate {
+        target: Target::App(update),
+        ..
+    }) = sm.context_mut().update.take()
+    {
+        update.abort();
+    }
+
+    sm.process_event(Events::Cancelled).ok();
+    mode.set(DeviceMode::Idle);
+    led.show(Pattern::Failure);
+}
+
+/// Cuts a resume checkpoint once enough segments have accumulated and
+/// the write position sits on a flash sector boundary, so a resumed
+/// transfer can erase from the checkpoint onward without clipping data
+/// it means to keep. The boundary check also guarantees the coalescing
+/// buffer is empty - it flushes exactly when full - so everything the
+/// checkpoint claims really is on flash.
+fn maybe_checkpoint(active: &mut ActiveUpdate, store: &mut resume::Store, interval: u32) {
+    if let Some(tracking) = &mut active.resume {
+        tracking.segments_since_checkpoint += 1;
+
+        if tracking.segments_since_checkpoint >= interval
+            && tracking.written as usize % resume::SECTOR_SIZE == 0
+        {
+            store.save(&resume::Checkpoint {
+                sha256: tracking.sha256,
+                size: tracking.size,
+                offset: tracking.written,
+                crc: tracking.crc.finalize(),
+            });
+
+            tracking.segments_since_checkpoint = 0;
+        }
+    }
+}
+
+/// Common bookkeeping once a segment's bytes have landed on flash:
+/// advance the tracker, count it, maybe cut a checkpoint, report
+/// progress if the host asked, and run the anti-rollback gate. The gate
+/// sits here, after the write, because the descriptor only becomes
+/// readable once the first segment(s) carrying it have arrived - a few
+/// hundred bytes in the slot that the abort releases again.
+#[allow(clippy::too_many_arguments)]
+fn segment_written(
+    active: &mut ActiveUpdate,
+    segments_written: &mut u32,
+    resume_store: &mut resume::Store,
+    checkpoint_interval: u32,
+    security: &Security,
+    replies: &ReplyRouter,
+    link: Link,
+    progress_interval: u32,
+) -> Status {
+    active.tracker.advance();
+    *segments_written += 1;
+    maybe_checkpoint(active, resume_store, checkpoint_interval);
+
+    if active.progress && *segments_written % progress_interval == 0 {
+        replies.try_send(
+            link,
+            MessageTypeMcu::Progress {
+                bytes_written: active.check.written() as u32,
+                phase: UpdatePhase::Receiving,
+            },
+        );
+    }
+
+    match active.image_head() {
+        Some(head) => version_gate(&head, security),
+        None => Status::Ok,
+    }
+}
+
+/// Segment statuses after which the device has torn the update down, so
+/// resending the segment would only earn "no update in progress".
+fn fatal_segment_status(status: Status) -> bool {
+    matches!(status, Status::FlashWrite | Status::VersionTooOld)
+}
+
+/// Applies the anti-rollback policy to a freshly captured image head.
+/// Anything but `Ok` is fatal and tears the update down like a write
+/// failure would.
+fn version_gate(head: &[u8; IMAGE_HEAD_LEN], security: &Security) -> Status {
+    let refused = match descriptor_version(head) {
+        Some(incoming) => match version_floor(security) {
+            Some(floor) if incoming < floor => {
+                warn!(
+                    "Incoming image version {} is older than the enforced {}",
+                    incoming, floor
+                );
+                true
+            }
+            _ => {
+                debug!(
+                    "Incoming image version {} passes the rollback check",
+                    incoming
+                );
+                false
+            }
+        },
+        None if security.allow_unversioned => {
+            debug!("Incoming image has no parseable version, allowed by configuration");
+            false
+        }
+        None => {
+            warn!("Incoming image has no parseable version");
+            true
+        }
+    };
+
+    if !refused {
+        return Status::Ok;
+    }
+
+    match security.rollback_protection {
+        RollbackProtection::Enforce => Status::VersionTooOld,
+        RollbackProtection::WarnOnly => {
+            warn!("Allowing it anyway: rollback protection is warn-only");
+            Status::Ok
+        }
+    }
+}
+
+/// The version an incoming image has to reach: the running image's own
+/// version, raised further by the configured minimum. A running version
+/// that does not parse contributes nothing, leaving the check to rest
+/// on `min_version` alone.
+fn version_floor(security: &Security) -> Option<version::Version> {
+    let app = unsafe { &*esp_idf_sys::esp_ota_get_app_description() };
+    let running = version::parse(&cstr_field(&app.version));
+
+    if running.is_none() {
+        warn!("The running image's own version does not parse");
+    }
+
+    match (running, security.min_version) {
+        (Some(running), Some(min)) => Some(running.max(min)),
+        (running, min) => running.or(min),
+    }
+}
+
+/// Pulls the version out of a captured image head; `None` when the
+/// descriptor magic is missing or the version text does not parse.
+fn descriptor_version(head: &[u8; IMAGE_HEAD_LEN]) -> Option<version::Version> {
+    let magic = u32::from_le_bytes([
+        head[APP_DESC_OFFSET],
+        head[APP_DESC_OFFSET + 1],
+        head[APP_DESC_OFFSET + 2],
+        head[APP_DESC_OFFSET + 3],
+    ]);
+
+    if magic != APP_DESC_MAGIC {
+        warn!("Incoming image has no app descriptor at the expected offset");
+        return None;
+    }
+
+    let raw = &head[APP_DESC_OFFSET + APP_DESC_VERSION_OFFSET..][..APP_DESC_VERSION_LEN];
+    let text = &raw[..raw
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(APP_DESC_VERSION_LEN)];
+
+    version::parse(core::str::from_utf8(text).ok()?)
+}
@@ -0,0 +1,912 @@
+//! The device side of the update protocol, factored out of the
+//! firmware so it can be compiled and tested off-target.
+//!
+//! [`UpdaterCore`] is the pure message engine: it owns the segment
+//! bookkeeping and the image check from [`messages`] and turns each
+//! incoming [`MessageTypeHost`] into the replies the firmware would
+//! send, with all flash access behind [`SegmentSink`]. The firmware
+//! implements the sink over its OTA handles; tests implement it over a
+//! `Vec<u8>`, which is what lets duplicates, verification failures and
+//! every status the device can answer be exercised with `cargo test`.
+//!
+//! [`serve`] wraps the engine in the serial thread's framing - checksum
+//! verification, byte-by-byte resynchronization and the desync notice -
+//! over any [`Transport`], so the flasher's integration tests run
+//! against the same recovery behaviour the firmware ships.
+//!
+//! The engine advertises no optional capabilities, so a host sends
+//! plain segments; compression, encryption and deltas stay with the
+//! firmware where their codecs live.
+
+use std::io;
+use std::time::Duration;
+
+use messages::segments::{SegmentAction, SegmentTracker};
+use messages::transport::Transport;
+use messages::verify::{ImageCheck, ImageError};
+use messages::{
+    codec, Caps, MessageTypeHost, MessageTypeMcu, Status, UpdateStartStatus, PROTOCOL_VERSION,
+    SEGMENT_SIZE,
+};
+
+/// Consecutive undecodable parse attempts before the stream is declared
+/// desynchronized and everything pending is flushed. Below the
+/// threshold the scanner resynchronizes byte by byte, which recovers
+/// from a short burst of line noise without losing the frames behind
+/// it. The firmware's serial thread uses this same constant, so the
+/// recovery behaviour under test is the shipped one.
+pub const DESYNC_THRESHOLD: u32 = 16;
+
+/// How long one [`serve`] read waits before polling the link again.
+const RX_POLL: Duration = Duration::from_millis(100);
+
+/// Where the accepted image bytes go. The engine decides *whether* a
+/// segment is written; the sink decides *how*, and reports failures as
+/// the [`Status`] the host should see - the firmware's rich mapping
+/// from flash errors to statuses lives in its sink, not here.
+pub trait SegmentSink {
+    /// Prepares the sink for an image of `size` bytes. An `Err` is
+    /// reported to the host in the `UpdateStartStatus` and no transfer
+    /// starts.
+    fn begin(&mut self, size: u32) -> Result<(), Status>;
+
+    /// Appends `data` at the current position. An `Err` keeps the
+    /// transfer position, so the host's retry of the same segment is
+    /// written rather than falsely acked.
+    fn write(&mut self, data: &[u8]) -> Result<(), Status>;
+
+    /// Finalizes the image once it verified cleanly.
+    fn complete(&mut self) -> Result<(), Status>;
+
+    /// Releases whatever [`begin`](Self::begin) reserved, after a
+    /// cancel or a failed verification.
+    fn abort(&mut self);
+}
+
+impl<S: SegmentSink> SegmentSink for &mut S {
+    fn begin(&mut self, size: u32) -> Result<(), Status> {
+        (**self).begin(size)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Status> {
+        (**self).write(data)
+    }
+
+    fn complete(&mut self) -> Result<(), Status> {
+        (**self).complete()
+    }
+
+    fn abort(&mut self) {
+        (**self).abort()
+    }
+}
+
+/// What one transfer saw; the device-side mirror of the flasher's
+/// `Stats`, for test assertions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    /// Segments written to the sink, duplicates excluded.
+    pub segments_written: u32,
+    /// Retransmitted segments re-acked without touching the sink.
+    pub duplicates_acked: u32,
+    /// Frames dropped for a failed checksum; counted by [`serve`].
+    pub crc_drops: u32,
+    /// Times the stream was declared desynchronized and flushed;
+    /// counted by [`serve`].
+    pub desyncs: u32,
+}
+
+/// Why a transfer that reached `UpdateEnd` did not complete. The host
+/// was told via `UpdateEndStatus` before this is reported.
+#[derive(Debug)]
+pub enum EndError {
+    /// The reassembled image failed its size or digest check.
+    Image(ImageError),
+    /// The image verified but the sink refused to finalize it.
+    Sink(Status),
+}
+
+/// The state of the transfer in progress, if any.
+struct Active {
+    tracker: SegmentTracker,
+    check: ImageCheck,
+}
+
+/// The pure device-side engine: feed it host messages, send back the
+/// replies. No clocks, no threads, no transport - anything that can
+/// carry a `MessageTypeHost` can drive it.
+pub struct UpdaterCore<S> {
+    sink: S,
+    active: Option<Active>,
+    /// Set once `UpdateEnd` was handled; the transfer is over either way.
+    outcome: Option<Result<(), EndError>>,
+    pub(crate) summary: Summary,
+}
+
+impl<S: SegmentSink> UpdaterCore<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            active: None,
+            outcome: None,
+            summary: Summary::default(),
+        }
+    }
+
+    /// Counters of the transfer so far.
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// The segment id expected next, while an update is in flight; what
+    /// the desync notice points the host at.
+    pub fn expected_segment(&self) -> Option<u16> {
+        self.active.as_ref().map(|active| active.tracker.expected())
+    }
+
+    /// The finished transfer's outcome, if `UpdateEnd` was handled.
+    pub fn take_outcome(&mut self) -> Option<Result<(), EndError>> {
+        self.outcome.take()
+    }
+
+    /// Hands the sink back, for tests that inspect what landed in it.
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Handles one verified frame, returning the replies to send. Most
+    /// messages earn exactly one; the ones this engine does not speak -
+    /// the optional segment codecs it never advertised, `GetInfo` on
+    /// old firmware - earn none.
+    pub fn handle(&mut self, msg: MessageTypeHost) -> Vec<MessageTypeMcu> {
+        match msg {
+            MessageTypeHost::UpdateStart(start) => {
+                // A start over a running transfer replaces it, like the
+                // firmware aborting on a fresh handshake
+                if self.active.take().is_some() {
+                    self.sink.abort();
+                }
+
+                let status = match self.sink.begin(start.size) {
+                    Ok(()) => {
+                        self.active = Some(Active {
+                            tracker: SegmentTracker::new(),
+                            check: ImageCheck::new(start.size),
+                        });
+
+                        Status::Ok
+                    }
+                    Err(status) => status,
+                };
+
+                vec![MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                    status,
+                    capabilities: 0,
+                    max_segment_size: None,
+                    resume_offset: None,
+                })]
+            }
+            MessageTypeHost::UpdateSegment(segment) => {
+                let status = match self.active.as_mut() {
+                    Some(active) => match active.tracker.classify(segment.id) {
+                        SegmentAction::Write => match self.sink.write(&segment.data) {
+                            Ok(()) => {
+                                active.check.update(&segment.data);
+                                active.tracker.advance();
+                                self.summary.segments_written += 1;
+                                Status::Ok
+                            }
+                            // The position stays: the retry is a write
+                            Err(status) => status,
+                        },
+                        SegmentAction::AckDuplicate => {
+                            self.summary.duplicates_acked += 1;
+                            Status::Ok
+                        }
+                        SegmentAction::Reject => Status::Failed,
+                    },
+                    None => Status::WrongState,
+                };
+
+                vec![MessageTypeMcu::UpdateSegmentStatus {
+                    id: segment.id,
+                    status,
+                }]
+            }
+            MessageTypeHost::UpdateEnd(end) => {
+                let active = match self.active.take() {
+                    Some(active) => active,
+                    None => return Vec::new(),
+                };
+
+                let result = match active.check.verify(end.sha256.as_ref()) {
+                    Ok(_) => self.sink.complete().map_err(EndError::Sink),
+                    Err(err) => {
+                        self.sink.abort();
+                        Err(EndError::Image(err))
+                    }
+                };
+
+                let status = match &result {
+                    Ok(()) => Status::Ok,
+                    Err(EndError::Image(_)) => Status::InvalidImage,
+                    Err(EndError::Sink(status)) => *status,
+                };
+
+                self.outcome = Some(result);
+
+                vec![MessageTypeMcu::UpdateEndStatus(status)]
+            }
+            MessageTypeHost::Cancel => {
+                if self.active.take().is_some() {
+                    self.sink.abort();
+                }
+
+                vec![MessageTypeMcu::CancelStatus(Status::Ok)]
+            }
+            // The engine advertises no optional capabilities, in the
+            // Hello exchange as in UpdateStartStatus; the embedding
+            // answers for what its sink and transport can actually do
+            MessageTypeHost::Hello { .. } => vec![MessageTypeMcu::HelloAck {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: Caps::empty(),
+                max_segment_len: SEGMENT_SIZE as u16,
+            }],
+            MessageTypeHost::Ping => vec![MessageTypeMcu::Pong],
+            // No clock in the engine; zero stands in for the uptime
+            MessageTypeHost::TimedPing(nonce) => vec![MessageTypeMcu::TimedPong {
+                nonce,
+                uptime_ms: 0,
+            }],
+            // GetInfo goes unanswered like on old firmware; the optional
+            // segment codecs were never advertised, so their frames and
+            // everything else are ignored rather than guessed at
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Why [`serve`] stopped without a completed transfer.
+#[derive(Debug)]
+pub enum ServeError {
+    /// The link failed outright; timeouts are handled internally.
+    Link(io::Error),
+    /// The peer closed the link mid-exchange.
+    LinkClosed,
+    /// The transfer reached `UpdateEnd` but did not complete.
+    End(EndError),
+}
+
+/// Serves one update over `link` into `sink`, returning once
+/// `UpdateEnd` completed cleanly. Frames are reassembled and checked
+/// exactly like the firmware's serial thread does it: corrupt frames
+/// are dropped, garbage is scanned past byte by byte, and a
+/// desynchronized stream is flushed and answered with a
+/// `Status::Retry` pointing the host at the expected segment.
+pub fn serve<T: Transport, S: SegmentSink>(
+    link: &mut T,
+    sink: &mut S,
+) -> Result<Summary, ServeError> {
+    let mut core = UpdaterCore::new(sink);
+
+    let mut buf = [0_u8; 256];
+    let mut accumulated: Vec<u8> = Vec::new();
+    let mut failures: u32 = 0;
+
+    loop {
+        match link.read_available(&mut buf, RX_POLL) {
+            Ok(0) => return Err(ServeError::LinkClosed),
+            Ok(n) => accumulated.extend_from_slice(&buf[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) => return Err(ServeError::Link(err)),
+        }
+
+        loop {
+            match codec::take::<MessageTypeHost>(&accumulated) {
+                Ok((payload, rest)) => {
+                    let consumed = accumulated.len() - rest.len();
+                    accumulated.drain(..consumed);
+
+                    failures = 0;
+
+                    for reply in core.handle(payload) {
+                        send(link, reply).map_err(ServeError::Link)?;
+                    }
+
+                    if let Some(outcome) = core.take_outcome() {
+                        return outcome
+                            .map(|()| core.summary().clone())
+                            .map_err(ServeError::End);
+                    }
+                }
+                // Not enough bytes yet; more are on the way
+                Err(codec::DecodeError::Truncated) => break,
+                Err(codec::DecodeError::BadCrc { frame_len, .. }) => {
+                    accumulated.drain(..frame_len);
+                    core.summary.crc_drops += 1;
+                    failures += 1;
+                }
+                Err(_) => {
+                    // No frame delimiters on the wire: skip one byte
+                    // and try to parse at the next
+                    accumulated.drain(..1);
+                    failures += 1;
+                }
+            }
+
+            if failures >= DESYNC_THRESHOLD {
+                core.summary.desyncs += 1;
+                failures = 0;
+                accumulated.clear();
+
+                // Drop whatever else is in flight, like the serial
+                // thread flushing the driver's RX buffer
+                while matches!(link.read_available(&mut buf, Duration::ZERO), Ok(1..)) {}
+
+                // Point the host at the segment still expected instead
+                // of leaving it to time out
+                if let Some(expected) = core.expected_segment() {
+                    send(
+                        link,
+                        MessageTypeMcu::UpdateSegmentStatus {
+                            id: expected,
+                            status: Status::Retry,
+                        },
+                    )
+                    .map_err(ServeError::Link)?;
+                }
+
+                break;
+            }
+        }
+    }
+}
+
+fn send<T: Transport>(link: &mut T, msg: MessageTypeMcu) -> io::Result<()> {
+    let frame = codec::encode(&msg).expect("reply serialization cannot fail");
+
+    link.write_all(&frame)?;
+    link.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use messages::transport::{pair, Loopback};
+    use messages::{Checksum, UpdateEnd, UpdateSegment, UpdateStart};
+
+    use sha2::{Digest, Sha256};
+
+    /// A buffer standing in for flash, recording the sink lifecycle.
+    #[derive(Default)]
+    struct VecSink {
+        image: Vec<u8>,
+        begun: Option<u32>,
+        completed: bool,
+        aborted: bool,
+    }
+
+    impl SegmentSink for VecSink {
+        fn begin(&mut self, size: u32) -> Result<(), Status> {
+            self.begun = Some(size);
+            self.image.clear();
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), Status> {
+            self.image.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn complete(&mut self) -> Result<(), Status> {
+            self.completed = true;
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.aborted = true;
+        }
+    }
+
+    fn start(size: u32) -> MessageTypeHost {
+        MessageTypeHost::UpdateStart(UpdateStart {
+            size,
+            nonce_prefix: None,
+            delta_base: None,
+            partition: None,
+            sha256: None,
+            segment_size: None,
+            resume: false,
+            progress: false,
+        })
+    }
+
+    fn segment(id: u16, data: &[u8]) -> MessageTypeHost {
+        MessageTypeHost::UpdateSegment(UpdateSegment {
+            id,
+            data: data.to_vec(),
+        })
+    }
+
+    fn end(digest: [u8; 32]) -> MessageTypeHost {
+        MessageTypeHost::UpdateEnd(UpdateEnd {
+            signature: None,
+            reboot: false,
+            sha256: Some(digest),
+        })
+    }
+
+    /// The single segment status a reply list should carry.
+    fn segment_status(replies: Vec<MessageTypeMcu>) -> (u16, Status) {
+        match replies.as_slice() {
+            [MessageTypeMcu::UpdateSegmentStatus { id, status }] => (*id, *status),
+            other => panic!("expected one segment status, got {:?}", other),
+        }
+    }
+
+    /// An engine with an already-acked `UpdateStart` for `size` bytes.
+    fn started(size: u32) -> UpdaterCore<VecSink> {
+        let mut core = UpdaterCore::new(VecSink::default());
+
+        assert!(matches!(
+            core.handle(start(size)).as_slice(),
+            [MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                status: Status::Ok,
+                ..
+            })]
+        ));
+
+        core
+    }
+
+    #[test]
+    fn a_plain_transfer_lands_byte_identical() {
+        let image = b"aaaabbbbcc";
+        let mut core = started(image.len() as u32);
+
+        assert_eq!(
+            core.handle(segment(0, &image[..4])),
+            vec![MessageTypeMcu::UpdateSegmentStatus {
+                id: 0,
+                status: Status::Ok
+            }]
+        );
+        core.handle(segment(1, &image[4..8]));
+        core.handle(segment(2, &image[8..]));
+
+        assert_eq!(
+            core.handle(end(Sha256::digest(image).into())),
+            vec![MessageTypeMcu::UpdateEndStatus(Status::Ok)]
+        );
+
+        assert!(matches!(core.take_outcome(), Some(Ok(()))));
+        assert_eq!(core.summary().segments_written, 3);
+        assert_eq!(core.summary().duplicates_acked, 0);
+
+        let sink = core.into_sink();
+        assert_eq!(sink.image, image);
+        assert_eq!(sink.begun, Some(image.len() as u32));
+        assert!(sink.completed);
+        assert!(!sink.aborted);
+    }
+
+    #[test]
+    fn a_retransmitted_segment_is_acked_but_written_once() {
+        let image = b"aaaabbbb";
+        let mut core = started(image.len() as u32);
+
+        // The ack for segment 0 "got lost"; the host sends it again
+        for _ in 0..2 {
+            assert_eq!(
+                segment_status(core.handle(segment(0, &image[..4]))),
+                (0, Status::Ok)
+            );
+        }
+
+        core.handle(segment(1, &image[4..]));
+        core.handle(end(Sha256::digest(image).into()));
+
+        assert_eq!(core.summary().segments_written, 2);
+        assert_eq!(core.summary().duplicates_acked, 1);
+        assert_eq!(core.into_sink().image, image);
+    }
+
+    #[test]
+    fn an_out_of_order_segment_is_rejected() {
+        let mut core = started(8);
+
+        core.handle(segment(0, b"aaaa"));
+
+        // Skipping ahead is more than a lost ack can explain
+        assert_eq!(
+            segment_status(core.handle(segment(3, b"xxxx"))),
+            (3, Status::Failed)
+        );
+        assert_eq!(core.into_sink().image, b"aaaa");
+    }
+
+    #[test]
+    fn a_segment_without_an_update_earns_wrong_state() {
+        let mut core = UpdaterCore::new(VecSink::default());
+
+        assert_eq!(
+            segment_status(core.handle(segment(0, b"aaaa"))),
+            (0, Status::WrongState)
+        );
+        assert!(core.into_sink().image.is_empty());
+    }
+
+    #[test]
+    fn a_wrong_digest_aborts_the_sink() {
+        let image = b"aaaabbbb";
+        let mut core = started(image.len() as u32);
+
+        core.handle(segment(0, &image[..4]));
+        core.handle(segment(1, &image[4..]));
+
+        assert_eq!(
+            core.handle(end(Sha256::digest(b"something else").into())),
+            vec![MessageTypeMcu::UpdateEndStatus(Status::InvalidImage)]
+        );
+
+        assert!(matches!(
+            core.take_outcome(),
+            Some(Err(EndError::Image(ImageError::Digest)))
+        ));
+
+        let sink = core.into_sink();
+        assert!(sink.aborted);
+        assert!(!sink.completed);
+    }
+
+    #[test]
+    fn a_short_image_is_a_size_mismatch() {
+        let image = b"aaaabbbb";
+        let mut core = started(image.len() as u32 + 4);
+
+        core.handle(segment(0, &image[..4]));
+        core.handle(segment(1, &image[4..]));
+
+        assert_eq!(
+            core.handle(end(Sha256::digest(image).into())),
+            vec![MessageTypeMcu::UpdateEndStatus(Status::InvalidImage)]
+        );
+        assert!(matches!(
+            core.take_outcome(),
+            Some(Err(EndError::Image(ImageError::Size { .. })))
+        ));
+    }
+
+    #[test]
+    fn cancel_releases_the_sink_and_later_segments_are_refused() {
+        let mut core = started(8);
+        core.handle(segment(0, b"aaaa"));
+
+        assert_eq!(
+            core.handle(MessageTypeHost::Cancel),
+            vec![MessageTypeMcu::CancelStatus(Status::Ok)]
+        );
+
+        assert_eq!(
+            segment_status(core.handle(segment(1, b"bbbb"))),
+            (1, Status::WrongState)
+        );
+        assert!(core.into_sink().aborted);
+    }
+
+    #[test]
+    fn a_restart_mid_transfer_aborts_and_the_fresh_transfer_lands() {
+        let image = b"ccccddddee";
+        let mut core = started(8);
+
+        core.handle(segment(0, b"aaaa"));
+
+        // The host crashed after its handshake and was re-run: its
+        // fresh start replaces the half-done transfer - an implicit
+        // cancel-and-restart - instead of wedging the device
+        assert!(matches!(
+            core.handle(start(image.len() as u32)).as_slice(),
+            [MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                status: Status::Ok,
+                ..
+            })]
+        ));
+
+        // Segment numbering starts over with the new session
+        assert_eq!(
+            segment_status(core.handle(segment(0, &image[..4]))),
+            (0, Status::Ok)
+        );
+        core.handle(segment(1, &image[4..8]));
+        core.handle(segment(2, &image[8..]));
+        core.handle(end(Sha256::digest(image).into()));
+
+        assert!(matches!(core.take_outcome(), Some(Ok(()))));
+
+        let sink = core.into_sink();
+        assert!(sink.aborted, "the first transfer was not released");
+        assert_eq!(sink.begun, Some(image.len() as u32));
+        assert_eq!(sink.image, image);
+    }
+
+    #[test]
+    fn a_refused_begin_reports_its_reason() {
+        /// A device flashed without an OTA-capable partition table.
+        struct NoSlot;
+
+        impl SegmentSink for NoSlot {
+            fn begin(&mut self, _size: u32) -> Result<(), Status> {
+                Err(Status::NoOtaPartition)
+            }
+
+            fn write(&mut self, _data: &[u8]) -> Result<(), Status> {
+                unreachable!("nothing may be written without a begin")
+            }
+
+            fn complete(&mut self) -> Result<(), Status> {
+                unreachable!()
+            }
+
+            fn abort(&mut self) {}
+        }
+
+        let mut core = UpdaterCore::new(NoSlot);
+
+        assert!(matches!(
+            core.handle(start(8)).as_slice(),
+            [MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                status: Status::NoOtaPartition,
+                ..
+            })]
+        ));
+
+        // No transfer started; a segment anyway is out of state
+        assert_eq!(
+            segment_status(core.handle(segment(0, b"aaaa"))),
+            (0, Status::WrongState)
+        );
+    }
+
+    #[test]
+    fn a_failed_write_keeps_the_position_for_the_retry() {
+        /// A sink whose first write fails transiently.
+        struct Flaky {
+            inner: VecSink,
+            failures_left: u32,
+        }
+
+        impl SegmentSink for Flaky {
+            fn begin(&mut self, size: u32) -> Result<(), Status> {
+                self.inner.begin(size)
+            }
+
+            fn write(&mut self, data: &[u8]) -> Result<(), Status> {
+                if self.failures_left > 0 {
+                    self.failures_left -= 1;
+                    return Err(Status::Retry);
+                }
+
+                self.inner.write(data)
+            }
+
+            fn complete(&mut self) -> Result<(), Status> {
+                self.inner.complete()
+            }
+
+            fn abort(&mut self) {
+                self.inner.abort()
+            }
+        }
+
+        let image = b"aaaabbbb";
+        let mut core = UpdaterCore::new(Flaky {
+            inner: VecSink::default(),
+            failures_left: 1,
+        });
+        core.handle(start(image.len() as u32));
+
+        // The failed write surfaces the sink's status and does not
+        // advance, so the host's retry of the same id is written
+        assert_eq!(
+            segment_status(core.handle(segment(0, &image[..4]))),
+            (0, Status::Retry)
+        );
+        assert_eq!(
+            segment_status(core.handle(segment(0, &image[..4]))),
+            (0, Status::Ok)
+        );
+
+        core.handle(segment(1, &image[4..]));
+        core.handle(end(Sha256::digest(image).into()));
+
+        assert!(matches!(core.take_outcome(), Some(Ok(()))));
+        assert_eq!(core.into_sink().inner.image, image);
+    }
+
+    #[test]
+    fn pings_are_answered_without_an_update() {
+        let mut core = UpdaterCore::new(VecSink::default());
+
+        assert_eq!(
+            core.handle(MessageTypeHost::Ping),
+            vec![MessageTypeMcu::Pong]
+        );
+        assert!(matches!(
+            core.handle(MessageTypeHost::TimedPing(7)).as_slice(),
+            [MessageTypeMcu::TimedPong { nonce: 7, .. }]
+        ));
+    }
+
+    // The framing tests below drive serve() over the loopback, the way
+    // the flasher's integration tests do.
+
+    fn host_send(link: &mut Loopback, msg: MessageTypeHost) {
+        let frame = postcard::to_allocvec(&Checksum::new(msg)).unwrap();
+        link.write_all(&frame).unwrap();
+    }
+
+    fn host_recv(link: &mut Loopback, accumulated: &mut Vec<u8>) -> MessageTypeMcu {
+        let mut buf = [0_u8; 256];
+
+        loop {
+            match postcard::take_from_bytes::<Checksum<MessageTypeMcu>>(accumulated) {
+                Ok((frame, rest)) => {
+                    let consumed = accumulated.len() - rest.len();
+                    accumulated.drain(..consumed);
+
+                    assert!(frame.verify());
+                    return frame.payload;
+                }
+                Err(postcard::Error::DeserializeUnexpectedEnd) => (),
+                Err(err) => panic!("undecodable reply: {:?}", err),
+            }
+
+            let n = link
+                .read_available(&mut buf, Duration::from_secs(5))
+                .unwrap();
+            assert_ne!(n, 0, "engine closed the link");
+            accumulated.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Runs serve() in a thread and hands the test the host end plus an
+    /// already-acked `UpdateStart` for an image of `size` bytes.
+    #[allow(clippy::type_complexity)]
+    fn serving(
+        size: u32,
+    ) -> (
+        Loopback,
+        Vec<u8>,
+        thread::JoinHandle<(Result<Summary, ServeError>, VecSink)>,
+    ) {
+        let (mut host, mut device) = pair();
+
+        let served = thread::spawn(move || {
+            let mut sink = VecSink::default();
+            let result = serve(&mut device, &mut sink);
+            (result, sink)
+        });
+
+        let mut accumulated = Vec::new();
+
+        host_send(&mut host, start(size));
+        assert!(matches!(
+            host_recv(&mut host, &mut accumulated),
+            MessageTypeMcu::UpdateStartStatus(UpdateStartStatus {
+                status: Status::Ok,
+                ..
+            })
+        ));
+
+        (host, accumulated, served)
+    }
+
+    #[test]
+    fn serve_reassembles_a_framed_transfer() {
+        let image = b"aaaabbbb";
+        let (mut host, mut accumulated, served) = serving(image.len() as u32);
+
+        host_send(&mut host, segment(0, &image[..4]));
+        host_recv(&mut host, &mut accumulated);
+        host_send(&mut host, segment(1, &image[4..]));
+        host_recv(&mut host, &mut accumulated);
+
+        host_send(&mut host, end(Sha256::digest(image).into()));
+        assert!(matches!(
+            host_recv(&mut host, &mut accumulated),
+            MessageTypeMcu::UpdateEndStatus(Status::Ok)
+        ));
+
+        let (result, sink) = served.join().unwrap();
+        assert_eq!(result.unwrap().segments_written, 2);
+        assert_eq!(sink.image, image);
+        assert!(sink.completed);
+    }
+
+    #[test]
+    fn a_corrupt_frame_is_dropped_and_its_retry_is_written() {
+        let image = b"aaaabbbb";
+        let (mut host, mut accumulated, served) = serving(image.len() as u32);
+
+        // Flip a data byte inside an otherwise decodable frame: the
+        // checksum rejects it and the engine stays silent
+        let mut frame = postcard::to_allocvec(&Checksum::new(segment(0, &image[..4]))).unwrap();
+        let middle = frame.len() / 2;
+        frame[middle] ^= 0xff;
+        host.write_all(&frame).unwrap();
+
+        // The host would notice the missing ack and retransmit
+        host_send(&mut host, segment(0, &image[..4]));
+        assert!(matches!(
+            host_recv(&mut host, &mut accumulated),
+            MessageTypeMcu::UpdateSegmentStatus {
+                id: 0,
+                status: Status::Ok,
+            }
+        ));
+
+        host_send(&mut host, segment(1, &image[4..]));
+        host_recv(&mut host, &mut accumulated);
+        host_send(&mut host, end(Sha256::digest(image).into()));
+        host_recv(&mut host, &mut accumulated);
+
+        let (result, sink) = served.join().unwrap();
+        let summary = result.unwrap();
+
+        assert_eq!(sink.image, image);
+        assert_eq!(summary.crc_drops, 1);
+        assert_eq!(summary.duplicates_acked, 0);
+    }
+
+    #[test]
+    fn garbage_desynchronizes_the_stream_and_points_at_the_expected_segment() {
+        let image = b"aaaabbbb";
+        let (mut host, mut accumulated, served) = serving(image.len() as u32);
+
+        host_send(&mut host, segment(0, &image[..4]));
+        host_recv(&mut host, &mut accumulated);
+
+        // 0xff never opens a valid frame, but a scan attempt only
+        // counts as a failure while enough bytes remain for the decode
+        // to fail outright instead of looking truncated - so send well
+        // over a threshold's worth to trip the desync flush
+        host.write_all(&[0xff; 2 * DESYNC_THRESHOLD as usize])
+            .unwrap();
+
+        assert!(matches!(
+            host_recv(&mut host, &mut accumulated),
+            MessageTypeMcu::UpdateSegmentStatus {
+                id: 1,
+                status: Status::Retry,
+            }
+        ));
+
+        host_send(&mut host, segment(1, &image[4..]));
+        host_recv(&mut host, &mut accumulated);
+        host_send(&mut host, end(Sha256::digest(image).into()));
+        host_recv(&mut host, &mut accumulated);
+
+        let (result, sink) = served.join().unwrap();
+        assert_eq!(result.unwrap().desyncs, 1);
+        assert_eq!(sink.image, image);
+    }
+
+    #[test]
+    fn a_dropped_host_closes_the_engine_down() {
+        let (host, mut device) = pair();
+
+        let served = thread::spawn(move || {
+            let mut sink = VecSink::default();
+            serve(&mut device, &mut sink)
+        });
+
+        drop(host);
+        assert!(matches!(
+            served.join().unwrap(),
+            Err(ServeError::LinkClosed)
+        ));
+    }
+}